@@ -0,0 +1,143 @@
+use std::fmt;
+use std::fs;
+use std::io;
+
+use overlay_mount::{MountState, info, warn};
+
+/// What is left behind after shutdown unmounted, so an operator can tell what needs cleaning up
+/// by hand when the unmount failed part way
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CleanupReport {
+    /// `None` when the mount table couldn't be read
+    pub mount_state: Option<MountState>,
+    /// Processes this one spawned (ie rsync) that are still around
+    pub child_pids: Vec<u32>,
+}
+
+impl CleanupReport {
+    pub fn is_clean(&self) -> bool {
+        self.mount_state.as_ref().is_some_and(MountState::is_clear) && self.child_pids.is_empty()
+    }
+
+    pub fn log(&self) {
+        if self.is_clean() {
+            info!("Cleanup complete, nothing is left mounted or running");
+        } else {
+            warn!("Cleanup incomplete: {self}");
+        }
+    }
+}
+
+impl fmt::Display for CleanupReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut leftovers = Vec::new();
+        match &self.mount_state {
+            None => leftovers.push("mount state unknown, the mount table couldn't be read".into()),
+            Some(state) => {
+                if state.overlay_mounted {
+                    leftovers.push("overlay still mounted".to_string());
+                }
+                if !state.bind_targets_mounted.is_empty() {
+                    leftovers.push(format!(
+                        "bind targets still mounted: {:?}",
+                        state.bind_targets_mounted
+                    ));
+                }
+            }
+        }
+        if !self.child_pids.is_empty() {
+            leftovers.push(format!(
+                "child processes still running: {:?}",
+                self.child_pids
+            ));
+        }
+        if leftovers.is_empty() {
+            return f.write_str("nothing left behind");
+        }
+        f.write_str(&leftovers.join(", "))
+    }
+}
+
+/// Pids of the processes whose parent is this one, including exited ones not yet reaped
+pub fn child_pids() -> io::Result<Vec<u32>> {
+    let own_pid = std::process::id();
+    let mut pids = Vec::new();
+    for entry in fs::read_dir("/proc")? {
+        let entry = entry?;
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        else {
+            continue;
+        };
+        // Gone since listing /proc
+        let Ok(stat) = fs::read_to_string(entry.path().join("stat")) else {
+            continue;
+        };
+        if parent_pid(&stat) == Some(own_pid) {
+            pids.push(pid);
+        }
+    }
+    pids.sort_unstable();
+    Ok(pids)
+}
+
+/// The parent pid from a `/proc/<pid>/stat` line. The command name before it is parenthesized
+/// but may itself contain spaces and parentheses, so fields are counted from the last `)`.
+fn parent_pid(stat: &str) -> Option<u32> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(1)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    #[test]
+    fn test_parent_pid() {
+        assert_eq!(parent_pid("1234 (rsync) S 42 1234 1 0 -1"), Some(42));
+        assert_eq!(parent_pid("1234 (odd) name)) R 7 1234 1 0 -1"), Some(7));
+        assert_eq!(parent_pid("garbage"), None);
+    }
+
+    #[test]
+    fn test_child_pids() {
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        let pids = child_pids().unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(pids.contains(&child.id()), "{pids:?}");
+    }
+
+    #[test]
+    fn test_cleanup_report_display() {
+        let clean = CleanupReport {
+            mount_state: Some(MountState::default()),
+            child_pids: vec![],
+        };
+        assert!(clean.is_clean());
+
+        let unknown = CleanupReport::default();
+        assert!(!unknown.is_clean());
+        assert_eq!(
+            unknown.to_string(),
+            "mount state unknown, the mount table couldn't be read"
+        );
+
+        let report = CleanupReport {
+            mount_state: Some(MountState {
+                overlay_mounted: true,
+                bind_targets_mounted: vec![PathBuf::from("/srv/bind")],
+            }),
+            child_pids: vec![4321],
+        };
+        assert!(!report.is_clean());
+        assert_eq!(
+            report.to_string(),
+            "overlay still mounted, bind targets still mounted: [\"/srv/bind\"], child processes still running: [4321]"
+        );
+    }
+}
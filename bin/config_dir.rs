@@ -0,0 +1,77 @@
+//! Loading the configs of a drop-in directory, see `overlay_mount::load_configs_from_dir`.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+use crate::{Config, include};
+
+/// Load and validate every config in `dir`, each with its own result
+pub fn load_checked_configs(dir: &Path) -> Result<Vec<(PathBuf, Result<Config>)>> {
+    overlay_mount::load_configs_from_dir(dir, load_checked)
+        .with_context(|| format!("Failed to read config dir: {dir:?}"))
+}
+
+fn load_checked(path: &Path) -> Result<Config> {
+    let config: Config = serde_json::from_value(include::load(path, None)?)
+        .with_context(|| format!("Failed to parse config file: {path:?}"))?;
+    config
+        .mount_config
+        .check()
+        .with_context(|| format!("Invalid config: {path:?}"))?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    const VALID: &str = r#"
+[[lower_dirs]]
+volume = "/lower"
+
+[upper_dir]
+volume = "/nonexistent-upper"
+upper_subdir = "upper"
+work_subdir = "work"
+merged_subdir = "merged"
+
+[options]
+"#;
+
+    #[test]
+    fn test_load_checked_configs() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        fs::write(dir.join("b-valid.toml"), VALID).unwrap();
+        fs::write(dir.join("a-unparsable.toml"), "lower_dirs = [").unwrap();
+        fs::write(
+            dir.join("c-invalid.toml"),
+            format!("selinux_context = \"\"\n{VALID}"),
+        )
+        .unwrap();
+
+        let results = load_checked_configs(dir).unwrap();
+        let names: Vec<_> = results
+            .iter()
+            .map(|(path, _)| path.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            ["a-unparsable.toml", "b-valid.toml", "c-invalid.toml"]
+        );
+
+        let error = |index: usize| format!("{:#}", results[index].1.as_ref().unwrap_err());
+        assert!(error(0).contains("a-unparsable.toml"), "{}", error(0));
+        let valid = results[1].1.as_ref().unwrap();
+        assert_eq!(valid.mount_config.lower_dirs.len(), 1);
+        assert!(error(2).contains("selinux"), "{}", error(2));
+    }
+
+    #[test]
+    fn test_load_checked_configs_from_missing_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        assert!(load_checked_configs(&temp_dir.path().join("missing")).is_err());
+    }
+}
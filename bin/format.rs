@@ -0,0 +1,180 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::path::Path;
+
+use overlay_mount::warn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Pick the format from the file extension, falling back to TOML for anything unrecognised.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            Some("json") => ConfigFormat::Json,
+            _ => {
+                warn!("unknown config extension for {path:?}, parsing as TOML");
+                ConfigFormat::Toml
+            }
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(&self, content: &str) -> Result<T> {
+        match self {
+            ConfigFormat::Toml => toml::from_str(content).context("invalid TOML"),
+            ConfigFormat::Yaml => serde_yaml::from_str(content).context("invalid YAML"),
+            ConfigFormat::Json => serde_json::from_str(content).context("invalid JSON"),
+        }
+    }
+
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<String> {
+        match self {
+            ConfigFormat::Toml => toml::to_string_pretty(value).context("failed to write TOML"),
+            ConfigFormat::Yaml => serde_yaml::to_string(value).context("failed to write YAML"),
+            ConfigFormat::Json => {
+                serde_json::to_string_pretty(value).context("failed to write JSON")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    const TOML_CONFIG: &str = r#"
+allowed_masked_files = ["allowed.txt"]
+
+[[lower_dirs]]
+volume = "/lower"
+subdir = "sub"
+
+[[lower_dirs]]
+volume = "/synced"
+sync_mode = { constant = "/synced-target" }
+numeric_ids = true
+
+[upper_dir]
+volume = "/upper"
+upper_subdir = "upper"
+work_subdir = "work"
+merged_subdir = "merged"
+
+[options]
+resync_interval_seconds = 60
+"#;
+
+    const YAML_CONFIG: &str = r#"
+allowed_masked_files: [allowed.txt]
+lower_dirs:
+  - volume: /lower
+    subdir: sub
+  - volume: /synced
+    sync_mode:
+      constant: /synced-target
+    numeric_ids: true
+upper_dir:
+  volume: /upper
+  upper_subdir: upper
+  work_subdir: work
+  merged_subdir: merged
+options:
+  resync_interval_seconds: 60
+"#;
+
+    const JSON_CONFIG: &str = r#"
+{
+  "allowed_masked_files": ["allowed.txt"],
+  "lower_dirs": [
+    {"volume": "/lower", "subdir": "sub"},
+    {"volume": "/synced", "sync_mode": {"constant": "/synced-target"}, "numeric_ids": true}
+  ],
+  "upper_dir": {
+    "volume": "/upper",
+    "upper_subdir": "upper",
+    "work_subdir": "work",
+    "merged_subdir": "merged"
+  },
+  "options": {"resync_interval_seconds": 60}
+}
+"#;
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("a.toml")),
+            ConfigFormat::Toml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("a.yaml")),
+            ConfigFormat::Yaml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("a.yml")),
+            ConfigFormat::Yaml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("a.json")),
+            ConfigFormat::Json
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("a.conf")),
+            ConfigFormat::Toml
+        );
+        assert_eq!(ConfigFormat::from_path(Path::new("a")), ConfigFormat::Toml);
+    }
+
+    #[test]
+    fn test_all_formats_equal() {
+        let toml: Config = ConfigFormat::Toml.parse(TOML_CONFIG).unwrap();
+        let yaml: Config = ConfigFormat::Yaml.parse(YAML_CONFIG).unwrap();
+        let json: Config = ConfigFormat::Json.parse(JSON_CONFIG).unwrap();
+
+        assert_eq!(toml.mount_config, yaml.mount_config);
+        assert_eq!(toml.mount_config, json.mount_config);
+        assert_eq!(toml.mount_config.lower_dirs.len(), 2);
+        assert!(toml.mount_config.lower_dirs[1].sync_options().numeric_ids);
+    }
+
+    #[test]
+    fn test_print_config_round_trip() {
+        let minimal = r#"
+[[lower_dirs]]
+volume = "/lower"
+
+[upper_dir]
+volume = "/upper"
+upper_subdir = "upper"
+work_subdir = "work"
+merged_subdir = "merged"
+
+[options]
+"#;
+
+        for format in [ConfigFormat::Toml, ConfigFormat::Yaml, ConfigFormat::Json] {
+            let config: Config = ConfigFormat::Toml.parse(minimal).unwrap();
+            let printed = format.serialize(&config).unwrap();
+            let reparsed: Config = format.parse(&printed).unwrap();
+
+            assert_eq!(config.mount_config, reparsed.mount_config);
+            assert_eq!(config.options, reparsed.options);
+            // Defaults are made visible in the printed config
+            assert!(printed.contains("resync_interval_seconds"), "{printed}");
+            assert_eq!(reparsed.options.resync_interval_seconds, 300);
+        }
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let result: Result<Config> = ConfigFormat::Json.parse(TOML_CONFIG);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bumped whenever a field is removed or changes meaning, adding fields keeps the version
+pub const HEALTH_REPORT_VERSION: u32 = 1;
+
+/// The JSON object written to the success file once mounted and rewritten after every resync.
+/// Times are seconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct HealthReport {
+    pub version: u32,
+    pub mounted_at: u64,
+    /// When the last regular sync finished, the initial syncs finish before mounting so this
+    /// starts out at `mounted_at`
+    pub last_sync_at: u64,
+    /// Whether every target synced successfully in the last sync
+    pub healthy: bool,
+}
+
+impl HealthReport {
+    pub fn new(mounted_at: u64) -> Self {
+        Self {
+            version: HEALTH_REPORT_VERSION,
+            mounted_at,
+            last_sync_at: mounted_at,
+            healthy: true,
+        }
+    }
+
+    /// Record a finished sync
+    pub fn synced(&mut self, at: u64, healthy: bool) {
+        self.last_sync_at = at;
+        self.healthy = healthy;
+    }
+}
+
+pub fn unix_now() -> Result<u64> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("Failed to get current time")?
+        .as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_report_round_trip() {
+        let mut report = HealthReport::new(1_700_000_000);
+        report.synced(1_700_000_300, false);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "version": HEALTH_REPORT_VERSION,
+                "mounted_at": 1_700_000_000u64,
+                "last_sync_at": 1_700_000_300u64,
+                "healthy": false,
+            })
+        );
+        assert_eq!(
+            serde_json::from_value::<HealthReport>(json).unwrap(),
+            report
+        );
+    }
+}
@@ -0,0 +1,326 @@
+//! Composition of configs from multiple files via a top-level `include` key.
+//!
+//! Each file may list other config files in `include = ["base.toml", ...]`, resolved relative to
+//! the including file. Included files are merged in order and the including file is merged last,
+//! so later files take precedence:
+//!
+//! * tables are merged key by key, recursively
+//! * scalars and arrays from the later file replace the earlier value
+//! * a key written as `"+name"` appends its array to the existing `name` array instead of
+//!   replacing it, e.g. `[["+lower_dirs"]]` adds a layer to the ones from the base config
+//!
+//! Files are parsed according to their own extension so TOML, YAML and JSON can be mixed.
+
+use anyhow::{Context, Result, bail};
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::format::ConfigFormat;
+
+const INCLUDE_KEY: &str = "include";
+const APPEND_PREFIX: char = '+';
+
+/// Read `path`, resolve its includes and return the merged document. `path` is parsed as
+/// `format` if given and by its extension otherwise, the files it includes always go by theirs.
+pub fn load(path: &Path, format: Option<ConfigFormat>) -> Result<Value> {
+    let mut merged = Value::Object(Map::new());
+    load_into(path, format, &mut merged, &mut Vec::new())?;
+    Ok(merged)
+}
+
+fn load_into(
+    path: &Path,
+    format: Option<ConfigFormat>,
+    merged: &mut Value,
+    stack: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let canonical =
+        fs::canonicalize(path).with_context(|| format!("Failed to read config file: {path:?}"))?;
+    if stack.contains(&canonical) {
+        bail!("config include cycle detected at {path:?}");
+    }
+
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file: {path:?}"))?;
+    let value = format
+        .unwrap_or_else(|| ConfigFormat::from_path(path))
+        .parse(&content)
+        .with_context(|| format!("Failed to parse config file: {path:?}"))?;
+
+    stack.push(canonical);
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+    merge_with_includes(value, path, base_dir, merged, stack)?;
+    stack.pop();
+    Ok(())
+}
+
+/// Parse a config that doesn't come from a file, eg stdin, relative includes are resolved against
+/// `base_dir`. `source` names where it came from in errors.
+pub fn load_str(
+    content: &str,
+    format: ConfigFormat,
+    source: &str,
+    base_dir: &Path,
+) -> Result<Value> {
+    let value = format
+        .parse(content)
+        .with_context(|| format!("Failed to parse config from {source}"))?;
+    let mut merged = Value::Object(Map::new());
+    merge_with_includes(
+        value,
+        Path::new(source),
+        base_dir,
+        &mut merged,
+        &mut Vec::new(),
+    )?;
+    Ok(merged)
+}
+
+/// Merge the includes listed in `value` and then `value` itself into `merged`, `path` is only
+/// used in errors
+fn merge_with_includes(
+    mut value: Value,
+    path: &Path,
+    base_dir: &Path,
+    merged: &mut Value,
+    stack: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let includes = match value
+        .as_object_mut()
+        .and_then(|obj| obj.remove(INCLUDE_KEY))
+    {
+        Some(includes) => serde_json::from_value::<Vec<PathBuf>>(includes)
+            .with_context(|| format!("'{INCLUDE_KEY}' must be a list of paths in {path:?}"))?,
+        None => Vec::new(),
+    };
+
+    for include in includes {
+        let include_path = base_dir.join(&include);
+        load_into(&include_path, None, merged, stack)
+            .with_context(|| format!("Failed to include {include:?} from {path:?}"))?;
+    }
+
+    merge(merged, value);
+    Ok(())
+}
+
+/// Deep-merge `overlay` on top of `base` following the rules in the module docs
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                if let Some(name) = key.strip_prefix(APPEND_PREFIX) {
+                    match (base.get_mut(name), value) {
+                        (Some(Value::Array(existing)), Value::Array(extra)) => {
+                            existing.extend(extra)
+                        }
+                        (_, value) => {
+                            base.insert(name.to_string(), value);
+                        }
+                    }
+                    continue;
+                }
+
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use tempfile::TempDir;
+
+    const BASE: &str = r#"
+allowed_masked_files = ["base.txt"]
+
+[[lower_dirs]]
+volume = "/base-lower"
+
+[upper_dir]
+volume = "/upper"
+upper_subdir = "upper"
+work_subdir = "work"
+merged_subdir = "merged"
+
+[options]
+resync_interval_seconds = 10
+sync_timeout_seconds = 20
+"#;
+
+    fn write(dir: &Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn load_config(path: &Path) -> Result<Config> {
+        Ok(serde_json::from_value(load(path, None)?)?)
+    }
+
+    #[test]
+    fn test_no_include() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = write(temp_dir.path(), "base.toml", BASE);
+
+        let config = load_config(&path).unwrap();
+        assert_eq!(config.mount_config.lower_dirs.len(), 1);
+        assert_eq!(config.options.resync_interval_seconds, 10);
+    }
+
+    #[test]
+    fn test_include_precedence() {
+        let temp_dir = TempDir::new().unwrap();
+        write(temp_dir.path(), "conf/base.toml", BASE);
+        write(
+            temp_dir.path(),
+            "conf/overrides.json",
+            r#"{"options": {"sync_timeout_seconds": 30}}"#,
+        );
+        let path = write(
+            temp_dir.path(),
+            "conf/service.toml",
+            r#"
+include = ["base.toml", "overrides.json"]
+
+[options]
+resync_interval_seconds = 5
+"#,
+        );
+
+        let config = load_config(&path).unwrap();
+        // Field level merge: the including file wins, overrides win over the base
+        assert_eq!(config.options.resync_interval_seconds, 5);
+        assert_eq!(config.options.sync_timeout_seconds, 30);
+        // Untouched tables survive from the base
+        assert_eq!(config.mount_config.lower_dirs.len(), 1);
+        assert_eq!(
+            config.mount_config.upper_dir.upper_path(),
+            PathBuf::from("/upper/upper")
+        );
+    }
+
+    #[test]
+    fn test_include_list_replace_and_append() {
+        let temp_dir = TempDir::new().unwrap();
+        write(temp_dir.path(), "base.toml", BASE);
+        let path = write(
+            temp_dir.path(),
+            "service.toml",
+            r#"
+include = ["base.toml"]
+allowed_masked_files = ["service.txt"]
+
+[["+lower_dirs"]]
+volume = "/service-lower"
+"#,
+        );
+
+        let config = load_config(&path).unwrap();
+        let lowers: Vec<_> = config
+            .mount_config
+            .lower_dirs
+            .iter()
+            .map(|lower| lower.full_path())
+            .collect();
+        assert_eq!(
+            lowers,
+            [
+                PathBuf::from("/base-lower"),
+                PathBuf::from("/service-lower")
+            ]
+        );
+        assert_eq!(
+            config.mount_config.allowed_masked_files,
+            [PathBuf::from("service.txt")].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_nested_include_relative_to_includer() {
+        let temp_dir = TempDir::new().unwrap();
+        write(temp_dir.path(), "shared/base.toml", BASE);
+        write(
+            temp_dir.path(),
+            "shared/middle.toml",
+            "include = [\"base.toml\"]\n[options]\nsync_timeout_seconds = 99\n",
+        );
+        let path = write(
+            temp_dir.path(),
+            "service.toml",
+            "include = [\"shared/middle.toml\"]\n",
+        );
+
+        let config = load_config(&path).unwrap();
+        assert_eq!(config.options.resync_interval_seconds, 10);
+        assert_eq!(config.options.sync_timeout_seconds, 99);
+    }
+
+    #[test]
+    fn test_missing_include() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = write(
+            temp_dir.path(),
+            "service.toml",
+            "include = [\"missing.toml\"]\n",
+        );
+
+        let err = load(&path, None).unwrap_err();
+        assert!(format!("{err:#}").contains("missing.toml"), "{err:#}");
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let temp_dir = TempDir::new().unwrap();
+        write(temp_dir.path(), "a.toml", "include = [\"b.toml\"]\n");
+        let path = write(temp_dir.path(), "b.toml", "include = [\"a.toml\"]\n");
+
+        let err = load(&path, None).unwrap_err();
+        assert!(format!("{err:#}").contains("cycle"), "{err:#}");
+    }
+
+    #[test]
+    fn test_load_with_format() {
+        let temp_dir = TempDir::new().unwrap();
+        write(temp_dir.path(), "base.toml", BASE);
+        // Parsed as the given format despite the extension, the include still by its own
+        let path = write(
+            temp_dir.path(),
+            "config.conf",
+            "{\"include\": [\"base.toml\"], \"options\": {\"resync_interval_seconds\": 20}}",
+        );
+        let config: Config =
+            serde_json::from_value(load(&path, Some(ConfigFormat::Json)).unwrap()).unwrap();
+        assert_eq!(config.options.resync_interval_seconds, 20);
+        assert_eq!(config.mount_config.lower_dirs.len(), 1);
+
+        // Errors name the file
+        let broken = write(temp_dir.path(), "broken.conf", "not = [valid");
+        let err = load(&broken, Some(ConfigFormat::Toml)).unwrap_err();
+        assert!(format!("{err:#}").contains("broken.conf"), "{err:#}");
+        assert!(!format!("{err:#}").contains("stdin"), "{err:#}");
+
+        // Including itself is caught at the top level
+        let looped = write(temp_dir.path(), "loop.conf", "include = [\"loop.conf\"]\n");
+        let err = load(&looped, Some(ConfigFormat::Toml)).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            format!(
+                "Failed to include \"loop.conf\" from {looped:?}: config include cycle detected at {:?}",
+                temp_dir.path().join("loop.conf")
+            )
+        );
+    }
+}
@@ -1,32 +1,175 @@
 use anyhow::{Context, Result};
-use clap::Parser;
-use serde::Deserialize;
-use signal_hook::{consts::SIGINT, consts::SIGTERM, iterator::Signals};
-use std::fs;
-use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use nix::mount::MsFlags;
+use serde::{Deserialize, Serialize};
+use signal_hook::{
+    consts::SIGHUP, consts::SIGINT, consts::SIGTERM, consts::SIGUSR1, iterator::Signals,
+};
+use std::collections::hash_map::RandomState;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex, OnceLock};
 use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime};
 
-use overlay_mount::{OverlayManager, config::MountConfig, rsync::SyncManager, rsync::SyncResult};
+use overlay_mount::{
+    DEFAULT_DMESG_LINES, ManagerError, MountState, OverlayManager,
+    config::{MountConfig, UpperDir, ValidationReport},
+    enter_private_namespace, info,
+    rsync::{SyncError, SyncManager, SyncResult},
+    warn,
+};
+
+use cleanup::CleanupReport;
+use format::ConfigFormat;
+use health::HealthReport;
+use pidfile::PidFile;
+use signals::{SignalAction, SignalState};
+use watchdog::Heartbeat;
+
+mod cleanup;
+mod config_dir;
+mod format;
+mod health;
+mod include;
+mod pidfile;
+mod signals;
+mod watchdog;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Path to TOML configuration file
+    /// Path to configuration file, format is picked from the extension (toml, yaml/yml, json).
+    /// With `validate` this can also be a directory of `*.toml` configs, which are all checked.
+    /// `-` reads the config from stdin.
     #[arg(long)]
     config: PathBuf,
+
+    /// Format of the config, overriding the extension. Defaults to TOML for stdin.
+    #[arg(long, value_enum)]
+    config_format: Option<ConfigFormat>,
+
+    /// Directory relative includes and lower dir volumes of a config read from stdin are resolved
+    /// against, unless it sets `base_volume`. Defaults to the working directory.
+    #[arg(long)]
+    config_base: Option<PathBuf>,
+
+    /// Print the effective config, with defaults applied, in the same format as the input and exit
+    #[arg(long)]
+    print_config: bool,
+
+    /// Mount, run the initial syncs and write the success file, then exit leaving the overlay
+    /// mounted (eg for use in an init container) instead of running the resync loop
+    #[arg(long)]
+    oneshot: bool,
+
+    /// Defaults to mounting and running the resync loop
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the changes a sync would make to every synced lower dir, without applying them
+    SyncDiff,
+    /// Validate the config against the dirs on disk and report any masked files, or only whether
+    /// each is valid for a directory of configs
+    Validate {
+        /// Print the result as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the files more than one lower dir provides, of which only the highest precedence
+    /// copy is visible in the merged dir
+    Lint,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Options {
     show_dmesg: Option<bool>,
+    /// How many of the most recent dmesg lines to capture when the mount fails
+    #[serde(default = "default_dmesg_lines")]
+    dmesg_lines: usize,
+    /// Written with a JSON `HealthReport` once mounted and after every resync
     success_file: Option<PathBuf>,
+    /// Refuse to start if another live instance holds this pid file
+    pid_file: Option<PathBuf>,
     #[serde(default = "default_resync_interval")]
     resync_interval_seconds: u64,
+    /// Randomise each resync interval within +/- this many seconds so a fleet started at the same
+    /// time doesn't hit the sources in lockstep
+    #[serde(default)]
+    resync_jitter_seconds: u64,
     #[serde(default = "default_sync_timeout")]
     sync_timeout_seconds: u64,
+    /// Upper bound on the total time spent on the initial syncs before mounting
+    startup_timeout_seconds: Option<u64>,
+    /// Log the disk usage of the upper layer after every resync
+    #[serde(default)]
+    log_upper_usage: bool,
+    /// Disk usage of the upper layer above which `upper_size_limit_action` is taken, checked
+    /// after every resync
+    upper_size_limit_bytes: Option<u64>,
+    #[serde(default)]
+    upper_size_limit_action: UpperLimitAction,
+    /// Hold back the success file until the first resync has succeeded for every `Constant`
+    /// lower dir, instead of writing it as soon as the overlay is mounted. Overlays without any
+    /// are ready straight away, as are oneshot runs.
+    #[serde(default)]
+    success_after_first_sync: bool,
+    /// Shut down if the resync loop makes no progress for this long, eg stuck on a hung
+    /// filesystem. Waiting for the next sync, finishing the sync of a lower dir and each of the
+    /// checks after a sync count as progress, so this has to be longer than the slowest of those
+    /// takes on its own. `sync_timeout_seconds` doesn't bound a sync's run time. Watching starts
+    /// with the loop, after mounting and the `ready_delay_seconds` wait.
+    watchdog_timeout_seconds: Option<u64>,
+    /// How long the watchdog gives the graceful shutdown before detaching and exiting
+    #[serde(default = "default_watchdog_grace")]
+    watchdog_grace_seconds: u64,
+    /// Wait this long after mounting before writing the success file, for consumers that race
+    /// the overlay becoming usable in their namespace. A shutdown signal cuts the wait short.
+    #[serde(default)]
+    ready_delay_seconds: u64,
+}
+
+impl Options {
+    /// Reject combinations that can't work, before anything is mounted
+    fn check(&self) -> Result<()> {
+        if let Some(timeout) = self.watchdog_timeout_seconds {
+            // Even an idle loop only pets the heartbeat this often
+            if Duration::from_secs(timeout) <= WAIT_HEARTBEAT_INTERVAL {
+                anyhow::bail!(
+                    "watchdog_timeout_seconds ({timeout}) must be longer than {WAIT_HEARTBEAT_INTERVAL:?}"
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How often `LoopFlags::wait` pets the heartbeat while it sleeps
+const WAIT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What to do when the upper layer grows past `upper_size_limit_bytes`. The action is taken when
+/// the limit is first exceeded, and again if usage drops back under and then exceeds it again.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum UpperLimitAction {
+    #[default]
+    Warn,
+    /// Write a description of the problem to this file, eg for a probe to pick up
+    FailureFile(PathBuf),
+    /// Stop further writes by remounting the overlay read only
+    RemountReadOnly,
+}
+
+fn default_dmesg_lines() -> usize {
+    DEFAULT_DMESG_LINES
 }
 
 fn default_resync_interval() -> u64 {
@@ -37,7 +180,11 @@ fn default_sync_timeout() -> u64 {
     1800 // 30 minutes
 }
 
-#[derive(Debug, Clone, Deserialize)]
+fn default_watchdog_grace() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     #[serde(flatten)]
     mount_config: MountConfig,
@@ -45,116 +192,1571 @@ pub struct Config {
     options: Options,
 }
 
+/// `--config` value that reads the config from stdin
+const STDIN_CONFIG: &str = "-";
+
+/// Read a config without a file behind it, such as stdin. There is no config path for relative
+/// paths to be resolved against so `base_dir` is used for includes and, unless the config sets
+/// `base_volume`, lower dir volumes.
+fn load_config_from(
+    mut reader: impl io::Read,
+    format: ConfigFormat,
+    base_dir: &Path,
+) -> Result<Config> {
+    let mut content = String::new();
+    reader
+        .read_to_string(&mut content)
+        .context("Failed to read config from stdin")?;
+    let mut config: Config =
+        serde_json::from_value(include::load_str(&content, format, "stdin", base_dir)?)
+            .context("Failed to parse config from stdin")?;
+    config
+        .mount_config
+        .base_volume
+        .get_or_insert_with(|| base_dir.to_path_buf());
+    Ok(config)
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
 
-    // Read and parse TOML config
-    let config_content = fs::read_to_string(&args.config)
-        .with_context(|| format!("Failed to read config file: {:?}", args.config))?;
+    #[cfg(feature = "tracing")]
+    tracing_subscriber::fmt().json().init();
+
+    if let Some(Command::Validate { json }) = args.command
+        && args.config.is_dir()
+    {
+        return validate_dir(&args.config, json);
+    }
+
+    // Read and parse config, including any files it pulls in
+    let from_stdin = args.config == Path::new(STDIN_CONFIG);
+    let config = if from_stdin {
+        let base_dir = std::path::absolute(args.config_base.as_deref().unwrap_or(Path::new(".")))
+            .context("Failed to resolve the config base dir")?;
+        load_config_from(
+            io::stdin().lock(),
+            args.config_format.unwrap_or(ConfigFormat::Toml),
+            &base_dir,
+        )?
+    } else {
+        let value = include::load(&args.config, args.config_format)?;
+        serde_json::from_value(value)
+            .with_context(|| format!("Failed to parse config file: {:?}", args.config))?
+    };
+
+    if args.print_config {
+        let format = match args.config_format {
+            Some(format) => format,
+            None if from_stdin => ConfigFormat::Toml,
+            None => ConfigFormat::from_path(&args.config),
+        };
+        print!("{}", format.serialize(&config)?);
+        return Ok(());
+    }
 
-    let config: Config = toml::from_str(&config_content)
-        .with_context(|| format!("Failed to parse config file: {:?}", args.config))?;
+    match args.command {
+        Some(Command::SyncDiff) => return sync_diff(&config.mount_config),
+        Some(Command::Validate { json }) => return validate(&config.mount_config, json),
+        Some(Command::Lint) => return lint(&config.mount_config),
+        None => {}
+    }
 
-    println!("Config: {config:#?}");
+    info!("Config: {config:#?}");
 
     let options = config.options;
+    options.check()?;
+
+    let _pid_file = options
+        .pid_file
+        .as_deref()
+        .map(PidFile::acquire)
+        .transpose()?;
+
+    // Before any thread is spawned, otherwise the signal thread stays behind in the original
+    // namespace and a forced exit can't detach the overlay
+    if config.mount_config.private_namespace {
+        enter_private_namespace()?;
+    }
 
     // Setup signal handling
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
+    let flags = Arc::new(LoopFlags::new());
+    let f = flags.clone();
 
-    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    let forced_exit = Arc::new(ForcedExit {
+        manager: OnceLock::new(),
+        upper_dir: config.mount_config.upper_dir.clone(),
+        pid_file: options.pid_file.clone(),
+    });
+    let exit = forced_exit.clone();
+
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP, SIGUSR1])
+        .context("Failed to register signal handlers")?;
     thread::spawn(move || {
+        let mut state = SignalState::default();
         for sig in signals.forever() {
-            println!("Received interrupt signal {sig:?}, shutting down...");
-            r.store(false, Ordering::SeqCst);
+            match state.handle(sig) {
+                SignalAction::ResyncOnce => {
+                    info!("Received SIGHUP, re-syncing once mode lower dirs");
+                    f.set(&f.resync_once, true);
+                }
+                SignalAction::SyncNow => {
+                    info!("Received SIGUSR1, syncing now");
+                    f.set(&f.sync_now, true);
+                }
+                SignalAction::Shutdown => {
+                    info!("Received interrupt signal {sig:?}, shutting down...");
+                    f.set(&f.running, false);
+                }
+                SignalAction::ForceExit => {
+                    warn!(
+                        "Received interrupt signal {sig:?} during shutdown, detaching and exiting now"
+                    );
+                    exit.exit()
+                }
+            }
         }
+        warn!("Signal handling stopped, further signals will be ignored");
     });
 
+    // Everything from here to the overlay being mounted runs with the tmpfs upper (if any)
+    // mounted, so it has to go again if any of it fails
+    let result = mount_and_maintain(
+        config.mount_config,
+        &options,
+        &flags,
+        &forced_exit,
+        args.oneshot,
+    );
+    if result.is_err() {
+        match forced_exit.upper_dir.release_tmpfs() {
+            Ok(true) => info!("Unmounted the tmpfs upper after the failed start"),
+            Ok(false) => {}
+            Err(e) => warn!("Failed to unmount the tmpfs upper: {e}"),
+        }
+    }
+    result
+}
+
+/// Validate, sync and mount, then keep the overlay maintained until shutdown
+fn mount_and_maintain(
+    mount_config: MountConfig,
+    options: &Options,
+    flags: &Arc<LoopFlags>,
+    forced_exit: &Arc<ForcedExit>,
+    oneshot: bool,
+) -> Result<()> {
     // Validate config and create manager
-    let validated_config = config
-        .mount_config
+    let validated_config = mount_config
         .validate()
         .context("Failed to validate config")?;
 
-    let (mut sync_manager, synced_config) = match SyncManager::new(validated_config) {
-        Ok(res) => res,
-        Err((path, err)) => {
-            return Err(err).context(format!("failed to sync: {path:?}"));
+    let startup_timeout = options.startup_timeout_seconds.map(Duration::from_secs);
+    let (mut sync_manager, synced_config) =
+        SyncManager::new_with_startup_timeout(validated_config, startup_timeout)?;
+
+    Into::<&MountConfig>::into(&synced_config)
+        .preflight()
+        .context("Lower dir preflight check failed")?;
+
+    let manager = OverlayManager::new(synced_config)
+        .context("Failed to create overlay manager")?
+        .with_dmesg_lines(options.dmesg_lines);
+    let manager = forced_exit.manager.get_or_init(|| manager);
+
+    let mut over_limit = false;
+    let defer_success = options.success_after_first_sync && sync_manager.has_constant_targets();
+    run(
+        manager,
+        options,
+        flags,
+        oneshot,
+        defer_success,
+        |mut report, mut published| {
+            // Only the loop is watched, mounting and the ready delay happen before it
+            if let Some(timeout) = options.watchdog_timeout_seconds {
+                spawn_watchdog(
+                    flags.clone(),
+                    Duration::from_secs(timeout),
+                    Duration::from_secs(options.watchdog_grace_seconds),
+                    forced_exit.clone(),
+                );
+            }
+            let result = maintenance_loop(flags, options, &mut sync_manager, |healthy| {
+                check_upper_usage(manager, options, &mut over_limit);
+                flags.heartbeat.pet();
+                update_success_file(options, &mut report, &mut published, healthy);
+                flags.heartbeat.pet();
+                manager
+                    .check_lower_available()
+                    .context("Lower dir became unavailable")
+            });
+            // The unmount that follows may retry for a while, that isn't the loop stalling
+            flags.heartbeat.stop();
+            result
+        },
+    )
+}
+
+/// Watch the maintenance loop's heartbeat, shutting down once it goes stale
+fn spawn_watchdog(
+    flags: Arc<LoopFlags>,
+    timeout: Duration,
+    grace: Duration,
+    forced_exit: Arc<ForcedExit>,
+) {
+    // Whatever ran before the loop (eg the initial syncs and mounting) doesn't count
+    flags.heartbeat.pet();
+    thread::spawn(move || {
+        let Some(stale_for) = watchdog::wait_for_stall(&flags.heartbeat, timeout, timeout / 4)
+        else {
+            return;
+        };
+        warn!("Resync loop made no progress for {stale_for:?}, shutting down");
+        handle_stall(&flags, grace, || {
+            warn!(
+                "Shutdown did not finish within {grace:?} of the loop stalling, detaching and exiting now"
+            );
+            forced_exit.exit()
+        });
+    });
+}
+
+/// Ask the maintenance loop to stop, calling `exit` if the process is still around after `grace`
+fn handle_stall(flags: &LoopFlags, grace: Duration, exit: impl FnOnce()) {
+    flags.set(&flags.running, false);
+    thread::sleep(grace);
+    exit();
+}
+
+/// What giving up on the graceful shutdown still has to clean up, exiting skips the destructors
+/// (eg the `PidFile`'s) and the tmpfs release that normally do it
+struct ForcedExit {
+    /// Filled in once the manager exists, so the overlay can be detached
+    manager: OnceLock<OverlayManager>,
+    upper_dir: UpperDir,
+    pid_file: Option<PathBuf>,
+}
+
+impl ForcedExit {
+    /// Detach the overlay if it got as far as being set up, release the tmpfs upper and remove
+    /// the pid file, then exit
+    fn exit(&self) -> ! {
+        match self.manager.get() {
+            // Takes the tmpfs with it
+            Some(manager) => {
+                if let Err(e) = manager.detach() {
+                    warn!("Failed to detach overlay: {e}");
+                }
+            }
+            None => {
+                if let Err(e) = self.upper_dir.release_tmpfs() {
+                    warn!("Failed to unmount the tmpfs upper: {e}");
+                }
+            }
         }
-    };
+        if let Some(pid_file) = &self.pid_file
+            && let Err(e) = fs::remove_file(pid_file)
+            && e.kind() != io::ErrorKind::NotFound
+        {
+            warn!("Failed to remove pid file {pid_file:?}: {e}");
+        }
+        std::process::exit(1)
+    }
+}
+
+/// The parts of the overlay manager `run` and the maintenance loop drive, split out so the
+/// control flow can be exercised without mounting anything.
+trait Mounter {
+    fn mount(&self) -> Result<(), ManagerError>;
+    fn umount(&self) -> Result<(), ManagerError>;
+    fn remount(&self, new_flags: MsFlags) -> Result<(), ManagerError>;
+    fn upper_usage(&self) -> io::Result<u64>;
+    fn mount_state(&self) -> Result<MountState, ManagerError>;
+}
 
-    let manager = OverlayManager::new(synced_config).context("Failed to create overlay manager")?;
+impl Mounter for OverlayManager {
+    fn mount(&self) -> Result<(), ManagerError> {
+        OverlayManager::mount(self)
+    }
+
+    fn umount(&self) -> Result<(), ManagerError> {
+        let attempts = OverlayManager::umount(self)?;
+        if attempts > 1 {
+            info!("Unmounted overlay after {attempts} attempts");
+        }
+        Ok(())
+    }
+
+    fn remount(&self, new_flags: MsFlags) -> Result<(), ManagerError> {
+        OverlayManager::remount(self, new_flags)
+    }
+
+    fn upper_usage(&self) -> io::Result<u64> {
+        OverlayManager::upper_usage(self)
+    }
+
+    fn mount_state(&self) -> Result<MountState, ManagerError> {
+        OverlayManager::mount_state(self)
+    }
+}
 
+/// Mount, publish readiness and then either return straight away (`oneshot`, leaving the overlay
+/// mounted) or run `maintain` until shutdown and unmount. `maintain` gets the report and whether
+/// it was published, with `defer_success` that is left to the first successful resync. A shutdown
+/// during the `ready_delay_seconds` wait skips straight to unmounting.
+fn run(
+    manager: &impl Mounter,
+    options: &Options,
+    flags: &LoopFlags,
+    oneshot: bool,
+    defer_success: bool,
+    maintain: impl FnOnce(HealthReport, bool) -> Result<()>,
+) -> Result<()> {
     // Mount the overlay
     if let Err(e) = manager.mount() {
-        if options.show_dmesg.unwrap_or(false) {
-            if let overlay_mount::ManagerError::MountError(_, Ok(dmesg_lines)) = &e {
-                eprintln!("Recent dmesg output:");
-                for line in dmesg_lines {
-                    eprintln!("  {line}");
-                }
+        if options.show_dmesg.unwrap_or(false)
+            && let ManagerError::MountError(_, Ok(dmesg_lines)) = &e
+        {
+            eprintln!("Recent dmesg output:");
+            for line in dmesg_lines {
+                eprintln!("  {line}");
             }
         }
         return Err(anyhow::Error::from(e).context("Failed to mount overlay"));
     }
 
-    println!("Overlay mount setup complete.");
-    match post_mount(running, options, &mut sync_manager) {
-        Ok(_) => manager.umount().context("Error during cleanup"),
-        Err(run_err) => match manager.umount() {
+    info!("Overlay mount setup complete.");
+    let ready_delay = Duration::from_secs(options.ready_delay_seconds);
+    if !ready_delay.is_zero() {
+        info!("Waiting {ready_delay:?} before publishing readiness");
+    }
+    let result = if flags.wait_unless_stopped(ready_delay) {
+        // A oneshot run never resyncs, its initial syncs are all there is to wait for
+        let publish = oneshot || !defer_success;
+        let result = health::unix_now().and_then(|now| {
+            let report = HealthReport::new(now);
+            if publish {
+                write_success_file(options, &report)?;
+            } else {
+                info!("Waiting for the first resync before writing the success file");
+            }
+            Ok(report)
+        });
+        match result {
+            Ok(_) if oneshot => {
+                info!("Oneshot mode, exiting and leaving the overlay mounted");
+                return Ok(());
+            }
+            Ok(report) => maintain(report, publish),
+            Err(e) => Err(e),
+        }
+    } else {
+        info!("Shutdown requested before the ready delay passed");
+        Ok(())
+    };
+
+    let umount = manager.umount();
+    let cleanup = cleanup_report(manager);
+    cleanup.log();
+    match result {
+        Ok(_) => umount
+            .context("Error during cleanup")
+            .with_context(|| format!("Cleanup incomplete: {cleanup}")),
+        Err(run_err) => match umount {
             Ok(_) => Err(run_err).context("Error during maintenance loop"),
             Err(umount_err) => Err(umount_err)
                 .context("failed umount")
-                .with_context(|| format!("after getting error: {run_err:?}")),
+                .with_context(|| format!("after getting error: {run_err:?}"))
+                .with_context(|| format!("Cleanup incomplete: {cleanup}")),
         },
     }
 }
 
-fn post_mount(
-    running: Arc<AtomicBool>,
-    options: Options,
-    sync_manager: &mut SyncManager,
-) -> Result<()> {
-    // Create success file if specified
-    if let Some(success_file) = &options.success_file {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .context("Failed to get current time")?
-            .as_secs();
+/// Look at what the unmount at shutdown left behind, failures to look are logged and leave that
+/// part of the report unknown
+fn cleanup_report(manager: &impl Mounter) -> CleanupReport {
+    let mount_state = manager
+        .mount_state()
+        .inspect_err(|e| warn!("Failed to check what is still mounted: {e}"))
+        .ok();
+    let child_pids = cleanup::child_pids().unwrap_or_else(|e| {
+        warn!("Failed to check for leftover child processes: {e}");
+        Vec::new()
+    });
+    CleanupReport {
+        mount_state,
+        child_pids,
+    }
+}
+
+fn sync_diff(mount_config: &MountConfig) -> Result<()> {
+    let mut mount_config = mount_config.clone();
+    mount_config.resolve_lower_volumes()?;
+    let mut failed = false;
+    for (path, res) in SyncManager::diff(&mount_config) {
+        match res {
+            Ok(changes) => {
+                println!("{path:?}: {} change(s)", changes.len());
+                for change in changes {
+                    println!("  {change}");
+                }
+            }
+            Err(e) => {
+                eprintln!("{path:?}: failed to diff: {e}");
+                failed = true;
+            }
+        }
+    }
 
-        fs::write(success_file, timestamp.to_string())
+    if failed {
+        anyhow::bail!("failed to diff one or more lower dirs");
+    }
+    Ok(())
+}
+
+fn lint(mount_config: &MountConfig) -> Result<()> {
+    let mut mount_config = mount_config.clone();
+    mount_config.resolve_lower_volumes()?;
+    let overlapping = mount_config.overlapping_lower_files()?;
+    for (path, indices) in &overlapping {
+        println!("{path:?} is in more than one lower dir:");
+        for &index in indices {
+            println!(
+                "  lower_dirs[{index}] {:?}",
+                mount_config.lower_dirs[index].mount_path()
+            );
+        }
+    }
+    println!(
+        "{} path(s) provided by more than one lower dir",
+        overlapping.len()
+    );
+    Ok(())
+}
+
+/// Output of the `validate` subcommand
+#[derive(Debug, Serialize)]
+struct ValidateOutput {
+    valid: bool,
+    error: Option<String>,
+    #[serde(flatten)]
+    report: ValidationReport,
+}
+
+fn validate(mount_config: &MountConfig, json: bool) -> Result<()> {
+    let error = mount_config.check().err().map(|e| e.to_string());
+    let output = ValidateOutput {
+        valid: error.is_none(),
+        error,
+        report: mount_config.validate_report()?,
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    } else {
+        for masked in &output.report.masked_files {
+            println!(
+                "masked: {:?} (upper copy at {:?})",
+                masked.relative_path, masked.upper_path
+            );
+        }
+        for applied in &output.report.applied_masks {
+            println!(
+                "allowed mask: {:?} (upper copy at {:?})",
+                applied.relative_path, applied.upper_path
+            );
+        }
+        for unused in &output.report.unused_allowed_masks {
+            println!("unused allowed_masked_files entry: {unused:?}");
+        }
+        match &output.error {
+            Some(error) => eprintln!("Invalid config: {error}"),
+            None => println!("Config is valid"),
+        }
+    }
+
+    if !output.valid {
+        anyhow::bail!("config failed validation");
+    }
+    Ok(())
+}
+
+/// Per file result of the `validate` subcommand on a config dir
+#[derive(Debug, Serialize)]
+struct ValidateDirEntry {
+    path: PathBuf,
+    valid: bool,
+    error: Option<String>,
+}
+
+fn validate_dir(dir: &Path, json: bool) -> Result<()> {
+    let entries: Vec<_> = config_dir::load_checked_configs(dir)?
+        .into_iter()
+        .map(|(path, result)| {
+            let error = result.err().map(|e| format!("{e:#}"));
+            ValidateDirEntry {
+                path,
+                valid: error.is_none(),
+                error,
+            }
+        })
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else {
+        for entry in &entries {
+            match &entry.error {
+                Some(error) => eprintln!("{:?}: {error}", entry.path),
+                None => println!("{:?}: valid", entry.path),
+            }
+        }
+    }
+
+    let invalid = entries.iter().filter(|entry| !entry.valid).count();
+    if invalid > 0 {
+        anyhow::bail!("{invalid} of {} configs failed validation", entries.len());
+    }
+    Ok(())
+}
+
+fn write_success_file(options: &Options, report: &HealthReport) -> Result<()> {
+    if let Some(success_file) = &options.success_file {
+        write_atomic(success_file, &serde_json::to_vec(report)?)
             .with_context(|| format!("Failed to write success file: {success_file:?}"))?;
 
-        println!("Success file created: {success_file:?}");
+        info!("Success file created: {success_file:?}");
     }
+    Ok(())
+}
+
+/// Record a finished resync in `report` and republish it. A failed write leaves the previous
+/// report in place, which goes stale rather than stopping the loop. Until `published` nothing is
+/// written unless the resync was healthy.
+fn update_success_file(
+    options: &Options,
+    report: &mut HealthReport,
+    published: &mut bool,
+    healthy: bool,
+) {
+    if !*published && !healthy {
+        return;
+    }
+    *published = true;
+    let result = health::unix_now().and_then(|now| {
+        report.synced(now, healthy);
+        write_success_file(options, report)
+    });
+    if let Err(e) = result {
+        warn!("Failed to update success file: {e:#}");
+    }
+}
 
-    let resync_interval = Duration::from_secs(options.resync_interval_seconds);
+/// Write `contents` to a temporary file next to `path` and rename it into place, so probes
+/// polling `path` never see it partially written
+fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".tmp.{}", std::process::id()));
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = File::create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Flags set by the signal handling thread and checked by the maintenance loop, which sleeps
+/// until the next scheduled sync unless woken by a change to one of them.
+struct LoopFlags {
+    running: AtomicBool,
+    /// Re-sync the `Once` lower dirs on the next iteration
+    resync_once: AtomicBool,
+    /// Run the regular sync on the next iteration without waiting for the interval
+    sync_now: AtomicBool,
+    /// Set when a flag changed since the loop last woke up, so a change made while the loop is
+    /// busy syncing isn't missed
+    changed: Mutex<bool>,
+    wakeup: Condvar,
+    /// Petted by the loop after each synced target and after-sync check and while waiting, for
+    /// the watchdog
+    heartbeat: Heartbeat,
+}
+
+impl LoopFlags {
+    fn new() -> Self {
+        Self {
+            running: AtomicBool::new(true),
+            resync_once: AtomicBool::new(false),
+            sync_now: AtomicBool::new(false),
+            changed: Mutex::new(false),
+            wakeup: Condvar::new(),
+            heartbeat: Heartbeat::new(),
+        }
+    }
+
+    /// Update one of the flags and wake the loop
+    fn set(&self, flag: &AtomicBool, value: bool) {
+        flag.store(value, Ordering::SeqCst);
+        *self.changed.lock().unwrap() = true;
+        self.wakeup.notify_all();
+    }
+
+    /// Sleep for `duration` unless shutdown is requested first, returning whether it ran to the
+    /// end. Other flag changes don't cut it short.
+    fn wait_unless_stopped(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        while self.running.load(Ordering::SeqCst) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return true;
+            }
+            self.wait(remaining);
+        }
+        false
+    }
+
+    /// Sleep for up to `timeout`, returning early if a flag is changed. The heartbeat is petted
+    /// throughout as waiting is what a healthy loop does most of the time.
+    fn wait(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let mut changed = self.changed.lock().unwrap();
+        loop {
+            self.heartbeat.pet();
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if *changed || remaining.is_zero() {
+                break;
+            }
+            (changed, _) = self
+                .wakeup
+                .wait_timeout_while(changed, remaining.min(WAIT_HEARTBEAT_INTERVAL), |changed| {
+                    !*changed
+                })
+                .unwrap();
+        }
+        *changed = false;
+    }
+}
+
+/// Run the resync loop until shutdown, calling `after_sync` after each regular sync with whether
+/// every target synced. An error from it ends the loop.
+fn maintenance_loop(
+    flags: &LoopFlags,
+    options: &Options,
+    sync_manager: &mut SyncManager,
+    mut after_sync: impl FnMut(bool) -> Result<()>,
+) -> Result<()> {
+    let base_interval = Duration::from_secs(options.resync_interval_seconds);
+    let jitter = Duration::from_secs(options.resync_jitter_seconds);
     let sync_timeout = Duration::from_secs(options.sync_timeout_seconds);
     let mut last_sync = SystemTime::now();
+    let mut resync_interval = jittered_interval(base_interval, jitter, random_u64());
 
     // Keep the program running until interrupted
-    while running.load(Ordering::SeqCst) {
-        thread::sleep(Duration::from_millis(200));
-
-        // Check if it's time to resync
-        if last_sync.elapsed().unwrap_or(Duration::ZERO) >= resync_interval {
-            for (path, res) in sync_manager.try_sync(sync_timeout) {
-                match res {
-                    SyncResult::Ok => {
-                        println!("Successfully synced: '{path:?}'");
-                    }
-                    SyncResult::Transient(e) => {
-                        println!("Transient sync failure for '{path:?}': {e}");
-                    }
-                    SyncResult::Fatal(e) => {
-                        return Err(e).context(format!("failed repeatedly to sync '{path:?}'"));
+    while flags.running.load(Ordering::SeqCst) {
+        flags.heartbeat.pet();
+        if flags.resync_once.swap(false, Ordering::SeqCst) {
+            report_sync_results(sync_manager.sync_once_targets_with(|| flags.heartbeat.pet()))?;
+            flags.heartbeat.pet();
+        }
+
+        if sync_due(flags, last_sync, resync_interval) {
+            let healthy = report_sync_results(
+                sync_manager.try_sync_with(sync_timeout, || flags.heartbeat.pet()),
+            )?;
+            flags.heartbeat.pet();
+            last_sync = SystemTime::now();
+            resync_interval = jittered_interval(base_interval, jitter, random_u64());
+            after_sync(healthy)?;
+        }
+
+        let since_last_sync = last_sync.elapsed().unwrap_or(Duration::ZERO);
+        flags.wait(resync_interval.saturating_sub(since_last_sync));
+    }
+
+    Ok(())
+}
+
+/// Whether the regular sync should run on this iteration, either because the interval has passed
+/// or because one was requested with SIGUSR1. A pending request is consumed either way.
+fn sync_due(flags: &LoopFlags, last_sync: SystemTime, resync_interval: Duration) -> bool {
+    let requested = flags.sync_now.swap(false, Ordering::SeqCst);
+    requested || last_sync.elapsed().unwrap_or(Duration::ZERO) >= resync_interval
+}
+
+/// `interval` shifted by an offset within +/- `jitter` picked by `random`, never below zero
+fn jittered_interval(interval: Duration, jitter: Duration, random: u64) -> Duration {
+    if jitter.is_zero() {
+        return interval;
+    }
+    let jitter_ms = jitter.as_millis() as i128;
+    let offset_ms = (random as i128).rem_euclid(2 * jitter_ms + 1) - jitter_ms;
+    let interval_ms = (interval.as_millis() as i128 + offset_ms).max(0);
+    Duration::from_millis(interval_ms as u64)
+}
+
+/// A random value good enough for spreading out timers, without pulling in an RNG
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// Measure the upper layer if it is being logged or limited, taking the limit action when usage
+/// crosses the limit. `over_limit` carries whether it was already over from the last check.
+fn check_upper_usage(manager: &impl Mounter, options: &Options, over_limit: &mut bool) {
+    if !options.log_upper_usage && options.upper_size_limit_bytes.is_none() {
+        return;
+    }
+
+    let usage = match manager.upper_usage() {
+        Ok(usage) => usage,
+        Err(e) => {
+            warn!("Failed to measure upper layer usage: {e}");
+            return;
+        }
+    };
+    if options.log_upper_usage {
+        info!("Upper layer is using {usage} bytes");
+    }
+
+    if let Some(limit) = options.upper_size_limit_bytes {
+        let exceeded = exceeds_limit(usage, limit);
+        if exceeded && !*over_limit {
+            apply_limit_action(manager, &options.upper_size_limit_action, usage, limit);
+        }
+        *over_limit = exceeded;
+    }
+}
+
+fn exceeds_limit(usage: u64, limit: u64) -> bool {
+    usage > limit
+}
+
+fn apply_limit_action(manager: &impl Mounter, action: &UpperLimitAction, usage: u64, limit: u64) {
+    let message = format!("upper layer is using {usage} bytes, over the limit of {limit}");
+    warn!("{message}");
+    match action {
+        UpperLimitAction::Warn => {}
+        UpperLimitAction::FailureFile(path) => {
+            if let Err(e) = write_atomic(path, message.as_bytes()) {
+                warn!("Failed to write failure file {path:?}: {e}");
+            }
+        }
+        UpperLimitAction::RemountReadOnly => {
+            if let Err(e) = manager.remount(MsFlags::MS_RDONLY) {
+                warn!("Failed to remount overlay read only: {e}");
+            }
+        }
+    }
+}
+
+/// Log the results, failing on the first fatal one and otherwise returning whether all succeeded
+fn report_sync_results(results: Vec<(PathBuf, SyncResult<SyncError>)>) -> Result<bool> {
+    let mut all_ok = true;
+    for (path, res) in results {
+        match res {
+            SyncResult::Ok => {
+                info!("Successfully synced: '{path:?}'");
+            }
+            SyncResult::Transient(e) => {
+                info!("Transient sync failure for '{path:?}': {e}");
+                all_ok = false;
+            }
+            SyncResult::Fatal(e) => {
+                return Err(e).context(format!("failed repeatedly to sync '{path:?}'"));
+            }
+        }
+    }
+    Ok(all_ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use overlay_mount::config::{LowerDir, LowerUnavailablePolicy, UpperDir};
+    use std::cell::RefCell;
+    use std::time::Instant;
+    use tempfile::TempDir;
+
+    #[derive(Default)]
+    struct FakeMounter {
+        calls: RefCell<Vec<&'static str>>,
+        upper_usage: u64,
+        /// Fail to unmount, leaving the overlay mounted
+        umount_fails: bool,
+    }
+
+    impl Mounter for FakeMounter {
+        fn mount(&self) -> Result<(), ManagerError> {
+            self.calls.borrow_mut().push("mount");
+            Ok(())
+        }
+
+        fn umount(&self) -> Result<(), ManagerError> {
+            self.calls.borrow_mut().push("umount");
+            if self.umount_fails {
+                return Err(ManagerError::UmountError {
+                    errno: nix::errno::Errno::EBUSY,
+                    attempts: 1,
+                });
+            }
+            Ok(())
+        }
+
+        fn remount(&self, new_flags: MsFlags) -> Result<(), ManagerError> {
+            assert_eq!(new_flags, MsFlags::MS_RDONLY);
+            self.calls.borrow_mut().push("remount");
+            Ok(())
+        }
+
+        fn upper_usage(&self) -> io::Result<u64> {
+            self.calls.borrow_mut().push("upper_usage");
+            Ok(self.upper_usage)
+        }
+
+        fn mount_state(&self) -> Result<MountState, ManagerError> {
+            Ok(MountState {
+                overlay_mounted: self.umount_fails,
+                bind_targets_mounted: vec![],
+            })
+        }
+    }
+
+    #[test]
+    fn test_load_config_from_stdin() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let base_dir = temp_dir.path();
+        fs::create_dir_all(base_dir.join("layers/lower")).unwrap();
+        fs::write(
+            base_dir.join("options.json"),
+            r#"{"options": {"resync_interval_seconds": 5}}"#,
+        )
+        .unwrap();
+        let stdin = format!(
+            r#"
+include = ["options.json"]
+
+[[lower_dirs]]
+volume = "layers/lower"
+
+[[lower_dirs]]
+volume = "/absolute-lower"
+
+[upper_dir]
+volume = "{}"
+upper_subdir = "upper"
+work_subdir = "work"
+merged_subdir = "merged"
+"#,
+            base_dir.join("upper").display()
+        );
+
+        let config = load_config_from(stdin.as_bytes(), ConfigFormat::Toml, base_dir).unwrap();
+        assert_eq!(config.options.resync_interval_seconds, 5);
+        // Validates with the relative volume resolved against the base dir
+        let plan = config.mount_config.plan().unwrap();
+        assert_eq!(
+            plan.layers.lower,
+            [
+                base_dir.join("layers/lower"),
+                PathBuf::from("/absolute-lower")
+            ]
+        );
+
+        // A base volume set by the config is kept
+        let json = r#"{
+            "base_volume": "/srv/layers",
+            "lower_dirs": [{"volume": "lower"}],
+            "upper_dir": {"volume": "/upper", "upper_subdir": "upper", "work_subdir": "work", "merged_subdir": "merged"},
+            "options": {}
+        }"#;
+        let config = load_config_from(json.as_bytes(), ConfigFormat::Json, base_dir).unwrap();
+        assert_eq!(
+            config.mount_config.base_volume,
+            Some(PathBuf::from("/srv/layers"))
+        );
+        config.mount_config.check().unwrap();
+
+        assert!(load_config_from(&b"lower_dirs = ["[..], ConfigFormat::Toml, base_dir).is_err());
+    }
+
+    fn test_options(success_file: Option<PathBuf>) -> Options {
+        Options {
+            show_dmesg: None,
+            dmesg_lines: default_dmesg_lines(),
+            success_file,
+            pid_file: None,
+            resync_interval_seconds: default_resync_interval(),
+            resync_jitter_seconds: 0,
+            sync_timeout_seconds: default_sync_timeout(),
+            startup_timeout_seconds: None,
+            log_upper_usage: false,
+            upper_size_limit_bytes: None,
+            upper_size_limit_action: UpperLimitAction::default(),
+            success_after_first_sync: false,
+            watchdog_timeout_seconds: None,
+            watchdog_grace_seconds: default_watchdog_grace(),
+            ready_delay_seconds: 0,
+        }
+    }
+
+    #[test]
+    fn test_run_oneshot_skips_loop_and_umount() {
+        let temp_dir = TempDir::new().unwrap();
+        let success_file = temp_dir.path().join("success");
+        let options = test_options(Some(success_file.clone()));
+        let mounter = FakeMounter::default();
+
+        run(
+            &mounter,
+            &options,
+            &LoopFlags::new(),
+            true,
+            false,
+            |_, _| {
+                mounter.calls.borrow_mut().push("loop");
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(*mounter.calls.borrow(), ["mount"]);
+        let report: HealthReport =
+            serde_json::from_slice(&fs::read(&success_file).unwrap()).unwrap();
+        assert_eq!(report.version, health::HEALTH_REPORT_VERSION);
+        assert_eq!(report.last_sync_at, report.mounted_at);
+        assert!(report.healthy);
+    }
+
+    #[test]
+    fn test_run_ready_delay() {
+        let temp_dir = TempDir::new().unwrap();
+        let success_file = temp_dir.path().join("success");
+        let mut options = test_options(Some(success_file.clone()));
+        options.ready_delay_seconds = 1;
+        let mounter = FakeMounter::default();
+
+        let start = Instant::now();
+        run(
+            &mounter,
+            &options,
+            &LoopFlags::new(),
+            false,
+            false,
+            |_, _| {
+                // Only written once the delay is over
+                assert!(start.elapsed() >= Duration::from_secs(1));
+                assert!(success_file.exists());
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(*mounter.calls.borrow(), ["mount", "umount"]);
+
+        // A shutdown during the delay unmounts without publishing or entering the loop
+        fs::remove_file(&success_file).unwrap();
+        options.ready_delay_seconds = 60;
+        let mounter = FakeMounter::default();
+        let flags = LoopFlags::new();
+        let start = Instant::now();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                // Other flags don't end the wait
+                flags.set(&flags.sync_now, true);
+                thread::sleep(Duration::from_millis(50));
+                flags.set(&flags.running, false);
+            });
+            run(&mounter, &options, &flags, false, false, |_, _| {
+                panic!("loop entered after shutdown")
+            })
+            .unwrap();
+        });
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(!success_file.exists());
+        assert_eq!(*mounter.calls.borrow(), ["mount", "umount"]);
+    }
+
+    #[test]
+    fn test_run_defer_success_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let success_file = temp_dir.path().join("success");
+        let options = test_options(Some(success_file.clone()));
+        let mounter = FakeMounter::default();
+
+        run(
+            &mounter,
+            &options,
+            &LoopFlags::new(),
+            false,
+            true,
+            |mut report, mut published| {
+                assert!(!published);
+                assert!(!success_file.exists());
+
+                // Still not ready after a resync with failures, only once one succeeds
+                update_success_file(&options, &mut report, &mut published, false);
+                assert!(!success_file.exists());
+                update_success_file(&options, &mut report, &mut published, true);
+                assert!(success_file.exists());
+
+                // From then on every resync is published, failed ones as unhealthy
+                update_success_file(&options, &mut report, &mut published, false);
+                let written: HealthReport =
+                    serde_json::from_slice(&fs::read(&success_file).unwrap()).unwrap();
+                assert!(!written.healthy);
+                Ok(())
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_run_without_defer_success_is_ready_on_mount() {
+        let temp_dir = TempDir::new().unwrap();
+        let success_file = temp_dir.path().join("success");
+        let mut options = test_options(Some(success_file.clone()));
+        // No constant lower dirs, so main doesn't defer even with the option set
+        options.success_after_first_sync = true;
+        let mounter = FakeMounter::default();
+
+        run(
+            &mounter,
+            &options,
+            &LoopFlags::new(),
+            false,
+            false,
+            |_, published| {
+                assert!(published);
+                assert!(success_file.exists());
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        // Oneshot runs never resync so are always ready on mount
+        fs::remove_file(&success_file).unwrap();
+        run(&mounter, &options, &LoopFlags::new(), true, true, |_, _| {
+            Ok(())
+        })
+        .unwrap();
+        assert!(success_file.exists());
+    }
+
+    #[test]
+    fn test_run_loops_then_umounts() {
+        let options = test_options(None);
+        let mounter = FakeMounter::default();
+
+        run(
+            &mounter,
+            &options,
+            &LoopFlags::new(),
+            false,
+            false,
+            |_, _| {
+                mounter.calls.borrow_mut().push("loop");
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(*mounter.calls.borrow(), ["mount", "loop", "umount"]);
+    }
+
+    #[test]
+    fn test_run_umounts_after_loop_error() {
+        let options = test_options(None);
+        let mounter = FakeMounter::default();
+
+        let result = run(
+            &mounter,
+            &options,
+            &LoopFlags::new(),
+            false,
+            false,
+            |_, _| {
+                mounter.calls.borrow_mut().push("loop");
+                anyhow::bail!("sync failed")
+            },
+        );
+
+        assert!(result.is_err());
+        assert_eq!(*mounter.calls.borrow(), ["mount", "loop", "umount"]);
+    }
+
+    #[test]
+    fn test_run_reports_lingering_mount_after_umount_failure() {
+        let options = test_options(None);
+        let mounter = FakeMounter {
+            umount_fails: true,
+            ..Default::default()
+        };
+
+        let report = cleanup_report(&mounter);
+        assert!(report.mount_state.as_ref().unwrap().overlay_mounted);
+        assert!(!report.is_clean());
+
+        let error = run(
+            &mounter,
+            &options,
+            &LoopFlags::new(),
+            false,
+            false,
+            |_, _| Ok(()),
+        )
+        .unwrap_err();
+        let message = format!("{error:#}");
+        assert!(
+            message.starts_with("Cleanup incomplete: overlay still mounted"),
+            "{message}"
+        );
+        assert!(message.contains("EBUSY"), "{message}");
+
+        // A loop error is still reported alongside the failed unmount
+        let error = run(
+            &mounter,
+            &options,
+            &LoopFlags::new(),
+            false,
+            false,
+            |_, _| anyhow::bail!("sync failed"),
+        )
+        .unwrap_err();
+        let message = format!("{error:#}");
+        assert!(message.contains("overlay still mounted"), "{message}");
+        assert!(message.contains("sync failed"), "{message}");
+    }
+
+    #[test]
+    fn test_cleanup_report_after_clean_umount() {
+        let mounter = FakeMounter::default();
+        mounter.umount().unwrap();
+        assert_eq!(
+            cleanup_report(&mounter).mount_state,
+            Some(MountState::default())
+        );
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("success");
+
+        write_atomic(&path, b"first").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first");
+        write_atomic(&path, b"second").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+
+        // Only the target is left behind
+        let entries: Vec<_> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, ["success"]);
+
+        assert!(write_atomic(&temp_dir.path().join("missing/success"), b"x").is_err());
+    }
+
+    #[test]
+    fn test_write_atomic_never_partial() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("success");
+        let contents = [vec![b'a'; 1024 * 1024], vec![b'b'; 1024 * 1024]];
+        let done = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for i in 0..20 {
+                    write_atomic(&path, &contents[i % 2]).unwrap();
+                }
+                done.store(true, Ordering::SeqCst);
+            });
+            while !done.load(Ordering::SeqCst) {
+                match fs::read(&path) {
+                    Ok(read) => assert!(contents.contains(&read), "read {} bytes", read.len()),
+                    Err(e) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_exceeds_limit() {
+        assert!(!exceeds_limit(0, 100));
+        assert!(!exceeds_limit(100, 100));
+        assert!(exceeds_limit(101, 100));
+    }
+
+    #[test]
+    fn test_apply_limit_action() {
+        let temp_dir = TempDir::new().unwrap();
+        let failure_file = temp_dir.path().join("failure");
+
+        let mounter = FakeMounter::default();
+        apply_limit_action(&mounter, &UpperLimitAction::Warn, 200, 100);
+        assert!(mounter.calls.borrow().is_empty());
+        assert!(!failure_file.exists());
+
+        let action = UpperLimitAction::FailureFile(failure_file.clone());
+        apply_limit_action(&mounter, &action, 200, 100);
+        assert!(mounter.calls.borrow().is_empty());
+        let message = fs::read_to_string(&failure_file).unwrap();
+        assert!(message.contains("200 bytes"), "{message}");
+
+        apply_limit_action(&mounter, &UpperLimitAction::RemountReadOnly, 200, 100);
+        assert_eq!(*mounter.calls.borrow(), ["remount"]);
+    }
+
+    #[test]
+    fn test_check_upper_usage_acts_on_crossing() {
+        let mut options = test_options(None);
+        let mut over_limit = false;
+
+        // Nothing to do without logging or a limit
+        let mounter = FakeMounter {
+            upper_usage: 200,
+            ..Default::default()
+        };
+        check_upper_usage(&mounter, &options, &mut over_limit);
+        assert!(mounter.calls.borrow().is_empty());
+
+        options.upper_size_limit_bytes = Some(100);
+        options.upper_size_limit_action = UpperLimitAction::RemountReadOnly;
+        check_upper_usage(&mounter, &options, &mut over_limit);
+        assert!(over_limit);
+        assert_eq!(*mounter.calls.borrow(), ["upper_usage", "remount"]);
+
+        // Still over, the action isn't repeated
+        check_upper_usage(&mounter, &options, &mut over_limit);
+        assert_eq!(
+            *mounter.calls.borrow(),
+            ["upper_usage", "remount", "upper_usage"]
+        );
+
+        let under = FakeMounter {
+            upper_usage: 50,
+            ..Default::default()
+        };
+        check_upper_usage(&under, &options, &mut over_limit);
+        assert!(!over_limit);
+        assert_eq!(*under.calls.borrow(), ["upper_usage"]);
+
+        // Crossing again acts again
+        check_upper_usage(&mounter, &options, &mut over_limit);
+        assert_eq!(mounter.calls.borrow().last(), Some(&"remount"));
+    }
+
+    #[test]
+    fn test_upper_limit_action_config() {
+        let parse = |value| serde_json::from_value::<UpperLimitAction>(value).unwrap();
+        assert_eq!(parse(serde_json::json!("warn")), UpperLimitAction::Warn);
+        assert_eq!(
+            parse(serde_json::json!("remount_read_only")),
+            UpperLimitAction::RemountReadOnly
+        );
+        assert_eq!(
+            parse(serde_json::json!({"failure_file": "/run/overlay-full"})),
+            UpperLimitAction::FailureFile(PathBuf::from("/run/overlay-full"))
+        );
+    }
+
+    #[test]
+    fn test_sync_due() {
+        let flags = LoopFlags::new();
+        let interval = Duration::from_secs(60);
+
+        let now = SystemTime::now();
+        assert!(!sync_due(&flags, now, interval));
+
+        // A request triggers exactly one sync ahead of the interval
+        flags.sync_now.store(true, Ordering::SeqCst);
+        assert!(sync_due(&flags, now, interval));
+        assert!(!flags.sync_now.load(Ordering::SeqCst));
+        assert!(!sync_due(&flags, now, interval));
+
+        // The interval still applies on its own
+        let stale = now - Duration::from_secs(61);
+        assert!(sync_due(&flags, stale, interval));
+        flags.sync_now.store(true, Ordering::SeqCst);
+        assert!(sync_due(&flags, stale, interval));
+        assert!(!flags.sync_now.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_maintenance_loop_wakes_on_signal() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let mount_config = MountConfig::builder()
+            .add_lower(LowerDir::new(volume.join("lower"), None).unwrap())
+            .upper(
+                UpperDir::new(
+                    volume.clone(),
+                    PathBuf::from("upper"),
+                    PathBuf::from("work"),
+                    PathBuf::from("merged"),
+                )
+                .unwrap(),
+            )
+            .build()
+            .unwrap();
+        let (mut sync_manager, _) = SyncManager::new(mount_config.validate().unwrap()).unwrap();
+
+        let mut options = test_options(None);
+        options.resync_interval_seconds = 3600;
+        let flags = LoopFlags::new();
+
+        let start = std::time::Instant::now();
+        thread::scope(|scope| {
+            let handle =
+                scope.spawn(|| maintenance_loop(&flags, &options, &mut sync_manager, |_| Ok(())));
+            thread::sleep(Duration::from_millis(100));
+            flags.set(&flags.sync_now, true);
+            flags.set(&flags.running, false);
+            handle.join().unwrap().unwrap();
+        });
+        // Woken by the flag rather than waiting out the interval
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_success_file_updated_after_sync() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let mount_config = MountConfig::builder()
+            .add_lower(LowerDir::new(volume.join("lower"), None).unwrap())
+            .upper(
+                UpperDir::new(
+                    volume.clone(),
+                    PathBuf::from("upper"),
+                    PathBuf::from("work"),
+                    PathBuf::from("merged"),
+                )
+                .unwrap(),
+            )
+            .build()
+            .unwrap();
+        let (mut sync_manager, _) = SyncManager::new(mount_config.validate().unwrap()).unwrap();
+
+        let success_file = volume.join("success");
+        let mut options = test_options(Some(success_file.clone()));
+        options.resync_interval_seconds = 3600;
+        let flags = LoopFlags::new();
+        flags.set(&flags.sync_now, true);
+
+        // Mounted long ago so the sync is guaranteed to move last_sync_at
+        let mut report = HealthReport::new(1);
+        let mut published = true;
+        maintenance_loop(&flags, &options, &mut sync_manager, |healthy| {
+            update_success_file(&options, &mut report, &mut published, healthy);
+            flags.set(&flags.running, false);
+            Ok(())
+        })
+        .unwrap();
+
+        let written: HealthReport =
+            serde_json::from_slice(&fs::read(&success_file).unwrap()).unwrap();
+        assert_eq!(written, report);
+        assert_eq!(written.mounted_at, 1);
+        assert!(written.last_sync_at > 1);
+        assert!(written.healthy);
+    }
+
+    #[test]
+    fn test_maintenance_loop_lower_unavailable() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let mut options = test_options(None);
+        options.resync_interval_seconds = 3600;
+
+        for policy in [
+            LowerUnavailablePolicy::Ignore,
+            LowerUnavailablePolicy::Warn,
+            LowerUnavailablePolicy::Fail,
+        ] {
+            let lower_path = volume.join("lower");
+            fs::create_dir_all(&lower_path).unwrap();
+            let mount_config = MountConfig::builder()
+                .add_lower(LowerDir::new(lower_path.clone(), None).unwrap())
+                .upper(
+                    UpperDir::new(
+                        volume.clone(),
+                        PathBuf::from("upper"),
+                        PathBuf::from("work"),
+                        PathBuf::from("merged"),
+                    )
+                    .unwrap(),
+                )
+                .on_lower_unavailable(policy)
+                .build()
+                .unwrap();
+            let (mut sync_manager, _) =
+                SyncManager::new(mount_config.clone().validate().unwrap()).unwrap();
+
+            // The lower dir goes away between the first and second sync
+            let flags = LoopFlags::new();
+            flags.set(&flags.sync_now, true);
+            let mut syncs = 0;
+            let result = maintenance_loop(&flags, &options, &mut sync_manager, |_| {
+                syncs += 1;
+                match syncs {
+                    1 => {
+                        fs::remove_dir(&lower_path).unwrap();
+                        flags.set(&flags.sync_now, true);
                     }
+                    _ => flags.set(&flags.running, false),
+                }
+                mount_config.check_lower_available().map_err(Into::into)
+            });
+
+            match policy {
+                LowerUnavailablePolicy::Fail => {
+                    assert_eq!(syncs, 1);
+                    assert!(result.is_err());
+                }
+                _ => {
+                    assert_eq!(syncs, 2);
+                    result.unwrap();
                 }
             }
-            last_sync = SystemTime::now();
         }
     }
 
-    Ok(())
+    #[test]
+    fn test_loop_flags_change_before_wait_is_not_lost() {
+        let flags = LoopFlags::new();
+        flags.set(&flags.sync_now, true);
+
+        let start = std::time::Instant::now();
+        flags.wait(Duration::from_secs(3600));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_handle_stall_stops_loop_then_exits() {
+        let flags = LoopFlags::new();
+        let exited = std::cell::Cell::new(false);
+        handle_stall(&flags, Duration::ZERO, || {
+            // The loop is asked to stop before giving up on it
+            assert!(!flags.running.load(Ordering::SeqCst));
+            exited.set(true);
+        });
+        assert!(exited.get());
+        assert!(*flags.changed.lock().unwrap());
+    }
+
+    #[test]
+    fn test_maintenance_loop_pets_heartbeat() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let mount_config = MountConfig::builder()
+            .add_lower(LowerDir::new(volume.join("lower"), None).unwrap())
+            .upper(
+                UpperDir::new(
+                    volume.clone(),
+                    PathBuf::from("upper"),
+                    PathBuf::from("work"),
+                    PathBuf::from("merged"),
+                )
+                .unwrap(),
+            )
+            .build()
+            .unwrap();
+        let (mut sync_manager, _) = SyncManager::new(mount_config.validate().unwrap()).unwrap();
+        let mut options = test_options(None);
+        options.resync_interval_seconds = 0;
+        let flags = LoopFlags::new();
+
+        thread::sleep(Duration::from_millis(20));
+        let loop_start = Instant::now();
+        let mut syncs = 0;
+        maintenance_loop(&flags, &options, &mut sync_manager, |_| {
+            syncs += 1;
+            if syncs == 3 {
+                flags.set(&flags.running, false);
+            }
+            Ok(())
+        })
+        .unwrap();
+        // Petted by the loop rather than left at when the flags were created
+        assert!(flags.heartbeat.age(Instant::now()) <= loop_start.elapsed());
+    }
+
+    #[test]
+    fn test_wait_pets_heartbeat() {
+        let flags = LoopFlags::new();
+        thread::sleep(Duration::from_millis(20));
+        let start = Instant::now();
+        flags.wait(Duration::from_millis(1500));
+        // Petted partway through and again at the end, not only before sleeping
+        assert!(
+            flags.heartbeat.age(Instant::now()) <= start.elapsed() - Duration::from_millis(1000)
+        );
+    }
+
+    #[test]
+    fn test_options_check_watchdog_timeout() {
+        let mut options = test_options(None);
+        options.check().unwrap();
+
+        for timeout in [0, 1] {
+            options.watchdog_timeout_seconds = Some(timeout);
+            assert!(options.check().is_err(), "{timeout}");
+        }
+        // Resync interval, jitter, ready delay and the sync timeout don't count, none of them
+        // hold up the heartbeat
+        options.sync_timeout_seconds = 1800;
+        options.resync_interval_seconds = 3600;
+        options.resync_jitter_seconds = 600;
+        options.ready_delay_seconds = 600;
+        options.watchdog_timeout_seconds = Some(60);
+        options.check().unwrap();
+    }
+
+    #[test]
+    fn test_jittered_interval() {
+        let interval = Duration::from_secs(300);
+        let jitter = Duration::from_secs(30);
+
+        assert_eq!(
+            jittered_interval(interval, Duration::ZERO, random_u64()),
+            interval
+        );
+        // The ends of the band are reachable
+        assert_eq!(
+            jittered_interval(interval, jitter, 0),
+            Duration::from_secs(270)
+        );
+        assert_eq!(
+            jittered_interval(interval, jitter, 60_000),
+            Duration::from_secs(330)
+        );
+
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..100 {
+            let next = jittered_interval(interval, jitter, random_u64());
+            assert!(
+                next >= interval - jitter && next <= interval + jitter,
+                "{next:?}"
+            );
+            seen.insert(next);
+        }
+        assert!(seen.len() > 1, "intervals should vary between cycles");
+
+        // Jitter larger than the interval can't go negative
+        for _ in 0..100 {
+            let next = jittered_interval(Duration::from_secs(5), jitter, random_u64());
+            assert!(next <= Duration::from_secs(35), "{next:?}");
+        }
+    }
 }
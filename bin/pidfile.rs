@@ -0,0 +1,148 @@
+use anyhow::{Context, Result, bail};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use overlay_mount::info;
+
+/// Exclusive claim on a PID file, held for the lifetime of the process so two managers can't
+/// fight over the same merged mount. The file is removed again on drop.
+pub struct PidFile {
+    path: PathBuf,
+    // Keeps the advisory lock held until the file has been removed
+    _lock: Flock<File>,
+}
+
+impl PidFile {
+    /// Take the lock on `path` and write our PID to it. Fails if another instance holds the lock
+    /// or if the file names a process that is still alive, a PID left behind by a dead process
+    /// is taken over.
+    pub fn acquire(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open pid file: {path:?}"))?;
+
+        let mut lock = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(lock) => lock,
+            Err((mut file, Errno::EWOULDBLOCK)) => {
+                let owner = read_pid(&mut file).map(|pid| pid.to_string());
+                bail!(
+                    "another instance (pid {}) holds the lock on {path:?}",
+                    owner.as_deref().unwrap_or("unknown")
+                );
+            }
+            Err((_, errno)) => {
+                return Err(errno).with_context(|| format!("Failed to lock pid file: {path:?}"));
+            }
+        };
+
+        if let Some(pid) = read_pid(&mut lock)
+            && pid != std::process::id()
+        {
+            if Path::new(&format!("/proc/{pid}")).exists() {
+                bail!("pid file {path:?} references running process {pid}, refusing to start");
+            }
+            info!("Taking over stale pid file {path:?} from dead process {pid}");
+        }
+
+        lock.set_len(0)
+            .and_then(|_| lock.rewind())
+            .and_then(|_| write!(lock, "{}", std::process::id()))
+            .and_then(|_| lock.flush())
+            .with_context(|| format!("Failed to write pid file: {path:?}"))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            _lock: lock,
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            eprintln!("Failed to remove pid file {:?}: {e}", self.path);
+        }
+    }
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut content = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut content).ok()?;
+    content.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_acquire_writes_pid_and_removes_on_drop() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("overlay.pid");
+
+        let pid_file = PidFile::acquire(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            std::process::id().to_string()
+        );
+
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_acquire_refuses_while_locked() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("overlay.pid");
+
+        let _pid_file = PidFile::acquire(&path).unwrap();
+        let err = PidFile::acquire(&path).err().unwrap();
+        assert!(err.to_string().contains("holds the lock"), "{err}");
+    }
+
+    #[test]
+    fn test_acquire_takes_over_stale_pid() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("overlay.pid");
+
+        // A process that has exited and been reaped leaves a dead PID behind
+        let mut child = Command::new("true").spawn().unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+        fs::write(&path, dead_pid.to_string()).unwrap();
+
+        let _pid_file = PidFile::acquire(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            std::process::id().to_string()
+        );
+    }
+
+    #[test]
+    fn test_acquire_refuses_live_pid() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("overlay.pid");
+
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        fs::write(&path, child.id().to_string()).unwrap();
+
+        let result = PidFile::acquire(&path);
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        let err = result.err().unwrap();
+        assert!(err.to_string().contains("running process"), "{err}");
+        // The other instance's pid file is left alone
+        assert_eq!(fs::read_to_string(&path).unwrap(), child.id().to_string());
+    }
+}
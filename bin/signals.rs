@@ -0,0 +1,68 @@
+use signal_hook::consts::{SIGHUP, SIGUSR1};
+
+/// What the signal handling thread should do about a signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalAction {
+    /// SIGHUP, re-sync the `Once` lower dirs
+    ResyncOnce,
+    /// SIGUSR1, run the regular sync without waiting for the interval
+    SyncNow,
+    /// The first termination signal, stop the maintenance loop and unmount as usual
+    Shutdown,
+    /// A termination signal after shutdown was already requested, eg because the graceful path
+    /// is stuck in a long sync. Detach the overlay and exit straight away.
+    ForceExit,
+}
+
+/// Counts the termination signals received so a repeated one escalates the shutdown
+#[derive(Debug, Default)]
+pub struct SignalState {
+    terminations: usize,
+}
+
+impl SignalState {
+    pub fn handle(&mut self, signal: i32) -> SignalAction {
+        match signal {
+            SIGHUP => SignalAction::ResyncOnce,
+            SIGUSR1 => SignalAction::SyncNow,
+            _ => {
+                self.terminations += 1;
+                if self.terminations == 1 {
+                    SignalAction::Shutdown
+                } else {
+                    SignalAction::ForceExit
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use signal_hook::consts::{SIGINT, SIGTERM};
+
+    #[test]
+    fn test_second_termination_signal_forces_exit() {
+        let mut state = SignalState::default();
+        assert_eq!(state.handle(SIGTERM), SignalAction::Shutdown);
+        assert_eq!(state.handle(SIGTERM), SignalAction::ForceExit);
+        assert_eq!(state.handle(SIGTERM), SignalAction::ForceExit);
+
+        // Any mix of termination signals escalates
+        let mut state = SignalState::default();
+        assert_eq!(state.handle(SIGINT), SignalAction::Shutdown);
+        assert_eq!(state.handle(SIGTERM), SignalAction::ForceExit);
+    }
+
+    #[test]
+    fn test_other_signals_do_not_escalate() {
+        let mut state = SignalState::default();
+        assert_eq!(state.handle(SIGHUP), SignalAction::ResyncOnce);
+        assert_eq!(state.handle(SIGUSR1), SignalAction::SyncNow);
+        assert_eq!(state.handle(SIGHUP), SignalAction::ResyncOnce);
+        assert_eq!(state.handle(SIGTERM), SignalAction::Shutdown);
+        assert_eq!(state.handle(SIGUSR1), SignalAction::SyncNow);
+        assert_eq!(state.handle(SIGINT), SignalAction::ForceExit);
+    }
+}
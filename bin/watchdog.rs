@@ -0,0 +1,122 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// When the maintenance loop last made progress, petted on every iteration
+#[derive(Debug)]
+pub struct Heartbeat {
+    last_pet: Mutex<Instant>,
+    stopped: AtomicBool,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Self {
+            last_pet: Mutex::new(Instant::now()),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    pub fn pet(&self) {
+        *self.last_pet.lock().unwrap() = Instant::now();
+    }
+
+    /// No more pets are coming as the loop is done, so stop watching for a stall
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// How long it has been since the last pet, as of `now`
+    pub fn age(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(*self.last_pet.lock().unwrap())
+    }
+
+    pub fn is_stale(&self, now: Instant, timeout: Duration) -> bool {
+        self.age(now) > timeout
+    }
+}
+
+/// Block until `heartbeat` goes without a pet for longer than `timeout`, checking every `poll`,
+/// and return how long it had gone. `None` once the heartbeat is stopped instead.
+pub fn wait_for_stall(
+    heartbeat: &Heartbeat,
+    timeout: Duration,
+    poll: Duration,
+) -> Option<Duration> {
+    loop {
+        if heartbeat.is_stopped() {
+            return None;
+        }
+        let now = Instant::now();
+        if heartbeat.is_stale(now, timeout) {
+            return Some(heartbeat.age(now));
+        }
+        thread::sleep(poll);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heartbeat_staleness() {
+        // Taken first so the pet on creation is no earlier
+        let now = Instant::now();
+        let heartbeat = Heartbeat::new();
+        let timeout = Duration::from_secs(60);
+        assert!(!heartbeat.is_stale(now, timeout));
+        assert!(!heartbeat.is_stale(now + timeout, timeout));
+        assert!(heartbeat.is_stale(now + timeout + Duration::from_secs(1), timeout));
+
+        // A pet starts the timeout over
+        let later = Instant::now() + Duration::from_secs(90);
+        assert!(heartbeat.is_stale(later, timeout));
+        heartbeat.pet();
+        assert!(!heartbeat.is_stale(later - Duration::from_secs(30), timeout));
+    }
+
+    #[test]
+    fn test_wait_for_stall() {
+        let heartbeat = Heartbeat::new();
+        let timeout = Duration::from_millis(100);
+        let start = Instant::now();
+        thread::scope(|scope| {
+            // Kept alive for a while, then left to go stale
+            scope.spawn(|| {
+                for _ in 0..5 {
+                    heartbeat.pet();
+                    thread::sleep(Duration::from_millis(40));
+                }
+            });
+            let stale_for = wait_for_stall(&heartbeat, timeout, Duration::from_millis(10));
+            assert!(stale_for.unwrap() > timeout);
+        });
+        assert!(start.elapsed() >= Duration::from_millis(260));
+    }
+
+    #[test]
+    fn test_wait_for_stall_stops() {
+        let heartbeat = Heartbeat::new();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(30));
+                heartbeat.stop();
+            });
+            // Returns once stopped rather than waiting out the timeout
+            assert_eq!(
+                wait_for_stall(
+                    &heartbeat,
+                    Duration::from_secs(60),
+                    Duration::from_millis(5)
+                ),
+                None
+            );
+        });
+    }
+}
@@ -1,10 +1,23 @@
-use serde::Deserialize;
+use nix::fcntl::AT_FDCWD;
+use nix::mount::{MsFlags, mount, umount};
+use nix::sys::stat::{FchmodatFlags, Mode, fchmodat};
+use nix::unistd::{Gid, Uid, User, chown};
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeSet;
 use std::fs;
 use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use crate::rsync::SyncMode;
+use crate::features::OverlayFeatures;
+use crate::mountinfo;
+use crate::rsync::{SyncMode, SyncOptions, is_remote_source};
+use crate::{info, warn};
+
+mod scan_cache;
+use scan_cache::ScanCache;
 
 #[derive(thiserror::Error, Debug)]
 #[error("IO Error at '{0:?}': {1}")]
@@ -19,6 +32,116 @@ pub enum ValidationError {
 
     #[error("one or more file paths are masked by rw layer: {0:?}")]
     MaskedFiles(Vec<PathBuf>),
+
+    #[error(
+        "allowed_masked_files entries {0:?} match no file in the upper dir, remove them or unset strict_allow_list"
+    )]
+    UnusedAllowEntries(Vec<PathBuf>),
+
+    #[error(
+        "work dir contains a dirty marker from a previous volatile mount at '{0:?}', remove it or set clear_volatile_marker"
+    )]
+    VolatileMarkerPresent(PathBuf),
+
+    #[error("lower dir '{0:?}' is listed more than once, set dedup_lower_dirs to drop duplicates")]
+    DuplicateLowerDir(PathBuf),
+
+    #[error("{count} lower dirs configured but at most {max} are allowed")]
+    TooManyLowerDirs { count: usize, max: usize },
+
+    #[error("overlay option '{0}' conflicts with '{1}'")]
+    ConflictingOverlayOptions(String, String),
+
+    #[error("required config field '{0}' was not set")]
+    MissingField(&'static str),
+
+    #[error("lower dir '{0:?}' can't be listed: {1}")]
+    LowerDirUnreadable(PathBuf, #[source] io::Error),
+
+    #[error(
+        "invalid selinux context {0:?}, it must be non-empty without quotes or control characters"
+    )]
+    InvalidSelinuxContext(String),
+
+    #[error(
+        "invalid mount source label {0:?}, it must be non-empty without whitespace, backslashes or control characters"
+    )]
+    InvalidMountSourceLabel(String),
+
+    #[error("rsync filter file '{0:?}' does not exist")]
+    MissingFilterFile(PathBuf),
+
+    #[error("rsync link dest '{0:?}' is not a directory")]
+    MissingLinkDest(PathBuf),
+
+    #[error("subdir '{0:?}' must stay within '{1:?}' ie must not contain '..'")]
+    SubdirEscapesVolume(PathBuf, PathBuf),
+
+    #[error("lower dir volume '{0:?}' is relative, which requires base_volume to be set")]
+    RelativeVolumeWithoutBase(PathBuf),
+
+    #[error("base volume '{0:?}' must be an absolute path")]
+    RelativeBaseVolume(PathBuf),
+
+    #[error("merged mount '{0:?}' must be an absolute path to an existing directory")]
+    InvalidMergedMount(PathBuf),
+
+    #[error(
+        "'{0:?}' and '{1:?}' overlap, none of the upper, work and merged dirs may contain another or a lower dir"
+    )]
+    OverlappingDirs(PathBuf, PathBuf),
+
+    #[error("rsync chmod spec must not be empty")]
+    EmptyChmod,
+
+    #[error("sync_as_user '{0}' does not exist")]
+    UnknownSyncUser(String),
+
+    #[error("rsh is set for '{0:?}', which is not a remote source")]
+    RshWithoutRemoteSource(PathBuf),
+
+    #[error(
+        "rsync arg {0:?} is not a flag, the source and target are added automatically and values go in the same arg as their flag (eg --exclude=foo)"
+    )]
+    PositionalRsyncArg(String),
+
+    #[error(
+        "skip_specials has no effect with rsync_base_args, leave -D out of the base args instead"
+    )]
+    SkipSpecialsWithBaseArgs,
+
+    #[error(
+        "merged dir '{0:?}' is not empty, remove the leftover content or unset require_empty_merged"
+    )]
+    MergedNotEmpty(PathBuf),
+
+    #[error(
+        "lower dir '{0:?}' is marked writable, which is not supported by the current mount assembly"
+    )]
+    WritableLowerUnsupported(PathBuf),
+
+    #[error("lower dir '{0:?}' is data-only, which requires metacopy=on")]
+    DataOnlyRequiresMetacopy(PathBuf),
+
+    #[error("every lower dir is data-only, at least one regular lower dir is required")]
+    NoRegularLowerDir,
+
+    #[error("lower dir '{0:?}' is no longer available: {1}")]
+    LowerDirUnavailable(PathBuf, #[source] io::Error),
+
+    #[error("hide path '{0:?}' must be relative to the layer roots without any '..'")]
+    InvalidHidePath(PathBuf),
+
+    #[error("hide path '{0:?}' does not exist in any lower dir")]
+    HidePathNotInLower(PathBuf),
+
+    #[error("invalid tmpfs size {0:?}, it must be a number with an optional k, m, g or % suffix")]
+    InvalidTmpfsSize(String),
+
+    #[error(
+        "tmpfs_size can't be combined with private_namespace, the tmpfs would outlive the namespace"
+    )]
+    TmpfsInPrivateNamespace,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -27,6 +150,66 @@ pub enum ConfigError {
     CreateDirError(#[from] IOErrorAtPath),
     #[error("Invalid config/environment: {0:?}")]
     ValidationError(#[from] ValidationError),
+    #[error(
+        "permission denied creating '{0:?}', check the volume is writable by this user or fix its ownership"
+    )]
+    PermissionDenied(PathBuf),
+    #[error("'{0:?}' is on a read-only filesystem, check the volume isn't mounted read only")]
+    ReadOnlyFilesystem(PathBuf),
+    #[error("failed to mount tmpfs at '{0:?}': {1}")]
+    TmpfsMountError(PathBuf, #[source] nix::errno::Errno),
+    #[error("failed to unmount tmpfs at '{0:?}': {1}")]
+    TmpfsUmountError(PathBuf, #[source] nix::errno::Errno),
+    #[error("failed to read the mount table: {0}")]
+    MountInfoError(#[source] io::Error),
+}
+
+impl ConfigError {
+    /// Pick out the create failures an operator fixes differently from a transient error
+    fn from_create_error(path: &Path, e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => ConfigError::PermissionDenied(path.to_path_buf()),
+            io::ErrorKind::ReadOnlyFilesystem => {
+                ConfigError::ReadOnlyFilesystem(path.to_path_buf())
+            }
+            _ => ConfigError::CreateDirError(IOErrorAtPath(path.to_path_buf(), e)),
+        }
+    }
+}
+
+/// A file in the upper layer that hides a file provided by one of the lower layers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MaskedFile {
+    /// Path relative to the root of the overlay
+    pub relative_path: PathBuf,
+    /// The masking copy in the upper layer
+    pub upper_path: PathBuf,
+    /// Whether the upper copy was modified no earlier than the lower one it hides. An older
+    /// upper copy is likely a stale override the lower file has since moved on from.
+    pub upper_newer: bool,
+}
+
+/// Machine readable summary of the checks `validate` runs against the layers on disk.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ValidationReport {
+    /// Masked files not covered by `allowed_masked_files`, sorted by relative path
+    pub masked_files: Vec<MaskedFile>,
+    /// Masked files that `allowed_masked_files` let through, ie the overrides in effect
+    pub applied_masks: Vec<MaskedFile>,
+    /// `allowed_masked_files` entries with no masking file in the upper dir, candidates for
+    /// trimming an over-broad allow list. Always empty while the upper dir is missing, empty or
+    /// covered by a tmpfs, as there is nothing yet for the entries to match.
+    pub unused_allowed_masks: Vec<PathBuf>,
+}
+
+/// What `validate` would mount, see `MountConfig::plan`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MountPlan {
+    pub layers: LayerPlan,
+    /// The option string passed to the overlay mount
+    pub mount_options: String,
+    /// Masked files let through by `masked_files_policy`, any others fail the plan
+    pub masked_files: Vec<MaskedFile>,
 }
 
 #[derive(Debug, Clone)]
@@ -44,26 +227,124 @@ impl<'a> From<&'a ValidatedMountConfig> for &'a MountConfig {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct LowerDir {
     volume: PathBuf,
     subdir: Option<PathBuf>,
     #[serde(default)]
     sync_mode: SyncMode,
+    #[serde(flatten)]
+    sync_options: SyncOptions,
+    /// Intent for this layer to be writable. Overlayfs only writes to the upper dir with the
+    /// lowerdir list we assemble, so this is rejected during validation for now.
+    #[serde(default)]
+    writable: bool,
+    /// Only supplies file data for metacopy redirects from the other layers, it is passed after
+    /// `::` and its files are not visible in the merged dir. Needs kernel 6.5+ and metacopy=on.
+    #[serde(default)]
+    data_only: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(PathBuf),
+    Many(Vec<PathBuf>),
+}
+
+/// Accept either a single path or a list of them
+fn one_or_many_paths<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<PathBuf>, D::Error> {
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(path) => vec![path],
+        OneOrMany::Many(paths) => paths,
+    })
+}
+
+/// Write a single path back as it is usually given rather than as a one item list
+fn serialize_one_or_many_paths<S: serde::Serializer>(
+    paths: &[PathBuf],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match paths {
+        [path] => path.serialize(serializer),
+        paths => paths.serialize(serializer),
+    }
+}
+
+/// Call `op` until it succeeds, another `retries` times while it fails with `EAGAIN` or
+/// `ETIMEDOUT`, sleeping `delay` before the first retry and twice as long before each after that
+fn retry_transient(
+    what: &str,
+    retries: usize,
+    delay: Duration,
+    op: impl FnMut() -> io::Result<()>,
+) -> io::Result<()> {
+    let transient = |e: &io::Error| {
+        matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        )
+    };
+    let on_retry = |e: &io::Error, delay| warn!("{what} failed, retrying in {delay:?}: {e}");
+    crate::retry(on_retry, retries, delay, 2, transient, op).0
+}
+
+/// Source the upper dir tmpfs is mounted with, telling it apart from a tmpfs something else
+/// mounted at the volume, eg a memory backed `emptyDir`
+pub const UPPER_TMPFS_SOURCE: &str = "overlay-mount-tmpfs";
+
+/// Whether the mount visible at `volume` is an upper dir tmpfs, a volume that doesn't exist has
+/// none. Any other mount there isn't ours to reuse or unmount.
+pub(crate) fn upper_tmpfs_mounted_at(volume: &Path) -> io::Result<bool> {
+    let volume = match fs::canonicalize(volume) {
+        Ok(volume) => volume,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let entries = mountinfo::read_mountinfo()?;
+    Ok(mountinfo::mount_at(&entries, &volume).is_some_and(is_upper_tmpfs))
+}
+
+fn is_upper_tmpfs(entry: &mountinfo::MountEntry) -> bool {
+    entry.fs_type == "tmpfs" && entry.source == UPPER_TMPFS_SOURCE
 }
 
 fn enforce_relative(volume: &Path, subdir: Option<&PathBuf>) -> Result<(), ValidationError> {
-    if let Some(subdir) = subdir {
-        if subdir.is_absolute() {
-            return Err(ValidationError::NonRelative(
-                subdir.to_path_buf(),
-                volume.to_path_buf(),
-            ));
-        }
+    if let Some(subdir) = subdir
+        && subdir.is_absolute()
+    {
+        return Err(ValidationError::NonRelative(
+            subdir.to_path_buf(),
+            volume.to_path_buf(),
+        ));
     }
     Ok(())
 }
 
+/// Modification time of `path` itself, not of what it links to
+fn modified(path: &Path) -> Result<std::time::SystemTime, IOErrorAtPath> {
+    fs::symlink_metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(|e| IOErrorAtPath(path.to_path_buf(), e))
+}
+
+/// Canonicalize `path`, resolving the longest existing ancestor when the path itself doesn't
+/// exist yet (eg a sync target or upper volume that is created later).
+fn canonicalize_existing(path: &Path) -> Result<PathBuf, IOErrorAtPath> {
+    match fs::canonicalize(path) {
+        Ok(resolved) => Ok(resolved),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => {
+                Ok(canonicalize_existing(parent)?.join(name))
+            }
+            _ => Ok(path.to_path_buf()),
+        },
+        Err(e) => Err(IOErrorAtPath(path.to_path_buf(), e)),
+    }
+}
+
 impl LowerDir {
     pub fn new(volume: PathBuf, subdir: Option<PathBuf>) -> Result<Self, ValidationError> {
         enforce_relative(&volume, subdir.as_ref())?;
@@ -71,6 +352,9 @@ impl LowerDir {
             volume,
             subdir,
             sync_mode: SyncMode::None,
+            sync_options: SyncOptions::default(),
+            writable: false,
+            data_only: false,
         })
     }
 
@@ -84,9 +368,20 @@ impl LowerDir {
             volume,
             subdir,
             sync_mode,
+            sync_options: SyncOptions::default(),
+            writable: false,
+            data_only: false,
         })
     }
 
+    pub fn volume(&self) -> &Path {
+        &self.volume
+    }
+
+    pub fn subdir(&self) -> Option<&Path> {
+        self.subdir.as_deref()
+    }
+
     pub fn full_path(&self) -> PathBuf {
         match &self.subdir {
             Some(subdir) => self.volume.join(subdir),
@@ -94,10 +389,37 @@ impl LowerDir {
         }
     }
 
+    pub fn with_sync_options(mut self, sync_options: SyncOptions) -> Self {
+        self.sync_options = sync_options;
+        self
+    }
+
+    pub fn with_writable(mut self, writable: bool) -> Self {
+        self.writable = writable;
+        self
+    }
+
+    pub fn writable(&self) -> bool {
+        self.writable
+    }
+
+    pub fn with_data_only(mut self, data_only: bool) -> Self {
+        self.data_only = data_only;
+        self
+    }
+
+    pub fn data_only(&self) -> bool {
+        self.data_only
+    }
+
     pub fn sync_mode(&self) -> &SyncMode {
         &self.sync_mode
     }
 
+    pub fn sync_options(&self) -> &SyncOptions {
+        &self.sync_options
+    }
+
     pub fn mount_path(&self) -> PathBuf {
         match &self.sync_mode {
             SyncMode::None => self.full_path(),
@@ -106,12 +428,74 @@ impl LowerDir {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// A source that populates the upper dir once at startup, eg to seed default files that can then
+/// be modified, rather than being mounted as a lower layer.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SeedDir {
+    source: PathBuf,
+    #[serde(default)]
+    only_once: bool,
+    #[serde(flatten)]
+    sync_options: SyncOptions,
+}
+
+impl SeedDir {
+    pub fn new(source: PathBuf) -> Self {
+        Self {
+            source,
+            only_once: false,
+            sync_options: SyncOptions::default(),
+        }
+    }
+
+    pub fn with_sync_options(mut self, sync_options: SyncOptions) -> Self {
+        self.sync_options = sync_options;
+        self
+    }
+
+    /// Only seed an empty upper dir that wasn't seeded before, ie on the first mount of a fresh
+    /// volume, so the upper dir starts out as a copy of the source and is left alone after that.
+    /// Completion is recorded at `UpperDir::seed_marker_path`, so an upper dir the workload since
+    /// emptied isn't seeded again either. Otherwise missing files are copied in on every startup.
+    pub fn with_only_once(mut self, only_once: bool) -> Self {
+        self.only_once = only_once;
+        self
+    }
+
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    pub fn only_once(&self) -> bool {
+        self.only_once
+    }
+
+    pub fn sync_options(&self) -> &SyncOptions {
+        &self.sync_options
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct UpperDir {
     volume: PathBuf,
     upper_subdir: PathBuf,
     work_subdir: PathBuf,
-    merged_subdir: PathBuf,
+    /// A single subdir or a list of them, see `with_merged_subdirs`
+    #[serde(
+        deserialize_with = "one_or_many_paths",
+        serialize_with = "serialize_one_or_many_paths"
+    )]
+    merged_subdir: Vec<PathBuf>,
+    #[serde(default)]
+    clear_volatile_marker: bool,
+    mode: Option<u32>,
+    owner: Option<(u32, u32)>,
+    #[serde(default)]
+    tmpfs_size: Option<String>,
+    #[serde(default)]
+    work_subdir_fallbacks: Vec<PathBuf>,
+    #[serde(default)]
+    merged_mount: Option<PathBuf>,
 }
 
 impl UpperDir {
@@ -128,10 +512,95 @@ impl UpperDir {
             volume,
             upper_subdir,
             work_subdir,
-            merged_subdir,
+            merged_subdir: vec![merged_subdir],
+            clear_volatile_marker: false,
+            mode: None,
+            owner: None,
+            tmpfs_size: None,
+            work_subdir_fallbacks: Vec::new(),
+            merged_mount: None,
         })
     }
 
+    /// Permission bits applied to the upper, work and merged dirs after creating them, instead
+    /// of whatever the process umask produces.
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// `(uid, gid)` applied to the upper, work and merged dirs after creating them.
+    pub fn with_owner(mut self, uid: u32, gid: u32) -> Self {
+        self.owner = Some((uid, gid));
+        self
+    }
+
+    /// Remove the marker a previous volatile mount leaves in the work dir instead of refusing to
+    /// reuse it.
+    pub fn with_clear_volatile_marker(mut self, clear_volatile_marker: bool) -> Self {
+        self.clear_volatile_marker = clear_volatile_marker;
+        self
+    }
+
+    /// Mount a tmpfs limited to `size` (as taken by the tmpfs `size=` option, eg `512m` or `20%`)
+    /// over the volume before creating the dirs, so the upper and work dirs are scratch space
+    /// that is thrown away on unmount.
+    pub fn with_tmpfs_size(mut self, size: String) -> Self {
+        self.tmpfs_size = Some(size);
+        self
+    }
+
+    /// Work subdirs tried in order when the work subdir can't be created, eg because it is owned
+    /// by another tenant. They are on the same volume so still share the upper dir's filesystem
+    /// as overlayfs requires.
+    pub fn with_work_subdir_fallbacks(mut self, work_subdir_fallbacks: Vec<PathBuf>) -> Self {
+        self.work_subdir_fallbacks = work_subdir_fallbacks;
+        self
+    }
+
+    /// Mount the overlay at this absolute path instead of the merged subdir of the volume, eg
+    /// at a fixed `/merged` consumers expect. It has to be an existing directory and is left
+    /// as it is, without applying the mode and owner. The merged subdir is ignored.
+    pub fn with_merged_mount(mut self, merged_mount: PathBuf) -> Self {
+        self.merged_mount = Some(merged_mount);
+        self
+    }
+
+    /// Mount the overlay at the first of these merged subdirs and bind mount it at the rest, so
+    /// consumers can each see it at their own path with their own propagation.
+    pub fn with_merged_subdirs(
+        mut self,
+        merged_subdirs: Vec<PathBuf>,
+    ) -> Result<Self, ValidationError> {
+        for subdir in &merged_subdirs {
+            enforce_relative(&self.volume, Some(subdir))?;
+        }
+        self.merged_subdir = merged_subdirs;
+        Ok(self)
+    }
+
+    pub fn tmpfs_size(&self) -> Option<&str> {
+        self.tmpfs_size.as_deref()
+    }
+
+    /// Unmount the tmpfs `MountConfig::prepare` mounted over the volume, for when setting up the
+    /// overlay failed after that. Returns whether there was one, there isn't when `tmpfs_size`
+    /// is unset or it has already been unmounted.
+    pub fn release_tmpfs(&self) -> Result<bool, ConfigError> {
+        if self.tmpfs_size.is_none()
+            || !upper_tmpfs_mounted_at(&self.volume).map_err(ConfigError::MountInfoError)?
+        {
+            return Ok(false);
+        }
+        umount(&self.volume).map_err(|e| ConfigError::TmpfsUmountError(self.volume.clone(), e))?;
+        info!("Unmounted tmpfs at {:?}", self.volume);
+        Ok(true)
+    }
+
+    pub fn volume(&self) -> &Path {
+        &self.volume
+    }
+
     pub fn upper_path(&self) -> PathBuf {
         self.volume.join(&self.upper_subdir)
     }
@@ -140,21 +609,325 @@ impl UpperDir {
         self.volume.join(&self.work_subdir)
     }
 
+    /// The `merged_mount` if set, the merged subdir of the volume otherwise
     pub fn merged_path(&self) -> PathBuf {
-        self.volume.join(&self.merged_subdir)
+        match &self.merged_mount {
+            Some(merged_mount) => merged_mount.clone(),
+            None => self
+                .volume
+                .join(self.merged_subdir.first().map_or(Path::new(""), |p| p)),
+        }
+    }
+
+    /// Merged subdirs after the first, the overlay is bind mounted at each of these
+    pub fn extra_merged_paths(&self) -> Vec<PathBuf> {
+        self.merged_subdir
+            .iter()
+            .skip(1)
+            .map(|subdir| self.volume.join(subdir))
+            .collect()
+    }
+
+    /// Directory the kernel creates inside the work dir while mounted with `volatile`. If it is
+    /// still present the mount was not cleanly torn down and overlayfs refuses to reuse the dir.
+    pub fn volatile_marker_path(&self) -> PathBuf {
+        self.work_path().join("work/incompat/volatile")
+    }
+
+    /// File written next to the upper dir once the `only_once` seeds have all completed. It is
+    /// kept out of the upper dir so it doesn't show up in the merged dir.
+    pub fn seed_marker_path(&self) -> PathBuf {
+        let mut marker = self.upper_path().into_os_string();
+        marker.push(".seeded");
+        PathBuf::from(marker)
+    }
+}
+
+/// The dirs that make up the overlay as passed to the mount syscall, see `MountConfig::layer_plan`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LayerPlan {
+    /// Regular lower dirs, highest precedence first
+    pub lower: Vec<PathBuf>,
+    /// Data-only lower dirs, in the order they are searched
+    pub data_only: Vec<PathBuf>,
+    pub upper: PathBuf,
+    pub work: PathBuf,
+    pub merged: PathBuf,
+}
+
+/// Default for `max_lower_dirs`, matching the overlayfs `OVL_MAX_STACK` limit. Layers are also
+/// bounded by the page sized mount option string, so large stacks can fail below this.
+pub const DEFAULT_MAX_LOWER_DIRS: usize = 500;
+
+fn default_max_lower_dirs() -> usize {
+    DEFAULT_MAX_LOWER_DIRS
+}
+
+/// Whether `size` is a byte count with an optional binary unit or a percentage of RAM, as the
+/// tmpfs `size=` option takes it
+fn is_tmpfs_size(size: &str) -> bool {
+    let digits = size.trim_end_matches(['k', 'K', 'm', 'M', 'g', 'G', '%']);
+    size.len() - digits.len() <= 1
+        && !digits.is_empty()
+        && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Default for `umount_retry_delay_ms`
+pub const DEFAULT_UMOUNT_RETRY_DELAY_MS: u64 = 500;
+
+fn default_umount_retry_delay_ms() -> u64 {
+    DEFAULT_UMOUNT_RETRY_DELAY_MS
+}
+
+/// Default for `create_dir_retry_delay_ms`
+pub const DEFAULT_CREATE_DIR_RETRY_DELAY_MS: u64 = 100;
+
+fn default_create_dir_retry_delay_ms() -> u64 {
+    DEFAULT_CREATE_DIR_RETRY_DELAY_MS
+}
+
+/// How `validate` treats masked files, they are listed in the `ValidationReport` either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaskedFilesPolicy {
+    /// Fail with `ValidationError::MaskedFiles`
+    #[default]
+    Error,
+    /// Log each masked file and carry on
+    Warn,
+    /// Carry on without logging, for deployments that override lower files on purpose
+    Allow,
+    /// Allow overrides at least as new as the lower file, fail on stale ones older than it
+    AllowNewer,
+}
+
+/// What to do when a lower dir disappears while mounted, eg when the volume backing it is
+/// remounted. The overlay keeps serving what it had cached either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LowerUnavailablePolicy {
+    /// Keep serving the stale content without logging
+    #[default]
+    Ignore,
+    /// Log the missing lower dir and keep serving
+    Warn,
+    /// Fail with `ValidationError::LowerDirUnavailable` so the overlay is unmounted
+    Fail,
+}
+
+/// What happens to a work dir left by an earlier run when the directories are created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkdirReuse {
+    /// Mount with the existing work dir as is
+    #[default]
+    Reuse,
+    /// Move the existing work dir aside to `<work dir>.prev`, replacing any earlier one, and
+    /// mount with a fresh one. The previous state is kept for debugging a failed mount.
+    Fresh,
+}
+
+/// Propagation type of the merged mount, see mount_namespaces(7). Being a single value only one
+/// can be configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Propagation {
+    Private,
+    /// Mounts made under the merged dir show up in its peers and the other way round, eg for
+    /// sharing with other containers
+    Shared,
+    /// Receives mount events from the parent's peer group without sending any back
+    Slave,
+    /// Private and can't be bind mounted
+    Unbindable,
+}
+
+impl Propagation {
+    pub fn flags(&self) -> MsFlags {
+        match self {
+            Propagation::Private => MsFlags::MS_PRIVATE,
+            Propagation::Shared => MsFlags::MS_SHARED,
+            Propagation::Slave => MsFlags::MS_SLAVE,
+            Propagation::Unbindable => MsFlags::MS_UNBINDABLE,
+        }
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Overlayfs gives the first `lowerdir` entry the highest precedence, so a file in an earlier
+/// layer hides the same file in every later one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LowerOrder {
+    /// `lower_dirs[0]` is the top (highest precedence) layer.
+    #[default]
+    AsConfigured,
+    /// The last entry of `lower_dirs` is the top layer, matching left-to-right layering where
+    /// later layers are stacked on top of earlier ones.
+    Reversed,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[non_exhaustive]
 pub struct MountConfig {
     pub lower_dirs: Vec<LowerDir>,
     pub upper_dir: UpperDir,
+    /// Lower dirs with a relative `volume` are resolved against this during validation, for
+    /// configs with many layers under one mount. Absolute volumes and remote sources are kept.
+    #[serde(default)]
+    pub base_volume: Option<PathBuf>,
     #[serde(default)]
     pub allowed_masked_files: BTreeSet<PathBuf>,
+    #[serde(default)]
+    pub overlay_options: OverlayFeatures,
+    #[serde(default)]
+    pub lower_order: LowerOrder,
+    #[serde(default)]
+    pub dedup_lower_dirs: bool,
+    #[serde(default = "default_max_lower_dirs")]
+    pub max_lower_dirs: usize,
+    /// Resolve symlinks in the lower, upper and sync target paths during validation so the
+    /// masking scan and the mount operate on the real locations.
+    #[serde(default)]
+    pub canonicalize: bool,
+    /// Only look for masked files this many directories below each lower layer root, files
+    /// directly in the root are at depth 0. Unbounded when unset.
+    #[serde(default)]
+    pub mask_scan_max_depth: Option<usize>,
+    /// Paths, relative to the layer root, skipped entirely by the masked-files scan. A directory
+    /// excludes its whole subtree.
+    #[serde(default)]
+    pub mask_scan_ignore: Vec<PathBuf>,
+    /// SELinux context applied to the whole merged mount via `context=`, eg
+    /// `system_u:object_r:container_file_t:s0`
+    #[serde(default)]
+    pub selinux_context: Option<String>,
+    /// Extra locations the merged dir is bind mounted to once the overlay is up, they are
+    /// created during validation and unmounted in reverse order before the overlay
+    #[serde(default)]
+    pub bind_targets: Vec<PathBuf>,
+    /// Fail validation if the merged dir, or any of the extra merged subdirs it is bind mounted
+    /// at, already has content, eg left behind by a crash, which the overlay would hide
+    #[serde(default)]
+    pub require_empty_merged: bool,
+    /// Sources copied into the upper dir before mounting without replacing anything already there,
+    /// they are not part of the mounted layers
+    #[serde(default)]
+    pub seed_dirs: Vec<SeedDir>,
+    /// What to do with masked files not covered by `allowed_masked_files`
+    #[serde(default)]
+    pub masked_files_policy: MaskedFilesPolicy,
+    /// Source shown for the overlay in `/proc/mounts` and mountinfo instead of `overlay`, eg the pod
+    /// name for tooling that identifies mounts by their source
+    #[serde(default)]
+    pub mount_source_label: Option<String>,
+    /// Remove the overlay's internal `work` and `index` dirs left in the work dir by an earlier
+    /// mount before mounting, eg after a crash left them in a state the kernel refuses
+    #[serde(default)]
+    pub clean_workdir_on_mount: bool,
+    /// Mount in a new private mount namespace so the overlay and bind targets are only visible to
+    /// this process (and what it spawns afterwards), and go away when it exits even without an
+    /// explicit unmount. The namespace is entered once, when the `OverlayManager` is created.
+    #[serde(default)]
+    pub private_namespace: bool,
+    /// Mount propagation set on the merged dir once the overlay is mounted, inherited from the
+    /// parent mount when unset
+    #[serde(default)]
+    pub propagation: Option<Propagation>,
+    /// File caching the lower layer walks of the masked-files scan between runs, layers whose
+    /// directories all kept their mtime aren't walked again. No cache when unset.
+    #[serde(default)]
+    pub mask_scan_cache: Option<PathBuf>,
+    /// File a JSON line is appended to for every mount, remount and unmount of the overlay, with
+    /// the exact options and flags used. Opened for each record so it can be rotated.
+    #[serde(default)]
+    pub audit_file: Option<PathBuf>,
+    /// Further unmount attempts made while the overlay is busy (`EBUSY`), `umount_retry_delay_ms`
+    /// apart, before giving up
+    #[serde(default)]
+    pub umount_retries: usize,
+    /// Time slept between the attempts of `umount_retries`
+    #[serde(default = "default_umount_retry_delay_ms")]
+    pub umount_retry_delay_ms: u64,
+    /// Further attempts at creating each overlay dir and bind target while that fails with a
+    /// transient error (`EAGAIN` or `ETIMEDOUT`), as networked and FUSE backed volumes can
+    /// while still attaching. Permanent errors such as `EACCES` or `EROFS` fail straight away.
+    #[serde(default)]
+    pub create_dir_retries: usize,
+    /// Time slept before the first of `create_dir_retries`, doubled after each attempt
+    #[serde(default = "default_create_dir_retry_delay_ms")]
+    pub create_dir_retry_delay_ms: u64,
+    /// After mounting wait up to this long for the overlay to show up in the mount table, see
+    /// `OverlayManager::wait_until_ready`. Not waited for when unset.
+    #[serde(default)]
+    pub wait_until_ready_ms: Option<u64>,
+    /// Hash file contents into `lower_fingerprint` instead of sizes and mtimes, slower but
+    /// unaffected by files being rewritten with the same content
+    #[serde(default)]
+    pub fingerprint_contents: bool,
+    /// Paths, relative to the layer roots, hidden from the merged view by whiteouts created in
+    /// the upper dir before mounting. Each has to exist in a lower dir, they are not reported as
+    /// masked files.
+    #[serde(default)]
+    pub hide_paths: Vec<PathBuf>,
+    /// What the resync loop does when a lower dir can no longer be found, see
+    /// `check_lower_available`
+    #[serde(default)]
+    pub on_lower_unavailable: LowerUnavailablePolicy,
+    /// Fail validation on `allowed_masked_files` entries that match no file in the upper dir,
+    /// instead of only warning, so stale entries can't grant an override nobody meant to keep
+    #[serde(default)]
+    pub strict_allow_list: bool,
+    /// Whether a work dir left by an earlier run is mounted with again or set aside for a fresh
+    /// one
+    #[serde(default)]
+    pub workdir_reuse: WorkdirReuse,
 }
 
 impl MountConfig {
+    pub fn builder() -> MountConfigBuilder {
+        MountConfigBuilder::default()
+    }
+
+    /// Config with every optional field at its default, so tests don't have to spell out each
+    /// new field
+    #[cfg(test)]
+    pub(crate) fn new_for_test(lower_dirs: Vec<LowerDir>, upper_dir: UpperDir) -> Self {
+        MountConfig {
+            lower_dirs,
+            upper_dir,
+            allowed_masked_files: BTreeSet::new(),
+            overlay_options: OverlayFeatures::default(),
+            lower_order: LowerOrder::default(),
+            dedup_lower_dirs: false,
+            max_lower_dirs: DEFAULT_MAX_LOWER_DIRS,
+            canonicalize: false,
+            mask_scan_max_depth: None,
+            mask_scan_ignore: Vec::new(),
+            selinux_context: None,
+            bind_targets: Vec::new(),
+            require_empty_merged: false,
+            seed_dirs: Vec::new(),
+            masked_files_policy: MaskedFilesPolicy::Error,
+            mount_source_label: None,
+            clean_workdir_on_mount: false,
+            private_namespace: false,
+            propagation: None,
+            mask_scan_cache: None,
+            audit_file: None,
+            umount_retries: 0,
+            umount_retry_delay_ms: DEFAULT_UMOUNT_RETRY_DELAY_MS,
+            create_dir_retries: 0,
+            create_dir_retry_delay_ms: DEFAULT_CREATE_DIR_RETRY_DELAY_MS,
+            wait_until_ready_ms: None,
+            fingerprint_contents: false,
+            hide_paths: Vec::new(),
+            on_lower_unavailable: LowerUnavailablePolicy::Ignore,
+            strict_allow_list: false,
+            base_volume: None,
+            workdir_reuse: WorkdirReuse::Reuse,
+        }
+    }
+
     /// We are running overlay FS but in a slightly constrained environment where we don't to allow
     /// masking of the top volume.
     ///
@@ -162,239 +935,3676 @@ impl MountConfig {
     /// mutations made should be in other files not already provided. So if we find any configs in
     /// the lower layers that are overwritten by the rw volume then we are not honoring that RO
     /// config layer correctly.
+    ///
+    /// This is `check` followed by `prepare`.
     pub fn validate(self) -> Result<ValidatedMountConfig, ConfigError> {
-        self.create_directories()?;
+        let (mut config, _) = self.checked()?;
+        config.prepare()?;
+        Ok(ValidatedMountConfig(config))
+    }
 
-        let masked_files = self.find_masked_files()?;
-        if !masked_files.is_empty() {
-            Err(ValidationError::MaskedFiles(masked_files).into())
-        } else {
-            Ok(ValidatedMountConfig(self))
-        }
+    /// Run every check `validate` does against the dirs as they currently are, without creating
+    /// or removing anything. The only write is refreshing the `mask_scan_cache` if one is
+    /// configured.
+    pub fn check(&self) -> Result<(), ConfigError> {
+        self.clone().checked().map(|_| ())
     }
 
-    /// Create necessary directories for overlay filesystem
-    fn create_directories(&self) -> Result<(), IOErrorAtPath> {
-        println!("Creating overlay directories...");
+    /// Run every check `validate` does and describe the resulting mount, without creating any
+    /// dirs or mounting. The only write is refreshing the `mask_scan_cache` if one is configured.
+    pub fn plan(&self) -> Result<MountPlan, ConfigError> {
+        let (config, masked_files) = self.clone().checked()?;
+        Ok(MountPlan {
+            layers: config.layer_plan(),
+            mount_options: config.mount_options(),
+            masked_files,
+        })
+    }
 
-        let upper_path = self.upper_dir.upper_path();
-        fs::create_dir_all(&upper_path).map_err(|e| IOErrorAtPath(upper_path, e))?;
+    /// Create the overlay dirs and bind targets and clear the volatile marker if configured to.
+    /// If the work dir fell back to one of the `work_subdir_fallbacks` that becomes the work
+    /// subdir, so everything after this uses it.
+    pub fn prepare(&mut self) -> Result<(), ConfigError> {
+        let mounted_tmpfs = self.mount_upper_tmpfs()?;
+        let result = self
+            .create_directories()
+            .and_then(|()| Ok(self.clear_volatile_marker()?));
+        if result.is_err()
+            && mounted_tmpfs
+            && let Err(e) = self.upper_dir.release_tmpfs()
+        {
+            warn!("Failed to unmount the tmpfs upper during rollback: {e}");
+        }
+        result
+    }
 
-        let work_path = self.upper_dir.work_path();
-        fs::create_dir_all(&work_path).map_err(|e| IOErrorAtPath(work_path, e))?;
+    /// The checks behind `check`, returning the config with its paths canonicalized if enabled
+    /// and the masked files the policy let through
+    fn checked(mut self) -> Result<(Self, Vec<MaskedFile>), ConfigError> {
+        self.resolve_lower_volumes()?;
+        self.overlay_options.validate()?;
+        self.check_subdirs()?;
+        self.check_merged_mount()?;
+        self.check_selinux_context()?;
+        self.check_mount_source_label()?;
+        if self.canonicalize {
+            self.canonicalize_paths()
+                .map_err(ValidationError::IOError)?;
+        }
+        self.check_duplicate_lower_dirs()?;
+        self.check_overlapping_dirs()?;
+        self.check_filter_files()?;
+        self.check_link_dests()?;
+        self.check_rsync_args()?;
+        self.check_rsync_chmod()?;
+        self.check_rsh()?;
+        self.check_sync_users()?;
+        self.check_writable_lower_dirs()?;
+        self.check_data_only_lower_dirs()?;
+        self.check_hide_paths()?;
+        self.check_tmpfs()?;
+        if self.lower_dirs.len() > self.max_lower_dirs {
+            return Err(ValidationError::TooManyLowerDirs {
+                count: self.lower_dirs.len(),
+                max: self.max_lower_dirs,
+            }
+            .into());
+        }
+        self.check_merged_empty()?;
+        self.check_volatile_marker()?;
+        let masked_files = self.check_masked_files(true)?;
 
-        let merged_path = self.upper_dir.merged_path();
-        fs::create_dir_all(&merged_path).map_err(|e| IOErrorAtPath(merged_path, e))?;
+        Ok((self, masked_files))
+    }
 
+    /// Join relative lower dir volumes onto `base_volume`, done as part of validation. Remote
+    /// sources look relative but are left alone as rsync connects to them.
+    pub fn resolve_lower_volumes(&mut self) -> Result<(), ValidationError> {
+        if let Some(base_volume) = &self.base_volume
+            && !base_volume.is_absolute()
+        {
+            return Err(ValidationError::RelativeBaseVolume(base_volume.clone()));
+        }
+        for lower_dir in &mut self.lower_dirs {
+            if lower_dir.volume.is_absolute() || is_remote_source(&lower_dir.volume) {
+                continue;
+            }
+            match &self.base_volume {
+                Some(base_volume) => lower_dir.volume = base_volume.join(&lower_dir.volume),
+                None => {
+                    return Err(ValidationError::RelativeVolumeWithoutBase(
+                        lower_dir.volume.clone(),
+                    ));
+                }
+            }
+        }
         Ok(())
     }
 
-    /// Find files in upper layer that would mask files in lower layers
-    fn find_masked_files(&self) -> Result<Vec<PathBuf>, ValidationError> {
-        let mut masked_files = Vec::new();
-        let upper_path = self.upper_dir.upper_path();
-
-        if !upper_path.exists() {
-            return Ok(masked_files);
+    /// Subdirs are joined onto their volume, so the constructors reject absolute ones. Configs
+    /// are usually deserialized instead, so that is checked again here along with `..` escapes.
+    fn check_subdirs(&self) -> Result<(), ValidationError> {
+        let lower_subdirs = self.lower_dirs.iter().filter_map(|lower_dir| {
+            lower_dir
+                .subdir
+                .as_ref()
+                .map(|subdir| (&lower_dir.volume, subdir))
+        });
+        if self.upper_dir.merged_subdir.is_empty() {
+            return Err(ValidationError::MissingField("merged_subdir"));
         }
+        let upper_subdirs = [&self.upper_dir.upper_subdir, &self.upper_dir.work_subdir]
+            .into_iter()
+            .chain(&self.upper_dir.merged_subdir)
+            .chain(&self.upper_dir.work_subdir_fallbacks)
+            .map(|subdir| (&self.upper_dir.volume, subdir));
 
-        // Collect all file paths from lower directories
-        let mut lower_files = std::collections::HashSet::new();
-        for lower_dir in &self.lower_dirs {
-            let lower_path = lower_dir.full_path();
-            if lower_path.exists() {
-                Self::collect_file_paths(&lower_path, &lower_path, &mut lower_files)?;
+        for (volume, subdir) in lower_subdirs.chain(upper_subdirs) {
+            enforce_relative(volume, Some(subdir))?;
+            if subdir
+                .components()
+                .any(|component| component == std::path::Component::ParentDir)
+            {
+                return Err(ValidationError::SubdirEscapesVolume(
+                    subdir.clone(),
+                    volume.clone(),
+                ));
             }
         }
+        Ok(())
+    }
 
-        // Check if any of these paths exist in upper layer
-        for relative_path in lower_files {
-            let upper_file_path = upper_path.join(&relative_path);
-            if upper_file_path.exists() && !self.allowed_masked_files.contains(&relative_path) {
-                masked_files.push(upper_file_path);
+    /// Unlike the merged subdir an unrelated mount point isn't created, as a typo would otherwise
+    /// quietly mount somewhere nobody looks
+    fn check_merged_mount(&self) -> Result<(), ValidationError> {
+        match &self.upper_dir.merged_mount {
+            Some(merged_mount) if !merged_mount.is_absolute() || !merged_mount.is_dir() => {
+                Err(ValidationError::InvalidMergedMount(merged_mount.clone()))
             }
+            _ => Ok(()),
         }
-
-        Ok(masked_files)
     }
 
-    /// Recursively collect relative file paths from a directory
-    fn collect_file_paths(
-        dir: &Path,
-        base_dir: &Path,
-        file_paths: &mut std::collections::HashSet<PathBuf>,
-    ) -> Result<(), IOErrorAtPath> {
-        for entry in fs::read_dir(dir).map_err(|e| IOErrorAtPath(dir.to_path_buf(), e))? {
-            let entry = entry.map_err(|e| IOErrorAtPath(dir.to_path_buf(), e))?;
-            let path = entry.path();
+    /// overlayfs needs the upper, work and merged dirs to be separate trees, and a lower dir
+    /// inside the upper or work dir (or the other way round) would be written to behind its back.
+    /// One inside the merged dir (or the other way round) would be hidden by the overlay it is part
+    /// of. The extra merged dirs are held to the same, which also keeps them distinct.
+    fn check_overlapping_dirs(&self) -> Result<(), ValidationError> {
+        let layers = self.layer_plan();
+        let overlaps = |a: &Path, b: &Path| a.starts_with(b) || b.starts_with(a);
+        let extra_merged = self.upper_dir.extra_merged_paths();
+        let overlay_dirs: Vec<&PathBuf> = [&layers.upper, &layers.work, &layers.merged]
+            .into_iter()
+            .chain(&extra_merged)
+            .collect();
 
-            if path.is_dir() {
-                Self::collect_file_paths(&path, base_dir, file_paths)?;
-            } else if let Ok(relative_path) = path.strip_prefix(base_dir) {
-                file_paths.insert(relative_path.to_path_buf());
+        for (index, dir) in overlay_dirs.iter().enumerate() {
+            for other in &overlay_dirs[index + 1..] {
+                if overlaps(dir, other) {
+                    return Err(ValidationError::OverlappingDirs(
+                        dir.to_path_buf(),
+                        other.to_path_buf(),
+                    ));
+                }
+            }
+        }
+        for lower in layers.lower.iter().chain(&layers.data_only) {
+            for dir in overlay_dirs.iter().copied() {
+                if overlaps(lower, dir) {
+                    return Err(ValidationError::OverlappingDirs(
+                        lower.clone(),
+                        dir.to_path_buf(),
+                    ));
+                }
+            }
+        }
+        // Any of the fallbacks can end up as the work dir, so they are held to the same rules
+        for fallback in &self.upper_dir.work_subdir_fallbacks {
+            let fallback = self.upper_dir.volume.join(fallback);
+            let others = [&layers.upper, &layers.merged]
+                .into_iter()
+                .chain(&extra_merged)
+                .chain(&layers.lower)
+                .chain(&layers.data_only);
+            for other in others {
+                if overlaps(&fallback, other) {
+                    return Err(ValidationError::OverlappingDirs(fallback, other.clone()));
+                }
             }
         }
         Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
-    use tempfile::TempDir;
 
-    fn create_test_file(dir: &Path, relative_path: &str, content: &str) -> PathBuf {
-        let file_path = dir.join(relative_path);
-        if let Some(parent) = file_path.parent() {
-            fs::create_dir_all(parent).unwrap();
+    /// Apply the `masked_files_policy` and `strict_allow_list`, `warn_unused` logs the unused
+    /// allow entries when they are otherwise let through
+    fn check_masked_files(&self, warn_unused: bool) -> Result<Vec<MaskedFile>, ValidationError> {
+        let ValidationReport {
+            masked_files,
+            unused_allowed_masks,
+            ..
+        } = self.validate_report()?;
+        if !unused_allowed_masks.is_empty() {
+            if self.strict_allow_list {
+                return Err(ValidationError::UnusedAllowEntries(unused_allowed_masks));
+            }
+            if warn_unused {
+                warn!(
+                    "allowed_masked_files entries {unused_allowed_masks:?} match no file in the upper dir"
+                );
+            }
+        }
+        if masked_files.is_empty() {
+            return Ok(masked_files);
+        }
+        match self.masked_files_policy {
+            MaskedFilesPolicy::Error => Err(ValidationError::MaskedFiles(
+                masked_files
+                    .into_iter()
+                    .map(|file| file.upper_path)
+                    .collect(),
+            )),
+            MaskedFilesPolicy::Warn => {
+                for file in &masked_files {
+                    warn!(
+                        "{:?} masks {:?} from the lower layers",
+                        file.upper_path, file.relative_path
+                    );
+                }
+                Ok(masked_files)
+            }
+            MaskedFilesPolicy::Allow => Ok(masked_files),
+            MaskedFilesPolicy::AllowNewer => {
+                let stale: Vec<_> = masked_files
+                    .iter()
+                    .filter(|file| !file.upper_newer)
+                    .map(|file| file.upper_path.clone())
+                    .collect();
+                if !stale.is_empty() {
+                    return Err(ValidationError::MaskedFiles(stale));
+                }
+                Ok(masked_files)
+            }
         }
-        fs::write(&file_path, content).unwrap();
-        file_path
+    }
+
+    /// Stat every lower dir's `full_path`, applying `on_lower_unavailable` to the first one that
+    /// can't be. Meant to be called periodically while mounted.
+    pub fn check_lower_available(&self) -> Result<(), ValidationError> {
+        if self.on_lower_unavailable == LowerUnavailablePolicy::Ignore {
+            return Ok(());
+        }
+        for lower_dir in &self.lower_dirs {
+            let path = lower_dir.full_path();
+            let Err(e) = fs::metadata(&path) else {
+                continue;
+            };
+            match self.on_lower_unavailable {
+                LowerUnavailablePolicy::Fail => {
+                    return Err(ValidationError::LowerDirUnavailable(path, e));
+                }
+                _ => warn!("Lower dir {path:?} is no longer available, serving stale content: {e}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// The context is quoted in the mount options, so only a quote or a control character could
+    /// break out of it
+    fn check_selinux_context(&self) -> Result<(), ValidationError> {
+        match &self.selinux_context {
+            Some(context)
+                if context.is_empty() || context.chars().any(|c| c == '"' || c.is_control()) =>
+            {
+                Err(ValidationError::InvalidSelinuxContext(context.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Mount tables are whitespace separated with backslash escapes, anything that would need
+    /// escaping is rejected so tools reading the label back see exactly what was configured
+    fn check_mount_source_label(&self) -> Result<(), ValidationError> {
+        match &self.mount_source_label {
+            Some(label)
+                if label.is_empty()
+                    || label
+                        .chars()
+                        .any(|c| c.is_whitespace() || c == '\\' || c.is_control()) =>
+            {
+                Err(ValidationError::InvalidMountSourceLabel(label.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Replace the lower, upper and sync target volumes with their canonical paths. Subdirs stay
+    /// as written since they are joined onto the already resolved volume.
+    fn canonicalize_paths(&mut self) -> Result<(), IOErrorAtPath> {
+        for lower_dir in &mut self.lower_dirs {
+            lower_dir.volume = canonicalize_existing(&lower_dir.volume)?;
+            match &mut lower_dir.sync_mode {
+                SyncMode::None => {}
+                SyncMode::Once(target) | SyncMode::Constant(target) => {
+                    *target = canonicalize_existing(target)?;
+                }
+            }
+        }
+        self.upper_dir.volume = canonicalize_existing(&self.upper_dir.volume)?;
+        if let Some(merged_mount) = &mut self.upper_dir.merged_mount {
+            *merged_mount = canonicalize_existing(merged_mount)?;
+        }
+        Ok(())
+    }
+
+    /// Reject (or drop, when `dedup_lower_dirs` is set) lower dirs that resolve to the same
+    /// directory. Paths that don't exist yet can't be canonicalized and are compared as written.
+    fn check_duplicate_lower_dirs(&mut self) -> Result<(), ValidationError> {
+        let mut seen = std::collections::HashSet::new();
+        let mut deduped = Vec::with_capacity(self.lower_dirs.len());
+        for lower_dir in self.lower_dirs.drain(..) {
+            let full_path = lower_dir.full_path();
+            let key = fs::canonicalize(&full_path).unwrap_or_else(|_| full_path.clone());
+            if seen.insert(key) {
+                deduped.push(lower_dir);
+            } else if self.dedup_lower_dirs {
+                info!("Dropping duplicate lower dir: {full_path:?}");
+            } else {
+                return Err(ValidationError::DuplicateLowerDir(full_path));
+            }
+        }
+        self.lower_dirs = deduped;
+        Ok(())
+    }
+
+    /// Run the on-disk layer checks without failing on their findings, so callers can inspect
+    /// exactly which files are masked. Unlike `validate` this doesn't create any directories, the
+    /// only write is refreshing the `mask_scan_cache` if one is configured.
+    pub fn validate_report(&self) -> Result<ValidationReport, ValidationError> {
+        let mut config = self.clone();
+        config.resolve_lower_volumes()?;
+        config.find_masked_files()
+    }
+
+    /// The upper dir files that would mask a lower layer file and aren't covered by
+    /// `allowed_masked_files`, ie what `validate` applies the `masked_files_policy` to. Finding
+    /// some isn't an error here, so callers can decide for themselves what to do about them.
+    pub fn masked_files(&self) -> Result<Vec<PathBuf>, ValidationError> {
+        Ok(self
+            .validate_report()?
+            .masked_files
+            .into_iter()
+            .map(|file| file.upper_path)
+            .collect())
+    }
+
+    /// Check that every lower dir, at the path it will be mounted from, can be opened and listed so
+    /// permission problems (eg a ConfigMap mounted 0600) are caught before the mount is attempted.
+    /// Sync targets only exist once synced so this is meant to run on the synced config, where
+    /// the masked file check is repeated against the freshly synced content.
+    pub fn preflight(&self) -> Result<(), ValidationError> {
+        for lower_dir in &self.lower_dirs {
+            let path = lower_dir.mount_path();
+            fs::read_dir(&path)
+                .and_then(|mut entries| entries.try_for_each(|entry| entry.map(|_| ())))
+                .map_err(|e| ValidationError::LowerDirUnreadable(path, e))?;
+        }
+        // `validate` already warned about any unused allow entries
+        self.check_masked_files(false).map(drop)
+    }
+
+    fn check_writable_lower_dirs(&self) -> Result<(), ValidationError> {
+        match self.lower_dirs.iter().find(|lower_dir| lower_dir.writable) {
+            Some(lower_dir) => Err(ValidationError::WritableLowerUnsupported(
+                lower_dir.full_path(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn check_data_only_lower_dirs(&self) -> Result<(), ValidationError> {
+        let Some(data_only) = self.lower_dirs.iter().find(|lower_dir| lower_dir.data_only) else {
+            return Ok(());
+        };
+        if self.overlay_options.metacopy != Some(true) {
+            return Err(ValidationError::DataOnlyRequiresMetacopy(
+                data_only.full_path(),
+            ));
+        }
+        if self.lower_dirs.iter().all(|lower_dir| lower_dir.data_only) {
+            return Err(ValidationError::NoRegularLowerDir);
+        }
+        Ok(())
+    }
+
+    /// Hide paths are joined onto the upper dir, so they must not be able to point outside it.
+    /// Synced lower dirs are checked at both the source and target as the target may not have
+    /// been synced yet.
+    fn check_hide_paths(&self) -> Result<(), ValidationError> {
+        for hide_path in &self.hide_paths {
+            let relative = hide_path
+                .components()
+                .all(|component| matches!(component, std::path::Component::Normal(_)));
+            if !relative || hide_path.as_os_str().is_empty() {
+                return Err(ValidationError::InvalidHidePath(hide_path.clone()));
+            }
+
+            let in_lower = self
+                .lower_dirs
+                .iter()
+                .filter(|lower_dir| !lower_dir.data_only)
+                .flat_map(|lower_dir| [lower_dir.full_path(), lower_dir.mount_path()])
+                .any(|root| fs::symlink_metadata(root.join(hide_path)).is_ok());
+            if !in_lower {
+                return Err(ValidationError::HidePathNotInLower(hide_path.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// The rsync options of every lower dir and seed source
+    fn all_sync_options(&self) -> impl Iterator<Item = &SyncOptions> {
+        self.lower_dirs
+            .iter()
+            .map(|lower_dir| &lower_dir.sync_options)
+            .chain(self.seed_dirs.iter().map(|seed| &seed.sync_options))
+    }
+
+    /// Base and extra args go before the paths, so anything that isn't a flag (including a `--`
+    /// ending the flags) would shift the source and target rsync sees
+    fn check_rsync_args(&self) -> Result<(), ValidationError> {
+        if self
+            .all_sync_options()
+            .any(|options| options.skip_specials && options.rsync_base_args.is_some())
+        {
+            return Err(ValidationError::SkipSpecialsWithBaseArgs);
+        }
+        let args = self.all_sync_options().flat_map(|options| {
+            options
+                .rsync_base_args
+                .iter()
+                .flatten()
+                .chain(&options.rsync_extra_args)
+        });
+        for arg in args {
+            if !arg.starts_with('-') || arg == "--" {
+                return Err(ValidationError::PositionalRsyncArg(arg.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_rsync_chmod(&self) -> Result<(), ValidationError> {
+        let empty = self
+            .all_sync_options()
+            .filter_map(|options| options.chmod.as_deref())
+            .any(|chmod| chmod.trim().is_empty());
+        if empty {
+            return Err(ValidationError::EmptyChmod);
+        }
+        Ok(())
+    }
+
+    /// rsync ignores `-e` for local copies, so a remote shell on a local source is a mistake
+    fn check_rsh(&self) -> Result<(), ValidationError> {
+        let lower_sources = self
+            .lower_dirs
+            .iter()
+            .map(|lower_dir| (&lower_dir.sync_options, lower_dir.full_path()));
+        let seed_sources = self
+            .seed_dirs
+            .iter()
+            .map(|seed| (&seed.sync_options, seed.source().to_path_buf()));
+        for (options, source) in lower_sources.chain(seed_sources) {
+            if options.rsh.is_some() && !is_remote_source(&source) {
+                return Err(ValidationError::RshWithoutRemoteSource(source));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_sync_users(&self) -> Result<(), ValidationError> {
+        for user in self
+            .all_sync_options()
+            .filter_map(|options| options.sync_as_user.as_ref())
+        {
+            if !matches!(User::from_name(user), Ok(Some(_))) {
+                return Err(ValidationError::UnknownSyncUser(user.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Relative link dests are resolved by rsync against the target, not the working directory.
+    /// That is done lexically here as the target may not exist before the first sync.
+    fn check_link_dests(&self) -> Result<(), ValidationError> {
+        let lower_targets = self.lower_dirs.iter().map(|lower_dir| {
+            let target = match lower_dir.sync_mode() {
+                SyncMode::Once(target) | SyncMode::Constant(target) => target.clone(),
+                SyncMode::None => PathBuf::new(),
+            };
+            (&lower_dir.sync_options, target)
+        });
+        let seed_targets = self
+            .seed_dirs
+            .iter()
+            .map(|seed| (&seed.sync_options, self.upper_dir.upper_path()));
+
+        for (options, target) in lower_targets.chain(seed_targets) {
+            let Some(link_dest) = &options.link_dest else {
+                continue;
+            };
+            let mut resolved = target;
+            for component in link_dest.components() {
+                match component {
+                    std::path::Component::ParentDir => {
+                        resolved.pop();
+                    }
+                    component => resolved.push(component),
+                }
+            }
+            if !resolved.is_dir() {
+                return Err(ValidationError::MissingLinkDest(link_dest.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_filter_files(&self) -> Result<(), ValidationError> {
+        for options in self.all_sync_options() {
+            if let Some(filter_file) = &options.filter_file
+                && !filter_file.is_file()
+            {
+                return Err(ValidationError::MissingFilterFile(filter_file.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// The resolved dirs the overlay is mounted from, lower dirs are the sync targets for synced
+    /// layers rather than their sources
+    pub fn layer_plan(&self) -> LayerPlan {
+        let (data_only, lower): (Vec<_>, Vec<_>) = self
+            .ordered_lower_dirs()
+            .into_iter()
+            .partition(|lower| lower.data_only);
+        let mount_paths =
+            |layers: Vec<&LowerDir>| layers.iter().map(|lower| lower.mount_path()).collect();
+        LayerPlan {
+            lower: mount_paths(lower),
+            data_only: mount_paths(data_only),
+            upper: self.upper_dir.upper_path(),
+            work: self.upper_dir.work_path(),
+            merged: self.upper_dir.merged_path(),
+        }
+    }
+
+    /// The option string passed to the overlay mount
+    pub fn mount_options(&self) -> String {
+        let layers = self.layer_plan();
+        let join = |paths: &[PathBuf], separator| {
+            paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(separator)
+        };
+        // Data-only layers go last, each after a `::` separator
+        let mut lowerdir = join(&layers.lower, ":");
+        if !layers.data_only.is_empty() {
+            lowerdir.push_str("::");
+            lowerdir.push_str(&join(&layers.data_only, "::"));
+        }
+
+        let mut mount_options = format!(
+            "lowerdir={},upperdir={},workdir={}",
+            lowerdir,
+            layers.upper.display(),
+            layers.work.display()
+        );
+        for option in self.overlay_options.mount_options() {
+            mount_options.push(',');
+            mount_options.push_str(&option);
+        }
+        // Quoted as contexts with MCS categories contain commas
+        if let Some(context) = &self.selinux_context {
+            mount_options.push_str(&format!(",context=\"{context}\""));
+        }
+        mount_options
+    }
+
+    /// A digest of every file in the lower layers as they are mounted, for telling across
+    /// restarts whether the assembled content changed. Covers each file's layer, relative path
+    /// and size and mtime, or its contents with `fingerprint_contents`. 16 hex digits, not
+    /// suitable where collisions have to be ruled out.
+    pub fn lower_fingerprint(&self) -> io::Result<String> {
+        let mut hasher = Fnv1a::default();
+        for (index, lower_dir) in self.ordered_lower_dirs().into_iter().enumerate() {
+            let lower_path = lower_dir.mount_path();
+            let files = Self::walk_layer(&lower_path, &ScanLimits::default())
+                .map_err(|e| io::Error::new(e.1.kind(), e))?
+                .files;
+            let mut files: Vec<_> = files.into_iter().collect();
+            files.sort();
+
+            hasher.write(&(index as u64).to_le_bytes());
+            for relative_path in files {
+                let path = lower_path.join(&relative_path);
+                hasher.write_field(relative_path.as_os_str().as_bytes());
+                let metadata = fs::symlink_metadata(&path)?;
+                if !self.fingerprint_contents {
+                    hasher.write(&metadata.size().to_le_bytes());
+                    hasher.write(&metadata.mtime().to_le_bytes());
+                    hasher.write(&metadata.mtime_nsec().to_le_bytes());
+                } else if metadata.is_symlink() {
+                    hasher.write_field(fs::read_link(&path)?.as_os_str().as_bytes());
+                } else {
+                    let mut file = fs::File::open(&path)?;
+                    io::copy(&mut file, &mut hasher)?;
+                    hasher.write(&metadata.size().to_le_bytes());
+                }
+            }
+        }
+        Ok(format!("{:016x}", hasher.0))
+    }
+
+    /// Lower dirs in the order they are passed to overlayfs, highest precedence first
+    pub fn ordered_lower_dirs(&self) -> Vec<&LowerDir> {
+        match self.lower_order {
+            LowerOrder::AsConfigured => self.lower_dirs.iter().collect(),
+            LowerOrder::Reversed => self.lower_dirs.iter().rev().collect(),
+        }
+    }
+
+    /// `prepare` mounts the tmpfs in the namespace it runs in, which `private_namespace` may only
+    /// leave after that, so the tmpfs would never be unmounted there
+    fn check_tmpfs(&self) -> Result<(), ValidationError> {
+        let Some(size) = &self.upper_dir.tmpfs_size else {
+            return Ok(());
+        };
+        if !is_tmpfs_size(size) {
+            return Err(ValidationError::InvalidTmpfsSize(size.clone()));
+        }
+        if self.private_namespace {
+            return Err(ValidationError::TmpfsInPrivateNamespace);
+        }
+        Ok(())
+    }
+
+    /// Mount the tmpfs for the upper dir if configured to, returning whether one was mounted.
+    /// One left behind by an earlier run that didn't get to unmount it is used as it is rather
+    /// than stacking another on top, a tmpfs mounted there by anything else is mounted over.
+    fn mount_upper_tmpfs(&self) -> Result<bool, ConfigError> {
+        let Some(size) = &self.upper_dir.tmpfs_size else {
+            return Ok(false);
+        };
+        let volume = &self.upper_dir.volume;
+        fs::create_dir_all(volume).map_err(|e| ConfigError::from_create_error(volume, e))?;
+        if upper_tmpfs_mounted_at(volume).map_err(ConfigError::MountInfoError)? {
+            info!("Reusing the tmpfs already mounted at {volume:?}");
+            return Ok(false);
+        }
+        mount(
+            Some(UPPER_TMPFS_SOURCE),
+            volume,
+            Some("tmpfs"),
+            MsFlags::empty(),
+            Some(format!("size={size}").as_str()),
+        )
+        .map_err(|e| ConfigError::TmpfsMountError(volume.clone(), e))?;
+        info!("Mounted {size} tmpfs at {volume:?}");
+        Ok(true)
+    }
+
+    /// Create necessary directories for overlay filesystem
+    fn create_directories(&mut self) -> Result<(), ConfigError> {
+        info!("Creating overlay directories...");
+
+        self.create_overlay_dir(&self.upper_dir.upper_path())?;
+        if self.upper_dir.merged_mount.is_none() {
+            self.create_overlay_dir(&self.upper_dir.merged_path())?;
+        }
+        for path in self.upper_dir.extra_merged_paths() {
+            self.create_overlay_dir(&path)?;
+        }
+        self.create_work_dir()?;
+        for path in &self.bind_targets {
+            self.create_dir_all(path)
+                .map_err(|e| ConfigError::from_create_error(path, e))?;
+        }
+
+        Ok(())
+    }
+
+    fn create_overlay_dir(&self, path: &Path) -> Result<(), ConfigError> {
+        self.create_dir_all(path)
+            .map_err(|e| ConfigError::from_create_error(path, e))?;
+        self.apply_permissions(path)
+            .map_err(|e| ConfigError::from_create_error(path, e.into()))
+    }
+
+    /// `fs::create_dir_all`, retried on transient errors as configured by `create_dir_retries`
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        retry_transient(
+            &format!("Creating {path:?}"),
+            self.create_dir_retries,
+            Duration::from_millis(self.create_dir_retry_delay_ms),
+            || fs::create_dir_all(path),
+        )
+    }
+
+    /// Create the work dir, moving on to each of the fallbacks while that fails. The error for
+    /// the configured work dir is returned if none of them can be created.
+    fn create_work_dir(&mut self) -> Result<(), ConfigError> {
+        let primary = match self.create_fresh_work_dir(&self.upper_dir.work_path()) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        for fallback in &self.upper_dir.work_subdir_fallbacks {
+            warn!("Failed to create work dir, trying fallback {fallback:?}: {primary}");
+            match self.create_fresh_work_dir(&self.upper_dir.volume.join(fallback)) {
+                Ok(()) => {
+                    info!("Using fallback work subdir {fallback:?}");
+                    self.upper_dir.work_subdir = fallback.clone();
+                    return Ok(());
+                }
+                Err(e) => warn!("Failed to create fallback work dir {fallback:?}: {e}"),
+            }
+        }
+        Err(primary)
+    }
+
+    /// Create the work dir at `path`, first setting an existing one aside with
+    /// `WorkdirReuse::Fresh`
+    fn create_fresh_work_dir(&self, path: &Path) -> Result<(), ConfigError> {
+        if self.workdir_reuse == WorkdirReuse::Fresh && path.exists() {
+            let mut previous = path.as_os_str().to_owned();
+            previous.push(".prev");
+            let previous = PathBuf::from(previous);
+            match fs::remove_dir_all(&previous) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(IOErrorAtPath(previous, e).into()),
+            }
+            fs::rename(path, &previous).map_err(|e| IOErrorAtPath(path.to_path_buf(), e))?;
+            info!("Moved previous work dir {path:?} aside to {previous:?}");
+        }
+        self.create_overlay_dir(path)
+    }
+
+    /// A merged dir that doesn't exist yet counts as empty, it is created by `prepare`
+    fn check_merged_empty(&self) -> Result<(), ValidationError> {
+        if !self.require_empty_merged {
+            return Ok(());
+        }
+        let merged_paths = std::iter::once(self.upper_dir.merged_path())
+            .chain(self.upper_dir.extra_merged_paths());
+        for merged_path in merged_paths {
+            let mut entries = match fs::read_dir(&merged_path) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(IOErrorAtPath(merged_path, e).into()),
+            };
+            if entries.next().is_some() {
+                return Err(ValidationError::MergedNotEmpty(merged_path));
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the configured mode and ownership of the upper dir to `path`
+    fn apply_permissions(&self, path: &Path) -> nix::Result<()> {
+        if let Some(mode) = self.upper_dir.mode {
+            fchmodat(
+                AT_FDCWD,
+                path,
+                Mode::from_bits_truncate(mode),
+                FchmodatFlags::FollowSymlink,
+            )?;
+        }
+        if let Some((uid, gid)) = self.upper_dir.owner {
+            chown(path, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))?;
+        }
+        Ok(())
+    }
+
+    /// Clear or reject the dirty marker left behind by a volatile mount that was not cleanly
+    /// unmounted.
+    fn check_volatile_marker(&self) -> Result<(), ValidationError> {
+        let marker = self.upper_dir.volatile_marker_path();
+        if self.upper_dir.tmpfs_size.is_none()
+            && marker.exists()
+            && !self.upper_dir.clear_volatile_marker
+        {
+            return Err(ValidationError::VolatileMarkerPresent(marker));
+        }
+        Ok(())
+    }
+
+    fn clear_volatile_marker(&self) -> Result<(), IOErrorAtPath> {
+        let marker = self.upper_dir.volatile_marker_path();
+        if !marker.exists() || !self.upper_dir.clear_volatile_marker {
+            return Ok(());
+        }
+
+        info!("Clearing volatile marker: {marker:?}");
+        fs::remove_dir_all(&marker).map_err(|e| IOErrorAtPath(marker, e))?;
+        Ok(())
+    }
+
+    /// Find files in upper layer that would mask files in lower layers, split by whether
+    /// `allowed_masked_files` covers them, along with the allow entries that matched nothing.
+    /// Entries only count as unused when there was an upper dir to scan. Every caller refreshes
+    /// the `mask_scan_cache`, including the otherwise read-only `check` and `validate_report`.
+    fn find_masked_files(&self) -> Result<ValidationReport, ValidationError> {
+        let Some(masked_files) = self.scan_masked_files()? else {
+            return Ok(ValidationReport::default());
+        };
+        let (applied, masked): (Vec<_>, Vec<_>) = masked_files
+            .into_iter()
+            .partition(|file| self.allowed_masked_files.contains(&file.relative_path));
+        let unused_allowed_masks = self
+            .allowed_masked_files
+            .iter()
+            .filter(|allowed| !applied.iter().any(|file| file.relative_path == **allowed))
+            .cloned()
+            .collect();
+        Ok(ValidationReport {
+            masked_files: masked,
+            applied_masks: applied,
+            unused_allowed_masks,
+        })
+    }
+
+    /// Every file in the upper layer masking one in the lower layers, allowed or not. `None` when
+    /// there is no upper content to scan yet.
+    fn scan_masked_files(&self) -> Result<Option<Vec<MaskedFile>>, ValidationError> {
+        let mut masked_files = Vec::new();
+        let upper_path = self.upper_dir.upper_path();
+
+        // Whatever is there now gets covered by the fresh tmpfs
+        if self.upper_dir.tmpfs_size.is_some() {
+            return Ok(None);
+        }
+        let has_content = match fs::read_dir(&upper_path) {
+            Ok(mut entries) => entries.next().is_some(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => return Err(IOErrorAtPath(upper_path, e).into()),
+        };
+        if !has_content {
+            return Ok(None);
+        }
+
+        // Collect all file paths from lower directories, remembering which layer first provided
+        // each one so that files hidden by a higher precedence layer can be reported. Synced
+        // layers are scanned at their target as that is what gets mounted, before the first sync
+        // that is whatever an earlier run left there.
+        let mut lower_files = std::collections::HashMap::new();
+        // Only the layers scanned this time are kept so removed ones don't linger in the cache
+        let previous_cache = self.mask_scan_cache.as_deref().map(ScanCache::load);
+        let mut scan_cache = ScanCache::default();
+        // Data-only layers don't contribute paths to the merged dir so can't be masked
+        for lower_dir in self.ordered_lower_dirs() {
+            if lower_dir.data_only {
+                continue;
+            }
+            let lower_path = lower_dir.mount_path();
+            if !lower_path.exists() {
+                continue;
+            }
+
+            let layer_files = match &previous_cache {
+                Some(previous) => {
+                    scan_cache.layer_files(previous, &lower_path, &self.scan_limits())?
+                }
+                None => self.collect_layer_files(&lower_path)?,
+            };
+            for relative_path in layer_files {
+                match lower_files.get(&relative_path) {
+                    Some(visible) => warn!(
+                        "{relative_path:?} exists in both {visible:?} and {lower_path:?}, only the copy in {visible:?} is visible as it has higher precedence"
+                    ),
+                    None => {
+                        lower_files.insert(relative_path, lower_path.clone());
+                    }
+                }
+            }
+        }
+
+        // A cache that can't be written only makes the next scan slower
+        if let Some(cache_path) = &self.mask_scan_cache
+            && let Err(e) = scan_cache.save(cache_path)
+        {
+            warn!("Failed to write mask scan cache {cache_path:?}: {e}");
+        }
+
+        // Check if any of these paths exist in upper layer
+        for (relative_path, lower_path) in lower_files {
+            let upper_file_path = upper_path.join(&relative_path);
+            // Hidden paths are masked on purpose, by the whiteouts created for them
+            if upper_file_path.exists() && !self.hide_paths.contains(&relative_path) {
+                let upper_newer =
+                    modified(&upper_file_path)? >= modified(&lower_path.join(&relative_path))?;
+                masked_files.push(MaskedFile {
+                    relative_path,
+                    upper_path: upper_file_path,
+                    upper_newer,
+                });
+            }
+        }
+
+        masked_files.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        Ok(Some(masked_files))
+    }
+
+    /// Every relative path that more than one lower dir provides, with the indices into
+    /// `lower_dirs` of those that have it, sorted by path. Only the copy in the highest
+    /// precedence of them shows up in the merged dir. Layers are scanned like for masked files,
+    /// within the scan limits and at the target of synced ones, and data-only layers are skipped.
+    pub fn overlapping_lower_files(&self) -> Result<Vec<(PathBuf, Vec<usize>)>, ValidationError> {
+        let mut config = self.clone();
+        config.resolve_lower_volumes()?;
+        let mut providers: std::collections::BTreeMap<PathBuf, Vec<usize>> = Default::default();
+        for (index, lower_dir) in config.lower_dirs.iter().enumerate() {
+            let lower_path = lower_dir.mount_path();
+            if lower_dir.data_only || !lower_path.exists() {
+                continue;
+            }
+            for relative_path in config.collect_layer_files(&lower_path)? {
+                providers.entry(relative_path).or_default().push(index);
+            }
+        }
+        Ok(providers
+            .into_iter()
+            .filter(|(_, indices)| indices.len() > 1)
+            .collect())
+    }
+
+    fn scan_limits(&self) -> ScanLimits<'_> {
+        ScanLimits {
+            max_depth: self.mask_scan_max_depth,
+            ignore: &self.mask_scan_ignore,
+        }
+    }
+
+    /// All file paths in a lower layer within the scan limits, relative to its root
+    fn collect_layer_files(
+        &self,
+        lower_path: &Path,
+    ) -> Result<std::collections::HashSet<PathBuf>, IOErrorAtPath> {
+        Ok(Self::walk_layer(lower_path, &self.scan_limits())?.files)
+    }
+
+    /// Walk the layer at `root` within `limits`, split across rayon tasks with the `parallel`
+    /// feature
+    fn walk_layer(root: &Path, limits: &ScanLimits) -> Result<LayerWalk, IOErrorAtPath> {
+        #[cfg(feature = "parallel")]
+        {
+            Self::collect_file_paths_parallel(root, root, limits)
+        }
+        #[cfg(not(feature = "parallel"))]
+        {
+            let mut walk = LayerWalk::default();
+            Self::collect_file_paths(root, root, limits, &mut walk)?;
+            Ok(walk)
+        }
+    }
+
+    /// Same walk as `collect_file_paths` but each subdirectory is scanned as a separate rayon
+    /// task and the per-directory results are merged on the way back up.
+    #[cfg(feature = "parallel")]
+    fn collect_file_paths_parallel(
+        dir: &Path,
+        base_dir: &Path,
+        limits: &ScanLimits,
+    ) -> Result<LayerWalk, IOErrorAtPath> {
+        use rayon::prelude::*;
+
+        let io_error = |e| IOErrorAtPath(dir.to_path_buf(), e);
+        let dir_mtime = mtime(dir).map_err(io_error)?;
+        let entries = fs::read_dir(dir)
+            .map_err(io_error)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(io_error)?;
+
+        let mut walk = entries
+            .into_par_iter()
+            .map(|path| {
+                let mut walk = LayerWalk::default();
+                let Ok(relative_path) = path.strip_prefix(base_dir) else {
+                    return Ok(walk);
+                };
+                if path.is_dir() {
+                    if limits.descend_into(relative_path) {
+                        walk = Self::collect_file_paths_parallel(&path, base_dir, limits)?;
+                    }
+                } else if !limits.is_ignored(relative_path) {
+                    walk.files.insert(relative_path.to_path_buf());
+                }
+                Ok(walk)
+            })
+            .try_reduce(LayerWalk::default, |mut a, b| {
+                a.files.extend(b.files);
+                a.dir_mtimes.extend(b.dir_mtimes);
+                Ok(a)
+            })?;
+        walk.record_dir(dir, base_dir, dir_mtime);
+        Ok(walk)
+    }
+
+    /// Recursively collect relative file paths from a directory
+    #[cfg(any(not(feature = "parallel"), test))]
+    fn collect_file_paths(
+        dir: &Path,
+        base_dir: &Path,
+        limits: &ScanLimits,
+        walk: &mut LayerWalk,
+    ) -> Result<(), IOErrorAtPath> {
+        let io_error = |e| IOErrorAtPath(dir.to_path_buf(), e);
+        let dir_mtime = mtime(dir).map_err(io_error)?;
+        for entry in fs::read_dir(dir).map_err(io_error)? {
+            let path = entry.map_err(io_error)?.path();
+            let Ok(relative_path) = path.strip_prefix(base_dir) else {
+                continue;
+            };
+
+            if path.is_dir() {
+                if limits.descend_into(relative_path) {
+                    Self::collect_file_paths(&path, base_dir, limits, walk)?;
+                }
+            } else if !limits.is_ignored(relative_path) {
+                walk.files.insert(relative_path.to_path_buf());
+            }
+        }
+        walk.record_dir(dir, base_dir, dir_mtime);
+        Ok(())
+    }
+}
+
+/// What a walk of a lower layer found, see `MountConfig::walk_layer`
+#[derive(Debug, Default, PartialEq, Eq)]
+struct LayerWalk {
+    /// Files within the scan limits, relative to the layer root
+    files: std::collections::HashSet<PathBuf>,
+    /// `(seconds, nanoseconds)` mtime of every directory read, relative to the layer root. Each
+    /// is taken before listing the directory so a change made during the walk still shows up.
+    dir_mtimes: std::collections::BTreeMap<PathBuf, (i64, i64)>,
+}
+
+impl LayerWalk {
+    fn record_dir(&mut self, dir: &Path, base_dir: &Path, mtime: (i64, i64)) {
+        let relative_dir = dir.strip_prefix(base_dir).unwrap_or(dir);
+        self.dir_mtimes.insert(relative_dir.to_path_buf(), mtime);
+    }
+}
+
+fn mtime(path: &Path) -> io::Result<(i64, i64)> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.mtime(), metadata.mtime_nsec()))
+}
+
+/// Programmatic construction of a `MountConfig`, with every optional field at the same default
+/// as when it is left out of a config file.
+#[derive(Debug, Clone, Default)]
+pub struct MountConfigBuilder {
+    lower_dirs: Vec<LowerDir>,
+    upper_dir: Option<UpperDir>,
+    allowed_masked_files: BTreeSet<PathBuf>,
+    overlay_options: OverlayFeatures,
+    lower_order: LowerOrder,
+    dedup_lower_dirs: bool,
+    max_lower_dirs: Option<usize>,
+    canonicalize: bool,
+    mask_scan_max_depth: Option<usize>,
+    mask_scan_ignore: Vec<PathBuf>,
+    selinux_context: Option<String>,
+    bind_targets: Vec<PathBuf>,
+    require_empty_merged: bool,
+    seed_dirs: Vec<SeedDir>,
+    masked_files_policy: MaskedFilesPolicy,
+    mount_source_label: Option<String>,
+    clean_workdir_on_mount: bool,
+    private_namespace: bool,
+    propagation: Option<Propagation>,
+    mask_scan_cache: Option<PathBuf>,
+    audit_file: Option<PathBuf>,
+    umount_retries: usize,
+    umount_retry_delay_ms: Option<u64>,
+    create_dir_retries: usize,
+    create_dir_retry_delay_ms: Option<u64>,
+    wait_until_ready_ms: Option<u64>,
+    fingerprint_contents: bool,
+    hide_paths: Vec<PathBuf>,
+    on_lower_unavailable: LowerUnavailablePolicy,
+    strict_allow_list: bool,
+    base_volume: Option<PathBuf>,
+    workdir_reuse: WorkdirReuse,
+}
+
+impl MountConfigBuilder {
+    /// Add a lower dir below the ones already added
+    pub fn add_lower(mut self, lower_dir: LowerDir) -> Self {
+        self.lower_dirs.push(lower_dir);
+        self
+    }
+
+    pub fn upper(mut self, upper_dir: UpperDir) -> Self {
+        self.upper_dir = Some(upper_dir);
+        self
+    }
+
+    pub fn allow_masked(mut self, relative_path: impl Into<PathBuf>) -> Self {
+        self.allowed_masked_files.insert(relative_path.into());
+        self
+    }
+
+    pub fn overlay_options(mut self, overlay_options: OverlayFeatures) -> Self {
+        self.overlay_options = overlay_options;
+        self
+    }
+
+    pub fn lower_order(mut self, lower_order: LowerOrder) -> Self {
+        self.lower_order = lower_order;
+        self
+    }
+
+    pub fn dedup_lower_dirs(mut self, dedup_lower_dirs: bool) -> Self {
+        self.dedup_lower_dirs = dedup_lower_dirs;
+        self
+    }
+
+    pub fn max_lower_dirs(mut self, max_lower_dirs: usize) -> Self {
+        self.max_lower_dirs = Some(max_lower_dirs);
+        self
+    }
+
+    pub fn canonicalize(mut self, canonicalize: bool) -> Self {
+        self.canonicalize = canonicalize;
+        self
+    }
+
+    pub fn mask_scan_max_depth(mut self, max_depth: usize) -> Self {
+        self.mask_scan_max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn mask_scan_ignore(mut self, relative_path: impl Into<PathBuf>) -> Self {
+        self.mask_scan_ignore.push(relative_path.into());
+        self
+    }
+
+    pub fn selinux_context(mut self, context: impl Into<String>) -> Self {
+        self.selinux_context = Some(context.into());
+        self
+    }
+
+    pub fn add_bind_target(mut self, target: impl Into<PathBuf>) -> Self {
+        self.bind_targets.push(target.into());
+        self
+    }
+
+    pub fn require_empty_merged(mut self, require_empty_merged: bool) -> Self {
+        self.require_empty_merged = require_empty_merged;
+        self
+    }
+
+    pub fn add_seed(mut self, seed: SeedDir) -> Self {
+        self.seed_dirs.push(seed);
+        self
+    }
+
+    pub fn masked_files_policy(mut self, masked_files_policy: MaskedFilesPolicy) -> Self {
+        self.masked_files_policy = masked_files_policy;
+        self
+    }
+
+    pub fn mount_source_label(mut self, label: impl Into<String>) -> Self {
+        self.mount_source_label = Some(label.into());
+        self
+    }
+
+    pub fn clean_workdir_on_mount(mut self, clean_workdir_on_mount: bool) -> Self {
+        self.clean_workdir_on_mount = clean_workdir_on_mount;
+        self
+    }
+
+    pub fn private_namespace(mut self, private_namespace: bool) -> Self {
+        self.private_namespace = private_namespace;
+        self
+    }
+
+    pub fn propagation(mut self, propagation: Propagation) -> Self {
+        self.propagation = Some(propagation);
+        self
+    }
+
+    pub fn mask_scan_cache(mut self, path: impl Into<PathBuf>) -> Self {
+        self.mask_scan_cache = Some(path.into());
+        self
+    }
+
+    pub fn audit_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.audit_file = Some(path.into());
+        self
+    }
+
+    pub fn umount_retries(mut self, umount_retries: usize) -> Self {
+        self.umount_retries = umount_retries;
+        self
+    }
+
+    pub fn umount_retry_delay_ms(mut self, delay_ms: u64) -> Self {
+        self.umount_retry_delay_ms = Some(delay_ms);
+        self
+    }
+
+    pub fn create_dir_retries(mut self, create_dir_retries: usize) -> Self {
+        self.create_dir_retries = create_dir_retries;
+        self
+    }
+
+    pub fn create_dir_retry_delay_ms(mut self, delay_ms: u64) -> Self {
+        self.create_dir_retry_delay_ms = Some(delay_ms);
+        self
+    }
+
+    pub fn wait_until_ready_ms(mut self, timeout_ms: u64) -> Self {
+        self.wait_until_ready_ms = Some(timeout_ms);
+        self
+    }
+
+    pub fn fingerprint_contents(mut self, fingerprint_contents: bool) -> Self {
+        self.fingerprint_contents = fingerprint_contents;
+        self
+    }
+
+    pub fn hide_path(mut self, relative_path: impl Into<PathBuf>) -> Self {
+        self.hide_paths.push(relative_path.into());
+        self
+    }
+
+    pub fn on_lower_unavailable(mut self, policy: LowerUnavailablePolicy) -> Self {
+        self.on_lower_unavailable = policy;
+        self
+    }
+
+    pub fn strict_allow_list(mut self, strict_allow_list: bool) -> Self {
+        self.strict_allow_list = strict_allow_list;
+        self
+    }
+
+    pub fn base_volume(mut self, base_volume: impl Into<PathBuf>) -> Self {
+        self.base_volume = Some(base_volume.into());
+        self
+    }
+
+    pub fn workdir_reuse(mut self, workdir_reuse: WorkdirReuse) -> Self {
+        self.workdir_reuse = workdir_reuse;
+        self
+    }
+
+    /// Fails if no upper dir or no lower dirs were given, overlayfs needs at least one of each.
+    pub fn build(self) -> Result<MountConfig, ValidationError> {
+        let upper_dir = self
+            .upper_dir
+            .ok_or(ValidationError::MissingField("upper_dir"))?;
+        if self.lower_dirs.is_empty() {
+            return Err(ValidationError::MissingField("lower_dirs"));
+        }
+
+        Ok(MountConfig {
+            lower_dirs: self.lower_dirs,
+            upper_dir,
+            allowed_masked_files: self.allowed_masked_files,
+            overlay_options: self.overlay_options,
+            lower_order: self.lower_order,
+            dedup_lower_dirs: self.dedup_lower_dirs,
+            max_lower_dirs: self.max_lower_dirs.unwrap_or(DEFAULT_MAX_LOWER_DIRS),
+            canonicalize: self.canonicalize,
+            mask_scan_max_depth: self.mask_scan_max_depth,
+            mask_scan_ignore: self.mask_scan_ignore,
+            selinux_context: self.selinux_context,
+            bind_targets: self.bind_targets,
+            require_empty_merged: self.require_empty_merged,
+            seed_dirs: self.seed_dirs,
+            masked_files_policy: self.masked_files_policy,
+            mount_source_label: self.mount_source_label,
+            clean_workdir_on_mount: self.clean_workdir_on_mount,
+            private_namespace: self.private_namespace,
+            propagation: self.propagation,
+            mask_scan_cache: self.mask_scan_cache,
+            audit_file: self.audit_file,
+            umount_retries: self.umount_retries,
+            umount_retry_delay_ms: self
+                .umount_retry_delay_ms
+                .unwrap_or(DEFAULT_UMOUNT_RETRY_DELAY_MS),
+            create_dir_retries: self.create_dir_retries,
+            create_dir_retry_delay_ms: self
+                .create_dir_retry_delay_ms
+                .unwrap_or(DEFAULT_CREATE_DIR_RETRY_DELAY_MS),
+            wait_until_ready_ms: self.wait_until_ready_ms,
+            fingerprint_contents: self.fingerprint_contents,
+            hide_paths: self.hide_paths,
+            on_lower_unavailable: self.on_lower_unavailable,
+            strict_allow_list: self.strict_allow_list,
+            base_volume: self.base_volume,
+            workdir_reuse: self.workdir_reuse,
+        })
+    }
+}
+
+/// 64 bit FNV-1a, a hash that stays the same across builds and platforms unlike the std one
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    /// Write variable length `bytes` prefixed with their length, so neighbouring fields can't
+    /// run into each other
+    fn write_field(&mut self, bytes: &[u8]) {
+        self.write(&(bytes.len() as u64).to_le_bytes());
+        self.write(bytes);
+    }
+}
+
+impl io::Write for Fnv1a {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Fnv1a::write(self, buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Bounds on the masked-files walk, see `mask_scan_max_depth` and `mask_scan_ignore`
+#[derive(Debug, Default)]
+struct ScanLimits<'a> {
+    max_depth: Option<usize>,
+    ignore: &'a [PathBuf],
+}
+
+impl ScanLimits<'_> {
+    fn is_ignored(&self, relative_path: &Path) -> bool {
+        self.ignore.iter().any(|ignored| ignored == relative_path)
+    }
+
+    /// Whether the walk should recurse into the directory at `relative_path`. Files directly in
+    /// the layer root are at depth 0, so a directory's contents sit at its component count.
+    fn descend_into(&self, relative_path: &Path) -> bool {
+        !self.is_ignored(relative_path)
+            && self
+                .max_depth
+                .is_none_or(|max_depth| relative_path.components().count() <= max_depth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn create_test_file(dir: &Path, relative_path: &str, content: &str) -> PathBuf {
+        let file_path = dir.join(relative_path);
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(&file_path, content).unwrap();
+        file_path
+    }
+
+    /// The `upper`, `work` and `merged` subdirs of `volume`
+    fn test_upper_dir(volume: impl Into<PathBuf>) -> UpperDir {
+        UpperDir::new(
+            volume.into(),
+            PathBuf::from("upper"),
+            PathBuf::from("work"),
+            PathBuf::from("merged"),
+        )
+        .unwrap()
+    }
+
+    /// A config mounting `lower_dirs` over `test_upper_dir(volume)`
+    fn test_config(volume: impl Into<PathBuf>, lower_dirs: Vec<LowerDir>) -> MountConfig {
+        MountConfig::new_for_test(lower_dirs, test_upper_dir(volume))
+    }
+
+    #[test]
+    fn test_lower_dir_new_valid() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().join("volume");
+        let subdir = Some(PathBuf::from("subdir"));
+
+        let lower_dir = LowerDir::new(volume.clone(), subdir.clone()).unwrap();
+        assert_eq!(lower_dir.volume, volume);
+        assert_eq!(lower_dir.subdir, subdir);
+    }
+
+    #[test]
+    fn test_lower_dir_new_absolute_subdir_fails() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().join("volume");
+        let subdir = Some(PathBuf::from("/absolute/path"));
+
+        let result = LowerDir::new(volume, subdir);
+        assert!(matches!(result, Err(ValidationError::NonRelative(_, _))));
+    }
+
+    #[test]
+    fn test_lower_dir_full_path() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().join("volume");
+        let subdir = Some(PathBuf::from("subdir"));
+
+        let lower_dir = LowerDir::new(volume.clone(), subdir).unwrap();
+        assert_eq!(lower_dir.full_path(), volume.join("subdir"));
+    }
+
+    #[test]
+    fn test_lower_dir_full_path_no_subdir() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().join("volume");
+
+        let lower_dir = LowerDir::new(volume.clone(), None).unwrap();
+        assert_eq!(lower_dir.full_path(), volume);
+    }
+
+    #[test]
+    fn test_upper_dir_new_valid() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().join("volume");
+        let upper_subdir = PathBuf::from("upper");
+        let work_subdir = PathBuf::from("work");
+        let merged_subdir = PathBuf::from("merged");
+
+        let upper_dir = UpperDir::new(
+            volume.clone(),
+            upper_subdir.clone(),
+            work_subdir.clone(),
+            merged_subdir.clone(),
+        )
+        .unwrap();
+
+        assert_eq!(upper_dir.volume, volume);
+        assert_eq!(upper_dir.upper_subdir, upper_subdir);
+        assert_eq!(upper_dir.work_subdir, work_subdir);
+        assert_eq!(upper_dir.merged_subdir, [merged_subdir]);
+    }
+
+    #[test]
+    fn test_upper_dir_new_absolute_paths_fail() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().join("volume");
+        let absolute_path = PathBuf::from("/absolute/path");
+
+        // Test absolute upper_subdir
+        let result = UpperDir::new(
+            volume.clone(),
+            absolute_path.clone(),
+            PathBuf::from("work"),
+            PathBuf::from("merged"),
+        );
+        assert!(matches!(result, Err(ValidationError::NonRelative(_, _))));
+
+        // Test absolute work_subdir
+        let result = UpperDir::new(
+            volume.clone(),
+            PathBuf::from("upper"),
+            absolute_path.clone(),
+            PathBuf::from("merged"),
+        );
+        assert!(matches!(result, Err(ValidationError::NonRelative(_, _))));
+
+        // Test absolute merged_subdir
+        let result = UpperDir::new(
+            volume.clone(),
+            PathBuf::from("upper"),
+            PathBuf::from("work"),
+            absolute_path,
+        );
+        assert!(matches!(result, Err(ValidationError::NonRelative(_, _))));
+    }
+
+    #[test]
+    fn test_upper_dir_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().join("volume");
+        let upper_dir = test_upper_dir(&volume);
+
+        assert_eq!(upper_dir.upper_path(), volume.join("upper"));
+        assert_eq!(upper_dir.work_path(), volume.join("work"));
+        assert_eq!(upper_dir.merged_path(), volume.join("merged"));
+    }
+
+    #[test]
+    fn test_upper_dir_multiple_merged_subdirs() {
+        let upper_dir = |merged_subdir: serde_json::Value| -> UpperDir {
+            serde_json::from_value(serde_json::json!({
+                "volume": "/volume",
+                "upper_subdir": "upper",
+                "work_subdir": "work",
+                "merged_subdir": merged_subdir,
+                "mode": null,
+                "owner": null,
+            }))
+            .unwrap()
+        };
+
+        // A single subdir round trips as a plain string
+        let single = upper_dir(serde_json::json!("merged"));
+        assert!(single.extra_merged_paths().is_empty());
+        assert_eq!(
+            serde_json::to_value(&single).unwrap()["merged_subdir"],
+            "merged"
+        );
+
+        let many = upper_dir(serde_json::json!(["merged", "app", "sidecar/data"]));
+        assert_eq!(many.merged_path(), Path::new("/volume/merged"));
+        assert_eq!(
+            many.extra_merged_paths(),
+            [
+                PathBuf::from("/volume/app"),
+                PathBuf::from("/volume/sidecar/data")
+            ]
+        );
+        let lower = || vec![LowerDir::new(PathBuf::from("/lower"), None).unwrap()];
+        let config = MountConfig::new_for_test(lower(), many.clone());
+        config.check_subdirs().unwrap();
+        config.check_overlapping_dirs().unwrap();
+
+        // Each has to be relative
+        assert!(matches!(
+            many.clone()
+                .with_merged_subdirs(vec!["merged".into(), "/app".into()]),
+            Err(ValidationError::NonRelative(..))
+        ));
+        let config =
+            MountConfig::new_for_test(lower(), upper_dir(serde_json::json!(["merged", "/app"])));
+        assert!(matches!(
+            config.check_subdirs(),
+            Err(ValidationError::NonRelative(..))
+        ));
+
+        // and distinct
+        for merged_subdirs in [
+            ["merged", "app", "app"],
+            ["merged", "merged", "app"],
+            ["merged", "app", "app/nested"],
+            ["merged", "upper", "app"],
+        ] {
+            let config =
+                MountConfig::new_for_test(lower(), upper_dir(serde_json::json!(merged_subdirs)));
+            assert!(
+                matches!(
+                    config.check_overlapping_dirs(),
+                    Err(ValidationError::OverlappingDirs(..))
+                ),
+                "{merged_subdirs:?}"
+            );
+        }
+
+        let config = MountConfig::new_for_test(lower(), upper_dir(serde_json::json!([])));
+        assert!(matches!(
+            config.check_subdirs(),
+            Err(ValidationError::MissingField("merged_subdir"))
+        ));
+    }
+
+    #[test]
+    fn test_mount_options() {
+        assert_eq!(
+            test_config(
+                "/volume",
+                vec![LowerDir::new(PathBuf::from("/lower"), None).unwrap()]
+            )
+            .mount_options(),
+            "lowerdir=/lower,upperdir=/volume/upper,workdir=/volume/work"
+        );
+    }
+
+    fn list_tree(dir: &Path) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                paths.extend(list_tree(&path));
+            }
+            paths.push(path);
+        }
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_plan_has_no_side_effects() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let base = root.join("base");
+        let app = root.join("app");
+        create_test_file(&base, "shared.conf", "base config");
+        create_test_file(&app, "conf/app.conf", "app config");
+        let volume = root.join("volume");
+        // A masked file let through by the policy is described rather than failing the plan
+        create_test_file(&volume.join("upper"), "shared.conf", "override");
+
+        let mut config = test_config(
+            &volume,
+            vec![
+                LowerDir::new(app.clone(), Some(PathBuf::from("conf"))).unwrap(),
+                LowerDir::new(base.clone(), None).unwrap(),
+            ],
+        );
+        config.masked_files_policy = MaskedFilesPolicy::Allow;
+        config.bind_targets = vec![root.join("bind")];
+
+        let before = list_tree(root);
+        let plan = config.plan().unwrap();
+        assert_eq!(list_tree(root), before);
+
+        assert_eq!(
+            plan.layers,
+            LayerPlan {
+                lower: vec![app.join("conf"), base],
+                data_only: vec![],
+                upper: volume.join("upper"),
+                work: volume.join("work"),
+                merged: volume.join("merged"),
+            }
+        );
+        assert_eq!(
+            plan.mount_options,
+            format!(
+                "lowerdir={root}/app/conf:{root}/base,upperdir={root}/volume/upper,workdir={root}/volume/work",
+                root = root.display()
+            )
+        );
+        assert_eq!(
+            plan.masked_files,
+            [MaskedFile {
+                relative_path: PathBuf::from("shared.conf"),
+                upper_path: volume.join("upper/shared.conf"),
+                upper_newer: true,
+            }]
+        );
+
+        config.masked_files_policy = MaskedFilesPolicy::Error;
+        assert!(matches!(
+            config.plan(),
+            Err(ConfigError::ValidationError(ValidationError::MaskedFiles(
+                _
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_check_subdirs() {
+        let config: MountConfig = serde_json::from_value(serde_json::json!({
+            "lower_dirs": [{"volume": "/lower", "subdir": "/etc"}],
+            "upper_dir": {
+                "volume": "/volume",
+                "upper_subdir": "upper",
+                "work_subdir": "work",
+                "merged_subdir": "merged",
+            },
+        }))
+        .unwrap();
+        assert!(matches!(
+            config.plan(),
+            Err(ConfigError::ValidationError(ValidationError::NonRelative(
+                ..
+            )))
+        ));
+
+        let config: MountConfig = serde_json::from_value(serde_json::json!({
+            "lower_dirs": [{"volume": "/lower"}],
+            "upper_dir": {
+                "volume": "/volume",
+                "upper_subdir": "upper",
+                "work_subdir": "../work",
+                "merged_subdir": "merged",
+            },
+        }))
+        .unwrap();
+        assert!(matches!(
+            config.plan(),
+            Err(ConfigError::ValidationError(ValidationError::SubdirEscapesVolume(subdir, _))) if subdir == Path::new("../work")
+        ));
+    }
+
+    #[test]
+    fn test_check_overlapping_dirs() {
+        let upper_dir = |upper: &str, work: &str, merged: &str| {
+            UpperDir::new(
+                PathBuf::from("/volume"),
+                PathBuf::from(upper),
+                PathBuf::from(work),
+                PathBuf::from(merged),
+            )
+            .unwrap()
+        };
+        let lower = || vec![LowerDir::new(PathBuf::from("/lower"), None).unwrap()];
+
+        for (upper, work, merged) in [
+            ("upper", "upper/work", "merged"),
+            ("data", "work", "data"),
+            ("overlay/upper", "work", "overlay"),
+        ] {
+            let config = MountConfig::new_for_test(lower(), upper_dir(upper, work, merged));
+            assert!(
+                matches!(
+                    config.check_overlapping_dirs(),
+                    Err(ValidationError::OverlappingDirs(..))
+                ),
+                "{upper} {work} {merged}"
+            );
+        }
+
+        let config = MountConfig::new_for_test(
+            vec![LowerDir::new(PathBuf::from("/volume/upper/lower"), None).unwrap()],
+            upper_dir("upper", "work", "merged"),
+        );
+        assert!(matches!(
+            config.check_overlapping_dirs(),
+            Err(ValidationError::OverlappingDirs(lower, upper))
+                if lower == Path::new("/volume/upper/lower") && upper == Path::new("/volume/upper")
+        ));
+
+        // Nor can the merged dir, in either direction
+        let config = MountConfig::new_for_test(
+            vec![LowerDir::new(PathBuf::from("/volume/merged/lower"), None).unwrap()],
+            upper_dir("upper", "work", "merged"),
+        );
+        assert!(matches!(
+            config.check_overlapping_dirs(),
+            Err(ValidationError::OverlappingDirs(lower, merged))
+                if lower == Path::new("/volume/merged/lower") && merged == Path::new("/volume/merged")
+        ));
+        let config = MountConfig::new_for_test(
+            lower(),
+            upper_dir("upper", "work", "merged").with_merged_mount(PathBuf::from("/lower/merged")),
+        );
+        assert!(matches!(
+            config.check_overlapping_dirs(),
+            Err(ValidationError::OverlappingDirs(lower, merged))
+                if lower == Path::new("/lower") && merged == Path::new("/lower/merged")
+        ));
+
+        // Siblings sharing a name prefix don't overlap
+        let config = MountConfig::new_for_test(
+            vec![LowerDir::new(PathBuf::from("/volume/upper-lower"), None).unwrap()],
+            upper_dir("upper", "upper-work", "merged"),
+        );
+        config.check_overlapping_dirs().unwrap();
+
+        // Work subdir fallbacks can't overlap the other dirs either
+        for fallback in ["upper/work", "merged/work", "merged"] {
+            let config = MountConfig::new_for_test(
+                lower(),
+                upper_dir("upper", "work", "merged")
+                    .with_work_subdir_fallbacks(vec![PathBuf::from(fallback)]),
+            );
+            assert!(
+                matches!(
+                    config.check_overlapping_dirs(),
+                    Err(ValidationError::OverlappingDirs(dir, _)) if dir == Path::new("/volume").join(fallback)
+                ),
+                "{fallback}"
+            );
+        }
+        // but can sit next to them or replace a work dir it overlaps
+        let config = MountConfig::new_for_test(
+            lower(),
+            upper_dir("upper", "work", "merged")
+                .with_work_subdir_fallbacks(vec!["work-fallback".into(), "work/nested".into()]),
+        );
+        config.check_overlapping_dirs().unwrap();
+    }
+
+    #[test]
+    fn test_propagation_flags() {
+        assert_eq!(Propagation::Private.flags(), MsFlags::MS_PRIVATE);
+        assert_eq!(Propagation::Shared.flags(), MsFlags::MS_SHARED);
+        assert_eq!(Propagation::Slave.flags(), MsFlags::MS_SLAVE);
+        assert_eq!(Propagation::Unbindable.flags(), MsFlags::MS_UNBINDABLE);
+
+        let config: MountConfig = serde_json::from_value(serde_json::json!({
+            "lower_dirs": [{"volume": "/lower"}],
+            "upper_dir": {
+                "volume": "/volume",
+                "upper_subdir": "upper",
+                "work_subdir": "work",
+                "merged_subdir": "merged",
+            },
+            "propagation": "shared",
+        }))
+        .unwrap();
+        assert_eq!(config.propagation, Some(Propagation::Shared));
+    }
+
+    #[test]
+    fn test_mount_options_layer_order() {
+        let mut config = test_config(
+            "/volume",
+            vec![LowerDir::new(PathBuf::from("/lower"), None).unwrap()],
+        );
+        config.lower_dirs = vec![
+            LowerDir::new(PathBuf::from("/base"), None).unwrap(),
+            LowerDir::new(PathBuf::from("/app"), Some(PathBuf::from("conf"))).unwrap(),
+        ];
+        assert_eq!(
+            config.mount_options(),
+            "lowerdir=/base:/app/conf,upperdir=/volume/upper,workdir=/volume/work"
+        );
+
+        config.lower_order = LowerOrder::Reversed;
+        config.overlay_options.index = Some(false);
+        assert_eq!(
+            config.mount_options(),
+            "lowerdir=/app/conf:/base,upperdir=/volume/upper,workdir=/volume/work,index=off"
+        );
+    }
+
+    #[test]
+    fn test_mount_options_selinux_context() {
+        let mut config = test_config(
+            "/volume",
+            vec![LowerDir::new(PathBuf::from("/lower"), None).unwrap()],
+        );
+        config.selinux_context = Some("system_u:object_r:container_file_t:s0:c1,c2".to_string());
+
+        assert_eq!(
+            config.mount_options(),
+            "lowerdir=/lower,upperdir=/volume/upper,workdir=/volume/work,\
+             context=\"system_u:object_r:container_file_t:s0:c1,c2\""
+        );
+    }
+
+    #[test]
+    fn test_mount_options_exclude_seed_dirs() {
+        let mut config = test_config(
+            "/volume",
+            vec![LowerDir::new(PathBuf::from("/lower"), None).unwrap()],
+        );
+        config.seed_dirs = vec![SeedDir::new(PathBuf::from("/defaults"))];
+
+        assert_eq!(
+            config.mount_options(),
+            "lowerdir=/lower,upperdir=/volume/upper,workdir=/volume/work"
+        );
+    }
+
+    #[test]
+    fn test_mount_options_data_only_layers() {
+        let mut config = test_config(
+            "/volume",
+            vec![LowerDir::new(PathBuf::from("/lower"), None).unwrap()],
+        );
+        config.lower_dirs = vec![
+            LowerDir::new(PathBuf::from("/data1"), None)
+                .unwrap()
+                .with_data_only(true),
+            LowerDir::new(PathBuf::from("/lower1"), None).unwrap(),
+            LowerDir::new(PathBuf::from("/data2"), None)
+                .unwrap()
+                .with_data_only(true),
+            LowerDir::new(PathBuf::from("/lower2"), None).unwrap(),
+        ];
+        config.overlay_options.metacopy = Some(true);
+
+        assert_eq!(
+            config.mount_options(),
+            "lowerdir=/lower1:/lower2::/data1::/data2,upperdir=/volume/upper,\
+             workdir=/volume/work,metacopy=on"
+        );
+    }
+
+    #[test]
+    fn test_mount_options_use_sync_target() {
+        let mut config = test_config(
+            "/volume",
+            vec![LowerDir::new(PathBuf::from("/lower"), None).unwrap()],
+        );
+        config.lower_dirs = vec![
+            LowerDir::new_with_sync(
+                PathBuf::from("/source"),
+                None,
+                SyncMode::Once(PathBuf::from("/synced")),
+            )
+            .unwrap(),
+        ];
+
+        // The synced copy is mounted, never the source it was synced from
+        assert_eq!(
+            config.mount_options(),
+            "lowerdir=/synced,upperdir=/volume/upper,workdir=/volume/work"
+        );
+    }
+
+    #[test]
+    fn test_mount_config_create_directories() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        let lower_dir = LowerDir::new(volume.join("lower"), None).unwrap();
+        let mut config = test_config(&volume, vec![lower_dir]);
+
+        config.create_directories().unwrap();
+
+        assert!(volume.join("upper").exists());
+        assert!(volume.join("work").exists());
+        assert!(volume.join("merged").exists());
+    }
+
+    #[test]
+    fn test_workdir_reuse() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        create_test_file(&volume, "work/work/leftover", "earlier mount");
+
+        let mut config = test_config(
+            &volume,
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+        );
+
+        config.create_directories().unwrap();
+        assert!(volume.join("work/work/leftover").exists());
+        assert!(!volume.join("work.prev").exists());
+
+        config.workdir_reuse = WorkdirReuse::Fresh;
+        config.create_directories().unwrap();
+        assert_eq!(fs::read_dir(volume.join("work")).unwrap().count(), 0);
+        assert!(volume.join("work.prev/work/leftover").exists());
+
+        // Only the most recent work dir is kept aside
+        create_test_file(&volume, "work/newer", "later mount");
+        config.create_directories().unwrap();
+        assert_eq!(fs::read_dir(volume.join("work")).unwrap().count(), 0);
+        assert_eq!(
+            list_tree(&volume.join("work.prev")),
+            [volume.join("work.prev/newer")]
+        );
+    }
+
+    #[test]
+    fn test_check_creates_nothing() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().join("volume");
+
+        let mut config = test_config(
+            &volume,
+            vec![LowerDir::new(temp_dir.path().join("lower"), None).unwrap()],
+        );
+        config.bind_targets = vec![temp_dir.path().join("bind")];
+        config.require_empty_merged = true;
+        config.canonicalize = true;
+
+        config.check().unwrap();
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+
+        config.prepare().unwrap();
+        for path in ["volume/upper", "volume/work", "volume/merged", "bind"] {
+            assert!(temp_dir.path().join(path).is_dir(), "{path}");
+        }
+
+        // Apart from the scan cache, which is refreshed by checks as well
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = test_config(
+            temp_dir.path(),
+            vec![create_masked_lower_dir(temp_dir.path())],
+        );
+        config.masked_files_policy = MaskedFilesPolicy::Allow;
+        config.mask_scan_cache = Some(temp_dir.path().join("scan-cache.json"));
+        let before = list_tree(temp_dir.path());
+
+        config.check().unwrap();
+        let mut after = list_tree(temp_dir.path());
+        after.retain(|path| !before.contains(path));
+        assert_eq!(after, [temp_dir.path().join("scan-cache.json")]);
+    }
+
+    #[test]
+    fn test_work_subdir_fallbacks() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().join("volume");
+        // Nothing can be created under a path taken by a file, even as root
+        create_test_file(&volume, "work", "not a dir");
+        create_test_file(&volume, "work-taken", "not a dir");
+
+        let upper_dir = UpperDir::new(
+            volume.clone(),
+            PathBuf::from("upper"),
+            PathBuf::from("work/dir"),
+            PathBuf::from("merged"),
+        )
+        .unwrap();
+        let lower_dir = LowerDir::new(temp_dir.path().join("lower"), None).unwrap();
+        let with_fallbacks = |fallbacks: &[&str]| {
+            MountConfig::new_for_test(
+                vec![lower_dir.clone()],
+                upper_dir
+                    .clone()
+                    .with_work_subdir_fallbacks(fallbacks.iter().map(PathBuf::from).collect()),
+            )
+        };
+
+        let config: MountConfig = with_fallbacks(&["work-taken/dir", "work-fallback"])
+            .validate()
+            .unwrap()
+            .into();
+        assert_eq!(config.upper_dir.work_path(), volume.join("work-fallback"));
+        assert!(volume.join("work-fallback").is_dir());
+        assert!(config.mount_options().contains(&format!(
+            "workdir={}",
+            volume.join("work-fallback").display()
+        )));
+
+        // The configured work dir is used whenever it can be
+        fs::remove_file(volume.join("work")).unwrap();
+        let config: MountConfig = with_fallbacks(&["work-fallback"])
+            .validate()
+            .unwrap()
+            .into();
+        assert_eq!(config.upper_dir.work_path(), volume.join("work/dir"));
+
+        // Without a fallback that works the configured dir's failure is reported
+        create_test_file(&volume, "work-blocked", "not a dir");
+        let upper_dir = upper_dir
+            .clone()
+            .with_work_subdir_fallbacks(vec![PathBuf::from("work-taken/dir")]);
+        let mut config = MountConfig::new_for_test(vec![lower_dir.clone()], upper_dir);
+        config.upper_dir.work_subdir = PathBuf::from("work-blocked/dir");
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::CreateDirError(IOErrorAtPath(path, _))) if path == volume.join("work-blocked/dir")
+        ));
+
+        assert!(matches!(
+            with_fallbacks(&["../elsewhere"]).check(),
+            Err(ConfigError::ValidationError(
+                ValidationError::SubdirEscapesVolume(..)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_merged_mount() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().join("volume");
+        let merged_mount = temp_dir.path().join("merged");
+        fs::create_dir(&merged_mount).unwrap();
+        let upper_dir = test_upper_dir(&volume);
+        let with_merged_mount = |merged_mount: PathBuf| {
+            MountConfig::new_for_test(
+                vec![LowerDir::new(temp_dir.path().join("lower"), None).unwrap()],
+                upper_dir
+                    .clone()
+                    .with_merged_mount(merged_mount)
+                    .with_mode(0o700),
+            )
+        };
+
+        let config: MountConfig = with_merged_mount(merged_mount.clone())
+            .validate()
+            .unwrap()
+            .into();
+        assert_eq!(config.layer_plan().merged, merged_mount);
+        // The volume's merged subdir is not used, and the mount point is left alone
+        assert!(!volume.join("merged").exists());
+        assert_ne!(
+            fs::metadata(&merged_mount).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+
+        for invalid in [PathBuf::from("merged"), temp_dir.path().join("missing")] {
+            assert!(matches!(
+                with_merged_mount(invalid.clone()).validate(),
+                Err(ConfigError::ValidationError(ValidationError::InvalidMergedMount(path))) if path == invalid
+            ));
+        }
+    }
+
+    #[test]
+    fn test_check_reports_existing_problems() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        create_test_file(&volume.join("lower"), "app.conf", "lower");
+        create_test_file(&volume.join("upper"), "app.conf", "upper");
+
+        let config = test_config(
+            &volume,
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+        );
+
+        assert!(matches!(
+            config.check(),
+            Err(ConfigError::ValidationError(ValidationError::MaskedFiles(
+                _
+            )))
+        ));
+        assert!(!volume.join("work").exists());
+        assert!(!volume.join("merged").exists());
+    }
+
+    #[test]
+    fn test_create_directories_permission_denied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Root can create directories regardless of the parent's mode
+        if nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().join("volume");
+        fs::create_dir(&volume).unwrap();
+        fs::set_permissions(&volume, fs::Permissions::from_mode(0o555)).unwrap();
+
+        let result = test_config(
+            &volume,
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+        )
+        .create_directories();
+        fs::set_permissions(&volume, fs::Permissions::from_mode(0o755)).unwrap();
+
+        match result {
+            Err(ConfigError::PermissionDenied(path)) => assert_eq!(path, volume.join("upper")),
+            other => panic!("expected permission denied, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_create_directories_read_only_filesystem() {
+        use nix::mount::{MsFlags, mount, umount};
+
+        // Mounting the read-only filesystem requires root
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        mount(
+            Some("tmpfs"),
+            &volume,
+            Some("tmpfs"),
+            MsFlags::MS_RDONLY,
+            None::<&str>,
+        )
+        .unwrap();
+
+        let result = test_config(
+            &volume,
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+        )
+        .create_directories();
+        umount(&volume).unwrap();
+
+        match result {
+            Err(ConfigError::ReadOnlyFilesystem(path)) => assert_eq!(path, volume.join("upper")),
+            other => panic!("expected read-only filesystem, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_classify_create_error() {
+        use nix::errno::Errno;
+
+        let path = Path::new("/volume/upper");
+        let classify = |errno: Errno| ConfigError::from_create_error(path, errno.into());
+        assert!(matches!(classify(Errno::EACCES), ConfigError::PermissionDenied(p) if p == path));
+        assert!(matches!(classify(Errno::EPERM), ConfigError::PermissionDenied(p) if p == path));
+        assert!(matches!(classify(Errno::EROFS), ConfigError::ReadOnlyFilesystem(p) if p == path));
+        assert!(matches!(
+            classify(Errno::EIO),
+            ConfigError::CreateDirError(IOErrorAtPath(p, _)) if p == path
+        ));
+    }
+
+    #[test]
+    fn test_mount_config_create_directories_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        let upper_dir = test_upper_dir(&volume).with_mode(0o750);
+
+        MountConfig::new_for_test(
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+            upper_dir,
+        )
+        .create_directories()
+        .unwrap();
+
+        for dir in ["upper", "work", "merged"] {
+            let mode = fs::metadata(volume.join(dir)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o7777, 0o750, "{dir}");
+        }
+    }
+
+    #[test]
+    fn test_mount_config_create_directories_owner() {
+        use std::os::unix::fs::MetadataExt;
+
+        // chown to an arbitrary id requires root
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        let upper_dir = test_upper_dir(&volume).with_owner(54321, 54322);
+
+        MountConfig::new_for_test(
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+            upper_dir,
+        )
+        .create_directories()
+        .unwrap();
+
+        for dir in ["upper", "work", "merged"] {
+            let metadata = fs::metadata(volume.join(dir)).unwrap();
+            assert_eq!(metadata.uid(), 54321, "{dir}");
+            assert_eq!(metadata.gid(), 54322, "{dir}");
+        }
+    }
+
+    #[test]
+    fn test_mount_config_no_masked_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        // Create lower directory with some files
+        let lower_path = volume.join("lower");
+        fs::create_dir_all(&lower_path).unwrap();
+        create_test_file(&lower_path, "config.txt", "lower config");
+        create_test_file(&lower_path, "subdir/nested.txt", "nested file");
+
+        let lower_dir = LowerDir::new(lower_path, None).unwrap();
+        let config = test_config(&volume, vec![lower_dir]);
+
+        let validated = config.validate().unwrap();
+        assert!(matches!(validated, ValidatedMountConfig(_)));
+    }
+
+    #[test]
+    fn test_mount_config_with_masked_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        // Create lower directory with some files
+        let lower_path = volume.join("lower");
+        fs::create_dir_all(&lower_path).unwrap();
+        create_test_file(&lower_path, "config.txt", "lower config");
+        create_test_file(&lower_path, "subdir/nested.txt", "nested file");
+
+        // Create upper directory with overlapping files
+        let upper_path = volume.join("upper");
+        fs::create_dir_all(&upper_path).unwrap();
+        create_test_file(&upper_path, "config.txt", "upper config");
+
+        let lower_dir = LowerDir::new(lower_path, None).unwrap();
+        let config = test_config(&volume, vec![lower_dir]);
+
+        let result = config.validate();
+        assert!(matches!(
+            result,
+            Err(ConfigError::ValidationError(ValidationError::MaskedFiles(
+                _
+            )))
+        ));
+
+        if let Err(ConfigError::ValidationError(ValidationError::MaskedFiles(masked_files))) =
+            result
+        {
+            assert_eq!(masked_files.len(), 1);
+            assert!(masked_files[0].ends_with("config.txt"));
+        }
+    }
+
+    #[test]
+    fn test_validate_report_serializes_masked_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        let lower_path = volume.join("lower");
+        create_test_file(&lower_path, "config.txt", "lower config");
+        create_test_file(&lower_path, "subdir/nested.txt", "nested file");
+        create_test_file(&lower_path, "allowed.txt", "lower allowed");
+
+        let upper_path = volume.join("upper");
+        create_test_file(&upper_path, "subdir/nested.txt", "upper nested");
+        create_test_file(&upper_path, "config.txt", "upper config");
+        create_test_file(&upper_path, "allowed.txt", "upper allowed");
+
+        let mut config = test_config(&volume, vec![LowerDir::new(lower_path, None).unwrap()]);
+        config.allowed_masked_files = [PathBuf::from("allowed.txt")].into_iter().collect();
+
+        let report = config.validate_report().unwrap();
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "masked_files": [
+                    {
+                        "relative_path": "config.txt",
+                        "upper_path": upper_path.join("config.txt"),
+                        "upper_newer": true,
+                    },
+                    {
+                        "relative_path": "subdir/nested.txt",
+                        "upper_path": upper_path.join("subdir/nested.txt"),
+                        "upper_newer": true,
+                    },
+                ],
+                "applied_masks": [
+                    {
+                        "relative_path": "allowed.txt",
+                        "upper_path": upper_path.join("allowed.txt"),
+                        "upper_newer": true,
+                    },
+                ],
+                "unused_allowed_masks": [],
+            })
+        );
+    }
+
+    #[test]
+    fn test_validate_report_applied_and_unused_masks() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        let lower_path = volume.join("lower");
+        create_test_file(&lower_path, "applied.txt", "lower applied");
+        create_test_file(&lower_path, "not-overridden.txt", "lower only");
+        let upper_path = volume.join("upper");
+        create_test_file(&upper_path, "applied.txt", "upper applied");
+
+        let mut config = test_config(&volume, vec![LowerDir::new(lower_path, None).unwrap()]);
+        config.allowed_masked_files = [
+            PathBuf::from("applied.txt"),
+            PathBuf::from("not-overridden.txt"),
+            PathBuf::from("missing.txt"),
+        ]
+        .into_iter()
+        .collect();
+
+        let report = config.validate_report().unwrap();
+        assert!(report.masked_files.is_empty());
+        let applied: Vec<_> = report
+            .applied_masks
+            .iter()
+            .map(|file| (file.relative_path.as_path(), file.upper_path.clone()))
+            .collect();
+        assert_eq!(
+            applied,
+            [(Path::new("applied.txt"), upper_path.join("applied.txt"))]
+        );
+        assert_eq!(
+            report.unused_allowed_masks,
+            [
+                PathBuf::from("missing.txt"),
+                PathBuf::from("not-overridden.txt")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mount_config_multiple_lower_dirs() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        // Create first lower directory
+        let lower1_path = volume.join("lower1");
+        fs::create_dir_all(&lower1_path).unwrap();
+        create_test_file(&lower1_path, "file1.txt", "content1");
+
+        // Create second lower directory
+        let lower2_path = volume.join("lower2");
+        fs::create_dir_all(&lower2_path).unwrap();
+        create_test_file(&lower2_path, "file2.txt", "content2");
+
+        // Create upper directory with file that masks lower1
+        let upper_path = volume.join("upper");
+        fs::create_dir_all(&upper_path).unwrap();
+        create_test_file(&upper_path, "file1.txt", "upper content");
+
+        let lower_dir1 = LowerDir::new(lower1_path, None).unwrap();
+        let lower_dir2 = LowerDir::new(lower2_path, None).unwrap();
+        let config = test_config(&volume, vec![lower_dir1, lower_dir2]);
+
+        let result = config.validate();
+        assert!(matches!(
+            result,
+            Err(ConfigError::ValidationError(ValidationError::MaskedFiles(
+                _
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_mount_config_with_subdirs() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        // Create lower directory with subdir
+        let lower_base = volume.join("lower_base");
+        let lower_subdir_path = lower_base.join("subdir");
+        fs::create_dir_all(&lower_subdir_path).unwrap();
+        create_test_file(&lower_subdir_path, "config.txt", "lower config");
+
+        let lower_dir = LowerDir::new(lower_base, Some(PathBuf::from("subdir"))).unwrap();
+        let config = test_config(&volume, vec![lower_dir]);
+
+        let validated = config.validate().unwrap();
+        assert!(matches!(validated, ValidatedMountConfig(_)));
+    }
+
+    #[test]
+    fn test_collect_file_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_path = temp_dir.path();
+
+        // Create a directory structure
+        create_test_file(base_path, "file1.txt", "content1");
+        create_test_file(base_path, "subdir/file2.txt", "content2");
+        create_test_file(base_path, "subdir/nested/file3.txt", "content3");
+
+        let mut walk = LayerWalk::default();
+        MountConfig::collect_file_paths(base_path, base_path, &ScanLimits::default(), &mut walk)
+            .unwrap();
+
+        let file_paths = walk.files;
+        assert_eq!(file_paths.len(), 3);
+        assert!(file_paths.contains(&PathBuf::from("file1.txt")));
+        assert!(file_paths.contains(&PathBuf::from("subdir/file2.txt")));
+        assert!(file_paths.contains(&PathBuf::from("subdir/nested/file3.txt")));
+        let dirs: Vec<_> = walk.dir_mtimes.keys().collect();
+        assert_eq!(
+            dirs,
+            [
+                Path::new(""),
+                Path::new("subdir"),
+                Path::new("subdir/nested")
+            ]
+        );
+    }
+
+    /// Two readable lower dirs under `volume`
+    fn create_preflight_lower_dirs(volume: &Path) -> Vec<LowerDir> {
+        ["lower1", "lower2"]
+            .map(|name| {
+                create_test_file(&volume.join(name), "file.txt", "content");
+                LowerDir::new(volume.join(name), None).unwrap()
+            })
+            .into()
+    }
+
+    #[test]
+    fn test_preflight_readable() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = test_config(
+            temp_dir.path(),
+            create_preflight_lower_dirs(temp_dir.path()),
+        );
+        config.preflight().unwrap();
+    }
+
+    #[test]
+    fn test_preflight_missing_lower_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = test_config(
+            temp_dir.path(),
+            create_preflight_lower_dirs(temp_dir.path()),
+        );
+        fs::remove_dir_all(temp_dir.path().join("lower2")).unwrap();
+
+        match config.preflight() {
+            Err(ValidationError::LowerDirUnreadable(path, e)) => {
+                assert_eq!(path, temp_dir.path().join("lower2"));
+                assert_eq!(e.kind(), io::ErrorKind::NotFound);
+            }
+            other => panic!("expected unreadable lower dir, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_preflight_permission_denied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Root can list the directory regardless of its mode
+        if nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let config = test_config(
+            temp_dir.path(),
+            create_preflight_lower_dirs(temp_dir.path()),
+        );
+        let lower1 = temp_dir.path().join("lower1");
+        fs::set_permissions(&lower1, fs::Permissions::from_mode(0o000)).unwrap();
+
+        let result = config.preflight();
+        fs::set_permissions(&lower1, fs::Permissions::from_mode(0o755)).unwrap();
+
+        match result {
+            Err(ValidationError::LowerDirUnreadable(path, e)) => {
+                assert_eq!(path, lower1);
+                assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+            }
+            other => panic!("expected unreadable lower dir, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_validate_selinux_context() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let mut config = test_config(
+            &volume,
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+        );
+
+        for invalid in ["", "system_u:object_r:\"evil", "system_u:object_r:x\n"] {
+            config.selinux_context = Some(invalid.to_string());
+            assert!(matches!(
+                config.clone().validate(),
+                Err(ConfigError::ValidationError(
+                    ValidationError::InvalidSelinuxContext(_)
+                ))
+            ));
+        }
+
+        config.selinux_context = Some("system_u:object_r:container_file_t:s0:c1,c2".to_string());
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_validate_mount_source_label() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let mut config = test_config(
+            &volume,
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+        );
+
+        for invalid in ["", "my pod", "pod\tname", "pod\\040", "pod\n"] {
+            config.mount_source_label = Some(invalid.to_string());
+            assert!(matches!(
+                config.clone().validate(),
+                Err(ConfigError::ValidationError(
+                    ValidationError::InvalidMountSourceLabel(_)
+                ))
+            ));
+        }
+
+        config.mount_source_label = Some("web-7d4b9-x2x8q".to_string());
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_validate_missing_filter_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let filter_file = volume.join("filters");
+
+        let lower_dir = LowerDir::new_with_sync(
+            volume.join("source"),
+            None,
+            SyncMode::Once(volume.join("target")),
+        )
+        .unwrap()
+        .with_sync_options(SyncOptions {
+            filter_file: Some(filter_file.clone()),
+            ..Default::default()
+        });
+        let config = test_config(&volume, vec![lower_dir]);
+
+        match config.clone().validate() {
+            Err(ConfigError::ValidationError(ValidationError::MissingFilterFile(path))) => {
+                assert_eq!(path, filter_file);
+            }
+            other => panic!("expected missing filter file, got {other:?}"),
+        }
+
+        create_test_file(&volume, "filters", "- /cache/\n");
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_validate_rsync_base_args() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let upper_dir = test_upper_dir(&volume);
+        let with_base_args = |args: &[&str]| {
+            let lower_dir = LowerDir::new_with_sync(
+                volume.join("source"),
+                None,
+                SyncMode::Once(volume.join("target")),
+            )
+            .unwrap()
+            .with_sync_options(SyncOptions {
+                rsync_base_args: Some(args.iter().map(|arg| arg.to_string()).collect()),
+                ..Default::default()
+            });
+            MountConfig::new_for_test(vec![lower_dir], upper_dir.clone())
+        };
+
+        for invalid in ["/elsewhere/", "--"] {
+            match with_base_args(&["-rt", invalid]).validate() {
+                Err(ConfigError::ValidationError(ValidationError::PositionalRsyncArg(arg))) => {
+                    assert_eq!(arg, invalid);
+                }
+                other => panic!("expected positional arg to be rejected, got {other:?}"),
+            }
+        }
+
+        with_base_args(&["-rtlpD", "--inplace"]).validate().unwrap();
+
+        // Only values joined to their flag can be told apart from paths
+        assert!(matches!(
+            with_base_args(&["-rt", "--exclude", "foo"]).check(),
+            Err(ConfigError::ValidationError(ValidationError::PositionalRsyncArg(arg))) if arg == "foo"
+        ));
+        with_base_args(&["-rt", "--exclude=foo", "-essh"])
+            .check()
+            .unwrap();
+
+        // The base args replace the flags skip_specials would pick
+        let mut config = with_base_args(&["-rtlp"]);
+        config.lower_dirs[0].sync_options.skip_specials = true;
+        assert!(matches!(
+            config.check(),
+            Err(ConfigError::ValidationError(
+                ValidationError::SkipSpecialsWithBaseArgs
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_validate_rsync_extra_args() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let upper_dir = test_upper_dir(&volume);
+        let with_extra_args = |args: &[&str]| {
+            let seed = SeedDir::new(volume.join("defaults")).with_sync_options(SyncOptions {
+                rsync_extra_args: args.iter().map(|arg| arg.to_string()).collect(),
+                ..Default::default()
+            });
+            let mut config = MountConfig::new_for_test(vec![], upper_dir.clone());
+            config.seed_dirs = vec![seed];
+            config
+        };
+
+        // A second source would have the real source taken as the target
+        for invalid in ["baseline:/srv/other/", "--"] {
+            assert!(matches!(
+                with_extra_args(&["--protocol=30", invalid]).check(),
+                Err(ConfigError::ValidationError(ValidationError::PositionalRsyncArg(arg))) if arg == invalid
+            ));
+        }
+        with_extra_args(&["--protocol=30", "--no-inc-recursive"])
+            .check()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_validate_rsync_chmod() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let upper_dir = test_upper_dir(&volume);
+        let with_chmod = |chmod: &str| {
+            let lower_dir = LowerDir::new_with_sync(
+                volume.join("source"),
+                None,
+                SyncMode::Once(volume.join("target")),
+            )
+            .unwrap()
+            .with_sync_options(SyncOptions {
+                chmod: Some(chmod.to_string()),
+                ..Default::default()
+            });
+            MountConfig::new_for_test(vec![lower_dir], upper_dir.clone())
+        };
+
+        for empty in ["", " "] {
+            assert!(matches!(
+                with_chmod(empty).validate(),
+                Err(ConfigError::ValidationError(ValidationError::EmptyChmod))
+            ));
+        }
+        with_chmod("D0755,F0644").validate().unwrap();
+    }
+
+    #[test]
+    fn test_validate_rsh_requires_remote_source() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let upper_dir = test_upper_dir(&volume);
+        let with_rsh = |source: PathBuf| {
+            let lower_dir =
+                LowerDir::new_with_sync(source, None, SyncMode::Once(volume.join("target")))
+                    .unwrap()
+                    .with_sync_options(SyncOptions {
+                        rsh: Some("ssh -p 2222".to_string()),
+                        ..Default::default()
+                    });
+            MountConfig::new_for_test(vec![lower_dir], upper_dir.clone())
+        };
+
+        let local = volume.join("source");
+        assert!(matches!(
+            with_rsh(local.clone()).validate(),
+            Err(ConfigError::ValidationError(ValidationError::RshWithoutRemoteSource(source))) if source == local
+        ));
+        with_rsh(PathBuf::from("baseline:/srv/config"))
+            .check()
+            .unwrap();
+
+        let mut config = MountConfig::new_for_test(vec![], upper_dir.clone());
+        config.seed_dirs = vec![SeedDir::new(local).with_sync_options(SyncOptions {
+            rsh: Some("ssh".to_string()),
+            ..Default::default()
+        })];
+        assert!(matches!(
+            config.check(),
+            Err(ConfigError::ValidationError(
+                ValidationError::RshWithoutRemoteSource(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_lower_volumes_relative_to_base_volume() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        create_test_file(&volume, "layers/base/base.txt", "base");
+        create_test_file(&volume, "layers/app/config/app.txt", "app");
+        create_test_file(&volume, "other/other.txt", "other");
+
+        let config = MountConfig::builder()
+            .base_volume(volume.join("layers"))
+            .add_lower(LowerDir::new(PathBuf::from("base"), None).unwrap())
+            .add_lower(LowerDir::new(PathBuf::from("app"), Some(PathBuf::from("config"))).unwrap())
+            .add_lower(LowerDir::new(volume.join("other"), None).unwrap())
+            .add_lower(
+                LowerDir::new_with_sync(
+                    PathBuf::from("baseline:/srv/config"),
+                    None,
+                    SyncMode::Once(volume.join("synced")),
+                )
+                .unwrap(),
+            )
+            .upper(test_upper_dir(&volume))
+            .build()
+            .unwrap();
+
+        let validated: MountConfig = config.validate().unwrap().into();
+        let volumes: Vec<_> = validated
+            .lower_dirs
+            .iter()
+            .map(|lower_dir| lower_dir.full_path())
+            .collect();
+        assert_eq!(
+            volumes,
+            [
+                volume.join("layers/base"),
+                volume.join("layers/app/config"),
+                volume.join("other"),
+                PathBuf::from("baseline:/srv/config"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_relative_volume_requires_base_volume() {
+        let mut config = test_config(
+            "/nonexistent-upper",
+            vec![LowerDir::new(PathBuf::from("base"), None).unwrap()],
+        );
+        assert!(matches!(
+            config.check(),
+            Err(ConfigError::ValidationError(ValidationError::RelativeVolumeWithoutBase(volume)))
+                if volume == Path::new("base")
+        ));
+
+        config.base_volume = Some(PathBuf::from("layers"));
+        assert!(matches!(
+            config.check(),
+            Err(ConfigError::ValidationError(
+                ValidationError::RelativeBaseVolume(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_validate_sync_as_user() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let upper_dir = test_upper_dir(&volume);
+        let as_user = |user: &str| {
+            let lower_dir = LowerDir::new_with_sync(
+                volume.join("source"),
+                None,
+                SyncMode::Once(volume.join("target")),
+            )
+            .unwrap()
+            .with_sync_options(SyncOptions {
+                sync_as_user: Some(user.to_string()),
+                ..Default::default()
+            });
+            MountConfig::new_for_test(vec![lower_dir], upper_dir.clone())
+        };
+
+        as_user("root").check().unwrap();
+        assert!(matches!(
+            as_user("no-such-user-for-overlay-mount").check(),
+            Err(ConfigError::ValidationError(ValidationError::UnknownSyncUser(user)))
+                if user == "no-such-user-for-overlay-mount"
+        ));
+    }
+
+    #[test]
+    fn test_validate_missing_link_dest() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let upper_dir = test_upper_dir(&volume);
+        let with_link_dest = |link_dest: PathBuf| {
+            let lower_dir = LowerDir::new_with_sync(
+                volume.join("source"),
+                None,
+                SyncMode::Once(volume.join("target")),
+            )
+            .unwrap()
+            .with_sync_options(SyncOptions {
+                link_dest: Some(link_dest),
+                ..Default::default()
+            });
+            MountConfig::new_for_test(vec![lower_dir], upper_dir.clone())
+        };
+
+        match with_link_dest(volume.join("snapshot")).validate() {
+            Err(ConfigError::ValidationError(ValidationError::MissingLinkDest(path))) => {
+                assert_eq!(path, volume.join("snapshot"));
+            }
+            other => panic!("expected missing link dest, got {other:?}"),
+        }
+
+        fs::create_dir_all(volume.join("snapshot")).unwrap();
+        with_link_dest(volume.join("snapshot")).validate().unwrap();
+        // Relative to the target
+        with_link_dest(PathBuf::from("../snapshot"))
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_validate_missing_seed_filter_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let filter_file = volume.join("seed-filters");
+
+        let mut config = test_config(
+            &volume,
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+        );
+        config.seed_dirs =
+            vec![
+                SeedDir::new(volume.join("defaults")).with_sync_options(SyncOptions {
+                    filter_file: Some(filter_file.clone()),
+                    ..Default::default()
+                }),
+            ];
+
+        match config.validate() {
+            Err(ConfigError::ValidationError(ValidationError::MissingFilterFile(path))) => {
+                assert_eq!(path, filter_file);
+            }
+            other => panic!("expected missing filter file, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_lower_dir_writable_round_trip() {
+        let lower_dir: LowerDir = toml::from_str(
+            r#"
+            volume = "/lower"
+            writable = true
+            "#,
+        )
+        .unwrap();
+        assert!(lower_dir.writable());
+
+        let reparsed: LowerDir = toml::from_str(&toml::to_string(&lower_dir).unwrap()).unwrap();
+        assert_eq!(reparsed, lower_dir);
+
+        let default: LowerDir = toml::from_str("volume = \"/lower\"").unwrap();
+        assert!(!default.writable());
+        assert!(!default.data_only());
+    }
+
+    #[test]
+    fn test_validate_writable_lower_rejected() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let config = test_config(
+            &volume,
+            vec![
+                LowerDir::new(volume.join("lower1"), None).unwrap(),
+                LowerDir::new(volume.join("lower2"), None)
+                    .unwrap()
+                    .with_writable(true),
+            ],
+        );
+
+        match config.validate() {
+            Err(ConfigError::ValidationError(ValidationError::WritableLowerUnsupported(path))) => {
+                assert_eq!(path, volume.join("lower2"));
+            }
+            other => panic!("expected writable lower to be rejected, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_masked_files_policy() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let lower_path = volume.join("lower");
+        let upper_path = volume.join("upper");
+        create_test_file(&lower_path, "app.conf", "lower");
+        create_test_file(&upper_path, "app.conf", "upper");
+
+        let mut config = test_config(&volume, vec![LowerDir::new(lower_path, None).unwrap()]);
+        assert_eq!(config.masked_files_policy, MaskedFilesPolicy::Error);
+        assert!(matches!(
+            config.clone().validate(),
+            Err(ConfigError::ValidationError(ValidationError::MaskedFiles(
+                _
+            )))
+        ));
+
+        for policy in [MaskedFilesPolicy::Warn, MaskedFilesPolicy::Allow] {
+            config.masked_files_policy = policy;
+            config.clone().validate().unwrap();
+            // Still reported whatever the policy
+            assert_eq!(
+                config.validate_report().unwrap().masked_files,
+                vec![MaskedFile {
+                    relative_path: PathBuf::from("app.conf"),
+                    upper_path: upper_path.join("app.conf"),
+                    upper_newer: true,
+                }]
+            );
+        }
+    }
+
+    fn set_mtime(path: &Path, seconds: u64) {
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_masked_files_compare_mtimes() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let lower_path = volume.join("lower");
+        let upper_path = volume.join("upper");
+        for name in ["newer.conf", "same.conf", "stale.conf"] {
+            create_test_file(&lower_path, name, "lower");
+            create_test_file(&upper_path, name, "upper");
+            set_mtime(&lower_path.join(name), 1_700_000_000);
+        }
+        set_mtime(&upper_path.join("newer.conf"), 1_700_000_100);
+        set_mtime(&upper_path.join("same.conf"), 1_700_000_000);
+        set_mtime(&upper_path.join("stale.conf"), 1_600_000_000);
+
+        let mut config = test_config(&volume, vec![LowerDir::new(lower_path, None).unwrap()]);
+        let classified: Vec<_> = config
+            .validate_report()
+            .unwrap()
+            .masked_files
+            .into_iter()
+            .map(|file| (file.relative_path, file.upper_newer))
+            .collect();
+        assert_eq!(
+            classified,
+            [
+                (PathBuf::from("newer.conf"), true),
+                (PathBuf::from("same.conf"), true),
+                (PathBuf::from("stale.conf"), false),
+            ]
+        );
+
+        // Only the stale override is rejected
+        config.masked_files_policy = MaskedFilesPolicy::AllowNewer;
+        assert!(matches!(
+            config.check(),
+            Err(ConfigError::ValidationError(ValidationError::MaskedFiles(paths)))
+                if paths == [upper_path.join("stale.conf")]
+        ));
+        fs::remove_file(upper_path.join("stale.conf")).unwrap();
+        assert_eq!(config.plan().unwrap().masked_files.len(), 2);
+    }
+
+    #[test]
+    fn test_masked_files_use_sync_target() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let source = volume.join("source");
+        let target = volume.join("target");
+        create_test_file(&source, "source_only.conf", "source");
+        create_test_file(&target, "target_only.conf", "target");
+
+        let config = test_config(
+            &volume,
+            vec![
+                LowerDir::new_with_sync(source, None, SyncMode::Constant(target.clone())).unwrap(),
+            ],
+        );
+
+        // Shadowing a file only in the source is fine, it isn't what gets mounted
+        let upper_path = volume.join("upper");
+        create_test_file(&upper_path, "source_only.conf", "upper");
+        let validated = config.clone().validate().unwrap();
+
+        // Content that turns up in the target with the sync is caught by preflight
+        create_test_file(&target, "source_only.conf", "source");
+        let validated: &MountConfig = (&validated).into();
+        match validated.preflight() {
+            Err(ValidationError::MaskedFiles(paths)) => {
+                assert_eq!(paths, vec![upper_path.join("source_only.conf")]);
+            }
+            other => panic!("expected masked files, got {other:?}"),
+        }
+
+        fs::remove_file(upper_path.join("source_only.conf")).unwrap();
+        create_test_file(&upper_path, "target_only.conf", "upper");
+        match config.validate() {
+            Err(ConfigError::ValidationError(ValidationError::MaskedFiles(paths))) => {
+                assert_eq!(paths, vec![upper_path.join("target_only.conf")]);
+            }
+            other => panic!("expected masked files, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_validate_data_only_lower_dirs() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let upper_dir = test_upper_dir(&volume);
+        let data = LowerDir::new(volume.join("data"), None)
+            .unwrap()
+            .with_data_only(true);
+        let mut config = MountConfig::new_for_test(
+            vec![
+                LowerDir::new(volume.join("lower"), None).unwrap(),
+                data.clone(),
+            ],
+            upper_dir.clone(),
+        );
+
+        match config.clone().validate() {
+            Err(ConfigError::ValidationError(ValidationError::DataOnlyRequiresMetacopy(path))) => {
+                assert_eq!(path, volume.join("data"));
+            }
+            other => panic!("expected metacopy to be required, got {other:?}"),
+        }
+
+        // Files in a data-only layer aren't visible so can't be masked
+        create_test_file(&volume.join("data"), "blob", "data");
+        create_test_file(&volume.join("upper"), "blob", "upper");
+        config.overlay_options.metacopy = Some(true);
+        config.validate().unwrap();
+
+        let mut config = MountConfig::new_for_test(vec![data], upper_dir);
+        config.overlay_options.metacopy = Some(true);
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::ValidationError(
+                ValidationError::NoRegularLowerDir
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_require_empty_merged_allows_empty() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path();
+        let mut config = test_config(
+            volume,
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+        );
+        config.require_empty_merged = true;
+
+        // Created by validation
+        config.clone().validate().unwrap();
+        // Already exists but empty
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_require_empty_merged_rejects_leftovers() {
+        let temp_dir = TempDir::new().unwrap();
+        let merged = temp_dir.path().join("merged");
+        create_test_file(&merged, "leftover.txt", "stale");
+
+        let volume = temp_dir.path();
+        let mut config = test_config(
+            volume,
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+        );
+        config.require_empty_merged = true;
+        match config.clone().validate() {
+            Err(ConfigError::ValidationError(ValidationError::MergedNotEmpty(path))) => {
+                assert_eq!(path, merged);
+            }
+            other => panic!("expected merged not empty, got {other:?}"),
+        }
+
+        // Off by default
+        config.require_empty_merged = false;
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_require_empty_merged_checks_extra_merged_subdirs() {
+        let temp_dir = TempDir::new().unwrap();
+        let extra = temp_dir.path().join("app");
+        create_test_file(&extra, "leftover.txt", "stale");
+
+        let volume = temp_dir.path();
+        let mut config = test_config(
+            volume,
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+        );
+        config.require_empty_merged = true;
+        config.upper_dir = config
+            .upper_dir
+            .with_merged_subdirs(vec!["merged".into(), "app".into()])
+            .unwrap();
+        match config.validate() {
+            Err(ConfigError::ValidationError(ValidationError::MergedNotEmpty(path))) => {
+                assert_eq!(path, extra);
+            }
+            other => panic!("expected merged not empty, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_new_for_test_defaults() {
+        let upper_dir = test_upper_dir("/upper");
+        let config = MountConfig::new_for_test(Vec::new(), upper_dir.clone());
+
+        assert!(config.allowed_masked_files.is_empty());
+        // Matches what the builder and an all-defaults config file produce
+        let built = MountConfig::builder()
+            .add_lower(LowerDir::new(PathBuf::from("/lower"), None).unwrap())
+            .upper(upper_dir.clone())
+            .build()
+            .unwrap();
+        assert_eq!(
+            built,
+            MountConfig::new_for_test(
+                vec![LowerDir::new(PathBuf::from("/lower"), None).unwrap()],
+                upper_dir
+            )
+        );
+    }
+
+    #[test]
+    fn test_builder_validates() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        create_test_file(&volume.join("lower"), "config.txt", "lower config");
+        create_test_file(&volume.join("upper"), "config.txt", "upper config");
+
+        let config = MountConfig::builder()
+            .add_lower(LowerDir::new(volume.join("lower"), None).unwrap())
+            .upper(test_upper_dir(&volume))
+            .allow_masked("config.txt")
+            .build()
+            .unwrap();
+
+        assert_eq!(config.lower_dirs.len(), 1);
+        assert_eq!(config.max_lower_dirs, DEFAULT_MAX_LOWER_DIRS);
+        assert_eq!(config.lower_order, LowerOrder::AsConfigured);
+        assert!(
+            config
+                .allowed_masked_files
+                .contains(Path::new("config.txt"))
+        );
+
+        let validated = config.validate().unwrap();
+        let config: &MountConfig = (&validated).into();
+        assert!(config.upper_dir.merged_path().exists());
+    }
+
+    #[test]
+    fn test_builder_matches_deserialized_defaults() {
+        let built = MountConfig::builder()
+            .add_lower(LowerDir::new(PathBuf::from("/lower"), None).unwrap())
+            .upper(test_upper_dir("/upper"))
+            .build()
+            .unwrap();
+
+        let parsed: MountConfig = toml::from_str(
+            r#"
+            [[lower_dirs]]
+            volume = "/lower"
+
+            [upper_dir]
+            volume = "/upper"
+            upper_subdir = "upper"
+            work_subdir = "work"
+            merged_subdir = "merged"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(built, parsed);
+    }
+
+    #[test]
+    fn test_builder_missing_fields() {
+        let lower_dir = LowerDir::new(PathBuf::from("/lower"), None).unwrap();
+        assert!(matches!(
+            MountConfig::builder().add_lower(lower_dir).build(),
+            Err(ValidationError::MissingField("upper_dir"))
+        ));
+
+        let upper_dir = test_upper_dir("/upper");
+        assert!(matches!(
+            MountConfig::builder().upper(upper_dir).build(),
+            Err(ValidationError::MissingField("lower_dirs"))
+        ));
+    }
+
+    fn masked_relative_paths(config: &MountConfig) -> Vec<PathBuf> {
+        config
+            .validate_report()
+            .unwrap()
+            .masked_files
+            .into_iter()
+            .map(|file| file.relative_path)
+            .collect()
+    }
+
+    /// A `lower` layer under `volume` with every file masked by a copy in the `upper` layer
+    fn create_masked_lower_dir(volume: &Path) -> LowerDir {
+        for relative_path in ["top.txt", "a/one.txt", "a/b/two.txt", "tmp/scratch.txt"] {
+            create_test_file(&volume.join("lower"), relative_path, "lower");
+            create_test_file(&volume.join("upper"), relative_path, "upper");
+        }
+        LowerDir::new(volume.join("lower"), None).unwrap()
     }
 
     #[test]
-    fn test_lower_dir_new_valid() {
+    fn test_mask_scan_max_depth() {
         let temp_dir = TempDir::new().unwrap();
-        let volume = temp_dir.path().join("volume");
-        let subdir = Some(PathBuf::from("subdir"));
+        let mut config = test_config(
+            temp_dir.path(),
+            vec![create_masked_lower_dir(temp_dir.path())],
+        );
+        assert_eq!(masked_relative_paths(&config).len(), 4);
 
-        let lower_dir = LowerDir::new(volume.clone(), subdir.clone()).unwrap();
-        assert_eq!(lower_dir.volume, volume);
-        assert_eq!(lower_dir.subdir, subdir);
+        config.mask_scan_max_depth = Some(0);
+        assert_eq!(masked_relative_paths(&config), [PathBuf::from("top.txt")]);
+
+        config.mask_scan_max_depth = Some(1);
+        assert_eq!(
+            masked_relative_paths(&config),
+            [
+                PathBuf::from("a/one.txt"),
+                PathBuf::from("tmp/scratch.txt"),
+                PathBuf::from("top.txt"),
+            ]
+        );
     }
 
     #[test]
-    fn test_lower_dir_new_absolute_subdir_fails() {
+    fn test_mask_scan_ignore() {
         let temp_dir = TempDir::new().unwrap();
-        let volume = temp_dir.path().join("volume");
-        let subdir = Some(PathBuf::from("/absolute/path"));
+        let mut config = test_config(
+            temp_dir.path(),
+            vec![create_masked_lower_dir(temp_dir.path())],
+        );
+        config.mask_scan_ignore = vec![PathBuf::from("tmp/"), PathBuf::from("a/b")];
 
-        let result = LowerDir::new(volume, subdir);
-        assert!(matches!(result, Err(ValidationError::NonRelative(_, _))));
+        assert_eq!(
+            masked_relative_paths(&config),
+            [PathBuf::from("a/one.txt"), PathBuf::from("top.txt")]
+        );
+
+        config.mask_scan_ignore = vec![PathBuf::from("top.txt")];
+        assert_eq!(masked_relative_paths(&config).len(), 3);
     }
 
     #[test]
-    fn test_lower_dir_full_path() {
+    fn test_validate_hide_paths() {
         let temp_dir = TempDir::new().unwrap();
-        let volume = temp_dir.path().join("volume");
-        let subdir = Some(PathBuf::from("subdir"));
+        let volume = temp_dir.path();
+        create_test_file(&volume.join("lower"), "defaults/unwanted.conf", "lower");
+        // Covered by the whiteout, rather than reported as masked
+        fs::create_dir_all(volume.join("upper/defaults")).unwrap();
+        nix::unistd::mkfifo(
+            &volume.join("upper/defaults/unwanted.conf"),
+            Mode::from_bits_truncate(0o600),
+        )
+        .unwrap();
+        let with_hide_path = |hide_path: &str| {
+            let mut config = test_config(
+                volume,
+                vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+            );
+            config.hide_paths = vec![PathBuf::from(hide_path)];
+            config.validate()
+        };
 
-        let lower_dir = LowerDir::new(volume.clone(), subdir).unwrap();
-        assert_eq!(lower_dir.full_path(), volume.join("subdir"));
+        with_hide_path("defaults/unwanted.conf").unwrap();
+        for invalid in ["/defaults/unwanted.conf", "defaults/../../escape", ""] {
+            assert!(matches!(
+                with_hide_path(invalid),
+                Err(ConfigError::ValidationError(
+                    ValidationError::InvalidHidePath(_)
+                ))
+            ));
+        }
+        assert!(matches!(
+            with_hide_path("defaults/missing.conf"),
+            Err(ConfigError::ValidationError(ValidationError::HidePathNotInLower(path)))
+                if path == Path::new("defaults/missing.conf")
+        ));
     }
 
     #[test]
-    fn test_lower_dir_full_path_no_subdir() {
+    fn test_check_lower_available() {
         let temp_dir = TempDir::new().unwrap();
-        let volume = temp_dir.path().join("volume");
+        let volume = temp_dir.path();
+        fs::create_dir_all(volume.join("lower")).unwrap();
+        let mut config = test_config(
+            volume,
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+        );
 
-        let lower_dir = LowerDir::new(volume.clone(), None).unwrap();
-        assert_eq!(lower_dir.full_path(), volume);
+        for policy in [
+            LowerUnavailablePolicy::Ignore,
+            LowerUnavailablePolicy::Warn,
+            LowerUnavailablePolicy::Fail,
+        ] {
+            config.on_lower_unavailable = policy;
+            config.check_lower_available().unwrap();
+        }
+
+        fs::remove_dir(volume.join("lower")).unwrap();
+        config.on_lower_unavailable = LowerUnavailablePolicy::Ignore;
+        config.check_lower_available().unwrap();
+        config.on_lower_unavailable = LowerUnavailablePolicy::Warn;
+        config.check_lower_available().unwrap();
+        config.on_lower_unavailable = LowerUnavailablePolicy::Fail;
+        assert!(matches!(
+            config.check_lower_available(),
+            Err(ValidationError::LowerDirUnavailable(path, _)) if path == volume.join("lower")
+        ));
     }
 
     #[test]
-    fn test_upper_dir_new_valid() {
+    fn test_lower_fingerprint() {
         let temp_dir = TempDir::new().unwrap();
-        let volume = temp_dir.path().join("volume");
-        let upper_subdir = PathBuf::from("upper");
-        let work_subdir = PathBuf::from("work");
-        let merged_subdir = PathBuf::from("merged");
+        let volume = temp_dir.path();
+        create_test_file(&volume.join("first"), "a.txt", "a");
+        create_test_file(&volume.join("second"), "nested/b.txt", "b");
+        let mut config = test_config(
+            volume,
+            vec![
+                LowerDir::new(volume.join("first"), None).unwrap(),
+                LowerDir::new(volume.join("second"), None).unwrap(),
+            ],
+        );
 
-        let upper_dir = UpperDir::new(
-            volume.clone(),
-            upper_subdir.clone(),
-            work_subdir.clone(),
-            merged_subdir.clone(),
+        for fingerprint_contents in [false, true] {
+            config.fingerprint_contents = fingerprint_contents;
+            let fingerprint = config.lower_fingerprint().unwrap();
+            assert_eq!(fingerprint.len(), 16);
+            assert_eq!(config.lower_fingerprint().unwrap(), fingerprint);
+
+            // Same size, but a different mtime and content
+            let file = volume.join("second/nested/b.txt");
+            let mtime = fs::metadata(&file).unwrap().modified().unwrap();
+            fs::write(&file, if fingerprint_contents { "c" } else { "b" }).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&file)
+                .unwrap()
+                .set_modified(mtime + std::time::Duration::from_secs(1))
+                .unwrap();
+            assert_ne!(config.lower_fingerprint().unwrap(), fingerprint);
+        }
+
+        // Which layer a file is in matters, not just the combined paths
+        let fingerprint = config.lower_fingerprint().unwrap();
+        config.lower_order = LowerOrder::Reversed;
+        assert_ne!(config.lower_fingerprint().unwrap(), fingerprint);
+    }
+
+    #[test]
+    fn test_fingerprint_contents_ignores_mtime() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path();
+        let file = create_test_file(&volume.join("lower"), "a.txt", "a");
+        let mut config = test_config(
+            volume,
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+        );
+        config.fingerprint_contents = true;
+
+        let fingerprint = config.lower_fingerprint().unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap();
+        assert_eq!(config.lower_fingerprint().unwrap(), fingerprint);
+    }
+
+    #[test]
+    fn test_mask_scan_cache() {
+        use nix::sys::stat::{UtimensatFlags, utimensat};
+        use nix::sys::time::TimeSpec;
+        use std::os::unix::fs::MetadataExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = test_config(
+            temp_dir.path(),
+            vec![create_masked_lower_dir(temp_dir.path())],
+        );
+        config.mask_scan_cache = Some(temp_dir.path().join("scan-cache.json"));
+        assert_eq!(masked_relative_paths(&config).len(), 4);
+        assert!(temp_dir.path().join("scan-cache.json").exists());
+
+        // Add a masked file but put back the dir mtime, only a cache hit can miss it
+        let dir = temp_dir.path().join("lower/a");
+        let metadata = fs::metadata(&dir).unwrap();
+        create_test_file(&dir, "new.txt", "lower");
+        create_test_file(&temp_dir.path().join("upper/a"), "new.txt", "upper");
+        let mtime = TimeSpec::new(metadata.mtime(), metadata.mtime_nsec());
+        utimensat(
+            AT_FDCWD,
+            &dir,
+            &mtime,
+            &mtime,
+            UtimensatFlags::FollowSymlink,
         )
         .unwrap();
+        assert_eq!(masked_relative_paths(&config).len(), 4);
 
-        assert_eq!(upper_dir.volume, volume);
-        assert_eq!(upper_dir.upper_subdir, upper_subdir);
-        assert_eq!(upper_dir.work_subdir, work_subdir);
-        assert_eq!(upper_dir.merged_subdir, merged_subdir);
+        // Once the mtime changes the layer is walked again
+        let later = TimeSpec::new(metadata.mtime() + 1, 0);
+        utimensat(
+            AT_FDCWD,
+            &dir,
+            &later,
+            &later,
+            UtimensatFlags::FollowSymlink,
+        )
+        .unwrap();
+        assert!(masked_relative_paths(&config).contains(&PathBuf::from("a/new.txt")));
     }
 
+    #[cfg(feature = "parallel")]
     #[test]
-    fn test_upper_dir_new_absolute_paths_fail() {
+    fn test_collect_file_paths_parallel_matches_serial() {
         let temp_dir = TempDir::new().unwrap();
-        let volume = temp_dir.path().join("volume");
-        let absolute_path = PathBuf::from("/absolute/path");
+        let base_path = temp_dir.path();
 
-        // Test absolute upper_subdir
-        let result = UpperDir::new(
-            volume.clone(),
-            absolute_path.clone(),
-            PathBuf::from("work"),
-            PathBuf::from("merged"),
-        );
-        assert!(matches!(result, Err(ValidationError::NonRelative(_, _))));
+        for dir in 0..8 {
+            for nested in 0..4 {
+                for file in 0..16 {
+                    create_test_file(
+                        base_path,
+                        &format!("dir{dir}/nested{nested}/file{file}.txt"),
+                        "content",
+                    );
+                }
+            }
+            create_test_file(base_path, &format!("dir{dir}/top.txt"), "content");
+        }
+        create_test_file(base_path, "root.txt", "content");
+        fs::create_dir_all(base_path.join("empty/dir")).unwrap();
 
-        // Test absolute work_subdir
-        let result = UpperDir::new(
-            volume.clone(),
-            PathBuf::from("upper"),
-            absolute_path.clone(),
-            PathBuf::from("merged"),
-        );
-        assert!(matches!(result, Err(ValidationError::NonRelative(_, _))));
+        let mut serial = LayerWalk::default();
+        MountConfig::collect_file_paths(base_path, base_path, &ScanLimits::default(), &mut serial)
+            .unwrap();
+        let parallel =
+            MountConfig::collect_file_paths_parallel(base_path, base_path, &ScanLimits::default())
+                .unwrap();
 
-        // Test absolute merged_subdir
-        let result = UpperDir::new(
-            volume.clone(),
-            PathBuf::from("upper"),
-            PathBuf::from("work"),
-            absolute_path,
-        );
-        assert!(matches!(result, Err(ValidationError::NonRelative(_, _))));
+        assert_eq!(serial.files.len(), 8 * 4 * 16 + 8 + 1);
+        assert_eq!(serial.dir_mtimes.len(), 1 + 8 * 5 + 2);
+        assert_eq!(parallel, serial);
+
+        let missing = base_path.join("missing");
+        let err =
+            MountConfig::collect_file_paths_parallel(&missing, base_path, &ScanLimits::default())
+                .unwrap_err();
+        assert_eq!(err.0, missing);
     }
 
     #[test]
-    fn test_upper_dir_paths() {
+    fn test_mount_config_with_allowed_masked_files() {
         let temp_dir = TempDir::new().unwrap();
-        let volume = temp_dir.path().join("volume");
-        let upper_dir = UpperDir::new(
-            volume.clone(),
-            PathBuf::from("upper"),
-            PathBuf::from("work"),
-            PathBuf::from("merged"),
-        )
-        .unwrap();
+        let volume = temp_dir.path().to_path_buf();
 
-        assert_eq!(upper_dir.upper_path(), volume.join("upper"));
-        assert_eq!(upper_dir.work_path(), volume.join("work"));
-        assert_eq!(upper_dir.merged_path(), volume.join("merged"));
+        // Create lower directory with some files
+        let lower_path = volume.join("lower");
+        fs::create_dir_all(&lower_path).unwrap();
+        create_test_file(&lower_path, "config.txt", "lower config");
+        create_test_file(&lower_path, "allowed.txt", "allowed file");
+
+        // Create upper directory with overlapping files
+        let upper_path = volume.join("upper");
+        fs::create_dir_all(&upper_path).unwrap();
+        create_test_file(&upper_path, "config.txt", "upper config");
+        create_test_file(&upper_path, "allowed.txt", "upper allowed");
+
+        let lower_dir = LowerDir::new(lower_path, None).unwrap();
+        let mut config = test_config(&volume, vec![lower_dir]);
+        config.allowed_masked_files = vec![PathBuf::from("allowed.txt")].into_iter().collect();
+
+        let result = config.validate();
+        assert!(matches!(
+            result,
+            Err(ConfigError::ValidationError(ValidationError::MaskedFiles(
+                _
+            )))
+        ));
+
+        if let Err(ConfigError::ValidationError(ValidationError::MaskedFiles(masked_files))) =
+            result
+        {
+            assert_eq!(masked_files.len(), 1);
+            assert!(masked_files[0].ends_with("config.txt"));
+            assert!(!masked_files.iter().any(|p| p.ends_with("allowed.txt")));
+        }
     }
 
     #[test]
-    fn test_mount_config_create_directories() {
+    fn test_unused_allow_entries() {
         let temp_dir = TempDir::new().unwrap();
         let volume = temp_dir.path().to_path_buf();
 
-        let lower_dir = LowerDir::new(volume.join("lower"), None).unwrap();
-        let upper_dir = UpperDir::new(
-            volume.clone(),
-            PathBuf::from("upper"),
-            PathBuf::from("work"),
-            PathBuf::from("merged"),
-        )
-        .unwrap();
+        let lower_path = volume.join("lower");
+        create_test_file(&lower_path, "config.txt", "lower config");
+        let upper_path = volume.join("upper");
+        create_test_file(&upper_path, "config.txt", "upper config");
 
-        let config = MountConfig {
-            lower_dirs: vec![lower_dir],
-            upper_dir,
-            allowed_masked_files: BTreeSet::new(),
-        };
+        let lower_dir = LowerDir::new(lower_path, None).unwrap();
+        let mut config = test_config(&volume, vec![lower_dir]);
+        config.allowed_masked_files = [PathBuf::from("config.txt"), PathBuf::from("stale.txt")]
+            .into_iter()
+            .collect();
 
-        config.create_directories().unwrap();
+        let report = config.validate_report().unwrap();
+        assert!(report.masked_files.is_empty());
+        assert_eq!(report.applied_masks.len(), 1);
+        assert_eq!(report.unused_allowed_masks, [PathBuf::from("stale.txt")]);
+        // Only warned about by default
+        config.check().unwrap();
 
-        assert!(volume.join("upper").exists());
-        assert!(volume.join("work").exists());
-        assert!(volume.join("merged").exists());
+        config.strict_allow_list = true;
+        assert!(matches!(
+            config.check(),
+            Err(ConfigError::ValidationError(ValidationError::UnusedAllowEntries(entries)))
+                if entries == [PathBuf::from("stale.txt")]
+        ));
+        config.allowed_masked_files.remove(Path::new("stale.txt"));
+        config.check().unwrap();
     }
 
     #[test]
-    fn test_mount_config_no_masked_files() {
+    fn test_strict_allow_list_without_upper_content() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        let lower_path = volume.join("lower");
+        create_test_file(&lower_path, "config.txt", "lower config");
+        let mut config = test_config(&volume, vec![LowerDir::new(lower_path, None).unwrap()]);
+        config.allowed_masked_files = [PathBuf::from("config.txt")].into_iter().collect();
+        config.strict_allow_list = true;
+
+        // The first start of a fresh deployment, before and after the upper dir is created
+        config.check().unwrap();
+        assert!(
+            config
+                .validate_report()
+                .unwrap()
+                .unused_allowed_masks
+                .is_empty()
+        );
+        fs::create_dir_all(volume.join("upper")).unwrap();
+        config.check().unwrap();
+
+        // Whatever the tmpfs covers isn't scanned either
+        create_test_file(&volume.join("upper"), "other.txt", "upper only");
+        assert!(config.check().is_err());
+        config.upper_dir = config.upper_dir.with_tmpfs_size("16m".to_string());
+        config.check().unwrap();
+    }
+
+    #[test]
+    fn test_masked_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        let lower_path = volume.join("lower");
+        create_test_file(&lower_path, "config.txt", "lower config");
+        create_test_file(&lower_path, "allowed.txt", "lower allowed");
+        let upper_path = volume.join("upper");
+        create_test_file(&upper_path, "only_upper.txt", "upper only");
+
+        let lower_dir = LowerDir::new(lower_path, None).unwrap();
+        let mut config = test_config(&volume, vec![lower_dir]);
+        config.allowed_masked_files = [PathBuf::from("allowed.txt")].into_iter().collect();
+
+        // Nothing in the upper dir overlaps the lower dir
+        assert!(config.masked_files().unwrap().is_empty());
+
+        create_test_file(&upper_path, "config.txt", "upper config");
+        create_test_file(&upper_path, "allowed.txt", "upper allowed");
+        // Reported without erroring, even though the default policy would
+        assert_eq!(
+            config.masked_files().unwrap(),
+            [upper_path.join("config.txt")]
+        );
+        assert!(matches!(
+            config.check(),
+            Err(ConfigError::ValidationError(ValidationError::MaskedFiles(
+                _
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_mount_config_all_files_allowed() {
         let temp_dir = TempDir::new().unwrap();
         let volume = temp_dir.path().to_path_buf();
 
@@ -402,253 +4612,428 @@ mod tests {
         let lower_path = volume.join("lower");
         fs::create_dir_all(&lower_path).unwrap();
         create_test_file(&lower_path, "config.txt", "lower config");
-        create_test_file(&lower_path, "subdir/nested.txt", "nested file");
+        create_test_file(&lower_path, "other.txt", "other file");
+
+        // Create upper directory with overlapping files
+        let upper_path = volume.join("upper");
+        fs::create_dir_all(&upper_path).unwrap();
+        create_test_file(&upper_path, "config.txt", "upper config");
+        create_test_file(&upper_path, "other.txt", "upper other");
 
         let lower_dir = LowerDir::new(lower_path, None).unwrap();
-        let upper_dir = UpperDir::new(
-            volume.clone(),
-            PathBuf::from("upper"),
-            PathBuf::from("work"),
-            PathBuf::from("merged"),
-        )
-        .unwrap();
+        let mut config = test_config(&volume, vec![lower_dir]);
+        config.allowed_masked_files = vec![PathBuf::from("config.txt"), PathBuf::from("other.txt")]
+            .into_iter()
+            .collect();
+
+        let validated = config.validate().unwrap();
+        assert!(matches!(validated, ValidatedMountConfig(_)));
+    }
+
+    #[test]
+    fn test_mount_config_conflicting_overlay_options() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        let lower_dir = LowerDir::new(volume.join("lower"), None).unwrap();
+        let mut config = test_config(&volume, vec![lower_dir]);
+        config.overlay_options = OverlayFeatures {
+            nfs_export: Some(true),
+            index: Some(false),
+            ..Default::default()
+        };
+
+        let result = config.validate();
+        assert!(matches!(
+            result,
+            Err(ConfigError::ValidationError(
+                ValidationError::ConflictingOverlayOptions(_, _)
+            ))
+        ));
+        // Validation of options happens before anything is created
+        assert!(!volume.join("upper").exists());
+    }
+
+    #[test]
+    fn test_mount_config_volatile_marker_rejected() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        create_test_file(&volume, "work/work/incompat/volatile/dirty", "");
+
+        let mut config = test_config(
+            &volume,
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+        );
+        config.overlay_options.volatile = true;
+        let result = config.validate();
+        assert!(matches!(
+            result,
+            Err(ConfigError::ValidationError(
+                ValidationError::VolatileMarkerPresent(_)
+            ))
+        ));
+        assert!(volume.join("work/work/incompat/volatile/dirty").exists());
+    }
 
-        let config = MountConfig {
-            lower_dirs: vec![lower_dir],
-            upper_dir,
-            allowed_masked_files: BTreeSet::new(),
-        };
+    #[test]
+    fn test_mount_config_volatile_marker_cleared() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        create_test_file(&volume, "work/work/incompat/volatile/dirty", "");
 
-        let validated = config.validate().unwrap();
-        assert!(matches!(validated, ValidatedMountConfig(_)));
+        let mut config = test_config(
+            &volume,
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+        );
+        config.overlay_options.volatile = true;
+        config.upper_dir = config.upper_dir.with_clear_volatile_marker(true);
+        config.validate().unwrap();
+        assert!(!volume.join("work/work/incompat/volatile").exists());
+        assert!(volume.join("work/work/incompat").exists());
     }
 
     #[test]
-    fn test_mount_config_with_masked_files() {
+    fn test_mount_config_no_volatile_marker() {
         let temp_dir = TempDir::new().unwrap();
         let volume = temp_dir.path().to_path_buf();
 
-        // Create lower directory with some files
-        let lower_path = volume.join("lower");
-        fs::create_dir_all(&lower_path).unwrap();
-        create_test_file(&lower_path, "config.txt", "lower config");
-        create_test_file(&lower_path, "subdir/nested.txt", "nested file");
+        let mut config = test_config(
+            &volume,
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+        );
+        config.overlay_options.volatile = true;
+        config.validate().unwrap();
+    }
 
-        // Create upper directory with overlapping files
-        let upper_path = volume.join("upper");
-        fs::create_dir_all(&upper_path).unwrap();
-        create_test_file(&upper_path, "config.txt", "upper config");
+    #[test]
+    fn test_mount_config_lower_order() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
 
-        let lower_dir = LowerDir::new(lower_path, None).unwrap();
-        let upper_dir = UpperDir::new(
-            volume.clone(),
-            PathBuf::from("upper"),
-            PathBuf::from("work"),
-            PathBuf::from("merged"),
-        )
-        .unwrap();
+        let mut config = test_config(
+            &volume,
+            vec![
+                LowerDir::new(volume.join("lower1"), None).unwrap(),
+                LowerDir::new(volume.join("lower2"), None).unwrap(),
+                LowerDir::new(volume.join("lower3"), None).unwrap(),
+            ],
+        );
 
-        let config = MountConfig {
-            lower_dirs: vec![lower_dir],
-            upper_dir,
-            allowed_masked_files: BTreeSet::new(),
+        let paths = |config: &MountConfig| {
+            config
+                .ordered_lower_dirs()
+                .iter()
+                .map(|lower| lower.mount_path())
+                .collect::<Vec<_>>()
         };
 
-        let result = config.validate();
-        assert!(matches!(
-            result,
-            Err(ConfigError::ValidationError(ValidationError::MaskedFiles(
-                _
-            )))
-        ));
+        assert_eq!(
+            paths(&config),
+            [
+                volume.join("lower1"),
+                volume.join("lower2"),
+                volume.join("lower3")
+            ]
+        );
 
-        if let Err(ConfigError::ValidationError(ValidationError::MaskedFiles(masked_files))) =
-            result
-        {
-            assert_eq!(masked_files.len(), 1);
-            assert!(masked_files[0].ends_with("config.txt"));
-        }
+        config.lower_order = LowerOrder::Reversed;
+        assert_eq!(
+            paths(&config),
+            [
+                volume.join("lower3"),
+                volume.join("lower2"),
+                volume.join("lower1")
+            ]
+        );
     }
 
     #[test]
-    fn test_mount_config_multiple_lower_dirs() {
+    fn test_mount_config_overlapping_lower_files_still_valid() {
         let temp_dir = TempDir::new().unwrap();
         let volume = temp_dir.path().to_path_buf();
 
-        // Create first lower directory
         let lower1_path = volume.join("lower1");
-        fs::create_dir_all(&lower1_path).unwrap();
-        create_test_file(&lower1_path, "file1.txt", "content1");
-
-        // Create second lower directory
+        create_test_file(&lower1_path, "shared.txt", "from lower1");
         let lower2_path = volume.join("lower2");
-        fs::create_dir_all(&lower2_path).unwrap();
-        create_test_file(&lower2_path, "file2.txt", "content2");
-
-        // Create upper directory with file that masks lower1
-        let upper_path = volume.join("upper");
-        fs::create_dir_all(&upper_path).unwrap();
-        create_test_file(&upper_path, "file1.txt", "upper content");
-
-        let lower_dir1 = LowerDir::new(lower1_path, None).unwrap();
-        let lower_dir2 = LowerDir::new(lower2_path, None).unwrap();
-        let upper_dir = UpperDir::new(
-            volume.clone(),
-            PathBuf::from("upper"),
-            PathBuf::from("work"),
-            PathBuf::from("merged"),
-        )
-        .unwrap();
+        create_test_file(&lower2_path, "shared.txt", "from lower2");
 
-        let config = MountConfig {
-            lower_dirs: vec![lower_dir1, lower_dir2],
-            upper_dir,
-            allowed_masked_files: BTreeSet::new(),
-        };
+        let mut config = test_config(
+            &volume,
+            vec![
+                LowerDir::new(lower1_path, None).unwrap(),
+                LowerDir::new(lower2_path, None).unwrap(),
+            ],
+        );
+        config.lower_order = LowerOrder::Reversed;
 
-        let result = config.validate();
-        assert!(matches!(
-            result,
-            Err(ConfigError::ValidationError(ValidationError::MaskedFiles(
-                _
-            )))
-        ));
+        // Overlap between lower layers is only a warning
+        config.validate().unwrap();
     }
 
     #[test]
-    fn test_mount_config_with_subdirs() {
+    fn test_overlapping_lower_files() {
         let temp_dir = TempDir::new().unwrap();
         let volume = temp_dir.path().to_path_buf();
 
-        // Create lower directory with subdir
-        let lower_base = volume.join("lower_base");
-        let lower_subdir_path = lower_base.join("subdir");
-        fs::create_dir_all(&lower_subdir_path).unwrap();
-        create_test_file(&lower_subdir_path, "config.txt", "lower config");
+        let lower0_path = volume.join("lower0");
+        create_test_file(&lower0_path, "shared.txt", "from lower0");
+        create_test_file(&lower0_path, "nested/all.conf", "from lower0");
+        create_test_file(&lower0_path, "only0.txt", "from lower0");
+        let lower1_path = volume.join("lower1");
+        create_test_file(&lower1_path, "only1.txt", "from lower1");
+        create_test_file(&lower1_path, "nested/all.conf", "from lower1");
+        let lower2_path = volume.join("lower2");
+        create_test_file(&lower2_path, "shared.txt", "from lower2");
+        create_test_file(&lower2_path, "nested/all.conf", "from lower2");
 
-        let lower_dir = LowerDir::new(lower_base, Some(PathBuf::from("subdir"))).unwrap();
-        let upper_dir = UpperDir::new(
-            volume.clone(),
-            PathBuf::from("upper"),
-            PathBuf::from("work"),
-            PathBuf::from("merged"),
-        )
-        .unwrap();
+        let mut config = test_config(
+            &volume,
+            vec![
+                LowerDir::new(lower0_path, None).unwrap(),
+                LowerDir::new(lower1_path.clone(), None).unwrap(),
+                LowerDir::new(lower2_path, None).unwrap(),
+            ],
+        );
 
-        let config = MountConfig {
-            lower_dirs: vec![lower_dir],
-            upper_dir,
-            allowed_masked_files: BTreeSet::new(),
-        };
+        assert_eq!(
+            config.overlapping_lower_files().unwrap(),
+            [
+                (PathBuf::from("nested/all.conf"), vec![0, 1, 2]),
+                (PathBuf::from("shared.txt"), vec![0, 2]),
+            ]
+        );
 
-        let validated = config.validate().unwrap();
-        assert!(matches!(validated, ValidatedMountConfig(_)));
+        // Nothing overlaps once the layers have no paths in common
+        config.lower_dirs.truncate(2);
+        fs::remove_file(lower1_path.join("nested/all.conf")).unwrap();
+        assert!(config.overlapping_lower_files().unwrap().is_empty());
+    }
+
+    /// `lower/sub` under `volume` listed twice, spelled differently the second time
+    fn create_duplicate_lower_dirs(volume: &Path) -> Vec<LowerDir> {
+        let lower_path = volume.join("lower");
+        fs::create_dir_all(lower_path.join("sub")).unwrap();
+        vec![
+            LowerDir::new(lower_path.join("sub"), None).unwrap(),
+            LowerDir::new(volume.join("other"), None).unwrap(),
+            LowerDir::new(lower_path, Some(PathBuf::from("./sub"))).unwrap(),
+        ]
     }
 
     #[test]
-    fn test_collect_file_paths() {
+    fn test_mount_config_duplicate_lower_dirs_rejected() {
         let temp_dir = TempDir::new().unwrap();
-        let base_path = temp_dir.path();
+        let volume = temp_dir.path().to_path_buf();
 
-        // Create a directory structure
-        create_test_file(base_path, "file1.txt", "content1");
-        create_test_file(base_path, "subdir/file2.txt", "content2");
-        create_test_file(base_path, "subdir/nested/file3.txt", "content3");
+        let result = test_config(&volume, create_duplicate_lower_dirs(&volume)).validate();
+        match result {
+            Err(ConfigError::ValidationError(ValidationError::DuplicateLowerDir(path))) => {
+                assert_eq!(path, volume.join("lower/./sub"));
+            }
+            _ => panic!("expected duplicate lower dir error"),
+        }
+    }
 
-        let mut file_paths = std::collections::HashSet::new();
-        MountConfig::collect_file_paths(base_path, base_path, &mut file_paths).unwrap();
+    #[test]
+    fn test_mount_config_duplicate_lower_dirs_deduped() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
 
-        assert_eq!(file_paths.len(), 3);
-        assert!(file_paths.contains(&PathBuf::from("file1.txt")));
-        assert!(file_paths.contains(&PathBuf::from("subdir/file2.txt")));
-        assert!(file_paths.contains(&PathBuf::from("subdir/nested/file3.txt")));
+        let mut config = test_config(&volume, create_duplicate_lower_dirs(&volume));
+        config.dedup_lower_dirs = true;
+        let validated = config.validate().unwrap();
+        let config: MountConfig = validated.into();
+        assert_eq!(config.lower_dirs.len(), 2);
+        assert_eq!(config.lower_dirs[0].full_path(), volume.join("lower/sub"));
+        assert_eq!(config.lower_dirs[1].full_path(), volume.join("other"));
     }
 
     #[test]
-    fn test_mount_config_with_allowed_masked_files() {
+    fn test_mount_config_too_many_lower_dirs() {
         let temp_dir = TempDir::new().unwrap();
         let volume = temp_dir.path().to_path_buf();
 
-        // Create lower directory with some files
-        let lower_path = volume.join("lower");
-        fs::create_dir_all(&lower_path).unwrap();
-        create_test_file(&lower_path, "config.txt", "lower config");
-        create_test_file(&lower_path, "allowed.txt", "allowed file");
+        let mut config = test_config(
+            &volume,
+            (0..4)
+                .map(|i| LowerDir::new(volume.join(format!("lower{i}")), None).unwrap())
+                .collect(),
+        );
+        config.max_lower_dirs = 3;
 
-        // Create upper directory with overlapping files
-        let upper_path = volume.join("upper");
-        fs::create_dir_all(&upper_path).unwrap();
-        create_test_file(&upper_path, "config.txt", "upper config");
-        create_test_file(&upper_path, "allowed.txt", "upper allowed");
+        let result = config.clone().validate();
+        assert!(matches!(
+            result,
+            Err(ConfigError::ValidationError(
+                ValidationError::TooManyLowerDirs { count: 4, max: 3 }
+            ))
+        ));
 
-        let lower_dir = LowerDir::new(lower_path, None).unwrap();
-        let upper_dir = UpperDir::new(
-            volume.clone(),
-            PathBuf::from("upper"),
-            PathBuf::from("work"),
-            PathBuf::from("merged"),
+        let config = MountConfig {
+            max_lower_dirs: 4,
+            ..config
+        };
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_mount_config_max_lower_dirs_default() {
+        let config: MountConfig = toml::from_str(
+            r#"
+            lower_dirs = []
+            [upper_dir]
+            volume = "/volume"
+            upper_subdir = "upper"
+            work_subdir = "work"
+            merged_subdir = "merged"
+            "#,
         )
         .unwrap();
+        assert_eq!(config.max_lower_dirs, DEFAULT_MAX_LOWER_DIRS);
+    }
 
-        let config = MountConfig {
-            lower_dirs: vec![lower_dir],
-            upper_dir,
-            allowed_masked_files: vec![PathBuf::from("allowed.txt")].into_iter().collect(),
+    #[test]
+    fn test_is_upper_tmpfs() {
+        let entry = |source: &str| {
+            mountinfo::MountEntry::parse(&format!(
+                "38 22 0:32 / /volume rw,relatime - tmpfs {source} rw,size=16384k"
+            ))
+            .unwrap()
         };
+        assert!(is_upper_tmpfs(&entry(UPPER_TMPFS_SOURCE)));
+        // eg a memory backed emptyDir the kubelet mounted
+        assert!(!is_upper_tmpfs(&entry("tmpfs")));
+        let mut overlay = entry(UPPER_TMPFS_SOURCE);
+        overlay.fs_type = "overlay".to_string();
+        assert!(!is_upper_tmpfs(&overlay));
+    }
 
-        let result = config.validate();
+    #[test]
+    fn test_upper_dir_tmpfs_size() {
+        let config: MountConfig = toml::from_str(
+            r#"
+            lower_dirs = []
+            [upper_dir]
+            volume = "/volume"
+            upper_subdir = "upper"
+            work_subdir = "work"
+            merged_subdir = "merged"
+            tmpfs_size = "256m"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.upper_dir.tmpfs_size(), Some("256m"));
+        assert_eq!(config.upper_dir.volume(), Path::new("/volume"));
+
+        let default: UpperDir = toml::from_str(
+            r#"
+            volume = "/volume"
+            upper_subdir = "upper"
+            work_subdir = "work"
+            merged_subdir = "merged"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(default.tmpfs_size(), None);
+
+        for valid in ["1048576", "512k", "256m", "2G", "20%"] {
+            assert!(is_tmpfs_size(valid), "{valid}");
+        }
+        for invalid in ["", "m", "1.5g", "256mb", "-1", "20 %", "size=1g"] {
+            assert!(!is_tmpfs_size(invalid), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_validate_tmpfs() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().join("volume");
+        let lower_path = temp_dir.path().join("lower");
+        create_test_file(&lower_path, "config.txt", "lower config");
+        // Left over on the volume, the tmpfs covers it so it doesn't mask anything
+        create_test_file(&volume.join("upper"), "config.txt", "stale upper config");
+
+        let upper_dir = test_upper_dir(volume);
+        let mut config = MountConfig::new_for_test(
+            vec![LowerDir::new(lower_path, None).unwrap()],
+            upper_dir.clone(),
+        );
         assert!(matches!(
-            result,
+            config.check(),
             Err(ConfigError::ValidationError(ValidationError::MaskedFiles(
                 _
             )))
         ));
 
-        if let Err(ConfigError::ValidationError(ValidationError::MaskedFiles(masked_files))) =
-            result
-        {
-            assert_eq!(masked_files.len(), 1);
-            assert!(masked_files[0].ends_with("config.txt"));
-            assert!(!masked_files.iter().any(|p| p.ends_with("allowed.txt")));
-        }
+        config.upper_dir = upper_dir.clone().with_tmpfs_size("64m".to_string());
+        config.check().unwrap();
+
+        config.private_namespace = true;
+        assert!(matches!(
+            config.check(),
+            Err(ConfigError::ValidationError(
+                ValidationError::TmpfsInPrivateNamespace
+            ))
+        ));
+
+        config.private_namespace = false;
+        config.upper_dir = upper_dir.with_tmpfs_size("64 MiB".to_string());
+        assert!(matches!(
+            config.check(),
+            Err(ConfigError::ValidationError(ValidationError::InvalidTmpfsSize(size))) if size == "64 MiB"
+        ));
     }
 
     #[test]
-    fn test_mount_config_all_files_allowed() {
+    fn test_mount_config_canonicalize_symlinked_volumes() {
         let temp_dir = TempDir::new().unwrap();
-        let volume = temp_dir.path().to_path_buf();
-
-        // Create lower directory with some files
-        let lower_path = volume.join("lower");
-        fs::create_dir_all(&lower_path).unwrap();
-        create_test_file(&lower_path, "config.txt", "lower config");
-        create_test_file(&lower_path, "other.txt", "other file");
+        let volume = fs::canonicalize(temp_dir.path()).unwrap();
 
-        // Create upper directory with overlapping files
-        let upper_path = volume.join("upper");
-        fs::create_dir_all(&upper_path).unwrap();
-        create_test_file(&upper_path, "config.txt", "upper config");
-        create_test_file(&upper_path, "other.txt", "upper other");
+        create_test_file(&volume.join("real-lower"), "sub/config.txt", "lower config");
+        fs::create_dir_all(volume.join("real-upper")).unwrap();
+        std::os::unix::fs::symlink(volume.join("real-lower"), volume.join("lower-link")).unwrap();
+        std::os::unix::fs::symlink(volume.join("real-upper"), volume.join("upper-link")).unwrap();
 
-        let lower_dir = LowerDir::new(lower_path, None).unwrap();
-        let upper_dir = UpperDir::new(
-            volume.clone(),
-            PathBuf::from("upper"),
-            PathBuf::from("work"),
-            PathBuf::from("merged"),
+        let lower_dir = LowerDir::new_with_sync(
+            volume.join("lower-link"),
+            Some(PathBuf::from("sub")),
+            // Doesn't exist yet, only its existing ancestor can be resolved
+            SyncMode::Once(volume.join("upper-link/not-yet/target")),
         )
         .unwrap();
+        let mut config = test_config(volume.join("upper-link"), vec![lower_dir]);
+        config.canonicalize = true;
 
-        let config = MountConfig {
-            lower_dirs: vec![lower_dir],
-            upper_dir,
-            allowed_masked_files: vec![PathBuf::from("config.txt"), PathBuf::from("other.txt")]
-                .into_iter()
-                .collect(),
-        };
+        let config: MountConfig = config.validate().unwrap().into();
+        assert_eq!(
+            config.lower_dirs[0].full_path(),
+            volume.join("real-lower/sub")
+        );
+        assert_eq!(
+            config.lower_dirs[0].mount_path(),
+            volume.join("real-upper/not-yet/target")
+        );
+        assert_eq!(
+            config.upper_dir.merged_path(),
+            volume.join("real-upper/merged")
+        );
+    }
 
-        let validated = config.validate().unwrap();
-        assert!(matches!(validated, ValidatedMountConfig(_)));
+    #[test]
+    fn test_mount_config_no_canonicalize_keeps_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = fs::canonicalize(temp_dir.path()).unwrap();
+
+        fs::create_dir_all(volume.join("real-lower")).unwrap();
+        std::os::unix::fs::symlink(volume.join("real-lower"), volume.join("lower-link")).unwrap();
+
+        let config = test_config(
+            &volume,
+            vec![LowerDir::new(volume.join("lower-link"), None).unwrap()],
+        );
+
+        let config: MountConfig = config.validate().unwrap().into();
+        assert_eq!(config.lower_dirs[0].full_path(), volume.join("lower-link"));
     }
 
     #[test]
@@ -657,19 +5042,9 @@ mod tests {
         let volume = temp_dir.path().to_path_buf();
 
         let lower_dir = LowerDir::new(volume.join("lower"), None).unwrap();
-        let upper_dir = UpperDir::new(
-            volume.clone(),
-            PathBuf::from("upper"),
-            PathBuf::from("work"),
-            PathBuf::from("merged"),
-        )
-        .unwrap();
+        let upper_dir = test_upper_dir(&volume);
 
-        let original_config = MountConfig {
-            lower_dirs: vec![lower_dir.clone()],
-            upper_dir: upper_dir.clone(),
-            allowed_masked_files: BTreeSet::new(),
-        };
+        let original_config = MountConfig::new_for_test(vec![lower_dir.clone()], upper_dir.clone());
 
         let validated = original_config.validate().unwrap();
         let converted_config: MountConfig = validated.into();
@@ -678,4 +5053,64 @@ mod tests {
         assert_eq!(converted_config.lower_dirs[0].volume, lower_dir.volume);
         assert_eq!(converted_config.upper_dir.volume, upper_dir.volume);
     }
+
+    /// Run `retry_transient` against a stubbed operation returning `results` in turn, along
+    /// with how many times it was called
+    fn stub_retry(results: Vec<io::Result<()>>, retries: usize) -> (io::Result<()>, usize) {
+        let mut results = results.into_iter();
+        let mut calls = 0;
+        let result = retry_transient("stub", retries, Duration::from_millis(1), || {
+            calls += 1;
+            results.next().unwrap()
+        });
+        (result, calls)
+    }
+
+    #[test]
+    fn test_retry_transient() {
+        use nix::errno::Errno;
+
+        let transient = || Err(io::Error::from_raw_os_error(Errno::EAGAIN as i32));
+        // Fails twice then succeeds
+        let (result, calls) = stub_retry(
+            vec![
+                transient(),
+                Err(io::Error::from_raw_os_error(Errno::ETIMEDOUT as i32)),
+                Ok(()),
+            ],
+            3,
+        );
+        result.unwrap();
+        assert_eq!(calls, 3);
+
+        // Gives up after the first attempt plus the retries
+        let (result, calls) = stub_retry(vec![transient(), transient(), transient()], 2);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(calls, 3);
+
+        // Permanent errors aren't retried
+        for errno in [Errno::EACCES as i32, Errno::EROFS as i32] {
+            let (result, calls) =
+                stub_retry(vec![Err(io::Error::from_raw_os_error(errno)), Ok(())], 3);
+            assert_eq!(result.unwrap_err().raw_os_error(), Some(errno));
+            assert_eq!(calls, 1);
+        }
+    }
+
+    #[test]
+    fn test_retry_transient_backs_off() {
+        let start = std::time::Instant::now();
+        let mut results = vec![
+            Err(io::ErrorKind::TimedOut.into()),
+            Err(io::ErrorKind::TimedOut.into()),
+            Ok(()),
+        ]
+        .into_iter();
+        retry_transient("stub", 2, Duration::from_millis(20), || {
+            results.next().unwrap()
+        })
+        .unwrap();
+        // 20ms then 40ms
+        assert!(start.elapsed() >= Duration::from_millis(60));
+    }
 }
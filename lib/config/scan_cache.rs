@@ -0,0 +1,187 @@
+//! On-disk cache of the lower layer walks done by the masked-files scan, see `mask_scan_cache`.
+//!
+//! A layer's file list can only change when an entry is added to, removed from or renamed within
+//! one of its directories, and each of those updates that directory's mtime. So a cached list is
+//! reused as long as every directory the walk read still has the mtime it had at the time.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::{IOErrorAtPath, MountConfig, ScanLimits, mtime};
+use crate::{info, warn};
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(super) struct ScanCache {
+    layers: BTreeMap<PathBuf, CachedLayer>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CachedLayer {
+    max_depth: Option<usize>,
+    ignore: Vec<PathBuf>,
+    /// `(seconds, nanoseconds)` mtime of every directory read, relative to the layer root
+    dir_mtimes: BTreeMap<PathBuf, (i64, i64)>,
+    files: Vec<PathBuf>,
+}
+
+impl ScanCache {
+    /// A missing or unreadable cache is treated as empty, it only costs a full walk
+    pub(super) fn load(path: &Path) -> Self {
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                warn!("Ignoring unreadable mask scan cache {path:?}: {e}");
+                return Self::default();
+            }
+        };
+        serde_json::from_slice(&content).unwrap_or_else(|e| {
+            warn!("Ignoring corrupt mask scan cache {path:?}: {e}");
+            Self::default()
+        })
+    }
+
+    /// Replace the cache at `path`, via a rename so a crash can't leave it half written
+    pub(super) fn save(&self, path: &Path) -> io::Result<()> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        fs::write(&temp_path, serde_json::to_vec(self)?)?;
+        fs::rename(&temp_path, path)
+    }
+
+    /// The cached files of the layer at `root` if it was walked with the same limits and none of
+    /// its directories changed since
+    pub(super) fn lookup(&self, root: &Path, limits: &ScanLimits) -> Option<&[PathBuf]> {
+        let layer = self.layers.get(root)?;
+        if layer.max_depth != limits.max_depth || layer.ignore != limits.ignore {
+            return None;
+        }
+        let unchanged = layer
+            .dir_mtimes
+            .iter()
+            .all(|(dir, cached)| mtime(&root.join(dir)).is_ok_and(|current| current == *cached));
+        unchanged.then_some(layer.files.as_slice())
+    }
+
+    /// The files of the layer at `root`, reused from `previous` when still valid and walked
+    /// otherwise. Either way the result is recorded in `self`.
+    pub(super) fn layer_files(
+        &mut self,
+        previous: &ScanCache,
+        root: &Path,
+        limits: &ScanLimits,
+    ) -> Result<HashSet<PathBuf>, IOErrorAtPath> {
+        if let Some(files) = previous.lookup(root, limits) {
+            info!("Using cached mask scan of {root:?}");
+            self.layers
+                .insert(root.to_path_buf(), previous.layers[root].clone());
+            return Ok(files.iter().cloned().collect());
+        }
+
+        let walk = MountConfig::walk_layer(root, limits)?;
+        let mut cached_files: Vec<_> = walk.files.iter().cloned().collect();
+        cached_files.sort();
+        let layer = CachedLayer {
+            max_depth: limits.max_depth,
+            ignore: limits.ignore.to_vec(),
+            dir_mtimes: walk.dir_mtimes,
+            files: cached_files,
+        };
+        self.layers.insert(root.to_path_buf(), layer);
+        Ok(walk.files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn create_test_file(dir: &Path, relative_path: &str) {
+        let file_path = dir.join(relative_path);
+        fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+        fs::write(file_path, "content").unwrap();
+    }
+
+    fn sorted(files: HashSet<PathBuf>) -> Vec<PathBuf> {
+        let mut files: Vec<_> = files.into_iter().collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_layer_files_cached_until_a_dir_changes() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("lower");
+        create_test_file(&root, "a.conf");
+        create_test_file(&root, "nested/deep/b.conf");
+        fs::create_dir_all(root.join("empty")).unwrap();
+        let limits = ScanLimits::default();
+
+        let mut cache = ScanCache::default();
+        let files = cache
+            .layer_files(&ScanCache::default(), &root, &limits)
+            .unwrap();
+        assert_eq!(
+            sorted(files),
+            [PathBuf::from("a.conf"), PathBuf::from("nested/deep/b.conf")]
+        );
+        assert!(cache.lookup(&root, &limits).is_some());
+
+        // A file added to a previously empty nested dir
+        create_test_file(&root, "empty/c.conf");
+        assert!(cache.lookup(&root, &limits).is_none());
+
+        let mut next = ScanCache::default();
+        let files = next.layer_files(&cache, &root, &limits).unwrap();
+        assert!(files.contains(Path::new("empty/c.conf")));
+        assert!(next.lookup(&root, &limits).is_some());
+    }
+
+    #[test]
+    fn test_lookup_requires_same_limits() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_path_buf();
+        create_test_file(&root, "nested/a.conf");
+
+        let mut cache = ScanCache::default();
+        cache
+            .layer_files(&ScanCache::default(), &root, &ScanLimits::default())
+            .unwrap();
+
+        let shallow = ScanLimits {
+            max_depth: Some(0),
+            ..Default::default()
+        };
+        assert!(cache.lookup(&root, &shallow).is_none());
+        let ignore = [PathBuf::from("nested")];
+        let ignoring = ScanLimits {
+            ignore: &ignore,
+            ..Default::default()
+        };
+        assert!(cache.lookup(&root, &ignoring).is_none());
+    }
+
+    #[test]
+    fn test_load_and_save() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("lower");
+        create_test_file(&root, "a.conf");
+        let cache_path = temp_dir.path().join("scan-cache.json");
+
+        assert_eq!(ScanCache::load(&cache_path), ScanCache::default());
+
+        let mut cache = ScanCache::default();
+        cache
+            .layer_files(&ScanCache::default(), &root, &ScanLimits::default())
+            .unwrap();
+        cache.save(&cache_path).unwrap();
+        assert_eq!(ScanCache::load(&cache_path), cache);
+
+        fs::write(&cache_path, "not json").unwrap();
+        assert_eq!(ScanCache::load(&cache_path), ScanCache::default());
+    }
+}
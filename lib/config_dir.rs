@@ -0,0 +1,73 @@
+//! Drop-in directories of configs, one overlay per `*.toml` file as in `/etc/overlay.d/`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::IOErrorAtPath;
+
+/// Load every `*.toml` file directly in `dir` with `load`, in file name order. Each file gets its
+/// own result so one broken config doesn't hide the state of the others, only failing to list
+/// `dir` fails the whole batch.
+pub fn load_configs_from_dir<T>(
+    dir: &Path,
+    mut load: impl FnMut(&Path) -> T,
+) -> Result<Vec<(PathBuf, T)>, IOErrorAtPath> {
+    let mut paths = Vec::new();
+    let read_error = |e| IOErrorAtPath(dir.to_path_buf(), e);
+    for entry in fs::read_dir(dir).map_err(read_error)? {
+        let path = entry.map_err(read_error)?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "toml")
+            && path.is_file()
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    Ok(paths
+        .into_iter()
+        .map(|path| {
+            let result = load(&path);
+            (path, result)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_load_configs_from_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        fs::write(dir.join("b.toml"), "b").unwrap();
+        fs::write(dir.join("a.toml"), "a").unwrap();
+        // Only toml files are picked up
+        fs::write(dir.join("README"), "not a config").unwrap();
+        fs::write(dir.join("d.toml.disabled"), "not a config").unwrap();
+        fs::create_dir(dir.join("e.toml")).unwrap();
+
+        let results = load_configs_from_dir(dir, |path| fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(
+            results,
+            [
+                (dir.join("a.toml"), "a".to_string()),
+                (dir.join("b.toml"), "b".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_configs_from_missing_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let missing = temp_dir.path().join("missing");
+        assert!(matches!(
+            load_configs_from_dir(&missing, |_| ()),
+            Err(IOErrorAtPath(path, _)) if path == missing
+        ));
+    }
+}
@@ -0,0 +1,290 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::ValidationError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedirectDir {
+    On,
+    Follow,
+    NoFollow,
+    Off,
+}
+
+impl RedirectDir {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RedirectDir::On => "on",
+            RedirectDir::Follow => "follow",
+            RedirectDir::NoFollow => "nofollow",
+            RedirectDir::Off => "off",
+        }
+    }
+}
+
+/// How overlayfs numbers inodes in the merged dir. With `On` they are unique and stable across
+/// layers by encoding the layer in the high bits, which can run out on filesystems already using
+/// them where `Off` keeps the real inode numbers. `Auto` only enables it when the layers allow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Xino {
+    On,
+    Off,
+    Auto,
+}
+
+impl Xino {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Xino::On => "on",
+            Xino::Off => "off",
+            Xino::Auto => "auto",
+        }
+    }
+}
+
+/// Optional overlayfs features appended to the mount options after the layer dirs.
+///
+/// Every field left unset is omitted from the option string so the kernel (or module parameter)
+/// default applies.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct OverlayFeatures {
+    pub redirect_dir: Option<RedirectDir>,
+    pub index: Option<bool>,
+    pub nfs_export: Option<bool>,
+    pub metacopy: Option<bool>,
+    pub userxattr: bool,
+    /// Skip syncfs on the upper layer. Only safe for overlays whose content can be thrown away
+    /// after a crash.
+    pub volatile: bool,
+    pub xino: Option<Xino>,
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value { "on" } else { "off" }
+}
+
+impl OverlayFeatures {
+    /// Reject combinations the kernel refuses when both options are set explicitly.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let conflict = |a: String, b: String| Err(ValidationError::ConflictingOverlayOptions(a, b));
+
+        if self.nfs_export == Some(true) && self.index == Some(false) {
+            return conflict("nfs_export=on".into(), "index=off".into());
+        }
+        if self.nfs_export == Some(true) && self.metacopy == Some(true) {
+            return conflict("nfs_export=on".into(), "metacopy=on".into());
+        }
+        // File handles have to be followable across renamed dirs
+        if self.nfs_export == Some(true) && self.redirect_dir == Some(RedirectDir::Off) {
+            return conflict("nfs_export=on".into(), "redirect_dir=off".into());
+        }
+        if let (Some(true), Some(redirect_dir)) = (self.metacopy, self.redirect_dir)
+            && redirect_dir != RedirectDir::On
+        {
+            return conflict(
+                "metacopy=on".into(),
+                format!("redirect_dir={}", redirect_dir.as_str()),
+            );
+        }
+        if self.userxattr {
+            if let Some(redirect_dir) = self.redirect_dir
+                && redirect_dir != RedirectDir::NoFollow
+            {
+                return conflict(
+                    "userxattr".into(),
+                    format!("redirect_dir={}", redirect_dir.as_str()),
+                );
+            }
+            if self.metacopy == Some(true) {
+                return conflict("userxattr".into(), "metacopy=on".into());
+            }
+        }
+        // xino combines with every other option, the kernel only downgrades it at mount time
+        // when the layers' inode numbers leave no bits free
+        Ok(())
+    }
+
+    /// The mount option tokens for the features that are set, in a stable order.
+    pub fn mount_options(&self) -> Vec<String> {
+        let mut options = Vec::new();
+        if let Some(redirect_dir) = self.redirect_dir {
+            options.push(format!("redirect_dir={}", redirect_dir.as_str()));
+        }
+        if let Some(index) = self.index {
+            options.push(format!("index={}", on_off(index)));
+        }
+        if let Some(nfs_export) = self.nfs_export {
+            options.push(format!("nfs_export={}", on_off(nfs_export)));
+        }
+        if let Some(metacopy) = self.metacopy {
+            options.push(format!("metacopy={}", on_off(metacopy)));
+        }
+        if self.userxattr {
+            options.push("userxattr".to_string());
+        }
+        if self.volatile {
+            options.push("volatile".to_string());
+        }
+        if let Some(xino) = self.xino {
+            options.push(format!("xino={}", xino.as_str()));
+        }
+        options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_emits_nothing() {
+        let features = OverlayFeatures::default();
+        assert!(features.mount_options().is_empty());
+        assert!(features.validate().is_ok());
+    }
+
+    #[test]
+    fn test_mount_options_stable_order() {
+        let features = OverlayFeatures {
+            metacopy: Some(true),
+            index: Some(true),
+            redirect_dir: Some(RedirectDir::On),
+            nfs_export: Some(false),
+            userxattr: false,
+            volatile: true,
+            xino: Some(Xino::Off),
+        };
+        assert!(features.validate().is_ok());
+        assert_eq!(
+            features.mount_options(),
+            [
+                "redirect_dir=on",
+                "index=on",
+                "nfs_export=off",
+                "metacopy=on",
+                "volatile",
+                "xino=off"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mount_options_userxattr() {
+        let features = OverlayFeatures {
+            userxattr: true,
+            redirect_dir: Some(RedirectDir::NoFollow),
+            ..Default::default()
+        };
+        assert!(features.validate().is_ok());
+        assert_eq!(
+            features.mount_options(),
+            ["redirect_dir=nofollow", "userxattr"]
+        );
+    }
+
+    #[test]
+    fn test_mount_options_xino() {
+        for (xino, token) in [
+            (Xino::On, "xino=on"),
+            (Xino::Off, "xino=off"),
+            (Xino::Auto, "xino=auto"),
+        ] {
+            let features = OverlayFeatures {
+                xino: Some(xino),
+                ..Default::default()
+            };
+            assert_eq!(features.mount_options(), [token]);
+
+            // Valid alongside each of the other options
+            let features = OverlayFeatures {
+                redirect_dir: Some(RedirectDir::On),
+                index: Some(true),
+                nfs_export: Some(true),
+                volatile: true,
+                xino: Some(xino),
+                ..Default::default()
+            };
+            assert!(features.validate().is_ok());
+            let features = OverlayFeatures {
+                userxattr: true,
+                metacopy: Some(false),
+                xino: Some(xino),
+                ..Default::default()
+            };
+            assert!(features.validate().is_ok());
+        }
+
+        let features: OverlayFeatures = toml::from_str(r#"xino = "auto""#).unwrap();
+        assert_eq!(features.xino, Some(Xino::Auto));
+        let features: OverlayFeatures = toml::from_str("").unwrap();
+        assert_eq!(features.xino, None);
+        assert!(
+            !features
+                .mount_options()
+                .iter()
+                .any(|option| option.starts_with("xino"))
+        );
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let features: OverlayFeatures = toml::from_str(
+            r#"
+            redirect_dir = "nofollow"
+            index = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(features.redirect_dir, Some(RedirectDir::NoFollow));
+        assert_eq!(features.index, Some(false));
+        assert_eq!(features.metacopy, None);
+    }
+
+    #[test]
+    fn test_conflicting_options() {
+        let conflicting = [
+            OverlayFeatures {
+                nfs_export: Some(true),
+                index: Some(false),
+                ..Default::default()
+            },
+            OverlayFeatures {
+                nfs_export: Some(true),
+                metacopy: Some(true),
+                ..Default::default()
+            },
+            OverlayFeatures {
+                nfs_export: Some(true),
+                redirect_dir: Some(RedirectDir::Off),
+                ..Default::default()
+            },
+            OverlayFeatures {
+                metacopy: Some(true),
+                redirect_dir: Some(RedirectDir::Off),
+                ..Default::default()
+            },
+            OverlayFeatures {
+                userxattr: true,
+                redirect_dir: Some(RedirectDir::On),
+                ..Default::default()
+            },
+            OverlayFeatures {
+                userxattr: true,
+                metacopy: Some(true),
+                ..Default::default()
+            },
+        ];
+
+        for features in conflicting {
+            assert!(
+                matches!(
+                    features.validate(),
+                    Err(ValidationError::ConflictingOverlayOptions(_, _))
+                ),
+                "expected conflict for {features:?}"
+            );
+        }
+    }
+}
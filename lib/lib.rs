@@ -1,83 +1,1915 @@
-use nix::mount::{MsFlags, mount, umount};
-use std::io;
+use nix::errno::Errno;
+use nix::mount::{MntFlags, MsFlags, mount, umount, umount2};
+use nix::sched::{CloneFlags, unshare};
+use nix::sys::stat::{Mode, SFlag, makedev, mknod};
+use nix::unistd::syncfs;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+pub use config::LayerPlan;
 use config::MountConfig;
-use rsync::SyncedConfig;
+pub use config_dir::load_configs_from_dir;
+use mountinfo::MountEntry;
+use rsync::{SyncFailure, SyncManager, SyncedConfig};
 
 pub mod config;
+pub mod config_dir;
+pub mod features;
+pub mod logging;
+pub mod mountinfo;
 pub mod rsync;
 
 #[derive(thiserror::Error, Debug)]
 pub enum ManagerError {
     #[error("mount error {0:}")]
     MountError(nix::errno::Errno, Result<Vec<String>, io::Error>),
-    #[error("failed to unmount volume: {0}")]
-    UmountError(nix::errno::Errno),
+    #[error("failed to unmount volume after {attempts} attempt(s): {errno}")]
+    UmountError {
+        errno: nix::errno::Errno,
+        attempts: usize,
+    },
+    #[error("volume still busy after {attempts} unmount attempt(s)")]
+    UmountBusy { attempts: usize },
+    #[error("failed to bind mount merged dir at '{0:?}': {1}")]
+    BindError(PathBuf, nix::errno::Errno),
+    #[error("failed to unmount bind target '{0:?}': {1}")]
+    BindUmountError(PathBuf, nix::errno::Errno),
+    #[error("failed to unmount the upper dir tmpfs at '{0:?}': {1}")]
+    TmpfsUmountError(PathBuf, nix::errno::Errno),
+    #[error("kernel rejected remount with flags {0:?}: {1}")]
+    RemountError(MsFlags, nix::errno::Errno),
+    #[error("failed to set {0:?} propagation on the merged dir: {1}")]
+    PropagationError(MsFlags, nix::errno::Errno),
+    #[error("failed to enter a private mount namespace: {0}")]
+    NamespaceError(nix::errno::Errno),
+    #[error("overlay did not appear at '{0:?}' in the mount table within {1:?}")]
+    NotReady(PathBuf, Duration),
+    #[error("failed to read the mount table: {0}")]
+    MountInfoError(#[source] io::Error),
+    #[error("failed to create whiteout at '{0:?}': {1}")]
+    WhiteoutError(PathBuf, #[source] io::Error),
+    #[error("failed to clean leftover work dir state at '{0:?}': {1}")]
+    CleanWorkdirError(PathBuf, #[source] io::Error),
+    #[error("overlay was mounted again after the refresh failed: {0}")]
+    RefreshSyncError(#[source] SyncFailure),
+    #[error("overlay is left unmounted, mounting it again after the refresh failed: {0}")]
+    RefreshMountError(#[source] Box<ManagerError>),
+}
+
+/// Entries overlayfs creates inside the work dir, nothing else there is touched when cleaning it
+const OVERLAY_WORKDIR_ENTRIES: [&str; 2] = ["work", "index"];
+
+/// How often `wait_until_ready` re-reads the mount table
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Number of dmesg lines captured on a failed mount unless configured otherwise
+pub const DEFAULT_DMESG_LINES: usize = 15;
+
+/// How long dmesg gets to produce its output on a failed mount unless configured otherwise
+pub const DEFAULT_DMESG_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Which of the mounts set up by `OverlayManager::mount` are still in the mount table
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MountState {
+    pub overlay_mounted: bool,
+    pub bind_targets_mounted: Vec<PathBuf>,
+}
+
+impl MountState {
+    pub fn is_clear(&self) -> bool {
+        !self.overlay_mounted && self.bind_targets_mounted.is_empty()
+    }
 }
 
 pub struct OverlayManager {
     config: MountConfig,
+    dmesg_lines: usize,
+    dmesg_timeout: Duration,
+    /// The diagnostic command run on a failed mount, only ever changed by tests
+    dmesg_command: Vec<String>,
 }
 
 impl OverlayManager {
+    /// With `private_namespace` this enters the namespace, see `enter_private_namespace` for
+    /// which threads end up in it.
     pub fn new(config: SyncedConfig) -> Result<Self, ManagerError> {
+        let config: MountConfig = config.into();
+        if config.private_namespace {
+            enter_private_namespace()?;
+        }
         Ok(OverlayManager {
-            config: config.into(),
+            config,
+            dmesg_lines: DEFAULT_DMESG_LINES,
+            dmesg_timeout: DEFAULT_DMESG_TIMEOUT,
+            dmesg_command: vec!["dmesg".to_string()],
         })
     }
 
+    /// How many of the most recent dmesg lines to capture when the mount fails
+    pub fn with_dmesg_lines(mut self, dmesg_lines: usize) -> Self {
+        self.dmesg_lines = dmesg_lines;
+        self
+    }
+
+    /// How long to wait for dmesg when the mount fails before giving up on its output, so a
+    /// stuck dmesg can't turn a failed mount into a hang
+    pub fn with_dmesg_timeout(mut self, dmesg_timeout: Duration) -> Self {
+        self.dmesg_timeout = dmesg_timeout;
+        self
+    }
+
     /// Mount the overlay filesystem
     pub fn mount(&self) -> Result<(), ManagerError> {
-        let lowerdir = self
-            .config
-            .lower_dirs
-            .iter()
-            .map(|lower| lower.mount_path().display().to_string())
-            .collect::<Vec<_>>()
-            .join(":");
-
-        let mount_options = format!(
-            "lowerdir={},upperdir={},workdir={}",
-            lowerdir,
-            self.config.upper_dir.upper_path().display(),
-            self.config.upper_dir.work_path().display()
+        let _span = span!(
+            "mount",
+            merged = ?self.config.upper_dir.merged_path(),
+            lower_count = self.config.lower_dirs.len()
         );
 
+        if self.config.clean_workdir_on_mount {
+            clean_workdir(&self.config.upper_dir.work_path())?;
+        }
+        self.create_whiteouts()?;
+
+        let mount_options = self.config.mount_options();
         match mount(
-            Some("overlay"),
+            Some(self.mount_source()),
             &self.config.upper_dir.merged_path(),
             Some("overlay"),
             MsFlags::empty(),
             Some(mount_options.as_str()),
         ) {
             Ok(_) => {
-                println!("Successfully mounted overlay filesystem");
-                Ok(())
+                info!("Successfully mounted overlay filesystem");
+                self.audit("mount", Some(&mount_options), MsFlags::empty());
+                self.set_propagation()?;
+                self.wait_if_configured()?;
+                self.mount_bind_targets()
             }
-            Err(e) => {
-                // Try to get dmesg output for debugging
-                let debug_logs = match Command::new("dmesg").output() {
-                    Ok(dmesg_output) => {
-                        let output = String::from_utf8_lossy(&dmesg_output.stdout);
-                        let dmesg_lines: Vec<String> = output
-                            .lines()
-                            .rev()
-                            .take(15)
-                            .map(|c| c.to_string())
-                            .collect();
-                        Ok(dmesg_lines)
+            Err(e) => Err(ManagerError::MountError(e, self.dmesg_logs())),
+        }
+    }
+
+    /// The most recent dmesg lines for debugging a failed mount
+    fn dmesg_logs(&self) -> Result<Vec<String>, io::Error> {
+        let (program, args) = self
+            .dmesg_command
+            .split_first()
+            .expect("dmesg command is never empty");
+        let deadline = Instant::now() + self.dmesg_timeout;
+        let output = rsync::output_until(Command::new(program).args(args), Some(deadline), false)?
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("dmesg did not finish within {:?}", self.dmesg_timeout),
+                )
+            })?;
+        Ok(recent_lines(
+            &String::from_utf8_lossy(&output.stdout),
+            self.dmesg_lines,
+        ))
+    }
+
+    /// Flush the filesystem holding the upper dir to disk with `syncfs`, for a known durable
+    /// point such as before reporting success. Mostly useful with `volatile`, which skips the
+    /// syncs overlayfs would otherwise do.
+    pub fn sync_upper(&self) -> io::Result<()> {
+        let upper = fs::File::open(self.config.upper_dir.upper_path())?;
+        syncfs(&upper)?;
+        Ok(())
+    }
+
+    /// The resolved dirs the overlay is mounted from, see `MountConfig::layer_plan`
+    pub fn describe_layers(&self) -> LayerPlan {
+        self.config.layer_plan()
+    }
+
+    /// Whether the lower dirs can still be found, see `MountConfig::check_lower_available`
+    pub fn check_lower_available(&self) -> Result<(), config::ValidationError> {
+        self.config.check_lower_available()
+    }
+
+    /// The source the overlay is mounted with and listed under in the mount table
+    fn mount_source(&self) -> &str {
+        self.config
+            .mount_source_label
+            .as_deref()
+            .unwrap_or("overlay")
+    }
+
+    /// Setup overlay mount with the given configuration, returning how many attempts unmounting
+    /// the overlay took. While it is busy it is retried up to `umount_retries` times before
+    /// failing with `ManagerError::UmountBusy`, see `MountGuard::with_lazy_fallback` for
+    /// detaching it instead.
+    ///
+    /// With `private_namespace` this has to run on a thread inside the namespace, any thread
+    /// started before it was entered is still in the original one where there is nothing to
+    /// unmount. Exiting also tears the namespace and its mounts down, so skipping this there
+    /// leaks nothing.
+    ///
+    /// With `tmpfs_size` the tmpfs under the upper dir is unmounted too once the overlay is, which
+    /// discards everything written to it.
+    pub fn umount(&self) -> Result<usize, ManagerError> {
+        let attempts = self.umount_overlay()?;
+        if let Some(volume) = self.tmpfs_volume()? {
+            umount(volume).map_err(|e| ManagerError::TmpfsUmountError(volume.to_path_buf(), e))?;
+            info!("Unmounted upper dir tmpfs at {volume:?}");
+        }
+        Ok(attempts)
+    }
+
+    /// The bind targets and overlay part of `umount`, leaving the upper dir in place
+    fn umount_overlay(&self) -> Result<usize, ManagerError> {
+        // Keep going on failure so as much as possible is torn down, reporting the first error
+        let mut result = Ok(());
+        for target in self.bind_points().iter().rev() {
+            if let Err(e) = umount(target) {
+                warn!("Failed to unmount bind target {target:?}: {e}");
+                result = result.and(Err(ManagerError::BindUmountError(target.clone(), e)));
+            }
+        }
+        let merged_path = self.config.upper_dir.merged_path();
+        let merged = retry_umount(
+            self.config.umount_retries,
+            Duration::from_millis(self.config.umount_retry_delay_ms),
+            || umount(&merged_path),
+        );
+        if merged.is_ok() {
+            self.audit("umount", None, MsFlags::empty());
+        }
+        result.and(merged)
+    }
+
+    /// Mount and return a guard that unmounts again when dropped, for using the overlay within a
+    /// scope
+    pub fn mount_scoped(self) -> Result<MountGuard, ManagerError> {
+        self.mount()?;
+        Ok(MountGuard {
+            manager: Some(self),
+            lazy_fallback: false,
+        })
+    }
+
+    /// Lazily detach the bind targets and the overlay, they disappear from the namespace straight
+    /// away and are cleaned up once nothing is using them any more
+    pub fn detach(&self) -> Result<(), ManagerError> {
+        for target in self.bind_points().iter().rev() {
+            if let Err(e) = umount2(target, MntFlags::MNT_DETACH) {
+                warn!("Failed to detach bind target {target:?}: {e}");
+            }
+        }
+        umount2(&self.config.upper_dir.merged_path(), MntFlags::MNT_DETACH)
+            .map_err(|errno| ManagerError::UmountError { errno, attempts: 1 })?;
+        if let Some(volume) = self.tmpfs_volume()? {
+            umount2(volume, MntFlags::MNT_DETACH)
+                .map_err(|e| ManagerError::TmpfsUmountError(volume.to_path_buf(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Where `MountConfig::prepare` mounted a tmpfs for the upper dir, if configured to and it
+    /// is still there. A tmpfs anything else mounted at the volume is left alone.
+    fn tmpfs_volume(&self) -> Result<Option<&Path>, ManagerError> {
+        let upper_dir = &self.config.upper_dir;
+        if upper_dir.tmpfs_size().is_none() {
+            return Ok(None);
+        }
+        let mounted = config::upper_tmpfs_mounted_at(upper_dir.volume())
+            .map_err(ManagerError::MountInfoError)?;
+        Ok(mounted.then(|| upper_dir.volume()))
+    }
+
+    /// Change the flags of the mounted overlay in place, eg `MsFlags::MS_RDONLY` to make it read
+    /// only and `MsFlags::empty()` to make it writable again. Bind targets keep their own flags.
+    pub fn remount(&self, new_flags: MsFlags) -> Result<(), ManagerError> {
+        let flags = remount_flags(new_flags);
+        mount(
+            None::<&str>,
+            &self.config.upper_dir.merged_path(),
+            None::<&str>,
+            flags,
+            None::<&str>,
+        )
+        .map_err(|e| ManagerError::RemountError(flags, e))?;
+        info!("Remounted overlay with flags {flags:?}");
+        self.audit("remount", None, flags);
+        Ok(())
+    }
+
+    /// Rebuild the overlay from freshly synced lower dirs: unmount, re-run every sync in
+    /// `sync_manager` and mount again.
+    ///
+    /// The merged dir and bind targets are unavailable from the unmount until the mount, so
+    /// consumers are briefly interrupted and anything holding files open keeps the overlay busy.
+    /// An upper dir tmpfs stays mounted so its content survives the refresh.
+    /// While still mounted nothing is changed if the unmount fails. If a sync fails the overlay
+    /// is mounted again over whatever the targets now hold and `RefreshSyncError` is returned,
+    /// `RefreshMountError` means it is left unmounted.
+    pub fn refresh(&self, sync_manager: &mut SyncManager) -> Result<(), ManagerError> {
+        let _span = span!("refresh", merged = ?self.config.upper_dir.merged_path());
+        refresh_with(
+            || self.umount_overlay().map(drop),
+            || sync_manager.sync_all(),
+            || self.mount(),
+        )
+    }
+
+    /// Space taken on disk by the upper layer, ie how much has been written through the overlay
+    pub fn upper_usage(&self) -> io::Result<u64> {
+        disk_usage(&self.config.upper_dir.upper_path())
+    }
+
+    /// Append a record of a successful `action` on the merged dir to the `audit_file`. The
+    /// operation already happened, so failing to record it is only logged.
+    fn audit(&self, action: &str, options: Option<&str>, flags: MsFlags) {
+        let Some(audit_file) = &self.config.audit_file else {
+            return;
+        };
+        let record = AuditRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            action,
+            merged: &self.config.upper_dir.merged_path(),
+            options,
+            flags: flags.iter_names().map(|(name, _)| name).collect(),
+        };
+        if let Err(e) = append_audit_record(audit_file, &record) {
+            warn!("Failed to write {action} to audit file {audit_file:?}: {e}");
+        }
+    }
+
+    /// Wait until the overlay is listed at the merged dir in this thread's mount table, for up to
+    /// `timeout`. Consumers that look at the merged dir in the meantime can still see the empty
+    /// dir underneath.
+    pub fn wait_until_ready(&self, timeout: Duration) -> Result<(), ManagerError> {
+        // The thread's own view, with `private_namespace` other threads are elsewhere
+        wait_for_overlay(&self.config.upper_dir.merged_path(), timeout, || {
+            fs::read_to_string("/proc/thread-self/mountinfo")
+        })
+    }
+
+    /// What is still mounted according to this thread's mount table, eg to find out what a failed
+    /// `umount` left behind
+    pub fn mount_state(&self) -> Result<MountState, ManagerError> {
+        let mountinfo = fs::read_to_string("/proc/thread-self/mountinfo")
+            .map_err(ManagerError::MountInfoError)?;
+        Ok(MountState {
+            overlay_mounted: lists_overlay(&mountinfo, &self.config.upper_dir.merged_path()),
+            // A bind mount of the overlay is listed with the overlay's type
+            bind_targets_mounted: self
+                .bind_points()
+                .into_iter()
+                .filter(|target| lists_overlay(&mountinfo, target))
+                .collect(),
+        })
+    }
+
+    /// `wait_until_ready` with the configured timeout, unmounting again if it runs out
+    fn wait_if_configured(&self) -> Result<(), ManagerError> {
+        let Some(timeout_ms) = self.config.wait_until_ready_ms else {
+            return Ok(());
+        };
+        let result = self.wait_until_ready(Duration::from_millis(timeout_ms));
+        if result.is_err()
+            && let Err(e) = umount(&self.config.upper_dir.merged_path())
+        {
+            warn!("Failed to unmount overlay during rollback: {e}");
+        }
+        result
+    }
+
+    /// Create a whiteout in the upper dir for every `hide_paths` entry, leaving any already there
+    /// from an earlier mount
+    fn create_whiteouts(&self) -> Result<(), ManagerError> {
+        let upper_path = self.config.upper_dir.upper_path();
+        let lower_paths: Vec<_> = self
+            .config
+            .ordered_lower_dirs()
+            .into_iter()
+            .filter(|lower_dir| !lower_dir.data_only())
+            .map(|lower_dir| lower_dir.mount_path())
+            .collect();
+        for hide_path in &self.config.hide_paths {
+            let whiteout = upper_path.join(hide_path);
+            create_whiteout(&upper_path, hide_path, &lower_paths)
+                .map_err(|e| ManagerError::WhiteoutError(whiteout.clone(), e))?;
+            info!("Hiding {hide_path:?} with whiteout {whiteout:?}");
+        }
+        Ok(())
+    }
+
+    /// Apply the configured propagation to the merged mount, unmounting the overlay again if the
+    /// kernel refuses
+    fn set_propagation(&self) -> Result<(), ManagerError> {
+        let Some(propagation) = self.config.propagation else {
+            return Ok(());
+        };
+        let merged_path = self.config.upper_dir.merged_path();
+        let flags = propagation.flags();
+        if let Err(e) = mount(
+            None::<&str>,
+            &merged_path,
+            None::<&str>,
+            flags,
+            None::<&str>,
+        ) {
+            if let Err(e) = umount(&merged_path) {
+                warn!("Failed to unmount overlay during rollback: {e}");
+            }
+            return Err(ManagerError::PropagationError(flags, e));
+        }
+        info!("Set {propagation:?} propagation on {merged_path:?}");
+        Ok(())
+    }
+
+    /// Where the overlay is bind mounted: the merged dirs after the first, then the bind targets
+    fn bind_points(&self) -> Vec<PathBuf> {
+        let mut points = self.config.upper_dir.extra_merged_paths();
+        points.extend(self.config.bind_targets.iter().cloned());
+        points
+    }
+
+    /// Bind the merged dir to the extra merged dirs and each of the bind targets. If one fails
+    /// everything mounted so far, including the overlay itself, is unmounted again.
+    fn mount_bind_targets(&self) -> Result<(), ManagerError> {
+        let merged_path = self.config.upper_dir.merged_path();
+        let bind_points = self.bind_points();
+        for (i, target) in bind_points.iter().enumerate() {
+            if let Err(e) = mount(
+                Some(&merged_path),
+                target,
+                None::<&str>,
+                MsFlags::MS_BIND,
+                None::<&str>,
+            ) {
+                for bound in bind_points[..i].iter().rev() {
+                    if let Err(e) = umount(bound) {
+                        warn!("Failed to unmount bind target {bound:?} during rollback: {e}");
                     }
-                    Err(e) => Err(e),
-                };
+                }
+                if let Err(e) = umount(&merged_path) {
+                    warn!("Failed to unmount overlay during rollback: {e}");
+                }
+                return Err(ManagerError::BindError(target.clone(), e));
+            }
+            info!("Bind mounted {merged_path:?} at {target:?}");
+        }
+        Ok(())
+    }
+}
+
+/// A mounted overlay that is unmounted when the guard is dropped, errors are logged as there is
+/// no one to return them to.
+pub struct MountGuard {
+    manager: Option<OverlayManager>,
+    lazy_fallback: bool,
+}
+
+impl MountGuard {
+    /// Lazily detach the overlay if the regular unmount fails on drop, eg because something still
+    /// has files open in it
+    pub fn with_lazy_fallback(mut self, lazy_fallback: bool) -> Self {
+        self.lazy_fallback = lazy_fallback;
+        self
+    }
+
+    pub fn manager(&self) -> &OverlayManager {
+        self.manager
+            .as_ref()
+            .expect("manager is only taken on consume")
+    }
+
+    /// Keep the overlay mounted and hand back the manager so it can be unmounted later
+    pub fn into_manager(mut self) -> OverlayManager {
+        self.manager
+            .take()
+            .expect("manager is only taken on consume")
+    }
+
+    /// Keep the overlay mounted beyond the lifetime of the guard
+    pub fn forget(self) {
+        drop(self.into_manager());
+    }
+}
+
+impl Drop for MountGuard {
+    fn drop(&mut self) {
+        let Some(manager) = self.manager.take() else {
+            return;
+        };
+        if let Err(e) = manager.umount() {
+            if !self.lazy_fallback {
+                warn!("Failed to unmount overlay: {e}");
+                return;
+            }
+            warn!("Failed to unmount overlay, detaching lazily: {e}");
+            if let Err(e) = manager.detach() {
+                warn!("Failed to detach overlay: {e}");
+            }
+        }
+    }
+}
+
+/// Create the `0:0` character device overlayfs treats as a deleted file at `relative_path` in
+/// `upper_path`. Missing parent dirs get the mode and owner of the highest precedence lower dir
+/// that has them, as in the merged view the upper copy of a dir hides the lower's attributes.
+/// The upper dir is writable by the workload, so symlinks in it are never followed.
+fn create_whiteout(
+    upper_path: &Path,
+    relative_path: &Path,
+    lower_paths: &[PathBuf],
+) -> io::Result<()> {
+    let mut dir = PathBuf::new();
+    for component in relative_path
+        .parent()
+        .into_iter()
+        .flat_map(Path::components)
+    {
+        dir.push(component);
+        let upper_dir = upper_path.join(&dir);
+        match fs::symlink_metadata(&upper_dir) {
+            Ok(metadata) if metadata.is_dir() => continue,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotADirectory,
+                    format!("{upper_dir:?} is not a directory"),
+                ));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        fs::create_dir(&upper_dir)?;
+        let lower_metadata = lower_paths
+            .iter()
+            .find_map(|lower_path| fs::metadata(lower_path.join(&dir)).ok());
+        if let Some(metadata) = lower_metadata {
+            fs::set_permissions(&upper_dir, metadata.permissions())?;
+            std::os::unix::fs::chown(&upper_dir, Some(metadata.uid()), Some(metadata.gid()))?;
+        }
+    }
+
+    let whiteout = upper_path.join(relative_path);
+    match fs::symlink_metadata(&whiteout) {
+        Ok(metadata) if is_whiteout(&metadata) => return Ok(()),
+        Ok(_) => return Err(io::Error::from(io::ErrorKind::AlreadyExists)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    mknod(&whiteout, SFlag::S_IFCHR, Mode::empty(), makedev(0, 0)).map_err(io::Error::from)
+}
+
+fn is_whiteout(metadata: &fs::Metadata) -> bool {
+    metadata.file_type().is_char_device() && metadata.rdev() == 0
+}
+
+/// Poll `read_mountinfo` until it lists an overlay mounted at `merged`
+fn wait_for_overlay(
+    merged: &Path,
+    timeout: Duration,
+    mut read_mountinfo: impl FnMut() -> io::Result<String>,
+) -> Result<(), ManagerError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let mountinfo = read_mountinfo().map_err(ManagerError::MountInfoError)?;
+        if lists_overlay(&mountinfo, merged) {
+            return Ok(());
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(ManagerError::NotReady(merged.to_path_buf(), timeout));
+        }
+        thread::sleep(remaining.min(READY_POLL_INTERVAL));
+    }
+}
+
+/// Whether a `/proc/<pid>/mountinfo` listing has an overlay mounted at `mount_point`
+fn lists_overlay(mountinfo: &str, mount_point: &Path) -> bool {
+    mountinfo
+        .lines()
+        .filter_map(MountEntry::parse)
+        .any(|entry| entry.is_overlay() && entry.mount_point == mount_point)
+}
+
+/// Call `op` until it succeeds, another `retries` times while it fails with an error `retryable`
+/// accepts. The first retry waits `delay` and each one after that `backoff` times as long as the
+/// one before, `on_retry` is told about each failure being retried and the wait before the next
+/// attempt. Returns the last result along with the number of attempts made.
+pub(crate) fn retry<T, E>(
+    on_retry: impl Fn(&E, Duration),
+    retries: usize,
+    mut delay: Duration,
+    backoff: u32,
+    retryable: impl Fn(&E) -> bool,
+    mut op: impl FnMut() -> Result<T, E>,
+) -> (Result<T, E>, usize) {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match op() {
+            Err(e) if attempts <= retries && retryable(&e) => {
+                on_retry(&e, delay);
+                thread::sleep(delay);
+                delay = delay.saturating_mul(backoff);
+            }
+            result => return (result, attempts),
+        }
+    }
+}
+
+/// Call `umount` until it succeeds, another `retries` times while it fails with `EBUSY`, returning
+/// the number of attempts made
+fn retry_umount(
+    retries: usize,
+    delay: Duration,
+    umount: impl FnMut() -> nix::Result<()>,
+) -> Result<usize, ManagerError> {
+    let busy = |e: &Errno| *e == Errno::EBUSY;
+    // Waiting for a busy overlay is expected, eg while a consumer finishes up
+    let on_retry = |_: &Errno, delay| info!("Overlay is busy, retrying unmount in {delay:?}");
+    match retry(on_retry, retries, delay, 1, busy, umount) {
+        (Ok(()), attempts) => Ok(attempts),
+        (Err(Errno::EBUSY), attempts) => Err(ManagerError::UmountBusy { attempts }),
+        (Err(errno), attempts) => Err(ManagerError::UmountError { errno, attempts }),
+    }
+}
+
+/// The steps of `OverlayManager::refresh`, separate so the ordering and rollback can be tested
+/// without mounting
+fn refresh_with(
+    umount: impl FnOnce() -> Result<(), ManagerError>,
+    sync: impl FnOnce() -> Result<(), SyncFailure>,
+    mount: impl FnOnce() -> Result<(), ManagerError>,
+) -> Result<(), ManagerError> {
+    umount()?;
+    let synced = sync();
+    if let Err(failure) = &synced {
+        warn!("Refresh {failure}, mounting again without it");
+    }
+    mount().map_err(|e| ManagerError::RefreshMountError(Box::new(e)))?;
+    synced.map_err(ManagerError::RefreshSyncError)?;
+    info!("Refreshed overlay");
+    Ok(())
+}
+
+/// One line of the `audit_file`, `timestamp` is in seconds since the Unix epoch
+#[derive(Debug, Serialize)]
+struct AuditRecord<'a> {
+    timestamp: u64,
+    action: &'a str,
+    merged: &'a Path,
+    options: Option<&'a str>,
+    flags: Vec<&'static str>,
+}
+
+/// Append `record` as a single JSON line. The file is opened each time so a rotated file is
+/// recreated, and `O_APPEND` with one write per line keeps concurrent writers from interleaving.
+fn append_audit_record(path: &Path, record: &AuditRecord) -> io::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(&line)
+}
+
+fn remount_flags(new_flags: MsFlags) -> MsFlags {
+    MsFlags::MS_REMOUNT | new_flags
+}
+
+/// Whether `enter_private_namespace` already moved into a new namespace
+static IN_PRIVATE_NAMESPACE: Mutex<bool> = Mutex::new(false);
+
+/// Move the calling thread into a new mount namespace and stop mount events propagating back out
+/// of it, otherwise mounts under a shared parent (the default with systemd) still show up in the
+/// original namespace. Only the first call does anything, later ones would nest yet another
+/// namespace under the one holding the overlay.
+///
+/// Threads and processes (eg rsync) started afterwards inherit the namespace, threads started
+/// before stay behind. Call this before spawning any thread that has to reach the overlay, eg
+/// one detaching it on a forced exit.
+pub fn enter_private_namespace() -> Result<(), ManagerError> {
+    let mut entered = IN_PRIVATE_NAMESPACE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if *entered {
+        return Ok(());
+    }
+    unshare(CloneFlags::CLONE_NEWNS).map_err(ManagerError::NamespaceError)?;
+    mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        None::<&str>,
+    )
+    .map_err(ManagerError::NamespaceError)?;
+    *entered = true;
+    info!("Entered a private mount namespace");
+    Ok(())
+}
+
+/// Remove the overlay internal state from `work_path`. This also takes the volatile marker with
+/// it, which validation has already refused or cleared by the time a mount is attempted.
+fn clean_workdir(work_path: &Path) -> Result<(), ManagerError> {
+    for entry in OVERLAY_WORKDIR_ENTRIES {
+        let path = work_path.join(entry);
+        match fs::remove_dir_all(&path) {
+            Ok(_) => info!("Removed leftover overlay work dir state {path:?}"),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(ManagerError::CleanWorkdirError(path, e)),
+        }
+    }
+    Ok(())
+}
+
+/// Allocated size of `path` and everything below it, counted from `st_blocks` so sparse files
+/// are not over counted. Symlinks are not followed and hard linked files only count once.
+fn disk_usage(path: &Path) -> io::Result<u64> {
+    disk_usage_of(fs::symlink_metadata(path)?, path, &mut HashSet::new())
+}
+
+/// `disk_usage` of `path` skipping the inodes in `seen`. Entries removed while walking (eg by a
+/// running sync) are skipped, not errors.
+fn disk_usage_of(
+    metadata: fs::Metadata,
+    path: &Path,
+    seen: &mut HashSet<(u64, u64)>,
+) -> io::Result<u64> {
+    if metadata.nlink() > 1 && !seen.insert((metadata.dev(), metadata.ino())) {
+        return Ok(0);
+    }
+    let mut total = metadata.blocks() * 512;
+    if metadata.is_dir() {
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(total),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry_path = entry?.path();
+            match fs::symlink_metadata(&entry_path) {
+                Ok(metadata) => total += disk_usage_of(metadata, &entry_path, seen)?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(total)
+}
+
+/// The last `count` lines of `output`, most recent first
+fn recent_lines(output: &str, count: usize) -> Vec<String> {
+    output
+        .lines()
+        .rev()
+        .take(count)
+        .map(|c| c.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{ConfigError, IOErrorAtPath, LowerDir, UpperDir};
+    use rsync::{ConfigRef, SyncError};
+    use tempfile::TempDir;
+
+    fn test_manager(config: MountConfig) -> OverlayManager {
+        OverlayManager {
+            config,
+            dmesg_lines: DEFAULT_DMESG_LINES,
+            dmesg_timeout: DEFAULT_DMESG_TIMEOUT,
+            dmesg_command: vec!["dmesg".to_string()],
+        }
+    }
+
+    fn test_mount_config() -> MountConfig {
+        MountConfig::new_for_test(
+            vec![LowerDir::new(PathBuf::from("/lower"), None).unwrap()],
+            UpperDir::new(
+                PathBuf::from("/volume"),
+                PathBuf::from("upper"),
+                PathBuf::from("work"),
+                PathBuf::from("merged"),
+            )
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_mount_error_with_slow_dmesg() {
+        // Nothing under /volume exists so the mount fails, as root or not
+        let mut manager = test_manager(test_mount_config())
+            .with_dmesg_timeout(Duration::from_millis(200))
+            .with_dmesg_lines(2);
+        manager.dmesg_command = vec!["sleep".to_string(), "30".to_string()];
+
+        let start = Instant::now();
+        let result = manager.mount();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(
+            matches!(&result, Err(ManagerError::MountError(_, Err(e))) if e.kind() == io::ErrorKind::TimedOut),
+            "{result:?}"
+        );
+
+        manager.dmesg_command = vec!["printf".to_string(), "a\\nb\\nc\\n".to_string()];
+        assert!(matches!(
+            manager.mount(),
+            Err(ManagerError::MountError(_, Ok(lines))) if lines == ["c", "b"]
+        ));
+    }
+
+    #[test]
+    fn test_sync_upper() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let manager = test_manager(MountConfig::new_for_test(
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+            UpperDir::new(
+                volume.clone(),
+                PathBuf::from("upper"),
+                PathBuf::from("work"),
+                PathBuf::from("merged"),
+            )
+            .unwrap(),
+        ));
+
+        assert_eq!(
+            manager.sync_upper().unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        fs::create_dir(volume.join("upper")).unwrap();
+        fs::write(volume.join("upper/file.txt"), "content").unwrap();
+        manager.sync_upper().unwrap();
+    }
+
+    #[test]
+    fn test_describe_layers_uses_sync_target() {
+        let mut config = test_mount_config();
+        config.lower_dirs = vec![
+            LowerDir::new_with_sync(
+                PathBuf::from("/source"),
+                None,
+                rsync::SyncMode::Constant(PathBuf::from("/synced")),
+            )
+            .unwrap(),
+            LowerDir::new(PathBuf::from("/lower"), None).unwrap(),
+        ];
+
+        assert_eq!(
+            test_manager(config).describe_layers(),
+            LayerPlan {
+                lower: vec![PathBuf::from("/synced"), PathBuf::from("/lower")],
+                data_only: vec![],
+                upper: PathBuf::from("/volume/upper"),
+                work: PathBuf::from("/volume/work"),
+                merged: PathBuf::from("/volume/merged"),
+            }
+        );
+    }
+
+    fn is_mounted(path: &Path) -> bool {
+        let mounts = std::fs::read_to_string("/proc/self/mounts").unwrap();
+        let path = path.to_str().unwrap();
+        mounts
+            .lines()
+            .any(|line| line.split_whitespace().nth(1) == Some(path))
+    }
+
+    fn create_test_manager(volume: &Path, bind_targets: Vec<PathBuf>) -> OverlayManager {
+        let lower_path = volume.join("lower");
+        std::fs::create_dir_all(&lower_path).unwrap();
+        std::fs::write(lower_path.join("file.txt"), "lower content").unwrap();
+
+        let mut config = MountConfig::new_for_test(
+            vec![LowerDir::new(lower_path, None).unwrap()],
+            UpperDir::new(
+                volume.to_path_buf(),
+                PathBuf::from("upper"),
+                PathBuf::from("work"),
+                PathBuf::from("merged"),
+            )
+            .unwrap(),
+        );
+        config.bind_targets = bind_targets;
+
+        let (_, synced_config) = SyncManager::new(config.validate().unwrap()).unwrap();
+        OverlayManager::new(synced_config).unwrap()
+    }
+
+    #[test]
+    fn test_mount_source() {
+        let mut config = test_mount_config();
+        assert_eq!(test_manager(config.clone()).mount_source(), "overlay");
+
+        config.mount_source_label = Some("web-7d4b9-x2x8q".to_string());
+        assert_eq!(test_manager(config).mount_source(), "web-7d4b9-x2x8q");
+    }
+
+    #[test]
+    fn test_mount_source_label_in_mount_table() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path();
+        std::fs::create_dir_all(volume.join("lower")).unwrap();
+        let mut config = MountConfig::new_for_test(
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+            UpperDir::new(
+                volume.to_path_buf(),
+                PathBuf::from("upper"),
+                PathBuf::from("work"),
+                PathBuf::from("merged"),
+            )
+            .unwrap(),
+        );
+        config.mount_source_label = Some("web-7d4b9-x2x8q".to_string());
+        let manager = test_manager(config.validate().unwrap().into());
+
+        manager.mount().unwrap();
+        let merged = volume.join("merged");
+        let mounts = std::fs::read_to_string("/proc/self/mounts").unwrap();
+        let entry = mounts
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>())
+            .find(|fields| fields.get(1) == merged.to_str().as_ref())
+            .unwrap();
+        manager.umount().unwrap();
+
+        assert_eq!(entry[0], "web-7d4b9-x2x8q");
+        assert_eq!(entry[2], "overlay");
+    }
+
+    #[test]
+    fn test_mount_bind_targets() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let bind_dir = TempDir::new().unwrap();
+        let bind_targets = vec![
+            bind_dir.path().join("first"),
+            bind_dir.path().join("second"),
+        ];
+        let manager = create_test_manager(temp_dir.path(), bind_targets.clone());
+
+        manager.mount().unwrap();
+        for target in &bind_targets {
+            assert_eq!(
+                std::fs::read_to_string(target.join("file.txt")).unwrap(),
+                "lower content"
+            );
+        }
+        // Writes through a bind target land in the upper layer
+        std::fs::write(bind_targets[1].join("new.txt"), "new").unwrap();
+        assert!(temp_dir.path().join("upper/new.txt").exists());
+
+        manager.umount().unwrap();
+        for target in &bind_targets {
+            assert!(!is_mounted(target));
+            assert!(!target.join("file.txt").exists());
+        }
+        assert!(!is_mounted(&temp_dir.path().join("merged")));
+    }
+
+    #[test]
+    fn test_mount_multiple_merged_subdirs() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path();
+        std::fs::create_dir_all(volume.join("lower")).unwrap();
+        std::fs::write(volume.join("lower/file.txt"), "lower content").unwrap();
+        let upper_dir = UpperDir::new(
+            volume.to_path_buf(),
+            PathBuf::from("upper"),
+            PathBuf::from("work"),
+            PathBuf::from("merged"),
+        )
+        .unwrap()
+        .with_merged_subdirs(vec!["merged".into(), "app".into(), "sidecar/data".into()])
+        .unwrap();
+        let config = MountConfig::new_for_test(
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+            upper_dir,
+        );
+        let manager = test_manager(config.validate().unwrap().into());
+
+        let merged_points = [
+            volume.join("merged"),
+            volume.join("app"),
+            volume.join("sidecar/data"),
+        ];
+        manager.mount().unwrap();
+        for point in &merged_points {
+            assert!(is_mounted(point), "{point:?}");
+            assert_eq!(
+                std::fs::read_to_string(point.join("file.txt")).unwrap(),
+                "lower content"
+            );
+        }
+        let state = manager.mount_state().unwrap();
+        assert!(state.overlay_mounted);
+        assert_eq!(state.bind_targets_mounted, merged_points[1..]);
+
+        manager.umount().unwrap();
+        for point in &merged_points {
+            assert!(!is_mounted(point), "{point:?}");
+            assert!(!point.join("file.txt").exists());
+        }
+        assert!(manager.mount_state().unwrap().is_clear());
+    }
+
+    #[test]
+    fn test_mount_at_merged_mount() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
 
-                Err(ManagerError::MountError(e, debug_logs))
+        let temp_dir = TempDir::new().unwrap();
+        let mount_point = TempDir::new().unwrap();
+        let lower_path = temp_dir.path().join("lower");
+        std::fs::create_dir_all(&lower_path).unwrap();
+        std::fs::write(lower_path.join("file.txt"), "lower content").unwrap();
+        let config = MountConfig::new_for_test(
+            vec![LowerDir::new(lower_path, None).unwrap()],
+            UpperDir::new(
+                temp_dir.path().to_path_buf(),
+                PathBuf::from("upper"),
+                PathBuf::from("work"),
+                PathBuf::from("merged"),
+            )
+            .unwrap()
+            .with_merged_mount(mount_point.path().to_path_buf()),
+        );
+        let manager = test_manager(config.validate().unwrap().into());
+
+        manager.mount().unwrap();
+        assert!(is_mounted(mount_point.path()));
+        assert!(!temp_dir.path().join("merged").exists());
+        assert_eq!(
+            std::fs::read_to_string(mount_point.path().join("file.txt")).unwrap(),
+            "lower content"
+        );
+        std::fs::write(mount_point.path().join("new.txt"), "new").unwrap();
+        assert!(temp_dir.path().join("upper/new.txt").exists());
+
+        manager.umount().unwrap();
+        assert!(!is_mounted(mount_point.path()));
+    }
+
+    #[test]
+    fn test_mount_state() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let bind_target = temp_dir.path().join("bind");
+        let manager = create_test_manager(temp_dir.path(), vec![bind_target.clone()]);
+        assert!(manager.mount_state().unwrap().is_clear());
+
+        manager.mount().unwrap();
+        assert_eq!(
+            manager.mount_state().unwrap(),
+            MountState {
+                overlay_mounted: true,
+                bind_targets_mounted: vec![bind_target.clone()],
             }
+        );
+
+        // As left by an unmount that only got through the bind targets
+        umount(&bind_target).unwrap();
+        let state = manager.mount_state().unwrap();
+        assert!(state.overlay_mounted);
+        assert!(state.bind_targets_mounted.is_empty());
+
+        // The overlay still comes off, the bind target is reported as failing to
+        assert!(matches!(
+            manager.umount(),
+            Err(ManagerError::BindUmountError(..))
+        ));
+        assert!(manager.mount_state().unwrap().is_clear());
+    }
+
+    #[test]
+    fn test_mount_bind_target_failure_rolls_back() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let bind_dir = TempDir::new().unwrap();
+        let first = bind_dir.path().join("first");
+        let missing = bind_dir.path().join("missing");
+        let manager = create_test_manager(temp_dir.path(), vec![first.clone(), missing.clone()]);
+        // Validation created the target, removing it makes the second bind mount fail
+        std::fs::remove_dir(&missing).unwrap();
+
+        match manager.mount() {
+            Err(ManagerError::BindError(path, _)) => assert_eq!(path, missing),
+            other => panic!("expected bind error, got {other:?}"),
+        }
+        assert!(!is_mounted(&first));
+        assert!(!is_mounted(&temp_dir.path().join("merged")));
+    }
+
+    #[test]
+    fn test_mount_scoped_unmounts_on_drop() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let bind_dir = TempDir::new().unwrap();
+        let bind_target = bind_dir.path().join("bind");
+        let merged = temp_dir.path().join("merged");
+        let manager = create_test_manager(temp_dir.path(), vec![bind_target.clone()]);
+
+        {
+            let _guard = manager.mount_scoped().unwrap();
+            assert!(is_mounted(&merged));
+            assert!(merged.join("file.txt").exists());
+            assert!(is_mounted(&bind_target));
+        }
+        assert!(!is_mounted(&merged));
+        assert!(!is_mounted(&bind_target));
+    }
+
+    #[test]
+    fn test_mount_guard_escape_hatches() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let merged = temp_dir.path().join("merged");
+
+        let manager = create_test_manager(temp_dir.path(), Vec::new())
+            .mount_scoped()
+            .unwrap()
+            .into_manager();
+        assert!(is_mounted(&merged));
+        manager.umount().unwrap();
+
+        let guard = manager.mount_scoped().unwrap();
+        guard.forget();
+        assert!(is_mounted(&merged));
+        umount(&merged).unwrap();
+    }
+
+    #[test]
+    fn test_mount_guard_lazy_fallback() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let merged = temp_dir.path().join("merged");
+        let guard = create_test_manager(temp_dir.path(), Vec::new())
+            .mount_scoped()
+            .unwrap()
+            .with_lazy_fallback(true);
+
+        // A process with its cwd inside keeps the mount busy so the regular unmount fails
+        let mut child = std::process::Command::new("sleep")
+            .arg("30")
+            .current_dir(&merged)
+            .spawn()
+            .unwrap();
+        drop(guard);
+        assert!(!is_mounted(&merged));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    fn refresh_steps(
+        umount: Result<(), ManagerError>,
+        sync: Result<(), SyncFailure>,
+        mount: Result<(), ManagerError>,
+    ) -> (Vec<&'static str>, Result<(), ManagerError>) {
+        let calls = std::cell::RefCell::new(Vec::new());
+        let result = refresh_with(
+            || {
+                calls.borrow_mut().push("umount");
+                umount
+            },
+            || {
+                calls.borrow_mut().push("sync");
+                sync
+            },
+            || {
+                calls.borrow_mut().push("mount");
+                mount
+            },
+        );
+        (calls.into_inner(), result)
+    }
+
+    fn sync_failure() -> SyncFailure {
+        SyncFailure {
+            config_ref: ConfigRef::LowerDir {
+                index: 1,
+                lower_dir: Box::new(LowerDir::new(PathBuf::from("/synced"), None).unwrap()),
+            },
+            error: SyncError::RsyncFailed {
+                code: 23,
+                stderr: String::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_refresh_ordering() {
+        let (calls, result) = refresh_steps(Ok(()), Ok(()), Ok(()));
+        assert_eq!(calls, ["umount", "sync", "mount"]);
+        result.unwrap();
+    }
+
+    #[test]
+    fn test_refresh_umount_failure_leaves_mounted() {
+        let (calls, result) = refresh_steps(
+            Err(ManagerError::UmountError {
+                errno: nix::errno::Errno::EBUSY,
+                attempts: 1,
+            }),
+            Ok(()),
+            Ok(()),
+        );
+        assert_eq!(calls, ["umount"]);
+        assert!(matches!(result, Err(ManagerError::UmountError { .. })));
+    }
+
+    #[test]
+    fn test_refresh_sync_failure_remounts() {
+        let (calls, result) = refresh_steps(Ok(()), Err(sync_failure()), Ok(()));
+        assert_eq!(calls, ["umount", "sync", "mount"]);
+        match result {
+            Err(ManagerError::RefreshSyncError(SyncFailure {
+                config_ref: ConfigRef::LowerDir { index, lower_dir },
+                error: SyncError::RsyncFailed { code: 23, .. },
+            })) => {
+                assert_eq!(index, 1);
+                assert_eq!(lower_dir.full_path(), PathBuf::from("/synced"));
+            }
+            other => panic!("expected a sync failure, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_refresh_mount_failure() {
+        let mount_error = || ManagerError::MountError(nix::errno::Errno::EINVAL, Ok(vec![]));
+        for sync in [Ok(()), Err(sync_failure())] {
+            let (calls, result) = refresh_steps(Ok(()), sync, Err(mount_error()));
+            assert_eq!(calls, ["umount", "sync", "mount"]);
+            assert!(matches!(
+                result,
+                Err(ManagerError::RefreshMountError(e)) if matches!(*e, ManagerError::MountError(..))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_refresh_remounts_overlay() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path();
+        let bind_target = volume.join("bind");
+        let manager = create_test_manager(volume, vec![bind_target.clone()]);
+        let (mut sync_manager, _) =
+            SyncManager::new(manager.config.clone().validate().unwrap()).unwrap();
+
+        manager.mount().unwrap();
+        manager.refresh(&mut sync_manager).unwrap();
+        let merged = manager.config.upper_dir.merged_path();
+        assert!(is_mounted(&merged));
+        assert!(is_mounted(&bind_target));
+        assert_eq!(
+            std::fs::read_to_string(merged.join("file.txt")).unwrap(),
+            "lower content"
+        );
+        manager.umount().unwrap();
+    }
+
+    fn mount_type(path: &Path) -> Option<String> {
+        let mounts = std::fs::read_to_string("/proc/self/mounts").unwrap();
+        let path = path.to_str().unwrap();
+        // The last mount listed at a path is the one on top
+        mounts.lines().rev().find_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            (fields.next() == Some(path)).then(|| fields.next().unwrap().to_string())
+        })
+    }
+
+    #[test]
+    fn test_upper_tmpfs_mount_and_umount_ordering() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        // The lower dir can't live on the volume, the tmpfs would hide it
+        let lower_path = temp_dir.path().join("lower");
+        std::fs::create_dir_all(&lower_path).unwrap();
+        std::fs::write(lower_path.join("file.txt"), "lower content").unwrap();
+        let volume = temp_dir.path().join("volume");
+        let config = MountConfig::new_for_test(
+            vec![LowerDir::new(lower_path, None).unwrap()],
+            UpperDir::new(
+                volume.clone(),
+                PathBuf::from("upper"),
+                PathBuf::from("work"),
+                PathBuf::from("merged"),
+            )
+            .unwrap()
+            .with_tmpfs_size("16m".to_string()),
+        );
+
+        // The tmpfs goes on before the dirs are created so they end up on it
+        let (mut sync_manager, synced_config) =
+            SyncManager::new(config.validate().unwrap()).unwrap();
+        assert_eq!(mount_type(&volume).as_deref(), Some("tmpfs"));
+        let manager = OverlayManager::new(synced_config).unwrap();
+        let merged = manager.config.upper_dir.merged_path();
+        assert!(merged.is_dir());
+
+        manager.mount().unwrap();
+        assert_eq!(mount_type(&merged).as_deref(), Some("overlay"));
+        std::fs::write(merged.join("scratch.txt"), "scratch").unwrap();
+
+        // A refresh keeps the tmpfs and so what was written to it
+        manager.refresh(&mut sync_manager).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(merged.join("scratch.txt")).unwrap(),
+            "scratch"
+        );
+
+        // The overlay comes off before the tmpfs it sits on, which takes the scratch space with it
+        manager.umount().unwrap();
+        assert!(!is_mounted(&merged));
+        assert!(!is_mounted(&volume));
+        assert!(!volume.join("upper/scratch.txt").exists());
+    }
+
+    #[test]
+    fn test_upper_tmpfs_reused_and_released() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let lower_path = temp_dir.path().join("lower");
+        std::fs::create_dir_all(&lower_path).unwrap();
+        let volume = temp_dir.path().join("volume");
+        let upper_dir = UpperDir::new(
+            volume.clone(),
+            PathBuf::from("upper"),
+            PathBuf::from("work"),
+            PathBuf::from("merged"),
+        )
+        .unwrap()
+        .with_tmpfs_size("16m".to_string());
+        let mut config = MountConfig::new_for_test(
+            vec![LowerDir::new(lower_path, None).unwrap()],
+            upper_dir.clone(),
+        );
+        let tmpfs_mounts = || {
+            let volume = std::fs::canonicalize(&volume).unwrap();
+            mountinfo::read_mountinfo()
+                .unwrap()
+                .into_iter()
+                .filter(|entry| entry.mount_point == volume)
+                .count()
+        };
+
+        config.clone().validate().unwrap();
+        std::fs::write(volume.join("upper/kept.txt"), "kept").unwrap();
+        // A restart after a crash uses the tmpfs still there rather than stacking another
+        config.clone().validate().unwrap();
+        assert_eq!(tmpfs_mounts(), 1);
+        assert!(volume.join("upper/kept.txt").exists());
+
+        assert!(upper_dir.release_tmpfs().unwrap());
+        assert_eq!(tmpfs_mounts(), 0);
+        assert!(!upper_dir.release_tmpfs().unwrap());
+
+        // A tmpfs mounted at the volume by something else is mounted over and left alone
+        mount(
+            Some("tmpfs"),
+            &volume,
+            Some("tmpfs"),
+            MsFlags::empty(),
+            None::<&str>,
+        )
+        .unwrap();
+        assert!(!upper_dir.release_tmpfs().unwrap());
+        config.clone().validate().unwrap();
+        assert_eq!(tmpfs_mounts(), 2);
+        assert!(upper_dir.release_tmpfs().unwrap());
+        assert!(!upper_dir.release_tmpfs().unwrap());
+        assert_eq!(tmpfs_mounts(), 1);
+        umount(&volume).unwrap();
+
+        // A bind target that can't be created fails prepare once the tmpfs is on
+        let blocker = temp_dir.path().join("blocker");
+        std::fs::write(&blocker, "not a dir").unwrap();
+        config.bind_targets = vec![blocker.join("target")];
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::CreateDirError(IOErrorAtPath(path, _))) if path == blocker.join("target")
+        ));
+        assert_eq!(tmpfs_mounts(), 0);
+    }
+
+    fn audit_lines(path: &Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_append_audit_record_recreates_rotated_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let audit_file = temp_dir.path().join("audit.log");
+        let record = AuditRecord {
+            timestamp: 1,
+            action: "remount",
+            merged: Path::new("/volume/merged"),
+            options: None,
+            flags: vec!["MS_RDONLY", "MS_REMOUNT"],
+        };
+
+        append_audit_record(&audit_file, &record).unwrap();
+        append_audit_record(&audit_file, &record).unwrap();
+        assert_eq!(audit_lines(&audit_file).len(), 2);
+
+        std::fs::rename(&audit_file, temp_dir.path().join("audit.log.1")).unwrap();
+        append_audit_record(&audit_file, &record).unwrap();
+        assert_eq!(
+            audit_lines(&audit_file),
+            [serde_json::json!({
+                "timestamp": 1,
+                "action": "remount",
+                "merged": "/volume/merged",
+                "options": null,
+                "flags": ["MS_RDONLY", "MS_REMOUNT"],
+            })]
+        );
+    }
+
+    #[test]
+    fn test_mount_writes_audit_file() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
         }
+
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path();
+        let mut manager = create_test_manager(volume, vec![]);
+        let audit_file = volume.join("audit.log");
+        manager.config.audit_file = Some(audit_file.clone());
+
+        manager.mount().unwrap();
+        manager.umount().unwrap();
+
+        let lines = audit_lines(&audit_file);
+        assert_eq!(lines.len(), 2);
+        let merged = manager.config.upper_dir.merged_path();
+        assert_eq!(lines[0]["action"], "mount");
+        assert_eq!(lines[0]["merged"], merged.to_str().unwrap());
+        assert_eq!(lines[0]["options"], manager.config.mount_options().as_str());
+        assert_eq!(lines[0]["flags"], serde_json::json!([]));
+        assert!(lines[0]["timestamp"].as_u64().unwrap() > 0);
+        assert_eq!(lines[1]["action"], "umount");
+        assert_eq!(lines[1]["options"], serde_json::Value::Null);
+    }
+
+    fn stub_umount(results: Vec<nix::Result<()>>) -> (Result<usize, ManagerError>, usize) {
+        let mut results = results.into_iter();
+        let mut calls = 0;
+        let result = retry_umount(3, Duration::ZERO, || {
+            calls += 1;
+            results.next().unwrap()
+        });
+        (result, calls)
+    }
+
+    #[test]
+    fn test_retry_umount() {
+        let (result, calls) = stub_umount(vec![Ok(())]);
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(calls, 1);
+
+        // Succeeds on the third try
+        let (result, calls) = stub_umount(vec![Err(Errno::EBUSY), Err(Errno::EBUSY), Ok(())]);
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(calls, 3);
+
+        // The first attempt plus three retries
+        let (result, calls) = stub_umount(vec![Err(Errno::EBUSY); 4]);
+        assert!(matches!(
+            result,
+            Err(ManagerError::UmountBusy { attempts: 4 })
+        ));
+        assert_eq!(calls, 4);
+
+        // Only a busy overlay is retried
+        let (result, calls) = stub_umount(vec![Err(Errno::EBUSY), Err(Errno::EINVAL)]);
+        assert!(matches!(
+            result,
+            Err(ManagerError::UmountError {
+                errno: Errno::EINVAL,
+                attempts: 2
+            })
+        ));
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn test_retry_umount_sleeps_between_attempts() {
+        let start = std::time::Instant::now();
+        let mut results = vec![Err(Errno::EBUSY), Ok(())].into_iter();
+        let attempts =
+            retry_umount(1, Duration::from_millis(50), || results.next().unwrap()).unwrap();
+        assert_eq!(attempts, 2);
+        assert!(start.elapsed() >= Duration::from_millis(50));
     }
 
-    /// Setup overlay mount with the given configuration
-    pub fn umount(&self) -> Result<(), ManagerError> {
-        umount(&self.config.upper_dir.merged_path()).map_err(ManagerError::UmountError)
+    const MOUNTINFO: &str = "22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+36 22 0:30 / /volume/merged rw,relatime shared:2 - overlay overlay rw,lowerdir=/lower
+37 22 0:31 / /with\\040space rw - overlay overlay rw,lowerdir=/lower
+38 22 0:32 / /volume/tmp rw - tmpfs tmpfs rw
+";
+
+    #[test]
+    fn test_lists_overlay() {
+        assert!(lists_overlay(MOUNTINFO, Path::new("/volume/merged")));
+        assert!(lists_overlay(MOUNTINFO, Path::new("/with space")));
+        assert!(!lists_overlay(MOUNTINFO, Path::new("/volume/tmp")));
+        assert!(!lists_overlay(MOUNTINFO, Path::new("/volume")));
+    }
+
+    #[test]
+    fn test_wait_for_overlay_polls_until_listed() {
+        // The overlay only shows up on the third read
+        let mut reads = 0;
+        let start = Instant::now();
+        wait_for_overlay(Path::new("/volume/merged"), Duration::from_secs(10), || {
+            reads += 1;
+            Ok(if reads < 3 { "" } else { MOUNTINFO }.to_string())
+        })
+        .unwrap();
+        assert_eq!(reads, 3);
+        assert!(start.elapsed() >= 2 * READY_POLL_INTERVAL);
+    }
+
+    #[test]
+    fn test_wait_for_overlay_times_out() {
+        let result = wait_for_overlay(
+            Path::new("/volume/merged"),
+            Duration::from_millis(120),
+            || Ok(String::new()),
+        );
+        assert!(
+            matches!(result, Err(ManagerError::NotReady(path, _)) if path == Path::new("/volume/merged"))
+        );
+
+        let result = wait_for_overlay(Path::new("/volume/merged"), Duration::ZERO, || {
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        });
+        assert!(matches!(result, Err(ManagerError::MountInfoError(_))));
+    }
+
+    #[test]
+    fn test_mount_waits_until_ready() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let mut manager = create_test_manager(temp_dir.path(), vec![]);
+        manager.config.wait_until_ready_ms = Some(1000);
+
+        manager.mount().unwrap();
+        manager.wait_until_ready(Duration::ZERO).unwrap();
+        manager.umount().unwrap();
+        assert!(matches!(
+            manager.wait_until_ready(Duration::ZERO),
+            Err(ManagerError::NotReady(..))
+        ));
+    }
+
+    #[test]
+    fn test_mount_creates_whiteouts() {
+        use std::os::unix::fs::PermissionsExt;
+
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path();
+        let mut manager = create_test_manager(volume, vec![]);
+        let lower_path = volume.join("lower");
+        std::fs::create_dir_all(lower_path.join("defaults/nested")).unwrap();
+        std::fs::write(lower_path.join("defaults/nested/unwanted.conf"), "lower").unwrap();
+        std::fs::write(lower_path.join("defaults/nested/kept.conf"), "lower").unwrap();
+        std::fs::set_permissions(
+            lower_path.join("defaults"),
+            std::fs::Permissions::from_mode(0o750),
+        )
+        .unwrap();
+        manager.config.hide_paths = vec![
+            PathBuf::from("file.txt"),
+            PathBuf::from("defaults/nested/unwanted.conf"),
+        ];
+
+        manager.mount().unwrap();
+        let upper_path = manager.config.upper_dir.upper_path();
+        for hide_path in &manager.config.hide_paths {
+            let metadata = std::fs::symlink_metadata(upper_path.join(hide_path)).unwrap();
+            assert!(is_whiteout(&metadata));
+        }
+        let upper_defaults = std::fs::metadata(upper_path.join("defaults")).unwrap();
+        assert_eq!(upper_defaults.permissions().mode() & 0o7777, 0o750);
+
+        let merged = manager.config.upper_dir.merged_path();
+        assert!(!merged.join("file.txt").exists());
+        assert!(!merged.join("defaults/nested/unwanted.conf").exists());
+        assert!(merged.join("defaults/nested/kept.conf").exists());
+        manager.umount().unwrap();
+
+        // Existing whiteouts are kept on the next mount
+        manager.mount().unwrap();
+        manager.umount().unwrap();
+    }
+
+    #[test]
+    fn test_create_whiteout_refuses_existing_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let upper_path = temp_dir.path();
+        std::fs::write(upper_path.join("file.txt"), "upper").unwrap();
+
+        let e = create_whiteout(upper_path, Path::new("file.txt"), &[]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(
+            std::fs::read_to_string(upper_path.join("file.txt")).unwrap(),
+            "upper"
+        );
+    }
+
+    #[test]
+    fn test_create_whiteout_refuses_symlinked_parent() {
+        let temp_dir = TempDir::new().unwrap();
+        let upper_path = temp_dir.path().join("upper");
+        let outside = temp_dir.path().join("outside");
+        std::fs::create_dir_all(&upper_path).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, upper_path.join("link")).unwrap();
+
+        for hide_path in ["link/secret", "link/nested/secret"] {
+            let e = create_whiteout(&upper_path, Path::new(hide_path), &[]).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::NotADirectory, "{hide_path}");
+        }
+        assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_remount_flags() {
+        assert_eq!(remount_flags(MsFlags::empty()), MsFlags::MS_REMOUNT);
+        assert_eq!(
+            remount_flags(MsFlags::MS_RDONLY | MsFlags::MS_NOATIME),
+            MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY | MsFlags::MS_NOATIME
+        );
+    }
+
+    #[test]
+    fn test_remount_read_only() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let manager = create_test_manager(temp_dir.path(), Vec::new());
+        let merged = temp_dir.path().join("merged");
+
+        manager.mount().unwrap();
+        manager.remount(MsFlags::MS_RDONLY).unwrap();
+        let err = std::fs::write(merged.join("new.txt"), "new").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(nix::errno::Errno::EROFS as i32));
+
+        manager.remount(MsFlags::empty()).unwrap();
+        std::fs::write(merged.join("new.txt"), "new").unwrap();
+        manager.umount().unwrap();
+
+        // Nothing is mounted any more so there is nothing to remount
+        assert!(matches!(
+            manager.remount(MsFlags::MS_RDONLY),
+            Err(ManagerError::RemountError(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_clean_workdir() {
+        let temp_dir = TempDir::new().unwrap();
+        let work = temp_dir.path();
+        fs::create_dir_all(work.join("work/incompat")).unwrap();
+        fs::write(work.join("work/#1b"), "").unwrap();
+        fs::create_dir_all(work.join("index")).unwrap();
+        fs::write(work.join("index/00fb1d"), "").unwrap();
+        fs::write(work.join("unrelated"), "keep").unwrap();
+
+        clean_workdir(work).unwrap();
+        assert!(!work.join("work").exists());
+        assert!(!work.join("index").exists());
+        assert_eq!(fs::read_to_string(work.join("unrelated")).unwrap(), "keep");
+
+        // Already clean is fine
+        clean_workdir(work).unwrap();
+    }
+
+    /// The optional fields of the mountinfo entry for `path`, eg `shared:5`
+    fn propagation_fields(path: &Path) -> Vec<String> {
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").unwrap();
+        let fields = mountinfo
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>())
+            .rfind(|fields| fields.get(4).copied() == path.to_str())
+            .unwrap();
+        fields[6..]
+            .iter()
+            .take_while(|field| **field != "-")
+            .map(|field| field.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_mount_propagation() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let merged = temp_dir.path().join("merged");
+        let mut manager = create_test_manager(temp_dir.path(), vec![]);
+
+        manager.config.propagation = Some(config::Propagation::Shared);
+        manager.mount().unwrap();
+        let shared = propagation_fields(&merged);
+        manager.umount().unwrap();
+        assert!(
+            shared.iter().any(|field| field.starts_with("shared:")),
+            "{shared:?}"
+        );
+
+        manager.config.propagation = Some(config::Propagation::Private);
+        manager.mount().unwrap();
+        let private = propagation_fields(&merged);
+        manager.umount().unwrap();
+        assert!(private.is_empty(), "{private:?}");
+    }
+
+    #[test]
+    fn test_mount_private_namespace() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        // Only this test's thread moves into the new namespace, /proc/self follows the main
+        // thread which stays behind in the original one
+        let is_mounted_in = |mounts: &str, path: &Path| {
+            std::fs::read_to_string(mounts)
+                .unwrap()
+                .lines()
+                .any(|line| line.split_whitespace().nth(1) == path.to_str())
+        };
+
+        let namespace = || std::fs::read_link("/proc/thread-self/ns/mnt").unwrap();
+
+        let temp_dir = TempDir::new().unwrap();
+        let mut manager = create_test_manager(temp_dir.path(), vec![]);
+        manager.config.private_namespace = true;
+        let merged = temp_dir.path().join("merged");
+
+        let original = namespace();
+        enter_private_namespace().unwrap();
+        let private = namespace();
+        assert_ne!(original, private);
+
+        manager.mount().unwrap();
+        assert!(is_mounted_in("/proc/thread-self/mounts", &merged));
+        assert!(!is_mounted_in("/proc/self/mounts", &merged));
+        assert_eq!(
+            std::fs::read_to_string(merged.join("file.txt")).unwrap(),
+            "lower content"
+        );
+
+        // Neither entering again nor remounting (eg a refresh) nests another namespace
+        enter_private_namespace().unwrap();
+        manager.umount().unwrap();
+        assert!(!is_mounted_in("/proc/thread-self/mounts", &merged));
+        manager.mount().unwrap();
+        assert_eq!(namespace(), private);
+        assert!(is_mounted_in("/proc/thread-self/mounts", &merged));
+
+        manager.umount().unwrap();
+        assert!(!is_mounted_in("/proc/thread-self/mounts", &merged));
+    }
+
+    #[test]
+    fn test_mount_cleans_dirty_workdir() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let mut manager = create_test_manager(temp_dir.path(), vec![]);
+        manager.config.clean_workdir_on_mount = true;
+
+        // An earlier mount leaves the kernel's work dir behind, plus an index from a mount
+        // with index=on that this one would otherwise inherit
+        manager.mount().unwrap();
+        manager.umount().unwrap();
+        let work = temp_dir.path().join("work");
+        assert!(work.join("work").exists());
+        fs::create_dir_all(work.join("index")).unwrap();
+        fs::write(work.join("index/00fb1d"), "").unwrap();
+        fs::write(work.join("unrelated"), "").unwrap();
+
+        manager.mount().unwrap();
+        manager.umount().unwrap();
+        assert!(!work.join("index").exists());
+        assert!(work.join("unrelated").exists());
+    }
+
+    #[test]
+    fn test_disk_usage() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let empty = disk_usage(root).unwrap();
+
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a/one"), vec![1u8; 64 * 1024]).unwrap();
+        fs::write(root.join("a/b/two"), vec![2u8; 128 * 1024]).unwrap();
+        std::os::unix::fs::symlink("/", root.join("a/root")).unwrap();
+
+        let expected = ["", "a", "a/b", "a/one", "a/b/two", "a/root"]
+            .iter()
+            .map(|path| fs::symlink_metadata(root.join(path)).unwrap().blocks() * 512)
+            .sum::<u64>();
+        assert_eq!(disk_usage(root).unwrap(), expected);
+        assert!(expected >= empty + 192 * 1024);
+
+        // A sparse file only counts what is allocated
+        let sparse = fs::File::create(root.join("sparse")).unwrap();
+        sparse.set_len(1024 * 1024 * 1024).unwrap();
+        let with_sparse = disk_usage(root).unwrap();
+        assert!(with_sparse < expected + 1024 * 1024);
+
+        // A hard link adds no blocks of its own
+        fs::hard_link(root.join("a/one"), root.join("one-link")).unwrap();
+        assert_eq!(disk_usage(root).unwrap(), with_sparse);
+
+        assert!(disk_usage(&root.join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_recent_lines() {
+        let output = (1..=40)
+            .map(|i| format!("[{i}.000000] line {i}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let lines = recent_lines(&output, DEFAULT_DMESG_LINES);
+        assert_eq!(lines.len(), 15);
+        assert_eq!(lines[0], "[40.000000] line 40");
+        assert_eq!(lines[14], "[26.000000] line 26");
+
+        assert_eq!(recent_lines(&output, 30).len(), 30);
+        // Fewer lines available than requested
+        assert_eq!(recent_lines(&output, 100).len(), 40);
+        assert!(recent_lines(&output, 0).is_empty());
+        assert!(recent_lines("", 15).is_empty());
     }
 }
@@ -0,0 +1,49 @@
+//! Log output that is routed through `tracing` when the `tracing` feature is enabled and falls
+//! back to plain stdout/stderr otherwise, so callers not using tracing don't have to pull it in.
+//!
+//! The macros expand `cfg(feature = "tracing")` in the calling crate, they are only meant for use
+//! inside this package.
+
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+pub use tracing as __tracing;
+
+/// Stand-in for an entered span when tracing is disabled
+#[doc(hidden)]
+pub struct NoSpan;
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        $crate::logging::__tracing::info!($($arg)*);
+        #[cfg(not(feature = "tracing"))]
+        println!($($arg)*);
+    }};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        $crate::logging::__tracing::warn!($($arg)*);
+        #[cfg(not(feature = "tracing"))]
+        eprintln!("Warning: {}", format_args!($($arg)*));
+    }};
+}
+
+/// Enter an info level span with the given name and fields, returning the guard. Without tracing
+/// the fields are not evaluated.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! span {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        let guard = $crate::logging::__tracing::info_span!($($arg)*).entered();
+        #[cfg(not(feature = "tracing"))]
+        let guard = $crate::logging::NoSpan;
+        guard
+    }};
+}
@@ -0,0 +1,327 @@
+//! Parsing of `/proc/<pid>/mountinfo`, see proc_pid_mountinfo(5), and of the layers an overlay
+//! entry was mounted with.
+
+use std::ffi::OsString;
+use std::io::{self, BufRead};
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+
+/// One line of a mountinfo listing. Paths are unescaped, the option strings are kept as listed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountEntry {
+    pub mount_id: u32,
+    pub parent_id: u32,
+    /// `major:minor` of the device
+    pub device: String,
+    /// The directory within the filesystem that forms the root of this mount
+    pub root: PathBuf,
+    pub mount_point: PathBuf,
+    /// Per mount options, eg `rw,relatime`
+    pub mount_options: String,
+    /// Tagged fields such as `shared:5`, there may be none
+    pub optional_fields: Vec<String>,
+    pub fs_type: String,
+    pub source: String,
+    /// Per superblock options, for an overlay these hold its layers
+    pub super_options: String,
+}
+
+/// The layers of a mounted overlay as listed in its super options
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OverlayDirs {
+    /// Lower dirs from the top layer down
+    pub lower: Vec<PathBuf>,
+    /// Data-only lower dirs, listed after `::` or with `datadir+`
+    pub data_only: Vec<PathBuf>,
+    /// `None` for a read-only overlay
+    pub upper: Option<PathBuf>,
+    pub work: Option<PathBuf>,
+}
+
+impl MountEntry {
+    /// Parse a single mountinfo line, `None` if it is malformed
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split(' ');
+        let mount_id = fields.next()?.parse().ok()?;
+        let parent_id = fields.next()?.parse().ok()?;
+        let device = fields.next()?.to_string();
+        let root = unescape(fields.next()?).into();
+        let mount_point = unescape(fields.next()?).into();
+        let mount_options = fields.next()?.to_string();
+        // Variable length, ended by a lone `-`
+        let mut optional_fields = Vec::new();
+        loop {
+            match fields.next()? {
+                "-" => break,
+                field => optional_fields.push(field.to_string()),
+            }
+        }
+        let fs_type = fields.next()?.to_string();
+        let source = unescape(fields.next()?).to_string_lossy().into_owned();
+        let super_options = fields.next()?.to_string();
+
+        Some(Self {
+            mount_id,
+            parent_id,
+            device,
+            root,
+            mount_point,
+            mount_options,
+            optional_fields,
+            fs_type,
+            source,
+            super_options,
+        })
+    }
+
+    pub fn is_overlay(&self) -> bool {
+        self.fs_type == "overlay"
+    }
+
+    /// The layers from the super options, `None` if this isn't an overlay
+    pub fn overlay_dirs(&self) -> Option<OverlayDirs> {
+        if !self.is_overlay() {
+            return None;
+        }
+        let mut dirs = OverlayDirs::default();
+        for option in self.super_options.split(',') {
+            let Some((key, value)) = option.split_once('=') else {
+                continue;
+            };
+            // Commas and the like in the paths are octal escaped, colons inside a lower dir are
+            // escaped with a backslash as when mounting
+            let value = unescape(value);
+            match key {
+                "lowerdir" => {
+                    let value = value.to_string_lossy();
+                    let (lower, data_only) = value.split_once("::").unwrap_or((&value, ""));
+                    dirs.lower.extend(split_lower_dirs(lower));
+                    dirs.data_only.extend(split_lower_dirs(data_only));
+                }
+                // Listed one per option on kernels that support adding layers one at a time
+                "lowerdir+" => dirs.lower.push(value.into()),
+                "datadir+" => dirs.data_only.push(value.into()),
+                "upperdir" => dirs.upper = Some(value.into()),
+                "workdir" => dirs.work = Some(value.into()),
+                _ => {}
+            }
+        }
+        Some(dirs)
+    }
+}
+
+/// The mount table of the calling thread's mount namespace
+pub fn read_mountinfo() -> io::Result<Vec<MountEntry>> {
+    parse_mountinfo(io::BufReader::new(std::fs::File::open(
+        "/proc/thread-self/mountinfo",
+    )?))
+}
+
+/// Every well formed entry of a mountinfo listing, malformed lines are skipped
+pub fn parse_mountinfo(reader: impl BufRead) -> io::Result<Vec<MountEntry>> {
+    let mut entries = Vec::new();
+    for line in reader.lines() {
+        entries.extend(MountEntry::parse(&line?));
+    }
+    Ok(entries)
+}
+
+/// The layers of the overlay mounted at `mount_point` in `entries`, the topmost if several are
+/// stacked there
+pub fn find_overlay_at(entries: &[MountEntry], mount_point: &Path) -> Option<OverlayDirs> {
+    entries
+        .iter()
+        .rev()
+        .find(|entry| entry.is_overlay() && entry.mount_point == mount_point)
+        .and_then(MountEntry::overlay_dirs)
+}
+
+/// The mount visible at `mount_point` in `entries`, ie the topmost if several are stacked there
+pub fn mount_at<'a>(entries: &'a [MountEntry], mount_point: &Path) -> Option<&'a MountEntry> {
+    entries
+        .iter()
+        .rev()
+        .find(|entry| entry.mount_point == mount_point)
+}
+
+/// Split a `:` separated lower dir list, a backslash escapes the character after it
+fn split_lower_dirs(list: &str) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    let mut current = String::new();
+    let mut chars = list.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => current.extend(chars.next()),
+            ':' => dirs.push(PathBuf::from(std::mem::take(&mut current))),
+            c => current.push(c),
+        }
+    }
+    dirs.push(PathBuf::from(current));
+    dirs.retain(|dir| !dir.as_os_str().is_empty());
+    dirs
+}
+
+/// Undo the octal escaping of spaces, tabs, newlines and backslashes in mountinfo paths
+fn unescape(field: &str) -> OsString {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match octal {
+            Some(byte) => {
+                unescaped.push(byte);
+                i += 4;
+            }
+            None => {
+                unescaped.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    OsString::from_vec(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Trimmed from a node running containerd, with overlays added by hand for the odd cases
+    const MOUNTINFO: &str = r"22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw,errors=remount-ro
+23 22 0:21 / /proc rw,nosuid,nodev,noexec,relatime shared:12 - proc proc rw
+24 22 0:22 / /sys rw,nosuid,nodev,noexec,relatime shared:7 master:1 - sysfs sysfs rw
+612 22 0:58 / /run/containerd/io.containerd.runtime.v2.task/k8s.io/3f2a/rootfs rw,relatime shared:302 - overlay overlay rw,lowerdir=/var/lib/containerd/snapshots/41/fs:/var/lib/containerd/snapshots/40/fs,upperdir=/var/lib/containerd/snapshots/42/fs,workdir=/var/lib/containerd/snapshots/42/work
+700 22 0:60 / /data/my\040merged rw,relatime - overlay overlay rw,lowerdir=/data/lower\040one:/data/with\134:colon,upperdir=/data/up\054per,workdir=/data/work,volatile
+701 22 0:61 / /data/readonly ro,relatime - overlay kube-overlay ro,lowerdir=/data/a:/data/b::/data/blobs
+702 22 0:62 / /data/plus rw,relatime - overlay overlay rw,lowerdir+=/data/a,lowerdir+=/data/b,datadir+=/data/blobs,upperdir=/data/up,workdir=/data/work
+703 22 0:63 / /data/tmp rw,nosuid,nodev - tmpfs tmpfs rw,size=65536k
+garbage line
+";
+
+    fn entries() -> Vec<MountEntry> {
+        parse_mountinfo(MOUNTINFO.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let entries = entries();
+        // The garbage line is dropped
+        assert_eq!(entries.len(), 8);
+
+        let sys = &entries[2];
+        assert_eq!(sys.mount_id, 24);
+        assert_eq!(sys.parent_id, 22);
+        assert_eq!(sys.device, "0:22");
+        assert_eq!(sys.root, Path::new("/"));
+        assert_eq!(sys.mount_point, Path::new("/sys"));
+        assert_eq!(sys.mount_options, "rw,nosuid,nodev,noexec,relatime");
+        assert_eq!(sys.optional_fields, ["shared:7", "master:1"]);
+        assert_eq!(sys.fs_type, "sysfs");
+        assert_eq!(sys.source, "sysfs");
+        assert!(!sys.is_overlay());
+        assert_eq!(sys.overlay_dirs(), None);
+
+        let escaped = &entries[4];
+        assert_eq!(escaped.mount_point, Path::new("/data/my merged"));
+        assert!(escaped.optional_fields.is_empty());
+        assert!(escaped.is_overlay());
+        assert_eq!(entries[5].source, "kube-overlay");
+    }
+
+    #[test]
+    fn test_overlay_dirs() {
+        let entries = entries();
+        assert_eq!(
+            entries[3].overlay_dirs().unwrap(),
+            OverlayDirs {
+                lower: vec![
+                    "/var/lib/containerd/snapshots/41/fs".into(),
+                    "/var/lib/containerd/snapshots/40/fs".into(),
+                ],
+                data_only: vec![],
+                upper: Some("/var/lib/containerd/snapshots/42/fs".into()),
+                work: Some("/var/lib/containerd/snapshots/42/work".into()),
+            }
+        );
+
+        // Spaces, commas and escaped colons in the paths, options without a value are skipped
+        assert_eq!(
+            entries[4].overlay_dirs().unwrap(),
+            OverlayDirs {
+                lower: vec!["/data/lower one".into(), "/data/with:colon".into()],
+                data_only: vec![],
+                upper: Some("/data/up,per".into()),
+                work: Some("/data/work".into()),
+            }
+        );
+
+        // Read-only with a data-only layer
+        assert_eq!(
+            entries[5].overlay_dirs().unwrap(),
+            OverlayDirs {
+                lower: vec!["/data/a".into(), "/data/b".into()],
+                data_only: vec!["/data/blobs".into()],
+                upper: None,
+                work: None,
+            }
+        );
+
+        // Layers added one at a time
+        assert_eq!(
+            entries[6].overlay_dirs().unwrap(),
+            OverlayDirs {
+                lower: vec!["/data/a".into(), "/data/b".into()],
+                data_only: vec!["/data/blobs".into()],
+                upper: Some("/data/up".into()),
+                work: Some("/data/work".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_find_overlay_at() {
+        let mut entries = entries();
+        let found = find_overlay_at(&entries, Path::new("/data/my merged")).unwrap();
+        assert_eq!(found.work, Some("/data/work".into()));
+        assert_eq!(find_overlay_at(&entries, Path::new("/data/tmp")), None);
+        assert_eq!(find_overlay_at(&entries, Path::new("/data")), None);
+
+        // An overlay mounted over another at the same point hides it
+        let mut stacked = entries[6].clone();
+        stacked.mount_point = "/data/my merged".into();
+        stacked.super_options = "rw,lowerdir=/data/top,upperdir=/data/up,workdir=/data/w".into();
+        entries.push(stacked);
+        let found = find_overlay_at(&entries, Path::new("/data/my merged")).unwrap();
+        assert_eq!(found.lower, [PathBuf::from("/data/top")]);
+    }
+
+    #[test]
+    fn test_mount_at() {
+        let mut entries = entries();
+        assert_eq!(
+            mount_at(&entries, Path::new("/data/tmp")).unwrap().fs_type,
+            "tmpfs"
+        );
+        assert_eq!(mount_at(&entries, Path::new("/data")), None);
+
+        // Whatever is mounted on top hides the tmpfs
+        let mut stacked = entries[6].clone();
+        stacked.mount_point = "/data/tmp".into();
+        entries.push(stacked);
+        assert!(
+            mount_at(&entries, Path::new("/data/tmp"))
+                .unwrap()
+                .is_overlay()
+        );
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape(r"/a\040b\011c\012d\134e"), "/a b\tc\nd\\e");
+        // Not a valid escape, kept as is
+        assert_eq!(unescape(r"/a\9b\04"), r"/a\9b\04");
+    }
+}
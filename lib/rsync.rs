@@ -1,19 +1,88 @@
-use std::path::PathBuf;
-use std::process::Command;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{BufReader, Read};
+use std::os::unix::process::CommandExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
 
-use serde::Deserialize;
+use nix::errno::Errno;
+use nix::unistd::{User, setgid, setgroups, setuid};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::config::{IOErrorAtPath, LowerDir, MountConfig, ValidatedMountConfig};
-
+use crate::config::{IOErrorAtPath, LowerDir, MountConfig, SeedDir, ValidatedMountConfig};
+use crate::{info, span};
+
+/// Outcome of a resync. A transient error is retried on the next cycle, a fatal one means the
+/// target can't be kept up to date.
+///
+/// The variants and `SyncError` can be built directly, eg to feed a simulated failure through
+/// code that handles the results of `SyncManager::try_sync`:
+///
+/// ```
+/// use overlay_mount::rsync::{SyncError, SyncResult};
+///
+/// fn describe(result: &SyncResult<SyncError>) -> String {
+///     match result {
+///         SyncResult::Ok => "synced".to_string(),
+///         SyncResult::Transient(e) => format!("retrying: {e}"),
+///         SyncResult::Fatal(e) => format!("giving up: {e}"),
+///     }
+/// }
+///
+/// let result = SyncResult::Fatal(SyncError::RsyncFailed {
+///     code: 23,
+///     stderr: "some files could not be transferred".to_string(),
+/// });
+/// assert!(result.is_fatal());
+/// assert_eq!(result.error().and_then(SyncError::exit_code), Some(23));
+/// assert!(describe(&result).starts_with("giving up"));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SyncResult<E> {
     Ok,
     Transient(E),
     Fatal(E),
 }
 
-#[derive(Debug, Clone, Default, Deserialize)]
+impl<E> SyncResult<E> {
+    pub fn is_ok(&self) -> bool {
+        matches!(self, SyncResult::Ok)
+    }
+
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, SyncResult::Fatal(_))
+    }
+
+    /// Whether this is `Ok`, `Transient` or `Fatal`, without the error
+    pub fn kind(&self) -> SyncResult<()> {
+        match self {
+            SyncResult::Ok => SyncResult::Ok,
+            SyncResult::Transient(_) => SyncResult::Transient(()),
+            SyncResult::Fatal(_) => SyncResult::Fatal(()),
+        }
+    }
+
+    /// The error of a transient or fatal result
+    pub fn error(&self) -> Option<&E> {
+        match self {
+            SyncResult::Ok => None,
+            SyncResult::Transient(e) | SyncResult::Fatal(e) => Some(e),
+        }
+    }
+
+    pub fn into_error(self) -> Option<E> {
+        match self {
+            SyncResult::Ok => None,
+            SyncResult::Transient(e) | SyncResult::Fatal(e) => Some(e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum SyncMode {
     #[default]
@@ -22,15 +91,203 @@ pub enum SyncMode {
     Constant(PathBuf),
 }
 
+/// Per lower dir tuning of the rsync invocation, flattened into the lower dir config.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SyncOptions {
+    /// Preserve raw UIDs/GIDs instead of mapping them by user/group name. Needed when the source
+    /// and target live in different user namespaces.
+    pub numeric_ids: bool,
+    /// Compress file data in transit (`-z`). Only worth it over slow links, local syncs just
+    /// burn CPU.
+    pub compress: bool,
+    /// Compression level passed as `--compress-level`, ignored unless `compress` is set.
+    pub compress_level: Option<u32>,
+    /// rsync filter rules merged in with `--filter='merge <file>'`, checked to exist during
+    /// validation so a missing file can't silently sync everything.
+    pub filter_file: Option<PathBuf>,
+    /// Replaces the default `-av`, eg `["-rtlpD", "--inplace"]`. `--delete` (or
+    /// `--ignore-existing` when seeding) and the other options are still added after these, and
+    /// only flags are accepted as the source and target are always appended. Values go in the
+    /// same arg as their flag (`--exclude=foo`, `-essh`), a separate `foo` reads as a path.
+    pub rsync_base_args: Option<Vec<String>>,
+    /// Re-run the sync as a dry run once it finishes and fail with `SyncError::IncompleteSync` if
+    /// it would still change anything, eg after an earlier sync was interrupted.
+    pub verify_after_sync: bool,
+    /// Sync with `-rlptgo` instead of `-a` so device and special files such as FIFOs are
+    /// skipped, for targets that can't create them. Can't be combined with `rsync_base_args`,
+    /// which replace those flags.
+    pub skip_specials: bool,
+    /// Passed as `--chmod`, eg `"D0755,F0644"`, so copies get uniform permissions whatever the
+    /// source has.
+    pub chmod: Option<String>,
+    /// Recreate hardlinks between source files at the target (`-H`) instead of copying each
+    /// link separately.
+    pub preserve_hardlinks: bool,
+    /// Hardlink files unchanged from this earlier copy into the target instead of copying them
+    /// (`--link-dest`), it has to be on the same filesystem as the target. A relative path is
+    /// taken by rsync as relative to the target.
+    pub link_dest: Option<PathBuf>,
+    /// Remote shell rsync connects to a remote source with (`-e`), eg
+    /// `"ssh -i /keys/id -p 2222 -o StrictHostKeyChecking=yes"`. Only valid with a remote source.
+    pub rsh: Option<String>,
+    /// Added after every other option and before the source and target, for flags there is no
+    /// option for such as `--protocol=30` or `--no-inc-recursive` with an old remote rsync. Only
+    /// flags are accepted, so values go in the same arg (`--timeout=30`).
+    pub rsync_extra_args: Vec<String>,
+    /// Pass `--info=progress2` and log rsync's overall progress as it syncs (at most every 10s
+    /// while the line is rewritten in place), for visibility into long initial syncs. Dry runs such as `verify_after_sync` don't report it.
+    pub progress: bool,
+    /// Run rsync as this user, with its primary group and no supplementary groups, so synced
+    /// files are owned by it and the copy doesn't run as root. The user needs write access to
+    /// the target, and rsync can only preserve ownership when running as root.
+    pub sync_as_user: Option<String>,
+}
+
 #[derive(Error, Debug)]
 pub enum SyncError {
     #[error("rsync command failed with exit code {code}: {stderr}")]
     RsyncFailed { code: i32, stderr: String },
+    /// Killed before it could exit, eg SIGKILL from the OOM killer
+    #[error("rsync command was killed by signal {signal}: {stderr}")]
+    RsyncKilled { signal: i32, stderr: String },
     #[error("failed to execute rsync command: {0}")]
     CommandError(#[from] std::io::Error),
 
     #[error("failed to create directory: {0}")]
     DirCreateError(#[from] IOErrorAtPath),
+
+    #[error("failed to check whether the upper dir is empty: {0}")]
+    UpperReadError(IOErrorAtPath),
+
+    #[error("failed to check or record that the upper dir was seeded: {0}")]
+    SeedMarkerError(IOErrorAtPath),
+
+    #[error("startup timeout exceeded before the sync finished")]
+    StartupTimeout,
+
+    #[error("target still differs from the source after syncing, {} change(s) remain", .0.len())]
+    IncompleteSync(Vec<ChangedPath>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Created,
+    Updated,
+    Deleted,
+    /// Only metadata such as permissions or timestamps differ
+    Attributes,
+}
+
+impl fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            ChangeKind::Created => "created",
+            ChangeKind::Updated => "updated",
+            ChangeKind::Deleted => "deleted",
+            ChangeKind::Attributes => "attributes",
+        };
+        f.write_str(kind)
+    }
+}
+
+/// A single change rsync would make to the sync target, relative to the target root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangedPath {
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+}
+
+impl ChangedPath {
+    /// Parse the output of `rsync --itemize-changes`, skipping any lines that aren't itemized
+    /// changes (eg the file list and transfer summary printed by `-v`).
+    pub fn parse_itemized(output: &str) -> Vec<ChangedPath> {
+        output.lines().filter_map(Self::parse_line).collect()
+    }
+
+    fn parse_line(line: &str) -> Option<ChangedPath> {
+        if let Some(path) = line.strip_prefix("*deleting") {
+            return Some(ChangedPath {
+                path: PathBuf::from(path.trim_start()),
+                kind: ChangeKind::Deleted,
+            });
+        }
+
+        // YXcstpoguax: update type, file type then one flag per attribute
+        let (item, path) = line.split_once(' ')?;
+        let mut chars = item.chars();
+        let update_type = chars.next()?;
+        let file_type = chars.next()?;
+        if item.len() != 11 || !"<>ch.".contains(update_type) || !"fdLDS".contains(file_type) {
+            return None;
+        }
+
+        let kind = if item[2..].starts_with('+') {
+            ChangeKind::Created
+        } else if update_type == '.' {
+            ChangeKind::Attributes
+        } else {
+            ChangeKind::Updated
+        };
+        Some(ChangedPath {
+            path: PathBuf::from(path),
+            kind,
+        })
+    }
+}
+
+impl fmt::Display for ChangedPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<10} {}", self.kind, self.path.display())
+    }
+}
+
+/// rsync exit codes caused by the invocation itself (1: syntax or usage error, 2: protocol
+/// incompatibility). Retrying won't help so they are fatal straight away.
+const PERMANENT_RSYNC_EXIT_CODES: [i32; 2] = [1, 2];
+
+impl SyncError {
+    /// The rsync exit code, for failures where rsync ran to completion
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            SyncError::RsyncFailed { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// The signal that killed rsync, for failures where it didn't get to exit
+    pub fn signal(&self) -> Option<i32> {
+        match self {
+            SyncError::RsyncKilled { signal, .. } => Some(*signal),
+            _ => None,
+        }
+    }
+
+    /// The error for an rsync that exited unsuccessfully with `status`
+    fn from_status(status: ExitStatus, stderr: &[u8]) -> Self {
+        let stderr = String::from_utf8_lossy(stderr).to_string();
+        match (status.code(), status.signal()) {
+            (None, Some(signal)) => SyncError::RsyncKilled { signal, stderr },
+            (code, _) => SyncError::RsyncFailed {
+                code: code.unwrap_or(-1),
+                stderr,
+            },
+        }
+    }
+
+    /// Whether retrying the sync can't succeed without a config change
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            SyncError::RsyncFailed { code, .. } => PERMANENT_RSYNC_EXIT_CODES.contains(code),
+            SyncError::RsyncKilled { .. }
+            | SyncError::CommandError(_)
+            | SyncError::DirCreateError(_)
+            | SyncError::UpperReadError(_)
+            | SyncError::SeedMarkerError(_)
+            | SyncError::StartupTimeout
+            | SyncError::IncompleteSync(_) => false,
+        }
+    }
 }
 
 pub struct SyncedConfig(MountConfig);
@@ -40,102 +297,634 @@ impl From<SyncedConfig> for MountConfig {
     }
 }
 
+impl<'a> From<&'a SyncedConfig> for &'a MountConfig {
+    fn from(config: &'a SyncedConfig) -> Self {
+        &config.0
+    }
+}
+
+/// The config entry a sync was run for, identified the way it was written rather than
+/// by the resolved path
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigRef {
+    /// `lower_dirs[index]`
+    LowerDir {
+        index: usize,
+        lower_dir: Box<LowerDir>,
+    },
+    /// `seed_dirs[index]`
+    SeedDir { index: usize, seed: Box<SeedDir> },
+}
+
+impl fmt::Display for ConfigRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigRef::LowerDir { index, lower_dir } => {
+                write!(f, "lower_dirs[{index}] (volume {:?}", lower_dir.volume())?;
+                if let Some(subdir) = lower_dir.subdir() {
+                    write!(f, ", subdir {subdir:?}")?;
+                }
+                match lower_dir.sync_mode() {
+                    SyncMode::None => {}
+                    SyncMode::Once(target) => write!(f, ", synced once to {target:?}")?,
+                    SyncMode::Constant(target) => write!(f, ", synced constantly to {target:?}")?,
+                }
+                write!(f, ")")
+            }
+            ConfigRef::SeedDir { index, seed } => {
+                write!(f, "seed_dirs[{index}] (source {:?})", seed.source())
+            }
+        }
+    }
+}
+
+/// A sync that failed, returned by `SyncManager::new` and `SyncManager::sync_all`
+#[derive(Error, Debug)]
+#[error("failed to sync {config_ref}: {error}")]
+pub struct SyncFailure {
+    pub config_ref: ConfigRef,
+    #[source]
+    pub error: SyncError,
+}
+
 pub struct SyncManager {
     targets: Vec<DirSyncer>,
 }
 
 impl SyncManager {
-    pub fn new(config: ValidatedMountConfig) -> Result<(Self, SyncedConfig), (PathBuf, SyncError)> {
+    pub fn new(config: ValidatedMountConfig) -> Result<(Self, SyncedConfig), SyncFailure> {
+        Self::new_with_startup_timeout(config, None)
+    }
+
+    /// Like `new` but gives up once the initial syncs have taken longer than `startup_timeout`
+    /// in total, killing the rsync in progress and returning `SyncError::StartupTimeout` for its
+    /// lower dir or seed source.
+    pub fn new_with_startup_timeout(
+        config: ValidatedMountConfig,
+        startup_timeout: Option<Duration>,
+    ) -> Result<(Self, SyncedConfig), SyncFailure> {
+        let deadline = startup_timeout.map(|timeout| Instant::now() + timeout);
+        let mount_config: &MountConfig = (&config).into();
+
         let mut targets = Vec::new();
-        for dir in &Into::<&MountConfig>::into(&config).lower_dirs {
+        for (index, dir) in mount_config.lower_dirs.iter().enumerate() {
             if let SyncMode::None = dir.sync_mode() {
                 continue;
             }
-            let dir_sync = DirSyncer::new(dir).map_err(|e| (dir.full_path(), e))?;
+            let dir_sync = DirSyncer::new(index, dir, deadline).map_err(|error| SyncFailure {
+                config_ref: ConfigRef::LowerDir {
+                    index,
+                    lower_dir: Box::new(dir.clone()),
+                },
+                error,
+            })?;
             targets.push(dir_sync);
         }
 
+        let upper_path = mount_config.upper_dir.upper_path();
+        let seed_ref = |index: usize| ConfigRef::SeedDir {
+            index,
+            seed: Box::new(mount_config.seed_dirs[index].clone()),
+        };
+        // Failures with the marker are put down to the first seed it is kept for
+        let once_index = mount_config.seed_dirs.iter().position(SeedDir::only_once);
+        let marker = mount_config.upper_dir.seed_marker_path();
+        let marker_failure = |index: usize, e| SyncFailure {
+            config_ref: seed_ref(index),
+            error: SyncError::SeedMarkerError(IOErrorAtPath(marker.clone(), e)),
+        };
+        // Checked before any seeding so every `only_once` seed applies to a fresh upper dir, one
+        // with content is left alone whether or not it was seeded by an earlier run
+        let already_seeded = match once_index {
+            Some(index) => {
+                marker.try_exists().map_err(|e| marker_failure(index, e))?
+                    || !is_empty_dir(&upper_path).map_err(|e| SyncFailure {
+                        config_ref: seed_ref(index),
+                        error: SyncError::UpperReadError(IOErrorAtPath(upper_path.clone(), e)),
+                    })?
+            }
+            None => false,
+        };
+        for (index, seed) in mount_config.seed_dirs.iter().enumerate() {
+            if seed.only_once() && already_seeded {
+                info!(
+                    "Not seeding {upper_path:?} from {:?}, it was seeded before or already has content",
+                    seed.source()
+                );
+                continue;
+            }
+            seed_upper(seed, &upper_path, deadline).map_err(|error| SyncFailure {
+                config_ref: seed_ref(index),
+                error,
+            })?;
+        }
+        // Only written once every seed made it
+        if let Some(index) = once_index
+            && !already_seeded
+        {
+            std::fs::write(&marker, b"").map_err(|e| marker_failure(index, e))?;
+        }
+
         Ok((Self { targets }, SyncedConfig(config.into())))
     }
 
+    /// Report the changes a sync would make for every synced lower dir in `config` without
+    /// touching any of the targets.
+    pub fn diff(config: &MountConfig) -> Vec<(PathBuf, Result<Vec<ChangedPath>, SyncError>)> {
+        config
+            .lower_dirs
+            .iter()
+            .filter(|dir| !matches!(dir.sync_mode(), SyncMode::None))
+            .map(|dir| (dir.full_path(), DirSyncer::diff(dir)))
+            .collect()
+    }
+
+    /// How long ago each target last synced successfully, for reporting staleness before a
+    /// target has failed for long enough to be fatal.
+    pub fn last_sync_ages(&self) -> Vec<(PathBuf, Duration)> {
+        self.targets
+            .iter()
+            .map(|target| {
+                (
+                    target.target.full_path(),
+                    target.last_successful_sync.elapsed(),
+                )
+            })
+            .collect()
+    }
+
+    /// Whether any target is synced by `try_sync`, ie has `SyncMode::Constant`
+    pub fn has_constant_targets(&self) -> bool {
+        self.targets
+            .iter()
+            .any(|target| matches!(target.target.sync_mode(), SyncMode::Constant(_)))
+    }
+
     pub fn try_sync(&mut self, max_age: Duration) -> Vec<(PathBuf, SyncResult<SyncError>)> {
+        self.try_sync_with(max_age, || {})
+    }
+
+    /// `try_sync`, calling `on_synced` after each target, eg to show a watchdog the syncs are
+    /// still making progress
+    pub fn try_sync_with(
+        &mut self,
+        max_age: Duration,
+        on_synced: impl FnMut(),
+    ) -> Vec<(PathBuf, SyncResult<SyncError>)> {
+        let _span = span!("resync_cycle", targets = self.targets.len());
+        self.sync_matching(
+            Some(max_age),
+            |mode| matches!(mode, SyncMode::Constant(_)),
+            on_synced,
+        )
+    }
+
+    /// Re-sync the `Once` targets on demand, `try_sync` leaves them alone after the initial sync.
+    /// Their last success is the startup sync however long ago that was, so only permanent
+    /// errors count as fatal here and the overlay keeps serving the previous content otherwise.
+    pub fn sync_once_targets(&mut self) -> Vec<(PathBuf, SyncResult<SyncError>)> {
+        self.sync_once_targets_with(|| {})
+    }
+
+    /// `sync_once_targets`, calling `on_synced` after each target as with `try_sync_with`
+    pub fn sync_once_targets_with(
+        &mut self,
+        on_synced: impl FnMut(),
+    ) -> Vec<(PathBuf, SyncResult<SyncError>)> {
+        let _span = span!("resync_once", targets = self.targets.len());
+        self.sync_matching(None, |mode| matches!(mode, SyncMode::Once(_)), on_synced)
+    }
+
+    /// Sync every target straight away whatever its mode, stopping at the first one that fails
+    pub fn sync_all(&mut self) -> Result<(), SyncFailure> {
+        let _span = span!("resync_all", targets = self.targets.len());
+        for target in self.targets.iter_mut() {
+            match DirSyncer::sync(&target.target) {
+                Ok(_) => {
+                    target.last_successful_sync = Instant::now();
+                    target.record(&SyncResult::Ok);
+                }
+                Err(error) => {
+                    // Recorded for `flapping_targets`, with no age limit as in `sync_once_targets`
+                    let result = target.classify(error, None);
+                    target.record(&result);
+                    if let Some(error) = result.into_error() {
+                        return Err(SyncFailure {
+                            config_ref: target.config_ref(),
+                            error,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Targets with at least `threshold` failed syncs among their last `SYNC_HISTORY_LEN`
+    /// syncs, eg ones alternating between succeeding and failing that haven't yet failed
+    /// for long enough to be fatal.
+    pub fn flapping_targets(&self, threshold: usize) -> Vec<PathBuf> {
+        self.targets
+            .iter()
+            .filter(|target| target.recent_failures() >= threshold)
+            .map(|target| target.target.full_path())
+            .collect()
+    }
+
+    fn sync_matching(
+        &mut self,
+        max_age: Option<Duration>,
+        selected: impl Fn(&SyncMode) -> bool,
+        mut on_synced: impl FnMut(),
+    ) -> Vec<(PathBuf, SyncResult<SyncError>)> {
         let mut results = Vec::new();
         for target in self.targets.iter_mut() {
-            if let SyncMode::Constant(_) = target.target.sync_mode() {
+            if selected(target.target.sync_mode()) {
                 results.push((target.target.full_path(), target.try_sync(max_age)));
+                on_synced();
             }
         }
         results
     }
 }
 
+/// How many of the most recent sync outcomes each target keeps for `flapping_targets`
+pub const SYNC_HISTORY_LEN: usize = 10;
+
 struct DirSyncer {
+    /// Position of `target` in `lower_dirs`
+    index: usize,
     target: LowerDir,
     last_successful_sync: Instant,
+    /// The kind of each recent sync result, oldest first
+    history: VecDeque<SyncResult<()>>,
 }
 
 impl DirSyncer {
-    pub fn new(target: &LowerDir) -> Result<Self, SyncError> {
-        Self::sync(target)?;
+    pub fn new(
+        index: usize,
+        target: &LowerDir,
+        deadline: Option<Instant>,
+    ) -> Result<Self, SyncError> {
+        Self::sync_until(target, deadline)?;
         Ok(Self {
+            index,
             target: target.clone(),
             last_successful_sync: Instant::now(),
+            history: VecDeque::with_capacity(SYNC_HISTORY_LEN),
         })
     }
 
-    pub fn try_sync(&mut self, max_age: Duration) -> SyncResult<SyncError> {
-        match Self::sync(&self.target) {
+    fn config_ref(&self) -> ConfigRef {
+        ConfigRef::LowerDir {
+            index: self.index,
+            lower_dir: Box::new(self.target.clone()),
+        }
+    }
+
+    /// Sync `target`, a failure is fatal if it is permanent or the last success is older than
+    /// `max_age`
+    pub fn try_sync(&mut self, max_age: Option<Duration>) -> SyncResult<SyncError> {
+        let result = match Self::sync(&self.target) {
             Ok(_) => {
                 self.last_successful_sync = Instant::now();
                 SyncResult::Ok
             }
-            Err(e) => {
-                if self.last_successful_sync.elapsed() <= max_age {
-                    SyncResult::Transient(e)
-                } else {
-                    SyncResult::Fatal(e)
-                }
-            }
+            Err(e) => self.classify(e, max_age),
+        };
+        self.record(&result);
+        result
+    }
+
+    fn record(&mut self, result: &SyncResult<SyncError>) {
+        if self.history.len() == SYNC_HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(result.kind());
+    }
+
+    fn recent_failures(&self) -> usize {
+        self.history.iter().filter(|result| !result.is_ok()).count()
+    }
+
+    fn classify(&self, e: SyncError, max_age: Option<Duration>) -> SyncResult<SyncError> {
+        let too_old = max_age.is_some_and(|max_age| self.last_successful_sync.elapsed() > max_age);
+        if e.is_permanent() || too_old {
+            SyncResult::Fatal(e)
+        } else {
+            SyncResult::Transient(e)
+        }
+    }
+
+    /// The changes the next sync of `target` would make, without modifying it
+    fn diff(target: &LowerDir) -> Result<Vec<ChangedPath>, SyncError> {
+        let output = Self::run(Self::command(target, &["--dry-run", "--itemize-changes"]))?;
+        Ok(ChangedPath::parse_itemized(&String::from_utf8_lossy(
+            &output.stdout,
+        )))
+    }
+
+    /// Build the rsync invocation for `target`, `extra_args` are placed before the source and
+    /// target paths.
+    fn command(target: &LowerDir, extra_args: &[&str]) -> Command {
+        let mut command = rsync_command(target.sync_options(), "--delete");
+        command
+            .args(extra_args)
+            .arg(format!("{}/", target.full_path().display()))
+            .arg(target.mount_path());
+        command
+    }
+
+    fn run(command: Command) -> Result<Output, SyncError> {
+        Self::run_until(command, None, false)
+    }
+
+    /// Run `command`, killing it if it is still going at `deadline`. With `log_progress` each
+    /// line of stdout is logged as it arrives instead of only being returned at the end.
+    fn run_until(
+        mut command: Command,
+        deadline: Option<Instant>,
+        log_progress: bool,
+    ) -> Result<Output, SyncError> {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(SyncError::StartupTimeout);
+        }
+        let output =
+            output_until(&mut command, deadline, log_progress)?.ok_or(SyncError::StartupTimeout)?;
+
+        if output.status.success() {
+            Ok(output)
+        } else {
+            Err(SyncError::from_status(output.status, &output.stderr))
         }
     }
 
     fn sync(target: &LowerDir) -> Result<(), SyncError> {
-        let source = target.full_path();
-        let target = target.mount_path();
+        Self::sync_until(target, None)
+    }
+
+    fn sync_until(target: &LowerDir, deadline: Option<Instant>) -> Result<(), SyncError> {
+        let mount_path = target.mount_path();
+        let _span = span!("sync", source = ?target.full_path(), target = ?mount_path);
+        info!("Syncing {:?} to {mount_path:?}", target.full_path());
 
         // Create target directory if it doesn't exist
-        if let Some(parent) = target.parent() {
+        if let Some(parent) = mount_path.parent() {
             std::fs::create_dir_all(parent).map_err(|e| IOErrorAtPath(parent.to_path_buf(), e))?;
         }
 
-        let output = Command::new("rsync")
-            .arg("-av")
-            .arg("--delete")
-            .arg(format!("{}/", source.display()))
-            .arg(target)
-            .output()?;
+        let progress = target.sync_options().progress;
+        let extra_args: &[&str] = if progress { &["--info=progress2"] } else { &[] };
+        Self::run_until(Self::command(target, extra_args), deadline, progress)?;
+        if target.sync_options().verify_after_sync {
+            Self::verify(target)?;
+        }
+        Ok(())
+    }
 
-        if output.status.success() {
+    /// Check a dry run of the sync has nothing left to do
+    fn verify(target: &LowerDir) -> Result<(), SyncError> {
+        let remaining = Self::diff(target)?;
+        if remaining.is_empty() {
             Ok(())
         } else {
-            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-            Err(SyncError::RsyncFailed {
-                code: output.status.code().unwrap_or(-1),
-                stderr,
-            })
+            Err(SyncError::IncompleteSync(remaining))
+        }
+    }
+}
+
+/// rsync with the flags shared by every transfer, `mode` picks how existing files at the
+/// destination are treated
+fn rsync_command(options: &SyncOptions, mode: &str) -> Command {
+    let mut command = Command::new("rsync");
+    match &options.rsync_base_args {
+        Some(base_args) => command.args(base_args),
+        None if options.skip_specials => command.arg("-rlptgov"),
+        None => command.arg("-av"),
+    };
+    command.arg(mode);
+    if options.numeric_ids {
+        command.arg("--numeric-ids");
+    }
+    if options.compress {
+        command.arg("-z");
+        if let Some(level) = options.compress_level {
+            command.arg(format!("--compress-level={level}"));
+        }
+    }
+    if let Some(filter_file) = &options.filter_file {
+        command.arg(format!("--filter=merge {}", filter_file.display()));
+    }
+    if let Some(chmod) = &options.chmod {
+        command.arg(format!("--chmod={chmod}"));
+    }
+    if options.preserve_hardlinks {
+        command.arg("-H");
+    }
+    if let Some(link_dest) = &options.link_dest {
+        command.arg(format!("--link-dest={}", link_dest.display()));
+    }
+    if let Some(rsh) = &options.rsh {
+        command.arg("-e").arg(rsh);
+    }
+    command.args(&options.rsync_extra_args);
+    if let Some(user) = &options.sync_as_user {
+        run_as(&mut command, user);
+    }
+    command
+}
+
+/// Switch `command` to `user` between fork and exec. The user is looked up now as that isn't
+/// safe to do after forking, if it doesn't exist spawning fails rather than running as root.
+fn run_as(command: &mut Command, user: &str) {
+    let ids = User::from_name(user)
+        .ok()
+        .flatten()
+        .map(|user| (user.uid, user.gid));
+    // SAFETY: the hook only makes raw syscalls and doesn't allocate, which is all that is allowed
+    // in the forked child
+    unsafe {
+        command.pre_exec(move || {
+            // Only an errno makes it back from the child
+            let (uid, gid) = ids.ok_or(Errno::ENOENT)?;
+            // Groups first, dropping the uid takes away the right to change them
+            setgroups(&[gid])?;
+            setgid(gid)?;
+            setuid(uid)?;
+            Ok(())
+        });
+    }
+}
+
+/// Whether rsync treats `source` as remote, ie `host:path`, `user@host:path`, `host::module` or
+/// an `rsync://` URL. As with rsync a colon only counts before the first slash, so local paths
+/// containing one are still local.
+pub fn is_remote_source(source: &Path) -> bool {
+    source
+        .as_os_str()
+        .as_encoded_bytes()
+        .split(|&b| b == b'/')
+        .next()
+        .is_some_and(|host| host.contains(&b':'))
+}
+
+/// Whether `path` has no entries, a missing dir counts as empty as rsync creates it
+fn is_empty_dir(path: &Path) -> std::io::Result<bool> {
+    match std::fs::read_dir(path) {
+        Ok(mut entries) => Ok(entries.next().is_none()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+/// The rsync invocation copying `seed` into the upper dir, files already there are kept
+fn seed_command(seed: &SeedDir, upper_path: &Path) -> Command {
+    let mut command = rsync_command(seed.sync_options(), "--ignore-existing");
+    if seed.sync_options().progress {
+        command.arg("--info=progress2");
+    }
+    command
+        .arg(format!("{}/", seed.source().display()))
+        .arg(upper_path);
+    command
+}
+
+fn seed_upper(
+    seed: &SeedDir,
+    upper_path: &Path,
+    deadline: Option<Instant>,
+) -> Result<(), SyncError> {
+    let _span = span!("seed", source = ?seed.source(), target = ?upper_path);
+    info!("Seeding {upper_path:?} from {:?}", seed.source());
+    DirSyncer::run_until(
+        seed_command(seed, upper_path),
+        deadline,
+        seed.sync_options().progress,
+    )?;
+    Ok(())
+}
+
+/// Run `command` to completion like `Command::output`, or return `None` once `deadline` passes
+/// with it killed. With `log_progress` each line of stdout is logged as it arrives.
+pub(crate) fn output_until(
+    command: &mut Command,
+    deadline: Option<Instant>,
+    log_progress: bool,
+) -> std::io::Result<Option<Output>> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Drain the pipes in the background so a chatty child can't block on a full pipe
+    let stdout = if log_progress {
+        let log = progress_logger(PROGRESS_LOG_INTERVAL, |line| {
+            info!("rsync progress: {line}");
+        });
+        stream_in_background(child.stdout.take(), log)
+    } else {
+        read_in_background(child.stdout.take())
+    };
+    let stderr = read_in_background(child.stderr.take());
+
+    let status = match deadline {
+        None => child.wait()?,
+        Some(deadline) => loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Ok(None);
+            }
+            thread::sleep(Duration::from_millis(50));
+        },
+    };
+    Ok(Some(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    }))
+}
+
+fn read_in_background(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+/// How often a progress line rewritten in place is logged, rsync updates it several times a
+/// second
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Wrap `log` for `stream_in_background` so lines rewritten in place are only passed on once
+/// per `interval`, every other line is passed on as it comes
+fn progress_logger(
+    interval: Duration,
+    mut log: impl FnMut(&str) + Send + 'static,
+) -> impl FnMut(&str, bool) + Send + 'static {
+    let mut last_update: Option<Instant> = None;
+    move |line, in_place| {
+        if in_place {
+            if last_update.is_some_and(|last| last.elapsed() < interval) {
+                return;
+            }
+            last_update = Some(Instant::now());
         }
+        log(line);
     }
 }
 
+/// As `read_in_background`, also calling `on_line` with each non-empty line as it is read and
+/// whether it ended in `\r`. `--info=progress2` rewrites its line in place with those, so that
+/// ends a line too.
+fn stream_in_background(
+    pipe: Option<impl Read + Send + 'static>,
+    mut on_line: impl FnMut(&str, bool) + Send + 'static,
+) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let Some(pipe) = pipe else {
+            return buf;
+        };
+        let mut line = Vec::new();
+        let mut emit = |line: &mut Vec<u8>, in_place| {
+            let text = String::from_utf8_lossy(line);
+            let text = text.trim();
+            if !text.is_empty() {
+                on_line(text, in_place);
+            }
+            line.clear();
+        };
+        for byte in BufReader::new(pipe).bytes() {
+            let Ok(byte) = byte else {
+                break;
+            };
+            buf.push(byte);
+            if byte == b'\n' || byte == b'\r' {
+                emit(&mut line, byte == b'\r');
+            } else {
+                line.push(byte);
+            }
+        }
+        emit(&mut line, false);
+        buf
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::config::{LowerDir, MountConfig, UpperDir, ValidatedMountConfig};
-    use std::collections::BTreeSet;
     use std::fs;
     use tempfile::TempDir;
 
-    fn create_test_file(dir: &std::path::Path, relative_path: &str, content: &str) -> PathBuf {
+    fn create_test_file(dir: &Path, relative_path: &str, content: &str) -> PathBuf {
         let file_path = dir.join(relative_path);
         if let Some(parent) = file_path.parent() {
             fs::create_dir_all(parent).unwrap();
@@ -144,6 +933,13 @@ mod tests {
         file_path
     }
 
+    fn command_args(lower_dir: &LowerDir) -> Vec<String> {
+        DirSyncer::command(lower_dir, &[])
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
     fn create_test_mount_config(temp_dir: &TempDir) -> ValidatedMountConfig {
         let volume = temp_dir.path().to_path_buf();
 
@@ -156,11 +952,7 @@ mod tests {
         )
         .unwrap();
 
-        let mount_config = MountConfig {
-            lower_dirs: vec![lower_dir],
-            upper_dir,
-            allowed_masked_files: BTreeSet::new(),
-        };
+        let mount_config = MountConfig::new_for_test(vec![lower_dir], upper_dir);
 
         mount_config.validate().unwrap()
     }
@@ -205,11 +997,7 @@ mod tests {
         )
         .unwrap();
 
-        let mount_config = MountConfig {
-            lower_dirs: vec![lower_dir],
-            upper_dir,
-            allowed_masked_files: BTreeSet::new(),
-        };
+        let mount_config = MountConfig::new_for_test(vec![lower_dir], upper_dir);
 
         let validated_config = mount_config.validate().unwrap();
         let (sync_manager, _synced_config) = SyncManager::new(validated_config).unwrap();
@@ -242,11 +1030,7 @@ mod tests {
         )
         .unwrap();
 
-        let mount_config = MountConfig {
-            lower_dirs: vec![lower_dir],
-            upper_dir,
-            allowed_masked_files: BTreeSet::new(),
-        };
+        let mount_config = MountConfig::new_for_test(vec![lower_dir], upper_dir);
 
         let validated_config = mount_config.validate().unwrap();
         let (mut sync_manager, _synced_config) = SyncManager::new(validated_config).unwrap();
@@ -285,11 +1069,7 @@ mod tests {
         )
         .unwrap();
 
-        let mount_config = MountConfig {
-            lower_dirs: vec![lower_dir],
-            upper_dir,
-            allowed_masked_files: BTreeSet::new(),
-        };
+        let mount_config = MountConfig::new_for_test(vec![lower_dir], upper_dir);
 
         let validated_config = mount_config.validate().unwrap();
         let (mut sync_manager, _synced_config) = SyncManager::new(validated_config).unwrap();
@@ -299,6 +1079,65 @@ mod tests {
         assert_eq!(results.len(), 0);
     }
 
+    #[test]
+    fn test_sync_manager_sync_once_targets() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        let source_path = volume.join("source");
+        create_test_file(&source_path, "test.txt", "initial content");
+        let once_target = volume.join("once-target");
+        let constant_source = volume.join("constant-source");
+        fs::create_dir_all(&constant_source).unwrap();
+
+        let upper_dir = UpperDir::new(
+            volume.clone(),
+            PathBuf::from("upper"),
+            PathBuf::from("work"),
+            PathBuf::from("merged"),
+        )
+        .unwrap();
+        let mount_config = MountConfig::new_for_test(
+            vec![
+                LowerDir::new_with_sync(
+                    source_path.clone(),
+                    None,
+                    SyncMode::Once(once_target.clone()),
+                )
+                .unwrap(),
+                LowerDir::new_with_sync(
+                    constant_source,
+                    None,
+                    SyncMode::Constant(volume.join("constant-target")),
+                )
+                .unwrap(),
+            ],
+            upper_dir,
+        );
+
+        let validated_config = mount_config.validate().unwrap();
+        let (mut sync_manager, _synced_config) = SyncManager::new(validated_config).unwrap();
+
+        create_test_file(&source_path, "test.txt", "updated content");
+
+        // The regular cycle only touches the constant target
+        let results = sync_manager.try_sync(Duration::from_secs(60));
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            fs::read_to_string(once_target.join("test.txt")).unwrap(),
+            "initial content"
+        );
+
+        let results = sync_manager.sync_once_targets();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, source_path);
+        assert!(matches!(results[0].1, SyncResult::Ok));
+        assert_eq!(
+            fs::read_to_string(once_target.join("test.txt")).unwrap(),
+            "updated content"
+        );
+    }
+
     #[test]
     fn test_dir_syncer_new_performs_initial_sync() {
         let temp_dir = TempDir::new().unwrap();
@@ -318,7 +1157,7 @@ mod tests {
             LowerDir::new_with_sync(source_path, None, SyncMode::Once(target_path.clone()))
                 .unwrap();
 
-        let _syncer = DirSyncer::new(&lower_dir).unwrap();
+        let _syncer = DirSyncer::new(0, &lower_dir, None).unwrap();
 
         // Verify files were synced
         assert!(target_path.join("test.txt").exists());
@@ -352,12 +1191,12 @@ mod tests {
         )
         .unwrap();
 
-        let mut syncer = DirSyncer::new(&lower_dir).unwrap();
+        let mut syncer = DirSyncer::new(0, &lower_dir, None).unwrap();
 
         // Add a new file to source
         create_test_file(&source_path, "new_file.txt", "new content");
 
-        let result = syncer.try_sync(Duration::from_secs(60));
+        let result = syncer.try_sync(Some(Duration::from_secs(60)));
         assert!(matches!(result, SyncResult::Ok));
 
         // Verify new file was synced
@@ -383,7 +1222,7 @@ mod tests {
         let lower_dir =
             LowerDir::new_with_sync(source_path, None, SyncMode::Constant(target_path)).unwrap();
 
-        let mut syncer = DirSyncer::new(&lower_dir).unwrap();
+        let mut syncer = DirSyncer::new(0, &lower_dir, None).unwrap();
 
         // Create an invalid target to force rsync failure
         let invalid_lower_dir = LowerDir::new_with_sync(
@@ -395,7 +1234,7 @@ mod tests {
 
         syncer.target = invalid_lower_dir;
 
-        let result = syncer.try_sync(Duration::from_secs(60));
+        let result = syncer.try_sync(Some(Duration::from_secs(60)));
         assert!(matches!(result, SyncResult::Transient(_)));
     }
 
@@ -416,7 +1255,7 @@ mod tests {
         let lower_dir =
             LowerDir::new_with_sync(source_path, None, SyncMode::Constant(target_path)).unwrap();
 
-        let mut syncer = DirSyncer::new(&lower_dir).unwrap();
+        let mut syncer = DirSyncer::new(0, &lower_dir, None).unwrap();
 
         // Simulate an old last successful sync
         syncer.last_successful_sync = Instant::now() - Duration::from_secs(120);
@@ -431,22 +1270,1397 @@ mod tests {
 
         syncer.target = invalid_lower_dir;
 
-        let result = syncer.try_sync(Duration::from_secs(60));
+        let result = syncer.try_sync(Some(Duration::from_secs(60)));
         assert!(matches!(result, SyncResult::Fatal(_)));
     }
 
     #[test]
-    fn test_synced_config_conversion() {
-        let temp_dir = TempDir::new().unwrap();
-        let config = create_test_mount_config(&temp_dir);
-        let original_config: &MountConfig = (&config).into();
+    fn test_dir_syncer_command_numeric_ids() {
+        let lower_dir = LowerDir::new_with_sync(
+            PathBuf::from("/source"),
+            None,
+            SyncMode::Once(PathBuf::from("/target")),
+        )
+        .unwrap();
+        assert_eq!(
+            command_args(&lower_dir),
+            ["-av", "--delete", "/source/", "/target"]
+        );
 
-        let synced_config = SyncedConfig(config.clone().into());
-        let converted_config: MountConfig = synced_config.into();
+        let lower_dir = lower_dir.with_sync_options(SyncOptions {
+            numeric_ids: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            command_args(&lower_dir),
+            ["-av", "--delete", "--numeric-ids", "/source/", "/target"]
+        );
+    }
+
+    #[test]
+    fn test_dir_syncer_run_until_deadline() {
+        let start = Instant::now();
+        let mut slow = Command::new("sleep");
+        slow.arg("30");
+        let result = DirSyncer::run_until(slow, Some(start + Duration::from_millis(200)), false);
+        assert!(matches!(result, Err(SyncError::StartupTimeout)));
+        // The slow command was killed rather than waited for
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let mut fast = Command::new("echo");
+        fast.arg("done");
+        let output =
+            DirSyncer::run_until(fast, Some(Instant::now() + Duration::from_secs(30)), false)
+                .unwrap();
+        assert_eq!(output.stdout, b"done\n");
+    }
 
+    #[test]
+    fn test_dir_syncer_command_progress() {
+        let lower_dir = LowerDir::new_with_sync(
+            PathBuf::from("/source"),
+            None,
+            SyncMode::Once(PathBuf::from("/target")),
+        )
+        .unwrap();
+        assert!(!command_args(&lower_dir).contains(&"--info=progress2".to_string()));
+
+        let lower_dir = lower_dir.with_sync_options(SyncOptions {
+            progress: true,
+            ..Default::default()
+        });
+        // Only added for the sync itself, not the dry run diffs parse itemized output from
+        assert!(!command_args(&lower_dir).contains(&"--info=progress2".to_string()));
+        let seed = SeedDir::new(PathBuf::from("/seed")).with_sync_options(SyncOptions {
+            progress: true,
+            ..Default::default()
+        });
+        let args: Vec<_> = seed_command(&seed, Path::new("/upper"))
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
         assert_eq!(
-            original_config.lower_dirs.len(),
+            args[args.len() - 3..],
+            ["--info=progress2", "/seed/", "/upper"]
+        );
+    }
+
+    #[test]
+    fn test_run_as_drops_privileges() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let nobody = User::from_name("nobody").unwrap().unwrap();
+
+        let mut command = Command::new("sh");
+        command.args(["-c", "id -u; id -g; id -G"]);
+        run_as(&mut command, "nobody");
+        let output = command.output().unwrap();
+        assert!(output.status.success(), "{output:?}");
+        let gid = nobody.gid.to_string();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!("{}\n{gid}\n{gid}\n", nobody.uid)
+        );
+
+        // An unknown user fails the spawn instead of running as root
+        let mut command = Command::new("true");
+        run_as(&mut command, "no-such-user-for-overlay-mount");
+        assert_eq!(
+            command.status().unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_sync_error_from_status() {
+        // Raw wait statuses, an exit code is in the second byte and a signal in the first
+        let exited = SyncError::from_status(ExitStatus::from_raw(23 << 8), b"partial transfer");
+        assert!(matches!(
+            &exited,
+            SyncError::RsyncFailed { code: 23, stderr } if stderr == "partial transfer"
+        ));
+        assert_eq!(exited.exit_code(), Some(23));
+        assert_eq!(exited.signal(), None);
+
+        let killed = SyncError::from_status(ExitStatus::from_raw(9), b"");
+        assert!(matches!(killed, SyncError::RsyncKilled { signal: 9, .. }));
+        assert_eq!(killed.exit_code(), None);
+        assert_eq!(killed.signal(), Some(9));
+        assert!(!killed.is_permanent());
+        assert_eq!(killed.to_string(), "rsync command was killed by signal 9: ");
+    }
+
+    #[test]
+    fn test_dir_syncer_run_until_reports_signal() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "kill -KILL $$"]);
+        assert!(matches!(
+            DirSyncer::run_until(command, None, false),
+            Err(SyncError::RsyncKilled { signal: 9, .. })
+        ));
+    }
+
+    #[test]
+    fn test_stream_in_background_reports_progress_lines() {
+        use std::sync::{Arc, Mutex};
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let recorded = lines.clone();
+        let stdout: &[u8] = b"\r        512  50%  1.00MB/s    0:00:00\r      1,024 100%  2.00MB/s    0:00:00 (xfr#1, to-chk=0/2)\n\nsent 1,200 bytes\n";
+        let output = stream_in_background(Some(stdout), move |line, in_place| {
+            recorded.lock().unwrap().push((line.to_string(), in_place));
+        })
+        .join()
+        .unwrap();
+
+        // Everything is still returned for error reporting
+        assert_eq!(output, stdout);
+        assert_eq!(
+            *lines.lock().unwrap(),
+            [
+                ("512  50%  1.00MB/s    0:00:00".to_string(), true),
+                (
+                    "1,024 100%  2.00MB/s    0:00:00 (xfr#1, to-chk=0/2)".to_string(),
+                    false
+                ),
+                ("sent 1,200 bytes".to_string(), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_progress_logger_throttles_in_place_updates() {
+        use std::sync::{Arc, Mutex};
+
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let recorded = logged.clone();
+        let log = progress_logger(Duration::from_secs(3600), move |line| {
+            recorded.lock().unwrap().push(line.to_string());
+        });
+        let stdout: &[u8] = b"\r  10%\r  20%\r  30%\r 100%\nsent 1,200 bytes\n";
+        stream_in_background(Some(stdout), log).join().unwrap();
+
+        // Only the first in place update made it within the interval, whole lines always do
+        assert_eq!(*logged.lock().unwrap(), ["10%", "100%", "sent 1,200 bytes"]);
+    }
+
+    #[test]
+    fn test_dir_syncer_run_until_logs_progress() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "printf '  10%%\\r 100%%\\n'; echo oops >&2; exit 3"]);
+        // stderr is still captured while stdout is streamed
+        let result = DirSyncer::run_until(command, None, true);
+        assert!(
+            matches!(&result, Err(SyncError::RsyncFailed { code: 3, stderr }) if stderr == "oops\n"),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn test_sync_manager_startup_timeout_names_target() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let source_path = volume.join("source");
+        fs::create_dir_all(&source_path).unwrap();
+
+        let upper_dir = UpperDir::new(
+            volume.clone(),
+            PathBuf::from("upper"),
+            PathBuf::from("work"),
+            PathBuf::from("merged"),
+        )
+        .unwrap();
+        let mount_config = MountConfig::new_for_test(
+            vec![
+                LowerDir::new(volume.join("static"), None).unwrap(),
+                LowerDir::new_with_sync(
+                    source_path.clone(),
+                    None,
+                    SyncMode::Once(volume.join("target")),
+                )
+                .unwrap(),
+            ],
+            upper_dir,
+        );
+
+        let result = SyncManager::new_with_startup_timeout(
+            mount_config.validate().unwrap(),
+            Some(Duration::ZERO),
+        );
+        match result {
+            Err(SyncFailure {
+                config_ref: ConfigRef::LowerDir { index, lower_dir },
+                error: SyncError::StartupTimeout,
+            }) => {
+                assert_eq!(index, 1);
+                assert_eq!(lower_dir.full_path(), source_path);
+            }
+            Err(e) => panic!("unexpected error: {e}"),
+            Ok(_) => panic!("expected startup timeout"),
+        }
+    }
+
+    #[test]
+    fn test_sync_failure_names_config_entry() {
+        let lower_dir = LowerDir::new_with_sync(
+            PathBuf::from("/sources"),
+            Some(PathBuf::from("app")),
+            SyncMode::Constant(PathBuf::from("/synced/app")),
+        )
+        .unwrap();
+        let failure = SyncFailure {
+            config_ref: ConfigRef::LowerDir {
+                index: 2,
+                lower_dir: Box::new(lower_dir),
+            },
+            error: SyncError::StartupTimeout,
+        };
+        assert_eq!(
+            failure.to_string(),
+            "failed to sync lower_dirs[2] (volume \"/sources\", subdir \"app\", synced constantly \
+             to \"/synced/app\"): startup timeout exceeded before the sync finished"
+        );
+
+        let seed = ConfigRef::SeedDir {
+            index: 0,
+            seed: Box::new(SeedDir::new(PathBuf::from("/defaults"))),
+        };
+        assert_eq!(seed.to_string(), "seed_dirs[0] (source \"/defaults\")");
+    }
+
+    #[test]
+    fn test_sync_manager_seed_failure_names_seed() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let upper_dir = UpperDir::new(
+            volume.clone(),
+            PathBuf::from("upper"),
+            PathBuf::from("work"),
+            PathBuf::from("merged"),
+        )
+        .unwrap();
+        let mut mount_config = MountConfig::new_for_test(
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+            upper_dir,
+        );
+        mount_config.seed_dirs = vec![
+            SeedDir::new(volume.join("defaults")),
+            SeedDir::new(volume.join("more-defaults")),
+        ];
+
+        let result = SyncManager::new_with_startup_timeout(
+            mount_config.validate().unwrap(),
+            Some(Duration::ZERO),
+        );
+        match result {
+            Err(SyncFailure {
+                config_ref: ConfigRef::SeedDir { index, seed },
+                error: SyncError::StartupTimeout,
+            }) => {
+                assert_eq!(index, 0);
+                assert_eq!(seed.source(), volume.join("defaults"));
+            }
+            Err(e) => panic!("unexpected error: {e}"),
+            Ok(_) => panic!("expected startup timeout"),
+        }
+    }
+
+    #[test]
+    fn test_dir_syncer_command_filter_file() {
+        let lower_dir = LowerDir::new_with_sync(
+            PathBuf::from("/source"),
+            None,
+            SyncMode::Once(PathBuf::from("/target")),
+        )
+        .unwrap()
+        .with_sync_options(SyncOptions {
+            filter_file: Some(PathBuf::from("/etc/rsync filters")),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            command_args(&lower_dir),
+            [
+                "-av",
+                "--delete",
+                "--filter=merge /etc/rsync filters",
+                "/source/",
+                "/target"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dir_syncer_filter_file_excludes_subtree() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        let source_path = volume.join("source");
+        create_test_file(&source_path, "keep/file.txt", "kept");
+        create_test_file(&source_path, "cache/big.bin", "excluded");
+        let filter_file = create_test_file(&volume, "filters", "- /cache/\n");
+
+        let target_path = volume.join("target");
+        let lower_dir =
+            LowerDir::new_with_sync(source_path, None, SyncMode::Once(target_path.clone()))
+                .unwrap()
+                .with_sync_options(SyncOptions {
+                    filter_file: Some(filter_file),
+                    ..Default::default()
+                });
+
+        DirSyncer::sync(&lower_dir).unwrap();
+        assert!(target_path.join("keep/file.txt").exists());
+        assert!(!target_path.join("cache").exists());
+    }
+
+    #[test]
+    fn test_dir_syncer_command_base_args() {
+        let lower_dir = LowerDir::new_with_sync(
+            PathBuf::from("/source"),
+            None,
+            SyncMode::Once(PathBuf::from("/target")),
+        )
+        .unwrap()
+        .with_sync_options(SyncOptions {
+            rsync_base_args: Some(vec!["-rtlpD".to_string(), "--inplace".to_string()]),
+            numeric_ids: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            command_args(&lower_dir),
+            [
+                "-rtlpD",
+                "--inplace",
+                "--delete",
+                "--numeric-ids",
+                "/source/",
+                "/target"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dir_syncer_command_rsh() {
+        let rsh = "ssh -i /keys/id -p 2222 -o StrictHostKeyChecking=yes";
+        let lower_dir = LowerDir::new_with_sync(
+            PathBuf::from("backup@baseline:/srv/config"),
+            None,
+            SyncMode::Once(PathBuf::from("/target")),
+        )
+        .unwrap()
+        .with_sync_options(SyncOptions {
+            rsh: Some(rsh.to_string()),
+            ..Default::default()
+        });
+        // The whole command is a single argument, rsync does its own word splitting
+        assert_eq!(
+            command_args(&lower_dir),
+            [
+                "-av",
+                "--delete",
+                "-e",
+                rsh,
+                "backup@baseline:/srv/config/",
+                "/target"
+            ]
+        );
+
+        let local = LowerDir::new_with_sync(
+            PathBuf::from("/source"),
+            None,
+            SyncMode::Once(PathBuf::from("/target")),
+        )
+        .unwrap();
+        assert!(!command_args(&local).contains(&"-e".to_string()));
+    }
+
+    #[test]
+    fn test_dir_syncer_command_extra_args() {
+        let lower_dir = LowerDir::new_with_sync(
+            PathBuf::from("baseline:/srv/config"),
+            None,
+            SyncMode::Once(PathBuf::from("/target")),
+        )
+        .unwrap()
+        .with_sync_options(SyncOptions {
+            compress: true,
+            rsh: Some("ssh".to_string()),
+            rsync_extra_args: vec![
+                "--protocol=30".to_string(),
+                "--no-inc-recursive".to_string(),
+            ],
+            ..Default::default()
+        });
+        assert_eq!(
+            command_args(&lower_dir),
+            [
+                "-av",
+                "--delete",
+                "-z",
+                "-e",
+                "ssh",
+                "--protocol=30",
+                "--no-inc-recursive",
+                "baseline:/srv/config/",
+                "/target"
+            ]
+        );
+        // Per invocation args such as a dry run still come right before the paths
+        let args: Vec<_> = DirSyncer::command(&lower_dir, &["--dry-run"])
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            args[args.len() - 4..],
+            [
+                "--no-inc-recursive",
+                "--dry-run",
+                "baseline:/srv/config/",
+                "/target"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_remote_source() {
+        for remote in [
+            "baseline:/srv/config",
+            "backup@baseline:config",
+            "baseline::module/config",
+            "rsync://baseline/module",
+        ] {
+            assert!(is_remote_source(Path::new(remote)), "{remote}");
+        }
+        for local in ["/srv/config", "/srv/host:config", "config", "./baseline:1"] {
+            assert!(!is_remote_source(Path::new(local)), "{local}");
+        }
+    }
+
+    #[test]
+    fn test_dir_syncer_verify() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let source_path = volume.join("source");
+        create_test_file(&source_path, "file.txt", "content");
+
+        let lower_dir = LowerDir::new_with_sync(
+            source_path.clone(),
+            None,
+            SyncMode::Constant(volume.join("target")),
+        )
+        .unwrap()
+        .with_sync_options(SyncOptions {
+            verify_after_sync: true,
+            ..Default::default()
+        });
+
+        DirSyncer::sync(&lower_dir).unwrap();
+        DirSyncer::verify(&lower_dir).unwrap();
+
+        // Changed after the sync finished
+        create_test_file(&source_path, "late.txt", "late");
+        match DirSyncer::verify(&lower_dir) {
+            Err(SyncError::IncompleteSync(remaining)) => assert_eq!(
+                remaining,
+                vec![ChangedPath {
+                    path: PathBuf::from("late.txt"),
+                    kind: ChangeKind::Created,
+                }]
+            ),
+            other => panic!("expected incomplete sync, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_sync_result_accessors() {
+        let ok: SyncResult<SyncError> = SyncResult::Ok;
+        assert!(ok.is_ok());
+        assert!(!ok.is_fatal());
+        assert!(ok.error().is_none());
+
+        let transient = SyncResult::Transient(SyncError::StartupTimeout);
+        assert!(!transient.is_ok() && !transient.is_fatal());
+        assert_eq!(transient.error().and_then(SyncError::exit_code), None);
+        assert!(matches!(
+            transient.into_error(),
+            Some(SyncError::StartupTimeout)
+        ));
+
+        let fatal = SyncResult::Fatal(SyncError::RsyncFailed {
+            code: 23,
+            stderr: String::new(),
+        });
+        assert!(fatal.is_fatal());
+        assert_eq!(fatal.into_error().and_then(|e| e.exit_code()), Some(23));
+    }
+
+    #[test]
+    fn test_incomplete_sync_is_transient() {
+        let e = SyncError::IncompleteSync(vec![ChangedPath {
+            path: PathBuf::from("late.txt"),
+            kind: ChangeKind::Created,
+        }]);
+        assert!(!e.is_permanent());
+        assert_eq!(
+            e.to_string(),
+            "target still differs from the source after syncing, 1 change(s) remain"
+        );
+    }
+
+    #[test]
+    fn test_dir_syncer_command_skip_specials() {
+        let lower_dir = LowerDir::new_with_sync(
+            PathBuf::from("/source"),
+            None,
+            SyncMode::Once(PathBuf::from("/target")),
+        )
+        .unwrap()
+        .with_sync_options(SyncOptions {
+            skip_specials: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            command_args(&lower_dir),
+            ["-rlptgov", "--delete", "/source/", "/target"]
+        );
+    }
+
+    #[test]
+    fn test_dir_syncer_skip_specials_skips_fifo() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let source_path = volume.join("source");
+        create_test_file(&source_path, "file.txt", "content");
+        nix::unistd::mkfifo(
+            &source_path.join("pipe"),
+            nix::sys::stat::Mode::from_bits_truncate(0o644),
+        )
+        .unwrap();
+
+        let target_path = volume.join("target");
+        let lower_dir =
+            LowerDir::new_with_sync(source_path, None, SyncMode::Once(target_path.clone()))
+                .unwrap()
+                .with_sync_options(SyncOptions {
+                    skip_specials: true,
+                    ..Default::default()
+                });
+
+        DirSyncer::sync(&lower_dir).unwrap();
+        assert!(target_path.join("file.txt").exists());
+        assert!(fs::symlink_metadata(target_path.join("pipe")).is_err());
+    }
+
+    #[test]
+    fn test_dir_syncer_command_chmod() {
+        let lower_dir = LowerDir::new_with_sync(
+            PathBuf::from("/source"),
+            None,
+            SyncMode::Once(PathBuf::from("/target")),
+        )
+        .unwrap()
+        .with_sync_options(SyncOptions {
+            chmod: Some("D0755,F0644".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(
+            command_args(&lower_dir),
+            [
+                "-av",
+                "--delete",
+                "--chmod=D0755,F0644",
+                "/source/",
+                "/target"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dir_syncer_chmod_normalizes_modes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let source_path = volume.join("source");
+        create_test_file(&source_path, "nested/file.txt", "content");
+        fs::set_permissions(
+            source_path.join("nested/file.txt"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+        fs::set_permissions(
+            source_path.join("nested"),
+            fs::Permissions::from_mode(0o700),
+        )
+        .unwrap();
+
+        let target_path = volume.join("target");
+        let lower_dir =
+            LowerDir::new_with_sync(source_path, None, SyncMode::Once(target_path.clone()))
+                .unwrap()
+                .with_sync_options(SyncOptions {
+                    chmod: Some("D0755,F0644".to_string()),
+                    ..Default::default()
+                });
+
+        DirSyncer::sync(&lower_dir).unwrap();
+        let mode = |path: &str| {
+            fs::metadata(target_path.join(path))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+        assert_eq!(mode("nested"), 0o755);
+        assert_eq!(mode("nested/file.txt"), 0o644);
+    }
+
+    #[test]
+    fn test_dir_syncer_command_hardlinks() {
+        let lower_dir = LowerDir::new_with_sync(
+            PathBuf::from("/source"),
+            None,
+            SyncMode::Once(PathBuf::from("/target")),
+        )
+        .unwrap()
+        .with_sync_options(SyncOptions {
+            preserve_hardlinks: true,
+            link_dest: Some(PathBuf::from("/snapshot")),
+            ..Default::default()
+        });
+        assert_eq!(
+            command_args(&lower_dir),
+            [
+                "-av",
+                "--delete",
+                "-H",
+                "--link-dest=/snapshot",
+                "/source/",
+                "/target"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dir_syncer_hardlinks() {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+        let source_path = volume.join("source");
+        create_test_file(&source_path, "file.txt", "content");
+        fs::hard_link(source_path.join("file.txt"), source_path.join("link.txt")).unwrap();
+        let snapshot_path = volume.join("snapshot");
+        create_test_file(&snapshot_path, "file.txt", "content");
+
+        let target_path = volume.join("target");
+        let inode = |path: PathBuf| fs::metadata(path).unwrap().ino();
+        let sync_with = |options| {
+            let lower_dir = LowerDir::new_with_sync(
+                source_path.clone(),
+                None,
+                SyncMode::Once(target_path.clone()),
+            )
+            .unwrap()
+            .with_sync_options(options);
+            DirSyncer::sync(&lower_dir).unwrap();
+        };
+
+        sync_with(SyncOptions {
+            preserve_hardlinks: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            inode(target_path.join("file.txt")),
+            inode(target_path.join("link.txt"))
+        );
+
+        // Unchanged files are linked to the snapshot copy rather than copied
+        fs::remove_dir_all(&target_path).unwrap();
+        let copy = fs::metadata(source_path.join("file.txt")).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(snapshot_path.join("file.txt"))
+            .unwrap()
+            .set_modified(copy.modified().unwrap())
+            .unwrap();
+        sync_with(SyncOptions {
+            link_dest: Some(snapshot_path.clone()),
+            ..Default::default()
+        });
+        assert_eq!(
+            inode(target_path.join("file.txt")),
+            inode(snapshot_path.join("file.txt"))
+        );
+    }
+
+    #[test]
+    fn test_seed_command() {
+        let seed = SeedDir::new(PathBuf::from("/defaults")).with_sync_options(SyncOptions {
+            numeric_ids: true,
+            ..Default::default()
+        });
+        let args: Vec<_> = seed_command(&seed, Path::new("/volume/upper"))
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        // Never deletes from or overwrites files in the upper dir
+        assert_eq!(
+            args,
+            [
+                "-av",
+                "--ignore-existing",
+                "--numeric-ids",
+                "/defaults/",
+                "/volume/upper"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sync_manager_seeds_upper() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        let seed_path = volume.join("defaults");
+        create_test_file(&seed_path, "settings.conf", "default");
+        create_test_file(&seed_path, "nested/extra.conf", "default");
+        let upper_path = volume.join("upper");
+        create_test_file(&upper_path, "settings.conf", "modified");
+
+        let upper_dir = UpperDir::new(
+            volume.clone(),
+            PathBuf::from("upper"),
+            PathBuf::from("work"),
+            PathBuf::from("merged"),
+        )
+        .unwrap();
+        let mut mount_config = MountConfig::new_for_test(
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+            upper_dir,
+        );
+        mount_config.seed_dirs = vec![SeedDir::new(seed_path)];
+
+        let (sync_manager, _) = SyncManager::new(mount_config.validate().unwrap()).unwrap();
+        assert_eq!(sync_manager.targets.len(), 0);
+        assert_eq!(
+            fs::read_to_string(upper_path.join("nested/extra.conf")).unwrap(),
+            "default"
+        );
+        // Existing content in the upper dir wins
+        assert_eq!(
+            fs::read_to_string(upper_path.join("settings.conf")).unwrap(),
+            "modified"
+        );
+    }
+
+    #[test]
+    fn test_sync_manager_seeds_only_once() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        let seed_path = volume.join("snapshot");
+        create_test_file(&seed_path, "state.db", "snapshot");
+        create_test_file(&seed_path, "nested/extra.conf", "snapshot");
+        let upper_path = volume.join("upper");
+
+        let mount_config = || {
+            let upper_dir = UpperDir::new(
+                volume.clone(),
+                PathBuf::from("upper"),
+                PathBuf::from("work"),
+                PathBuf::from("merged"),
+            )
+            .unwrap();
+            let mut mount_config = MountConfig::new_for_test(
+                vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+                upper_dir,
+            );
+            mount_config.seed_dirs = vec![SeedDir::new(seed_path.clone()).with_only_once(true)];
+            mount_config
+        };
+        let marker = volume.join("upper.seeded");
+
+        // A fresh upper dir starts out as a copy of the seed
+        SyncManager::new(mount_config().validate().unwrap()).unwrap();
+        assert!(marker.exists());
+        assert_eq!(
+            fs::read_to_string(upper_path.join("state.db")).unwrap(),
+            "snapshot"
+        );
+        assert!(upper_path.join("nested/extra.conf").exists());
+
+        // Once seeded nothing is copied in again, not even files removed since
+        fs::write(upper_path.join("state.db"), "modified").unwrap();
+        fs::remove_dir_all(upper_path.join("nested")).unwrap();
+        SyncManager::new(mount_config().validate().unwrap()).unwrap();
+        assert_eq!(
+            fs::read_to_string(upper_path.join("state.db")).unwrap(),
+            "modified"
+        );
+        assert!(!upper_path.join("nested").exists());
+
+        // nor after the workload emptied the upper dir
+        fs::remove_dir_all(&upper_path).unwrap();
+        fs::create_dir(&upper_path).unwrap();
+        SyncManager::new(mount_config().validate().unwrap()).unwrap();
+        assert!(!upper_path.join("state.db").exists());
+    }
+
+    #[test]
+    fn test_sync_manager_skips_seeding_populated_upper() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        let seed_path = volume.join("snapshot");
+        create_test_file(&seed_path, "state.db", "snapshot");
+        let upper_path = volume.join("upper");
+        // Eg a volume in use before `only_once` was turned on, so without the marker
+        create_test_file(&upper_path, "existing.txt", "existing");
+
+        let upper_dir = UpperDir::new(
+            volume.clone(),
+            PathBuf::from("upper"),
+            PathBuf::from("work"),
+            PathBuf::from("merged"),
+        )
+        .unwrap();
+        let mut mount_config = MountConfig::new_for_test(
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+            upper_dir,
+        );
+        mount_config.seed_dirs = vec![SeedDir::new(seed_path).with_only_once(true)];
+        let marker = mount_config.upper_dir.seed_marker_path();
+
+        SyncManager::new(mount_config.validate().unwrap()).unwrap();
+        assert!(!upper_path.join("state.db").exists());
+        assert!(!marker.exists());
+        assert_eq!(
+            fs::read_to_string(upper_path.join("existing.txt")).unwrap(),
+            "existing"
+        );
+    }
+
+    #[test]
+    fn test_sync_manager_skips_seeding_seeded_upper() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        let seed_path = volume.join("snapshot");
+        create_test_file(&seed_path, "state.db", "snapshot");
+        let upper_path = volume.join("upper");
+        fs::create_dir_all(&upper_path).unwrap();
+
+        let upper_dir = UpperDir::new(
+            volume.clone(),
+            PathBuf::from("upper"),
+            PathBuf::from("work"),
+            PathBuf::from("merged"),
+        )
+        .unwrap();
+        let mut mount_config = MountConfig::new_for_test(
+            vec![LowerDir::new(volume.join("lower"), None).unwrap()],
+            upper_dir,
+        );
+        mount_config.seed_dirs = vec![SeedDir::new(seed_path).with_only_once(true)];
+        // An upper dir emptied after seeding
+        fs::write(mount_config.upper_dir.seed_marker_path(), "").unwrap();
+
+        SyncManager::new(mount_config.validate().unwrap()).unwrap();
+        assert!(!upper_path.join("state.db").exists());
+    }
+
+    #[test]
+    fn test_dir_syncer_command_compress() {
+        let lower_dir = LowerDir::new_with_sync(
+            PathBuf::from("/source"),
+            None,
+            SyncMode::Once(PathBuf::from("/target")),
+        )
+        .unwrap();
+
+        // Level alone doesn't enable compression
+        let uncompressed = lower_dir.clone().with_sync_options(SyncOptions {
+            compress_level: Some(9),
+            ..Default::default()
+        });
+        assert_eq!(
+            command_args(&uncompressed),
+            ["-av", "--delete", "/source/", "/target"]
+        );
+
+        let compressed = lower_dir.clone().with_sync_options(SyncOptions {
+            compress: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            command_args(&compressed),
+            ["-av", "--delete", "-z", "/source/", "/target"]
+        );
+
+        let with_level = lower_dir.with_sync_options(SyncOptions {
+            compress: true,
+            compress_level: Some(3),
+            ..Default::default()
+        });
+        assert_eq!(
+            command_args(&with_level),
+            [
+                "-av",
+                "--delete",
+                "-z",
+                "--compress-level=3",
+                "/source/",
+                "/target"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dir_syncer_numeric_ids_preserves_ownership() {
+        use std::os::unix::fs::MetadataExt;
+
+        // chown to an arbitrary id requires root
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        let source_path = volume.join("source");
+        let file = create_test_file(&source_path, "owned.txt", "owned content");
+        // Use ids that are very unlikely to have a name in /etc/passwd
+        std::os::unix::fs::chown(&file, Some(54321), Some(54322)).unwrap();
+
+        let target_path = volume.join("target");
+        let lower_dir =
+            LowerDir::new_with_sync(source_path, None, SyncMode::Once(target_path.clone()))
+                .unwrap()
+                .with_sync_options(SyncOptions {
+                    numeric_ids: true,
+                    ..Default::default()
+                });
+
+        let _syncer = DirSyncer::new(0, &lower_dir, None).unwrap();
+
+        let metadata = fs::metadata(target_path.join("owned.txt")).unwrap();
+        assert_eq!(metadata.uid(), 54321);
+        assert_eq!(metadata.gid(), 54322);
+    }
+
+    #[test]
+    fn test_sync_manager_last_sync_ages() {
+        let once = LowerDir::new_with_sync(
+            PathBuf::from("/source/once"),
+            None,
+            SyncMode::Once(PathBuf::from("/target/once")),
+        )
+        .unwrap();
+        let constant = LowerDir::new_with_sync(
+            PathBuf::from("/source/constant"),
+            None,
+            SyncMode::Constant(PathBuf::from("/target/constant")),
+        )
+        .unwrap();
+
+        let now = Instant::now();
+        let sync_manager = SyncManager {
+            targets: vec![
+                DirSyncer {
+                    index: 0,
+                    target: once,
+                    last_successful_sync: now - Duration::from_secs(30),
+                    history: VecDeque::new(),
+                },
+                DirSyncer {
+                    index: 1,
+                    target: constant,
+                    last_successful_sync: now - Duration::from_secs(300),
+                    history: VecDeque::new(),
+                },
+            ],
+        };
+
+        let ages = sync_manager.last_sync_ages();
+        assert_eq!(ages.len(), 2);
+        assert_eq!(ages[0].0, PathBuf::from("/source/once"));
+        assert!(ages[0].1 >= Duration::from_secs(30) && ages[0].1 < Duration::from_secs(60));
+        assert_eq!(ages[1].0, PathBuf::from("/source/constant"));
+        assert!(ages[1].1 >= Duration::from_secs(300) && ages[1].1 < Duration::from_secs(330));
+    }
+
+    #[test]
+    fn test_flapping_targets() {
+        let syncer = |source: &str| DirSyncer {
+            index: 0,
+            target: LowerDir::new_with_sync(
+                PathBuf::from(source),
+                None,
+                SyncMode::Constant(PathBuf::from("/target")),
+            )
+            .unwrap(),
+            last_successful_sync: Instant::now(),
+            history: VecDeque::new(),
+        };
+        let transient = || {
+            SyncResult::Transient(SyncError::RsyncFailed {
+                code: 23,
+                stderr: String::new(),
+            })
+        };
+        let mut sync_manager = SyncManager {
+            targets: vec![syncer("/source/flapping"), syncer("/source/stable")],
+        };
+
+        // Alternating results reach the threshold of three failures on the fifth sync
+        for (i, failed) in [true, false, true, false, true].into_iter().enumerate() {
+            assert!(sync_manager.flapping_targets(3).is_empty(), "sync {i}");
+            let result = if failed { transient() } else { SyncResult::Ok };
+            sync_manager.targets[0].record(&result);
+            sync_manager.targets[1].record(&SyncResult::Ok);
+        }
+        assert_eq!(
+            sync_manager.flapping_targets(3),
+            [PathBuf::from("/source/flapping")]
+        );
+        assert!(sync_manager.flapping_targets(4).is_empty());
+
+        // Transient and fatal failures both count, and are told apart in the history
+        sync_manager.targets[1].record(&SyncResult::Fatal(SyncError::RsyncFailed {
+            code: 1,
+            stderr: String::new(),
+        }));
+        assert_eq!(
+            sync_manager.targets[0].history.back(),
+            Some(&SyncResult::Transient(()))
+        );
+        assert_eq!(
+            sync_manager.targets[1].history.back(),
+            Some(&SyncResult::Fatal(()))
+        );
+        assert_eq!(sync_manager.targets[1].recent_failures(), 1);
+
+        // Failures age out of the history
+        for _ in 0..SYNC_HISTORY_LEN - 2 {
+            sync_manager.targets[0].record(&SyncResult::Ok);
+        }
+        assert_eq!(sync_manager.targets[0].history.len(), SYNC_HISTORY_LEN);
+        assert_eq!(sync_manager.targets[0].recent_failures(), 1);
+        assert!(sync_manager.flapping_targets(3).is_empty());
+    }
+
+    #[test]
+    fn test_sync_all_records_history() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut sync_manager = SyncManager {
+            targets: vec![DirSyncer {
+                index: 0,
+                target: LowerDir::new_with_sync(
+                    temp_dir.path().join("missing"),
+                    None,
+                    SyncMode::Once(temp_dir.path().join("target")),
+                )
+                .unwrap(),
+                last_successful_sync: Instant::now(),
+                history: VecDeque::new(),
+            }],
+        };
+
+        assert!(sync_manager.sync_all().is_err());
+        assert_eq!(sync_manager.targets[0].history.len(), 1);
+        assert_eq!(sync_manager.flapping_targets(1).len(), 1);
+    }
+
+    #[test]
+    fn test_dir_syncer_classify_exit_codes() {
+        let syncer = DirSyncer {
+            index: 0,
+            target: LowerDir::new_with_sync(
+                PathBuf::from("/source"),
+                None,
+                SyncMode::Constant(PathBuf::from("/target")),
+            )
+            .unwrap(),
+            last_successful_sync: Instant::now(),
+            history: VecDeque::new(),
+        };
+        let rsync_failed = |code| SyncError::RsyncFailed {
+            code,
+            stderr: String::new(),
+        };
+        let max_age = Some(Duration::from_secs(60));
+
+        // Usage and protocol errors are fatal even though the last sync was recent
+        for code in [1, 2] {
+            assert!(matches!(
+                syncer.classify(rsync_failed(code), max_age),
+                SyncResult::Fatal(_)
+            ));
+        }
+
+        // Network, partial transfer and timeout errors can clear up by themselves
+        for code in [10, 12, 23, 24, 30, -1] {
+            assert!(matches!(
+                syncer.classify(rsync_failed(code), max_age),
+                SyncResult::Transient(_)
+            ));
+        }
+        assert!(matches!(
+            syncer.classify(
+                SyncError::CommandError(std::io::Error::other("spawn failed")),
+                max_age
+            ),
+            SyncResult::Transient(_)
+        ));
+
+        // ...until the last success is too old
+        let stale = DirSyncer {
+            last_successful_sync: Instant::now() - Duration::from_secs(120),
+            ..syncer
+        };
+        assert!(matches!(
+            stale.classify(rsync_failed(23), max_age),
+            SyncResult::Fatal(_)
+        ));
+        // unless there is no age limit
+        assert!(matches!(
+            stale.classify(rsync_failed(23), None),
+            SyncResult::Transient(_)
+        ));
+    }
+
+    #[test]
+    fn test_sync_once_targets_stale_failure_not_fatal() {
+        let temp_dir = TempDir::new().unwrap();
+        // Fails whether or not rsync is installed, as the source is missing
+        let once = LowerDir::new_with_sync(
+            temp_dir.path().join("missing"),
+            None,
+            SyncMode::Once(temp_dir.path().join("target")),
+        )
+        .unwrap();
+        let mut sync_manager = SyncManager {
+            targets: vec![DirSyncer {
+                index: 0,
+                target: once,
+                // Synced at startup long before the sync timeout
+                last_successful_sync: Instant::now() - Duration::from_secs(3600),
+                history: VecDeque::new(),
+            }],
+        };
+
+        let results = sync_manager.sync_once_targets();
+        assert_eq!(results.len(), 1);
+        assert!(
+            matches!(results[0].1, SyncResult::Transient(_)),
+            "{:?}",
+            results[0].1
+        );
+    }
+
+    #[test]
+    fn test_sync_with_calls_back_per_target() {
+        let temp_dir = TempDir::new().unwrap();
+        let syncer = |name: &str, mode: fn(PathBuf) -> SyncMode| DirSyncer {
+            index: 0,
+            target: LowerDir::new_with_sync(
+                temp_dir.path().join(name),
+                None,
+                mode(temp_dir.path().join("target").join(name)),
+            )
+            .unwrap(),
+            last_successful_sync: Instant::now(),
+            history: VecDeque::new(),
+        };
+        let mut sync_manager = SyncManager {
+            targets: vec![
+                syncer("a", SyncMode::Constant),
+                syncer("b", SyncMode::Constant),
+                syncer("c", SyncMode::Once),
+            ],
+        };
+
+        // Called for failed syncs too
+        let mut calls = 0;
+        sync_manager.try_sync_with(Duration::from_secs(60), || calls += 1);
+        assert_eq!(calls, 2);
+        let mut calls = 0;
+        sync_manager.sync_once_targets_with(|| calls += 1);
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_parse_itemized() {
+        let output = "\
+sending incremental file list
+.d..t...... ./
+>f+++++++++ new.txt
+>f.st...... changed.txt
+.f...p..... chmod.txt
+cd+++++++++ newdir/
+*deleting   old dir/file.txt
+
+sent 123 bytes  received 45 bytes  336.00 bytes/sec
+total size is 42  speedup is 0.25 (DRY RUN)
+";
+        let changes = ChangedPath::parse_itemized(output);
+        let expected = [
+            ("./", ChangeKind::Attributes),
+            ("new.txt", ChangeKind::Created),
+            ("changed.txt", ChangeKind::Updated),
+            ("chmod.txt", ChangeKind::Attributes),
+            ("newdir/", ChangeKind::Created),
+            ("old dir/file.txt", ChangeKind::Deleted),
+        ];
+        assert_eq!(
+            changes,
+            expected
+                .iter()
+                .map(|(path, kind)| ChangedPath {
+                    path: PathBuf::from(path),
+                    kind: *kind,
+                })
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_dir_syncer_diff() {
+        let temp_dir = TempDir::new().unwrap();
+        let volume = temp_dir.path().to_path_buf();
+
+        let source_path = volume.join("source");
+        create_test_file(&source_path, "same.txt", "same content");
+        create_test_file(&source_path, "changed.txt", "old content");
+
+        let target_path = volume.join("target");
+        let lower_dir = LowerDir::new_with_sync(
+            source_path.clone(),
+            None,
+            SyncMode::Constant(target_path.clone()),
+        )
+        .unwrap();
+        let _syncer = DirSyncer::new(0, &lower_dir, None).unwrap();
+
+        create_test_file(&source_path, "changed.txt", "new content, longer");
+        create_test_file(&source_path, "added.txt", "added");
+        fs::remove_file(source_path.join("same.txt")).unwrap();
+
+        let changes = DirSyncer::diff(&lower_dir).unwrap();
+        let find = |name: &str| {
+            changes
+                .iter()
+                .find(|change| change.path == Path::new(name))
+                .map(|change| change.kind)
+        };
+        assert_eq!(find("added.txt"), Some(ChangeKind::Created));
+        assert_eq!(find("changed.txt"), Some(ChangeKind::Updated));
+        assert_eq!(find("same.txt"), Some(ChangeKind::Deleted));
+
+        // Nothing was applied to the target
+        assert!(!target_path.join("added.txt").exists());
+        assert!(target_path.join("same.txt").exists());
+        assert_eq!(
+            fs::read_to_string(target_path.join("changed.txt")).unwrap(),
+            "old content"
+        );
+    }
+
+    #[test]
+    fn test_synced_config_conversion() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = create_test_mount_config(&temp_dir);
+        let original_config: &MountConfig = (&config).into();
+
+        let synced_config = SyncedConfig(config.clone().into());
+        let converted_config: MountConfig = synced_config.into();
+
+        assert_eq!(
+            original_config.lower_dirs.len(),
             converted_config.lower_dirs.len()
         );
     }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_dir_syncer_sync_span_fields() {
+        use std::collections::HashMap;
+        use std::sync::{Arc, Mutex};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id};
+        use tracing::{Event, Subscriber};
+        use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+        use tracing_subscriber::registry::LookupSpan;
+
+        #[derive(Default)]
+        struct Fields(HashMap<String, String>);
+
+        type RecordedSpans = Arc<Mutex<Vec<(String, HashMap<String, String>)>>>;
+
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                self.0
+                    .insert(field.name().to_string(), format!("{value:?}"));
+            }
+        }
+
+        /// Records the fields of every span and the span each event was emitted in
+        #[derive(Clone, Default)]
+        struct Capture {
+            spans: RecordedSpans,
+            event_spans: Arc<Mutex<Vec<String>>>,
+        }
+
+        impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Capture {
+            fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+                let mut fields = Fields::default();
+                attrs.record(&mut fields);
+                self.spans
+                    .lock()
+                    .unwrap()
+                    .push((attrs.metadata().name().to_string(), fields.0));
+            }
+
+            fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+                if let Some(span) = ctx.event_span(event) {
+                    self.event_spans
+                        .lock()
+                        .unwrap()
+                        .push(span.name().to_string());
+                }
+            }
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source");
+        let target = temp_dir.path().join("target");
+        create_test_file(&source, "file.txt", "content");
+        let lower_dir =
+            LowerDir::new_with_sync(source.clone(), None, SyncMode::Constant(target.clone()))
+                .unwrap();
+
+        let capture = Capture::default();
+        let subscriber = tracing_subscriber::registry().with(capture.clone());
+        // The span is recorded whether or not rsync itself succeeds
+        let _ = tracing::subscriber::with_default(subscriber, || DirSyncer::sync(&lower_dir));
+
+        let spans = capture.spans.lock().unwrap();
+        let (_, fields) = spans
+            .iter()
+            .find(|(name, _)| name == "sync")
+            .expect("sync span recorded");
+        assert_eq!(fields["source"], format!("{source:?}"));
+        assert_eq!(fields["target"], format!("{target:?}"));
+        assert!(
+            capture
+                .event_spans
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|name| name == "sync")
+        );
+    }
 }